@@ -10,8 +10,22 @@ const sorted = <T> (iter: Iterable<T>): T[] => Array.from(iter).sort();
 export class Query {
   private readonly modeTerms: ReadonlyArray<Set<string>> = Array(3).fill(void 0).map(() => new Set());
 
+  private readonly groups: Set<string>[] = [];
+
+  private readonly filters: Set<string> = new Set();
+
+  private readonly ranges: { field: string, min?: number, max?: number }[] = [];
+
+  private prefix: string | undefined;
+
   private continuation: number = 0;
 
+  private sort: { key: string, descending: boolean } | undefined;
+
+  private collapse: boolean = false;
+
+  private explain: boolean = false;
+
   public add (mode: Mode, ...terms: ReadonlyArray<string>): this {
     for (const w of terms) {
       this.modeTerms[mode].add(w);
@@ -19,6 +33,51 @@ export class Query {
     return this;
   }
 
+  // Require a term starting with `prefix`, such as the word being typed for autocomplete, in addition to the terms added.
+  // The worker matches the first indexed terms with the prefix, up to its `--maximum-prefix-expansions`.
+  public setPrefix (prefix: string | undefined): this {
+    this.prefix = prefix;
+    return this;
+  }
+
+  // Add a group of terms that must all be present. A document matches if it matches any group added.
+  public addGroup (...terms: ReadonlyArray<string>): this {
+    this.groups.push(new Set(terms));
+    return this;
+  }
+
+  // Require filter values, such as `category=books`, provided when building. Filter values are matched exactly.
+  public addFilter (...values: ReadonlyArray<string>): this {
+    for (const v of values) {
+      this.filters.add(v);
+    }
+    return this;
+  }
+
+  // Require a value of a range field provided when building between `min` and `max` inclusive. Leave a bound undefined to not limit that side.
+  public addRange (field: string, min?: number, max?: number): this {
+    this.ranges.push({field, min, max});
+    return this;
+  }
+
+  // Order results by a sort key provided when building, instead of by document order.
+  public setSort (key: string, descending: boolean = false): this {
+    this.sort = {key, descending};
+    return this;
+  }
+
+  // Return at most one result per group key provided when building, keeping the highest ranked.
+  public setCollapse (collapse: boolean = true): this {
+    this.collapse = collapse;
+    return this;
+  }
+
+  // Ask for an explanation of the results, if the worker was built with `--explain`.
+  public setExplain (explain: boolean = true): this {
+    this.explain = explain;
+    return this;
+  }
+
   public setContinuation (c: number): this {
     this.continuation = c;
     return this;
@@ -27,9 +86,19 @@ export class Query {
   public build (): string {
     return [
       `c=${this.continuation}`,
+      ...(this.sort ? [`s=${encodeURIComponent(this.sort.key)}`, `o=${this.sort.descending ? 'desc' : 'asc'}`] : []),
+      ...(this.collapse ? ['u=1'] : []),
+      ...(this.explain ? ['e=1'] : []),
       ...this.modeTerms
         .map((terms, mode) => sorted(terms).map(t => `t=${mode}_${encodeURIComponent(t)}`))
-        .reduce((flat, modeTerms) => flat.concat(modeTerms), [])
+        .reduce((flat, modeTerms) => flat.concat(modeTerms), []),
+      // The prefix must be the last term.
+      ...(this.prefix !== undefined ? ['p=1', `t=0_${encodeURIComponent(this.prefix)}`] : []),
+      ...this.groups
+        .map((terms, group) => sorted(terms).map(t => `g=${group}_${encodeURIComponent(t)}`))
+        .reduce((flat, groupTerms) => flat.concat(groupTerms), []),
+      ...sorted(this.filters).map(v => `f=${encodeURIComponent(v)}`),
+      ...this.ranges.map(({field, min, max}) => `r=${encodeURIComponent(`${field}:${min === undefined ? '' : min}:${max === undefined ? '' : max}`)}`),
     ].join('&');
   }
 }
@@ -42,6 +111,17 @@ export type SearchResponse<D> = {
   results: D[];
   continuation: number | null;
   total: number;
+  // Original ID of each result, at the same index, if document IDs were provided when building. An ID is null if it could not be fetched in time.
+  ids?: (string | null)[];
+  // Present and true if the worker skipped data that took too long to fetch, so results may be incomplete.
+  degraded?: boolean;
+  // Present if the query set explain.
+  explain?: {
+    // Amount of documents containing each known query term.
+    terms: { [term: string]: number };
+    // Query terms each result at the same index contains, and its proximity score if results were boosted.
+    results: { terms: string[]; proximity?: number }[];
+  };
 };
 
 export class Client<D> {
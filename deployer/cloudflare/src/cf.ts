@@ -80,12 +80,14 @@ export const publishWorker = ({
   script,
   wasm,
   kvNamespaceId,
+  analyticsDataset,
 }: {
   auth: CFAuth;
   name: string;
   script: Buffer;
   wasm: Buffer;
   kvNamespaceId: string | undefined;
+  analyticsDataset: string | undefined;
 }) => makeRequest<{
   script: string;
   etag: string;
@@ -109,6 +111,11 @@ export const publishWorker = ({
           type: 'kv_namespace',
           namespace_id: kvNamespaceId,
         },
+        analyticsDataset && {
+          name: 'QUERY_ANALYTICS',
+          type: 'analytics_engine',
+          dataset: analyticsDataset,
+        },
       ].filter(b => b),
     }))
     .add('script', script)
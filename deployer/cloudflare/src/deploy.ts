@@ -55,14 +55,30 @@ class UploadStateManager {
   }
 }
 
-const listDirChunks = async (dir: string): Promise<number[]> =>
-  (await fs.readdir(dir))
-    .map(e => Number.parseInt(e, 10))
-    .sort((a, b) => a - b);
+type ChunkFile = {
+  id: number;
+  path: string;
+};
+
+// Chunk files may be nested in subfolders, depending on the chunk layout used when building.
+const listDirChunks = async (dir: string): Promise<ChunkFile[]> => {
+  const chunks: ChunkFile[] = [];
+  for (const entry of await fs.readdir(dir, {withFileTypes: true})) {
+    const path = join(dir, entry.name);
+    if (entry.isDirectory()) {
+      chunks.push(...await listDirChunks(path));
+    } else if (/^[0-9]+$/.test(entry.name)) {
+      // Skip precompressed copies such as `3.gz`, which are only for static hosts.
+      chunks.push({id: Number.parseInt(entry.name, 10), path});
+    }
+  }
+  return chunks.sort((a, b) => a.id - b.id);
+};
 
 export const deploy = async ({
   accountEmail,
   accountId,
+  analyticsDataset,
   globalApiKey,
   kvNamespaceId,
   name,
@@ -71,6 +87,7 @@ export const deploy = async ({
 }: {
   accountEmail: string;
   accountId: string;
+  analyticsDataset: string | undefined;
   globalApiKey: string;
   kvNamespaceId: string | undefined;
   name: string;
@@ -84,7 +101,7 @@ export const deploy = async ({
     ['worker.js', 'runner.wasm'].map(f => fs.readFile(join(outputDir, f))),
   );
 
-  await publishWorker({auth, name, kvNamespaceId, script, wasm});
+  await publishWorker({auth, name, kvNamespaceId, analyticsDataset, script, wasm});
   console.log('Worker uploaded');
 
   if (!kvNamespaceId || !uploadData) {
@@ -92,36 +109,108 @@ export const deploy = async ({
     return;
   }
 
+  // Keys may be prefixed with a namespace set when building, so use the template the build describes.
+  const {chunkLayout: {kvKey: kvKeyTemplate}} = JSON.parse(await fs.readFile(join(outputDir, 'format.json'), 'utf8'));
+  const kvKey = (dataset: string, id: number) => kvKeyTemplate.replace('{dataset}', dataset).replace('{id}', `${id}`);
+
   const uploadState = await UploadStateManager.forOutputDir(outputDir);
 
-  for (const chunkId of await listDirChunks(join(outputDir, 'documents'))) {
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'documents'))) {
     if (chunkId < uploadState.getNextDocumentsChunk()) {
       continue;
     }
     console.log(`Uploading documents chunk ${chunkId}...`);
     await uploadKv({
       auth,
-      key: `documents/${chunkId}`,
+      key: kvKey('documents', chunkId),
       namespaceId: kvNamespaceId,
-      value: await fs.readFile(join(outputDir, 'documents', `${chunkId}`)),
+      value: await fs.readFile(path),
     });
     await uploadState.incrementDocumentsChunk();
   }
 
-  for (const chunkId of await listDirChunks(join(outputDir, 'terms'))) {
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'terms'))) {
     if (chunkId < uploadState.getNextTermsChunk()) {
       continue;
     }
     console.log(`Uploading terms chunk ${chunkId}...`);
     await uploadKv({
       auth,
-      key: `terms/${chunkId}`,
+      key: kvKey('terms', chunkId),
       namespaceId: kvNamespaceId,
-      value: await fs.readFile(join(outputDir, 'terms', `${chunkId}`)),
+      value: await fs.readFile(path),
     });
     await uploadState.incrementTermsChunk();
   }
 
+  // Sort tables are few and small, so they are always uploaded.
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'sorts'))) {
+    console.log(`Uploading sort table ${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: kvKey('sorts', chunkId),
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(path),
+    });
+  }
+
+  // Document IDs are uploaded in full, as they are much smaller than documents.
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'ids'))) {
+    console.log(`Uploading document IDs chunk ${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: kvKey('ids', chunkId),
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(path),
+    });
+  }
+
+  // The group table, if any, is a single chunk.
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'groups'))) {
+    console.log(`Uploading group table ${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: kvKey('groups', chunkId),
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(path),
+    });
+  }
+
+  // Block tables, if any, are only fetched for queries with several terms.
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'blocks'))) {
+    console.log(`Uploading block tables chunk ${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: kvKey('blocks', chunkId),
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(path),
+    });
+  }
+
+  // The dictionary used to compress documents, if any, is a single small chunk.
+  for (const {id: chunkId, path} of await listDirChunks(join(outputDir, 'dictionaries'))) {
+    console.log(`Uploading dictionary ${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: kvKey('dictionaries', chunkId),
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(path),
+    });
+  }
+
+  // Filter bitmaps are packed into chunks like terms, and each range field is a single table.
+  for (const dataset of ['filters', 'ranges']) {
+    for (const {id: chunkId, path} of await listDirChunks(join(outputDir, dataset))) {
+      console.log(`Uploading ${dataset} chunk ${chunkId}...`);
+      await uploadKv({
+        auth,
+        key: kvKey(dataset, chunkId),
+        namespaceId: kvNamespaceId,
+        value: await fs.readFile(path),
+      });
+    }
+  }
+
   await uploadState.delete();
   console.log(`Data successfully uploaded`);
 };
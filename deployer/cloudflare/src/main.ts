@@ -24,6 +24,13 @@ const cli = sacli.build({
           typeLabel: '<id>',
           description: 'Cloudflare account ID',
         },
+        {
+          name: 'analytics-dataset',
+          alias: 'a',
+          type: String,
+          typeLabel: '<dataset>',
+          description: 'Workers Analytics Engine dataset to bind for --query-log analyticsengine',
+        },
         {
           name: 'global-api-key',
           alias: 'k',
@@ -63,6 +70,7 @@ const cli = sacli.build({
         deploy({
           accountEmail: args['account-email'],
           accountId: args['account-id'],
+          analyticsDataset: args['analytics-dataset'],
           globalApiKey: args['global-api-key'],
           name: args['name'],
           kvNamespaceId: args['namespace'],
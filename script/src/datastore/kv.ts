@@ -6,9 +6,12 @@ type WorkersKVNamespace = {
 
 // Set by Cloudflare.
 declare var KV: WorkersKVNamespace;
+// Namespace of the build followed by a slash, such as `staging/`, or empty if keys aren't namespaced.
+declare var KV_KEY_PREFIX: string;
 
+// KV keys are always `{namespace}/{dataset}/{id}` or `{dataset}/{id}`, regardless of how chunk files are laid out.
 var fetchChunk = async (chunkIdPrefix: string, chunkId: number): Promise<ArrayBuffer> => {
-  const chunkData = await KV.get(`${chunkIdPrefix}${chunkId}`, 'arrayBuffer');
+  const chunkData = await KV.get(`${KV_KEY_PREFIX}${chunkIdPrefix}${chunkId}`, 'arrayBuffer');
   console.log('Fetched chunk from KV');
   return chunkData;
 };
@@ -1,7 +1,15 @@
 declare var DATASTORE_URL_PREFIX: string;
+// Whether to fetch the gzip copy of each chunk, which the host serves as is, and decompress it.
+declare var PRECOMPRESSED_GZIP: boolean;
 
 var fetchChunk = async (chunkIdPrefix: string, chunkId: number): Promise<ArrayBuffer> => {
-  const res = await fetch(`${DATASTORE_URL_PREFIX}${chunkIdPrefix}${chunkId}`);
+  const res = await fetch(`${DATASTORE_URL_PREFIX}${chunkPath(chunkIdPrefix, chunkId)}${PRECOMPRESSED_GZIP ? '.gz' : ''}`);
+  // Otherwise an error page would be read as chunk data. Failed fetches are retried.
+  if (!res.ok) {
+    throw new Error(`Fetching chunk ${chunkIdPrefix}${chunkId} failed with status ${res.status}`);
+  }
   console.log('Fetched chunk from KV');
-  return res.arrayBuffer();
+  return PRECOMPRESSED_GZIP
+    ? new Response(res.body!.pipeThrough(new DecompressionStream('gzip'))).arrayBuffer()
+    : res.arrayBuffer();
 };
@@ -1,16 +1,177 @@
 // Set by Cloudflare to the WebAssembly module that was uploaded alongside this script.
 declare var QUERY_RUNNER_WASM: WebAssembly.Module;
+// Set by Cloudflare to the Workers Analytics Engine dataset bound to this script, if searches are recorded to it.
+declare var QUERY_ANALYTICS: {
+  writeDataPoint (point: { blobs?: string[]; doubles?: number[]; indexes?: string[] }): void;
+};
 
 // Following variables are set by build/js.rs.
+// Terms whose postings lists only contain a sample of the documents with the term, mapped to the amount of documents actually with the term.
+declare var APPROXIMATE_TERMS: Map<string, number>;
+// Whether results of searches without a sort key are ranked by BM25, using the BM25 table.
+declare var BM25: boolean;
+// Maximum amount of chunks fetched at once for one lookup.
+declare var CHUNK_READ_CONCURRENCY: number;
+// Amount of times to retry a chunk fetch that fails before giving up.
+declare var CHUNK_READ_RETRIES: number;
+// Milliseconds to wait before the first retry of a chunk fetch, which doubles after each retry.
+declare var CHUNK_READ_RETRY_DELAY: number;
+// Maximum milliseconds to wait for a chunk to be fetched, or undefined to wait indefinitely.
+declare var CHUNK_READ_TIMEOUT: number | undefined;
+// Whether to return partial results when a chunk fetch times out, instead of failing the request.
+declare var CHUNK_READ_TIMEOUT_DEGRADE: boolean;
+// Amount of chunk files in each subfolder, or undefined if chunk files are not nested.
+declare var CHUNKS_PER_DIR: number | undefined;
+// How each document is compressed, or undefined if documents are stored as is. Keep in sync with build::compress::DocumentCompression.
+declare var DOCUMENT_COMPRESSION: 'gzip' | 'zstd' | undefined;
 // Total number of documents.
 declare var DOCUMENT_COUNT: number;
+// Internal ID of the first document in each chunk of original document IDs, or undefined if documents are identified by their position.
+declare var DOCUMENT_ID_CHUNKS: number[] | undefined;
+// Whether to serve documents by ID at `/doc/{id}`.
+declare var DOCUMENT_ROUTE: boolean;
+// Whether documents were stored when building. If not, search results are the internal IDs of matching documents.
+declare var DOCUMENTS_STORED: boolean;
+// HTTP status of search responses without any results. Keep in sync with build::EmptyResultBehaviour.
+declare var EMPTY_RESULT_STATUS: 200 | 404;
+// How to format error responses. Keep in sync with build::ErrorFormat.
+declare var ERROR_FORMAT: 'message' | 'envelope' | 'text';
+// Whether to drop terms beyond MAX_EXPANDED_TERMS instead of failing the request.
+declare var EXPANDED_TERMS_OVERFLOW_TRUNCATE: boolean;
+// Whether search requests can ask for an explanation of their results using `e=1`.
+declare var EXPLAIN: boolean;
+// Whether to handle requests using a fetch event listener. Otherwise, the generated module exports a handler that calls requestHandler.
+declare var FETCH_EVENT_LISTENER: boolean;
+// Whether documents have filter values, which search requests can require using `f`.
+declare var FILTERS: boolean;
+// Maximum amount of matching documents to scan when collapsing results by group key, or undefined if documents have no group keys.
+declare var GROUP_SCAN_LIMIT: number | undefined;
+// Maximum amount of terms a query can resolve to after all expansions, or undefined if not limited separately.
+declare var MAX_EXPANDED_TERMS: number | undefined;
+// Maximum amount of indexed terms the prefix of a prefix search is expanded to, or zero if prefix searches are disabled.
+declare var MAX_PREFIX_EXPANSIONS: number;
 // Maximum amount of terms a query can have across all modes.
 declare var MAX_QUERY_TERMS: number;
 // Maximum amount of results returned at once.
 declare var MAX_RESULTS: number;
+// Minimum UTF-8 length of each query term after normalisation.
+declare var MIN_QUERY_BYTES: number;
+// Minimum amount of terms a query must have across all modes, unless it has no terms at all.
+declare var MIN_QUERY_TERMS: number;
+// What to do with a required or group query term that was never indexed. Keep in sync with build::OnUnknownTerm.
+declare var ON_UNKNOWN_TERM: 'empty' | 'ignore' | 'error';
+// Maximum amount of matching documents to scan when boosting documents where query terms share a block, or undefined if terms have no block tables.
+declare var PROXIMITY_SCAN_LIMIT: number | undefined;
+// Where to record each search, or undefined to not record searches. Keep in sync with build::QueryLogSink.
+declare var QUERY_LOG: 'console' | 'analytics' | 'endpoint' | undefined;
+// URL to POST the record of each search to, if QUERY_LOG is 'endpoint'.
+declare var QUERY_LOG_ENDPOINT: string | undefined;
+// Fraction of searches to record.
+declare var QUERY_LOG_SAMPLE_RATE: number;
+// Names of range fields, where the index of each name is the ID of its range table chunk.
+declare var RANGE_FIELDS: string[];
+// Content-Type header of JSON responses.
+declare var RESPONSE_CONTENT_TYPE: string;
+// Whether to indent JSON responses for readability instead of minimising their size.
+declare var RESPONSE_PRETTY: boolean;
+// Fields to keep in each search result that is a JSON object, or undefined to return results as stored.
+declare var RESULT_PROJECTION: string[] | undefined;
+// Path that all routes are under, without a trailing slash. Empty if routes are at the root.
+declare var ROUTE_PREFIX: string;
+// Pages of 64 KiB in the runner's memory, which every query allocates from without freeing until the next query.
+declare var RUNNER_MEMORY_PAGES: number;
+// Whether search requests can be scoped to documents provided using `i`.
+declare var SCOPED_SEARCH: boolean;
+// Names of sort keys, where the index of each name is the ID of its sort table chunk.
+declare var SORT_KEYS: string[];
+// Whether to send search responses while documents are still being fetched, writing each result once it and the results before it are available.
+declare var STREAM_RESULTS: boolean;
+// Stages terms went through in order when building. Keep in sync with build::AnalyserStage.
+declare var TERM_ANALYSER: (
+  | { stage: 'lowercase'; caseFolding: 'unicode' | 'ascii' | 'turkic' }
+  | { stage: 'normalise'; form: 'NFC' | 'NFKC' }
+  | { stage: 'foldDiacritics' }
+  | { stage: 'stopwords'; words: Set<string> }
+)[];
+// Whether to serve the amount of documents containing a term at `/term/{term}/stats`.
+declare var TERM_STATS_ROUTE: boolean;
+// Length in UTF-8 bytes that terms longer than it were truncated to when building, if they were.
+declare var TERM_TRUNCATION_BYTES: number | undefined;
+// User code run after a response is produced, which can change its headers or return a Response to use instead. Keep in sync with build::WorkerConfig.
+declare var WORKER_MIDDLEWARE: ((request: Request, response: Response) => Promise<Response | undefined>) | undefined;
+// User code run before a request is handled, which can return a Response to respond with instead. Keep in sync with build::WorkerConfig.
+declare var WORKER_PRELUDE: ((request: Request) => Promise<Response | undefined>) | undefined;
+
+// Not yet part of the TypeScript DOM library, but available in Cloudflare Workers.
+declare class DecompressionStream {
+  constructor (format: 'gzip' | 'deflate');
+  readonly readable: ReadableStream<Uint8Array>;
+  readonly writable: WritableStream<Uint8Array>;
+}
 
 const exists = <V>(val: V | undefined): val is V => val !== undefined;
 
+// Get the path of a chunk file relative to the output folder.
+// Keep in sync with build::chunks::ChunkLayout::relative_path.
+const chunkPath = (chunkIdPrefix: string, chunkId: number): string => CHUNKS_PER_DIR === undefined
+  ? `${chunkIdPrefix}${chunkId}`
+  : `${chunkIdPrefix}${Math.floor(chunkId / CHUNKS_PER_DIR)}/${chunkId}`;
+
+class ChunkReadTimeoutError extends Error {
+}
+
+// Failed fetches are usually transient, so retry them with exponential backoff before failing the request.
+const fetchChunkWithRetries = async (chunkIdPrefix: string, chunkId: number): Promise<ArrayBuffer> => {
+  for (let attempt = 0; ; attempt++) {
+    try {
+      return await fetchChunk(chunkIdPrefix, chunkId);
+    } catch (err) {
+      if (attempt >= CHUNK_READ_RETRIES) {
+        throw err;
+      }
+      console.log('Retrying chunk fetch that failed');
+      await new Promise(resolve => setTimeout(resolve, CHUNK_READ_RETRY_DELAY * 2 ** attempt));
+    }
+  }
+};
+
+// The timeout covers all attempts, so retries can't make a request wait longer than configured.
+const fetchChunkWithTimeout = (chunkIdPrefix: string, chunkId: number): Promise<ArrayBuffer> => {
+  if (CHUNK_READ_TIMEOUT === undefined) {
+    return fetchChunkWithRetries(chunkIdPrefix, chunkId);
+  }
+  let timeout: any;
+  return Promise.race([
+    fetchChunkWithRetries(chunkIdPrefix, chunkId),
+    new Promise<never>((_, reject) => timeout = setTimeout(
+      () => reject(new ChunkReadTimeoutError(`Fetching chunk ${chunkIdPrefix}${chunkId} timed out`)),
+      CHUNK_READ_TIMEOUT,
+    )),
+  ]).finally(() => clearTimeout(timeout));
+};
+
+class UnknownTermError extends Error {
+}
+
+// State shared across all chunk fetches of one request.
+type RequestContext = {
+  // Set when some chunks could not be fetched in time and were skipped, so results may be incomplete.
+  degraded: boolean;
+  // Amount of chunks fetched, including ones that failed or timed out.
+  chunkReads: number;
+  // Set by searches once known, for recording them.
+  termCount: number;
+  resultCount: number;
+};
+
+const newRequestContext = (): RequestContext => ({degraded: false, chunkReads: 0, termCount: 0, resultCount: 0});
+
+// Fetch a chunk for a request, counting it towards the request's chunk reads.
+const fetchRequestChunk = (ctx: RequestContext, chunkIdPrefix: string, chunkId: number): Promise<ArrayBuffer> => {
+  ctx.chunkReads++;
+  return fetchChunkWithTimeout(chunkIdPrefix, chunkId);
+};
+
 // Easy reading and writing of memory sequentially without having to manage and update offsets/positions/pointers.
 class MemoryWalker {
   private readonly dataView: DataView;
@@ -168,7 +329,7 @@ const formatFromVarargs = (mem: MemoryWalker): string => mem
     return SPECIFIER_FORMATTERS[type](rawValue);
   }));
 
-const wasmMemory = new WebAssembly.Memory({initial: 1024});
+const wasmMemory = new WebAssembly.Memory({initial: RUNNER_MEMORY_PAGES});
 
 const wasmInstance = new WebAssembly.Instance(QUERY_RUNNER_WASM, {
   env: {
@@ -189,7 +350,16 @@ const queryRunner = wasmInstance.exports as {
   index_query_malloc (): number;
   index_query (input: number): number;
   find_chunk_containing_term (termPtr: number, termLen: number): number;
+  find_chunk_containing_term_blocks (termPtr: number, termLen: number): number;
+  find_chunk_containing_filter (valuePtr: number, valueLen: number): number;
   find_chunk_containing_doc (doc: number): number;
+  find_term_chunk (id: number): number;
+  postings_list_cardinality (serialisedPtr: number, serialisedLen: number): number;
+  postings_list_contains_each (serialisedPtr: number, serialisedLen: number, documentsPtr: number, count: number, outPtr: number): boolean;
+  index_explain_proximity (tableCount: number, tablesPtr: number, doc: number): number;
+  // Only available if documents are compressed with zstd.
+  load_document_dictionary (dictPtr: number, dictLen: number): boolean;
+  decompress_document (srcPtr: number, srcLen: number): number;
 };
 
 const queryRunnerMemory = new MemoryWalker(wasmMemory.buffer);
@@ -208,21 +378,42 @@ const responsePreflight = () => new Response(null, {
   headers: CORS_HEADERS,
 });
 
-const responseError = (error: string, status: number = 400) => new Response(JSON.stringify({error}), {
-  status, headers: {
-    'Content-Type': 'application/json',
-    ...CORS_HEADERS,
-  },
-});
+const JSON_HEADERS = {
+  'Content-Type': RESPONSE_CONTENT_TYPE,
+  ...CORS_HEADERS,
+};
 
-const responseRawJson = (json: string, status = 200) => new Response(json, {
-  status, headers: {
-    'Content-Type': 'application/json',
-    ...CORS_HEADERS,
-  },
+// The HTTP status of each category of error. Keep in sync with build/openapi.rs.
+const ERROR_STATUSES = {
+  invalid_query: 400,
+  unknown_term: 400,
+  not_found: 404,
+  too_many_terms: 413,
+  internal_error: 500,
+  timeout: 504,
+};
+
+type ErrorCode = keyof typeof ERROR_STATUSES;
+
+const TEXT_HEADERS = {
+  'Content-Type': 'text/plain; charset=utf-8',
+  ...CORS_HEADERS,
+};
+
+const responseError = (code: ErrorCode, message: string) => ERROR_FORMAT == 'text'
+  ? new Response(message, {
+    status: ERROR_STATUSES[code], headers: TEXT_HEADERS,
+  })
+  : new Response(JSON.stringify(ERROR_FORMAT == 'envelope' ? {error: {code, message}} : {error: message}, null, RESPONSE_PRETTY ? 2 : undefined), {
+    status: ERROR_STATUSES[code], headers: JSON_HEADERS,
+  });
+
+// Pretty printing requires parsing the entire response, so it's only done if enabled.
+const responseRawJson = (json: string, status = 200) => new Response(RESPONSE_PRETTY ? JSON.stringify(JSON.parse(json), null, 2) : json, {
+  status, headers: JSON_HEADERS,
 });
 
-const responseNoResults = () => responseRawJson(`{"results":[],"continuation":null,"total":0}`);
+const responseNoResults = (ctx: RequestContext) => responseRawJson(`{"results":[],"continuation":null,"total":0${ctx.degraded ? `,"degraded":true` : ''}}`, EMPTY_RESULT_STATUS);
 
 const allocateKey = (key: string | number) => {
   if (typeof key == 'string') {
@@ -241,24 +432,32 @@ type ChunkRef = {
   midPos: number;
 };
 
-const findContainingChunk = (key: string | number): ChunkRef | undefined => {
+const readChunkRef = (chunkRefPtr: number): ChunkRef | undefined => {
+  if (chunkRefPtr === 0) {
+    return undefined;
+  }
+  const chunkRef = queryRunnerMemory.forkAndJump(chunkRefPtr);
+  const chunkId = chunkRef.readUInt32LE();
+  const chunkMidPos = chunkRef.readUInt32LE();
+
+  return {id: chunkId, midPos: chunkMidPos};
+};
+
+const findContainingChunk = (chunkIdPrefix: string, key: string | number): ChunkRef | undefined => {
   let chunkRefPtr;
   let cKey = allocateKey(key);
   if (typeof cKey == 'number') {
     chunkRefPtr = queryRunner.find_chunk_containing_doc(cKey);
+  } else if (chunkIdPrefix == 'blocks/') {
+    chunkRefPtr = queryRunner.find_chunk_containing_term_blocks(cKey.ptr, cKey.len);
+  } else if (chunkIdPrefix == 'filters/') {
+    chunkRefPtr = queryRunner.find_chunk_containing_filter(cKey.ptr, cKey.len);
   } else {
     chunkRefPtr = queryRunner.find_chunk_containing_term(cKey.ptr, cKey.len);
   }
 
   console.log('Found containing chunk');
-  if (chunkRefPtr === 0) {
-    return undefined;
-  }
-  const chunkRef = queryRunnerMemory.forkAndJump(chunkRefPtr);
-  const chunkId = chunkRef.readUInt32LE();
-  const chunkMidPos = chunkRef.readUInt32LE();
-
-  return {id: chunkId, midPos: chunkMidPos};
+  return readChunkRef(chunkRefPtr);
 };
 
 const compareKey = (a: string | number, b: string | number): number => {
@@ -283,6 +482,8 @@ const searchInBstChunkJs = (chunk: MemoryWalker, targetKey: string | number): Ar
     const leftPos = chunk.readInt32LE();
     const rightPos = chunk.readInt32LE();
     const valueLen = chunk.readUInt32LE();
+    // Nodes with identical values share the same value bytes.
+    const valuePos = chunk.readUInt32LE();
     const cmp = compareKey(targetKey, currentKey);
     if (cmp < 0) {
       if (leftPos == -1) {
@@ -291,7 +492,7 @@ const searchInBstChunkJs = (chunk: MemoryWalker, targetKey: string | number): Ar
       chunk.jumpTo(leftPos);
     } else if (cmp == 0) {
       console.log('Found entry in chunk');
-      return chunk.readSlice(valueLen);
+      return chunk.jumpTo(valuePos).readSlice(valueLen);
     } else {
       if (rightPos == -1) {
         break;
@@ -303,19 +504,102 @@ const searchInBstChunkJs = (chunk: MemoryWalker, targetKey: string | number): Ar
   return undefined;
 };
 
-const findAllInChunks = async (chunkIdPrefix: string, keys: (string | number)[]): Promise<(ArrayBuffer | undefined)[]> => {
-  const results = [];
+const startsWithBytes = (bytes: Uint8Array, prefix: Uint8Array): boolean => bytes.length >= prefix.length && prefix.every((b, i) => bytes[i] == b);
+
+// Compare UTF-8 bytes, which is the order keys are in when building.
+const compareBytes = (a: Uint8Array, b: Uint8Array): number => {
+  const len = Math.min(a.length, b.length);
+  for (let i = 0; i < len; i++) {
+    if (a[i] != b[i]) {
+      return a[i] - b[i];
+    }
+  }
+  return a.length - b.length;
+};
+
+// Visit the entries of a chunk with string keys that start with `prefix` in key order, starting from the node at `pos`, until `visit` returns false.
+// Returns false if there are no more entries with the prefix, in this chunk or any later chunk, or `visit` returned false.
+const walkPrefixInBstChunkJs = (chunk: MemoryWalker, pos: number, prefix: Uint8Array, visit: (key: string, value: ArrayBuffer) => boolean): boolean => {
+  chunk.jumpTo(pos);
+  // Keep in sync with build::chunks::ChunkStrKey.
+  const key = new Uint8Array(chunk.readSlice(chunk.readUInt8()));
+  // Keep in sync with build::chunks::bst::BST::_serialise_node.
+  const leftPos = chunk.readInt32LE();
+  const rightPos = chunk.readInt32LE();
+  const valueLen = chunk.readUInt32LE();
+  const valuePos = chunk.readUInt32LE();
+  if (startsWithBytes(key, prefix)) {
+    return (leftPos == -1 || walkPrefixInBstChunkJs(chunk, leftPos, prefix, visit))
+      && visit(textDecoder.decode(key), chunk.jumpTo(valuePos).readSlice(valueLen))
+      && (rightPos == -1 || walkPrefixInBstChunkJs(chunk, rightPos, prefix, visit));
+  }
+  if (compareBytes(key, prefix) < 0) {
+    // Every key on the left is also before the prefix.
+    return rightPos == -1 || walkPrefixInBstChunkJs(chunk, rightPos, prefix, visit);
+  }
+  // Every key on the right is also past the prefix, so there are no more entries with it.
+  if (leftPos != -1) {
+    walkPrefixInBstChunkJs(chunk, leftPos, prefix, visit);
+  }
+  return false;
+};
+
+// Find the first MAX_PREFIX_EXPANSIONS indexed terms in term order that start with `prefix`, and their postings lists.
+// Terms with the prefix are contiguous and chunks are in term order, so chunks are read one at a time from the chunk the prefix would be in until there are enough terms or a term is past the prefix.
+const findPrefixExpansions = async (ctx: RequestContext, prefix: string): Promise<{ terms: string[], bitmaps: ArrayBuffer[] }> => {
+  const terms: string[] = [];
+  const bitmaps: ArrayBuffer[] = [];
+  const prefixBytes = textEncoder.encode(prefix);
+  let more = true;
+  for (let chunkRef = findContainingChunk('terms/', prefix); more && chunkRef; chunkRef = readChunkRef(queryRunner.find_term_chunk(chunkRef.id + 1))) {
+    let chunkData: ArrayBuffer;
+    try {
+      chunkData = await fetchRequestChunk(ctx, 'terms/', chunkRef.id);
+    } catch (err) {
+      if (!(err instanceof ChunkReadTimeoutError) || !CHUNK_READ_TIMEOUT_DEGRADE) {
+        throw err;
+      }
+      // Use the expansions found so far.
+      console.log('Skipped chunk that timed out');
+      ctx.degraded = true;
+      break;
+    }
+    more = walkPrefixInBstChunkJs(new MemoryWalker(chunkData), chunkRef.midPos, prefixBytes, (term, bitmap) => {
+      terms.push(term);
+      bitmaps.push(bitmap);
+      return terms.length < MAX_PREFIX_EXPANSIONS;
+    });
+  }
+  return {terms, bitmaps};
+};
+
+// If `stopOnMissing` is true, no more chunks are fetched once any key is known to be missing, so the results are only complete if no key is missing.
+// If provided, `onSettled` is called with the indices of keys whose result is final as soon as they are, such as after fetching each chunk, so results can be used before all chunks are fetched.
+const findAllInChunks = async (
+  ctx: RequestContext,
+  chunkIdPrefix: string,
+  keys: (string | number)[],
+  stopOnMissing: boolean = false,
+  onSettled?: (results: (ArrayBuffer | undefined)[], resultIdxs: number[]) => void,
+): Promise<(ArrayBuffer | undefined)[]> => {
+  const results: (ArrayBuffer | undefined)[] = [];
+  // Keys without a containing chunk are known to be missing without fetching anything.
+  const missingIdxs: number[] = [];
   // Group by chunk to avoid repeated fetches and memory management.
   const chunks = new Map<number, {
     keys: [(string | number), number][];
     midPos: number;
   }>();
   for (const key of keys) {
-    const chunkRef = findContainingChunk(key);
+    const chunkRef = findContainingChunk(chunkIdPrefix, key);
     // We reserve a spot in `results` and keep track of it so that results are in the same order as `keys`,
     // and missing keys have `undefined` and can be detected.
     const resultIdx = results.push(undefined) - 1;
     if (!chunkRef) {
+      if (stopOnMissing) {
+        return results;
+      }
+      missingIdxs.push(resultIdx);
       continue;
     }
     if (!chunks.has(chunkRef.id)) {
@@ -327,24 +611,211 @@ const findAllInChunks = async (chunkIdPrefix: string, keys: (string | number)[])
     chunks.get(chunkRef.id)!.keys.push([key, resultIdx]);
   }
 
-  // We want to process chunks one by one as otherwise we will run into memory limits
-  // from fetching and allocating memory for too many at once.
-  for (const [chunkId, {keys, midPos}] of chunks.entries()) {
-    const chunkData = await fetchChunk(chunkIdPrefix, chunkId);
-    // We need to reset as otherwise we might overflow memory with unused previous chunks.
-    // queryRunner.reset();
-    // const res = searchInBstChunk(chunkData, chunkRef.midPos, key);
-    for (const [key, resultIdx] of keys) {
-      const entry = searchInBstChunkJs(new MemoryWalker(chunkData).jumpTo(midPos), key);
-      if (!entry) {
+  if (onSettled && missingIdxs.length) {
+    onSettled(results, missingIdxs);
+  }
+
+  // Each chunk is fetched once, however many keys it contains.
+  // Fetching too many at once would run into subrequest and memory limits, so only CHUNK_READ_CONCURRENCY are fetched at a time.
+  const pending = [...chunks.entries()];
+  let stopped = false;
+  const fetchPending = async (): Promise<void> => {
+    for (let next = pending.shift(); next && !stopped; next = pending.shift()) {
+      const [chunkId, {keys, midPos}] = next;
+      let chunkData: ArrayBuffer;
+      try {
+        chunkData = await fetchRequestChunk(ctx, chunkIdPrefix, chunkId);
+      } catch (err) {
+        if (!(err instanceof ChunkReadTimeoutError) || !CHUNK_READ_TIMEOUT_DEGRADE) {
+          throw err;
+        }
+        // Treat all keys in the chunk as missing.
+        console.log('Skipped chunk that timed out');
+        ctx.degraded = true;
+        onSettled?.(results, keys.map(([_, resultIdx]) => resultIdx));
         continue;
       }
-      results[resultIdx] = entry;
+      for (const [key, resultIdx] of keys) {
+        const entry = searchInBstChunkJs(new MemoryWalker(chunkData).jumpTo(midPos), key);
+        if (!entry) {
+          if (stopOnMissing) {
+            // Don't start fetching any more chunks.
+            stopped = true;
+            return;
+          }
+          continue;
+        }
+        results[resultIdx] = entry;
+      }
+      onSettled?.(results, keys.map(([_, resultIdx]) => resultIdx));
     }
-  }
+  };
+  await Promise.all(Array.from({length: Math.min(CHUNK_READ_CONCURRENCY, pending.length)}, fetchPending));
   return results;
 };
 
+// The dictionary is fetched once per worker instance and reused across requests.
+let documentDictionary: Promise<ArrayBuffer> | undefined;
+// Whether the dictionary has been loaded into the runner, which keeps it for the lifetime of the instance.
+let documentDictionaryLoaded = false;
+
+const fetchDocumentDictionary = (): Promise<ArrayBuffer> => {
+  if (!documentDictionary) {
+    documentDictionary = fetchChunkWithTimeout('dictionaries/', 0);
+    // Don't keep failures such as timeouts, so that the next request tries again.
+    documentDictionary.catch(() => documentDictionary = undefined);
+  }
+  return documentDictionary;
+};
+
+// Get the JSON bytes of documents from their values in documents chunks.
+// Keep in sync with build::compress::encode_documents.
+const decodeDocuments = async (values: ArrayBuffer[]): Promise<Uint8Array[]> => {
+  if (DOCUMENT_COMPRESSION === undefined) {
+    return values.map(value => new Uint8Array(value));
+  }
+  // Documents that didn't shrink when compressed are stored as is.
+  const isCompressed = (value: ArrayBuffer) => new Uint8Array(value)[0] != 0;
+  if (DOCUMENT_COMPRESSION == 'gzip') {
+    return Promise.all(values.map(async value => {
+      const data = new Uint8Array(value, 1);
+      if (!isCompressed(value)) {
+        return data;
+      }
+      const decompressed = new Response(data).body!.pipeThrough(new DecompressionStream('gzip'));
+      return new Uint8Array(await new Response(decompressed).arrayBuffer());
+    }));
+  }
+  const dictionary = values.some(isCompressed) ? await fetchDocumentDictionary() : undefined;
+  // There must be no awaits from here on, as other requests could reset the runner in between.
+  if (dictionary && !documentDictionaryLoaded) {
+    // The runner keeps the dictionary across resets, so it's only loaded once per instance.
+    queryRunner.reset();
+    const dictionaryPtr = queryRunner.malloc(dictionary.byteLength);
+    queryRunnerMemory.forkAndJump(dictionaryPtr).writeAll(new Uint8Array(dictionary));
+    if (!queryRunner.load_document_dictionary(dictionaryPtr, dictionary.byteLength)) {
+      throw new Error('Failed to load document dictionary');
+    }
+    documentDictionaryLoaded = true;
+  }
+  return values.map(value => {
+    const data = new Uint8Array(value, 1);
+    if (!isCompressed(value)) {
+      return data;
+    }
+    // Each document is copied out before the next, so only one is in runner memory at a time.
+    queryRunner.reset();
+    const ptr = queryRunner.malloc(data.byteLength);
+    queryRunnerMemory.forkAndJump(ptr).writeAll(data);
+    const resultPtr = queryRunner.decompress_document(ptr, data.byteLength);
+    if (resultPtr === 0) {
+      throw new Error('Failed to decompress document');
+    }
+    // Synchronise with `decompressed_document_t` in wasm/zstd.c.
+    const result = queryRunnerMemory.forkAndJump(resultPtr);
+    const len = result.readUInt32LE();
+    // Copy out of runner memory, as it's reused by later queries.
+    return new Uint8Array(result.readAndDereferencePointer().readSlice(len));
+  });
+};
+
+// Look up the original ID of each document, or undefined if its chunk timed out and was skipped.
+// Keep in sync with build::ids::serialise_document_id_chunks.
+const findOriginalDocumentIds = async (ctx: RequestContext, documents: number[]): Promise<(string | undefined)[]> => {
+  const chunkStarts = DOCUMENT_ID_CHUNKS!;
+  const chunkIds = documents.map(docId => {
+    // Find the last chunk starting at or before the document.
+    let lo = 0, hi = chunkStarts.length - 1;
+    while (lo < hi) {
+      const mid = Math.ceil((lo + hi) / 2);
+      if (chunkStarts[mid] <= docId) {
+        lo = mid;
+      } else {
+        hi = mid - 1;
+      }
+    }
+    return lo;
+  });
+  const chunks = new Map<number, ArrayBuffer>();
+  for (const chunkId of new Set(chunkIds)) {
+    try {
+      chunks.set(chunkId, await fetchRequestChunk(ctx, 'ids/', chunkId));
+    } catch (err) {
+      if (!(err instanceof ChunkReadTimeoutError) || !CHUNK_READ_TIMEOUT_DEGRADE) {
+        throw err;
+      }
+      console.log('Skipped chunk that timed out');
+      ctx.degraded = true;
+    }
+  }
+  return documents.map((docId, i) => {
+    const chunk = chunks.get(chunkIds[i]);
+    if (!chunk) {
+      return undefined;
+    }
+    const view = new DataView(chunk);
+    const index = docId - chunkStarts[chunkIds[i]];
+    const idsPos = 4 + view.getUint32(0, true) * 4;
+    const start = index == 0 ? 0 : view.getUint32(4 + (index - 1) * 4, true);
+    const end = view.getUint32(4 + index * 4, true);
+    return textDecoder.decode(new Uint8Array(chunk, idsPos + start, end - start));
+  });
+};
+
+// Query terms must be transformed in the same way as terms were when building, otherwise they won't match.
+// Returns undefined if a stage drops the term, such as a stopword, in which case it is left out of the query.
+// Keep in sync with data::normalise::AnalyserStage.
+const analyseTerm = (term: string, keepStopwords: boolean = false): string | undefined => {
+  for (const stage of TERM_ANALYSER) {
+    switch (stage.stage) {
+    case 'lowercase':
+      switch (stage.caseFolding) {
+      case 'unicode':
+        term = term.toLowerCase();
+        break;
+      case 'ascii':
+        term = term.replace(/[A-Z]+/g, upper => upper.toLowerCase());
+        break;
+      case 'turkic':
+        // Lowercasing with the 'tr' locale would do the same, but depends on the locale data available.
+        term = term.replace(/I/g, 'ı').replace(/İ/g, 'i').toLowerCase();
+        break;
+      }
+      break;
+    case 'normalise':
+      term = term.normalize(stage.form);
+      break;
+    case 'foldDiacritics':
+      term = term.normalize('NFD').replace(/\p{M}/gu, '').normalize('NFC');
+      if (!term) {
+        return undefined;
+      }
+      break;
+    case 'stopwords':
+      if (!keepStopwords && stage.words.has(term)) {
+        return undefined;
+      }
+      break;
+    }
+  }
+  // Keep in sync with data::normalise::TermLengthLimit.
+  if (TERM_TRUNCATION_BYTES !== undefined) {
+    const bytes = textEncoder.encode(term);
+    if (bytes.length > TERM_TRUNCATION_BYTES) {
+      let end = TERM_TRUNCATION_BYTES;
+      // Move back to the start of the character the limit falls in, as UTF-8 continuation bytes are 0b10xxxxxx.
+      while (end > 0 && (bytes[end] & 0xc0) === 0x80) {
+        end--;
+      }
+      term = textDecoder.decode(bytes.subarray(0, end));
+      if (!term) {
+        return undefined;
+      }
+    }
+  }
+  return term;
+};
+
 // Keep order in sync with mode_t.
 type ParsedQuery = [
   // Require.
@@ -369,13 +840,191 @@ const parseQuery = (termsRaw: string[]): ParsedQuery | undefined => {
       return;
     }
     const mode = Number.parseInt(matches[1], 10);
-    const term = decodeURIComponent(matches[2]);
-    modeTerms[mode].push(term);
+    const term = analyseTerm(decodeURIComponent(matches[2]));
+    if (term !== undefined) {
+      modeTerms[mode].push(term);
+    }
   }
 
   return modeTerms;
 };
 
+// Take the raw last term of a prefix search, which must be required, and analyse it like other terms.
+// Stopwords are kept, as the prefix is usually an incomplete word, such as `the` while typing `theatre`.
+const parsePrefix = (termRaw: string | undefined): string | undefined => {
+  const matches = termRaw === undefined ? null : /^0_([^&]+)(?:&|$)/.exec(termRaw);
+  return matches ? analyseTerm(decodeURIComponent(matches[1]), true) : undefined;
+};
+
+// Each group is a conjunction of terms, and groups are combined using OR.
+type ParsedGroups = string[][];
+
+// Take raw group terms in the form `{group}_{term}` and parse them into an array of groups ordered by group number.
+const parseGroups = (termsRaw: string[]): ParsedGroups | undefined => {
+  const groups = new Map<number, string[]>();
+  for (const value of termsRaw) {
+    const matches = /^([0-9]+)_([^&]+)(?:&|$)/.exec(value);
+    if (!matches) {
+      return;
+    }
+    const group = Number.parseInt(matches[1], 10);
+    const term = analyseTerm(decodeURIComponent(matches[2]));
+    // Groups of only dropped terms are left out entirely.
+    if (term === undefined) {
+      continue;
+    }
+    if (!groups.has(group)) {
+      groups.set(group, []);
+    }
+    groups.get(group)!.push(term);
+  }
+
+  return [...groups.entries()].sort(([a], [b]) => a - b).map(([, terms]) => terms);
+};
+
+type Sort = {
+  // Keep in sync with build::sort::serialise_sort_table.
+  // document ID => rank.
+  ranks: Uint32Array;
+  // rank => document ID.
+  order: Uint32Array;
+  descending: boolean;
+};
+
+const fetchSort = async (ctx: RequestContext, name: string, descending: boolean): Promise<Sort | undefined> => {
+  const chunkId = SORT_KEYS.indexOf(name);
+  if (chunkId == -1) {
+    return undefined;
+  }
+  const table = await fetchRequestChunk(ctx, 'sorts/', chunkId);
+  const count = table.byteLength / 8;
+  return {
+    ranks: new Uint32Array(table, 0, count),
+    order: new Uint32Array(table, count * 4, count),
+    descending,
+  };
+};
+
+// A range of a numeric field that matching documents must have a value in, where both bounds are inclusive.
+type ParsedRange = {
+  field: string;
+  min: number;
+  max: number;
+};
+
+// Take raw ranges in the form `{field}:{min}:{max}` and parse them. Either bound can be empty to leave that side unbounded.
+const parseRanges = (rangesRaw: string[]): ParsedRange[] | undefined => {
+  const ranges: ParsedRange[] = [];
+  for (const value of rangesRaw) {
+    const matches = /^([^:]+):([^:]*):([^:]*)$/.exec(value);
+    if (!matches) {
+      return;
+    }
+    const min = matches[2] ? Number(matches[2]) : -Infinity;
+    const max = matches[3] ? Number(matches[3]) : Infinity;
+    if (Number.isNaN(min) || Number.isNaN(max)) {
+      return;
+    }
+    ranges.push({field: matches[1], min, max});
+  }
+  return ranges;
+};
+
+// Take a raw scope in the form described by build::scope::encode_scope and parse it.
+// Returns the start inclusive and end exclusive of each range of document IDs, flattened and clipped to the documents, or undefined if the scope is malformed.
+const parseScope = (scopeRaw: string): number[] | undefined => {
+  let bytes: string;
+  try {
+    bytes = atob(scopeRaw.replace(/-/g, '+').replace(/_/g, '/'));
+  } catch {
+    return;
+  }
+  const values: number[] = [];
+  let value = 0;
+  let shift = 0;
+  for (let i = 0; i < bytes.length; i++) {
+    const byte = bytes.charCodeAt(i);
+    value += (byte & 0x7f) * 2 ** shift;
+    shift += 7;
+    // Varints of more than five bytes can't be a u32.
+    if (shift > 35) {
+      return;
+    }
+    if (!(byte & 0x80)) {
+      values.push(value);
+      value = 0;
+      shift = 0;
+    }
+  }
+  if (shift || values.length % 2) {
+    return;
+  }
+  const scope: number[] = [];
+  let end = 0;
+  for (let i = 0; i < values.length; i += 2) {
+    const start = end + values[i];
+    end = start + values[i + 1];
+    if (!values[i + 1] || end > 2 ** 32) {
+      return;
+    }
+    if (start < DOCUMENT_COUNT) {
+      scope.push(start, Math.min(end, DOCUMENT_COUNT));
+    }
+  }
+  return scope;
+};
+
+type Range = {
+  // Keep in sync with build::range::serialise_range_table.
+  // document ID => rank.
+  ranks: Uint32Array;
+  // rank => document ID.
+  order: Uint32Array;
+  // The range covers the ranks from `start` inclusive to `end` exclusive.
+  start: number;
+  end: number;
+};
+
+const fetchRange = async (ctx: RequestContext, {field, min, max}: ParsedRange): Promise<Range | undefined> => {
+  const chunkId = RANGE_FIELDS.indexOf(field);
+  if (chunkId == -1) {
+    return undefined;
+  }
+  const table = await fetchRequestChunk(ctx, 'ranges/', chunkId);
+  const count = table.byteLength / 16;
+  // Values are in rank order, so ascending.
+  const values = new Float64Array(table, count * 8, count);
+  // Get the first rank whose value isn't before the bound.
+  const firstRankNotBefore = (isBefore: (value: number) => boolean): number => {
+    let lo = 0;
+    let hi = count;
+    while (lo < hi) {
+      const mid = (lo + hi) >>> 1;
+      if (isBefore(values[mid])) {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    return lo;
+  };
+  return {
+    ranks: new Uint32Array(table, 0, count),
+    order: new Uint32Array(table, count * 4, count),
+    start: firstRankNotBefore(value => value < min),
+    end: firstRankNotBefore(value => value <= max),
+  };
+};
+
+// Group ID of each document, in document ID order. Keep in sync with build::groups::serialise_group_table.
+const fetchGroupKeyIds = async (ctx: RequestContext): Promise<Uint32Array> => new Uint32Array(await fetchRequestChunk(ctx, 'groups/', 0));
+
+// Get the BM25 length normalisation of each document. Keep in sync with build::ranking::serialise_bm25_table.
+const fetchBm25Table = async (ctx: RequestContext): Promise<Float32Array> => new Float32Array(await fetchRequestChunk(ctx, 'ranking/', 0));
+
+// Get the BM25 inverse document frequency of a term in `documentCount` documents, which is never negative so that containing a term never lowers a score.
+const bm25Idf = (documentCount: number): number => Math.log(1 + (DOCUMENT_COUNT - documentCount + 0.5) / (documentCount + 0.5));
+
 type QueryResult = {
   continuation: number | null;
   total: number;
@@ -398,18 +1047,197 @@ const readResult = (result: MemoryWalker): QueryResult => {
   return {continuation: continuation == -1 ? null : continuation, total, documents};
 };
 
-const findSerialisedTermBitmaps = (query: ParsedQuery): Promise<(ArrayBuffer | undefined)[][]> =>
+const assertAllTermsKnown = (terms: string[], bitmaps: (ArrayBuffer | undefined)[]) => {
+  const unknownIdx = bitmaps.findIndex(bm => !bm);
+  if (unknownIdx != -1) {
+    throw new UnknownTermError(`Unknown term "${terms[unknownIdx]}"`);
+  }
+};
+
+// Returns undefined if a required term doesn't exist and unknown terms result in no results, as then other terms aren't fetched.
+// Throws UnknownTermError if unknown terms are errors.
+// Otherwise, unknown required terms have already been dropped according to ON_UNKNOWN_TERM.
+const findSerialisedTermBitmaps = async (ctx: RequestContext, query: ParsedQuery, groups: ParsedGroups): Promise<{
+  modeTermBitmaps: (ArrayBuffer | undefined)[][];
+  groupTermBitmaps: (ArrayBuffer | undefined)[][];
+  // Bitmap of every known term of any mode or group, for explaining results.
+  knownTermBitmaps: Map<string, ArrayBuffer>;
+} | undefined> => {
+  const knownTermBitmaps = new Map<string, ArrayBuffer>();
+  const addKnownTerms = (terms: string[], bitmaps: (ArrayBuffer | undefined)[]) => terms.forEach((term, i) => {
+    if (bitmaps[i]) {
+      knownTermBitmaps.set(term, bitmaps[i]!);
+    }
+  });
   // Keep in sync with deploy/mod.rs.
-  Promise.all(query.map(modeTerms => findAllInChunks('terms/', modeTerms)));
+  // Stopping at the first missing term leaves the other results incomplete, so it's only done when that means no results, and not when the unknown term must be named.
+  let requireTermBitmaps = await findAllInChunks(ctx, 'terms/', query[0], ON_UNKNOWN_TERM == 'empty');
+  addKnownTerms(query[0], requireTermBitmaps);
+  if (requireTermBitmaps.some(bm => !bm)) {
+    switch (ON_UNKNOWN_TERM) {
+    case 'empty':
+      return undefined;
+    case 'error':
+      assertAllTermsKnown(query[0], requireTermBitmaps);
+      break;
+    case 'ignore':
+      requireTermBitmaps = requireTermBitmaps.filter(bm => bm);
+      break;
+    }
+  }
+  const [otherModeTermBitmaps, groupTermBitmaps] = await Promise.all([
+    Promise.all(query.slice(1).map(modeTerms => findAllInChunks(ctx, 'terms/', modeTerms))),
+    Promise.all(groups.map(groupTerms => findAllInChunks(ctx, 'terms/', groupTerms))),
+  ]);
+  if (ON_UNKNOWN_TERM == 'error') {
+    otherModeTermBitmaps.forEach((bitmaps, i) => assertAllTermsKnown(query[i + 1], bitmaps));
+    groupTermBitmaps.forEach((bitmaps, i) => assertAllTermsKnown(groups[i], bitmaps));
+  }
+  otherModeTermBitmaps.forEach((bitmaps, i) => addKnownTerms(query[i + 1], bitmaps));
+  groupTermBitmaps.forEach((bitmaps, i) => addKnownTerms(groups[i], bitmaps));
+  return {
+    modeTermBitmaps: [requireTermBitmaps, ...otherModeTermBitmaps],
+    groupTermBitmaps: ON_UNKNOWN_TERM != 'ignore'
+      ? groupTermBitmaps
+      // Drop unknown terms from each group, and drop groups where every term is unknown so they don't match everything.
+      : groupTermBitmaps.map(bitmaps => bitmaps.filter(bm => bm)).filter(bitmaps => bitmaps.length),
+    knownTermBitmaps,
+  };
+};
+
+// The postings list of an approximate term is a sample, so intersecting it would drop most matches for little gain, as the term is in most documents anyway.
+// Such terms are therefore only used when all terms of a conjunction are approximate, as otherwise the exact terms already narrow results.
+const withoutApproximateTerms = (terms: string[]): string[] => {
+  const exact = terms.filter(term => !APPROXIMATE_TERMS.has(term));
+  return exact.length ? exact : terms;
+};
 
-const buildIndexQuery = async (firstRank: number, modeTermBitmaps: ArrayBuffer[][]): Promise<Uint8Array> => {
-  const bitmapCount = modeTermBitmaps.reduce((count, modeTerms) => count + modeTerms.length, 0);
+// Copy arrays, such as block tables or the postings lists of filter values or prefix expansions, into the runner's memory,
+// and return a pointer to the length and pointer of each, as expected by index_query_t.
+const allocateArrays = (arrays: ArrayBuffer[]): number => {
+  const arraysPtr = queryRunner.malloc(arrays.length * 2 * 4);
+  const refs = queryRunnerMemory.forkAndJump(arraysPtr);
+  for (const array of arrays) {
+    const ptr = queryRunner.malloc(array.byteLength);
+    queryRunnerMemory.forkAndJump(ptr).writeAll(new Uint8Array(array));
+    refs
+      .writeUInt32LE(array.byteLength)
+      .writeUInt32LE(ptr);
+  }
+  return arraysPtr;
+};
+
+// Copy range tables into the runner's memory, and return a pointer to an index_range_t for each range.
+const allocateRanges = (ranges: Range[]): number => {
+  const rangesPtr = queryRunner.malloc(ranges.length * 5 * 4);
+  const refs = queryRunnerMemory.forkAndJump(rangesPtr);
+  for (const range of ranges) {
+    const ranksPtr = queryRunner.malloc(range.ranks.byteLength);
+    queryRunnerMemory.forkAndJump(ranksPtr).writeAll(new Uint8Array(range.ranks.buffer, range.ranks.byteOffset, range.ranks.byteLength));
+    const orderPtr = queryRunner.malloc(range.order.byteLength);
+    queryRunnerMemory.forkAndJump(orderPtr).writeAll(new Uint8Array(range.order.buffer, range.order.byteOffset, range.order.byteLength));
+    refs
+      .writeUInt32LE(ranksPtr)
+      .writeUInt32LE(orderPtr)
+      .writeUInt32LE(range.ranks.length)
+      .writeUInt32LE(range.start)
+      .writeUInt32LE(range.end);
+  }
+  return rangesPtr;
+};
+
+const buildIndexQuery = async (firstRank: number, sort: Sort | undefined, groupKeyIds: Uint32Array | undefined, termBlockTables: ArrayBuffer[], filterBitmaps: ArrayBuffer[], ranges: Range[], prefixBitmaps: ArrayBuffer[], scope: number[], bm25Table: Float32Array | undefined, bm25Terms: [string, ArrayBuffer][], modeTermBitmaps: ArrayBuffer[][], groupTermBitmaps: ArrayBuffer[][]): Promise<Uint8Array> => {
+  const bitmapCount = [...modeTermBitmaps, ...groupTermBitmaps].reduce((count, terms) => count + terms.length, 0);
 
   // Synchronise with index_query_t.
-  const input = new MemoryWalker(new ArrayBuffer(4 + (bitmapCount * 2 + 3) * 4));
+  const input = new MemoryWalker(new ArrayBuffer(21 * 4 + (bitmapCount * 2 + 3 + groupTermBitmaps.length + 1) * 4));
   input.writeUInt32LE(firstRank);
-  for (const mode of modeTermBitmaps) {
-    for (const bitmap of mode) {
+  input.writeUInt32LE(sort && sort.descending ? 1 : 0);
+  if (sort) {
+    const ranksPtr = queryRunner.malloc(sort.ranks.byteLength);
+    queryRunnerMemory.forkAndJump(ranksPtr).writeAll(new Uint8Array(sort.ranks.buffer, sort.ranks.byteOffset, sort.ranks.byteLength));
+    const orderPtr = queryRunner.malloc(sort.order.byteLength);
+    queryRunnerMemory.forkAndJump(orderPtr).writeAll(new Uint8Array(sort.order.buffer, sort.order.byteOffset, sort.order.byteLength));
+    input
+      .writeUInt32LE(ranksPtr)
+      .writeUInt32LE(orderPtr);
+  } else {
+    input
+      .writeUInt32LE(0)
+      .writeUInt32LE(0);
+  }
+  if (groupKeyIds) {
+    const groupKeyIdsPtr = queryRunner.malloc(groupKeyIds.byteLength);
+    queryRunnerMemory.forkAndJump(groupKeyIdsPtr).writeAll(new Uint8Array(groupKeyIds.buffer, groupKeyIds.byteOffset, groupKeyIds.byteLength));
+    input
+      .writeUInt32LE(groupKeyIdsPtr)
+      .writeUInt32LE(GROUP_SCAN_LIMIT!);
+  } else {
+    input
+      .writeUInt32LE(0)
+      .writeUInt32LE(0);
+  }
+  // Boosting needs at least two terms to share a block.
+  if (termBlockTables.length >= 2) {
+    input
+      .writeUInt32LE(PROXIMITY_SCAN_LIMIT!)
+      .writeUInt32LE(termBlockTables.length)
+      .writeUInt32LE(allocateArrays(termBlockTables));
+  } else {
+    input
+      .writeUInt32LE(0)
+      .writeUInt32LE(0)
+      .writeUInt32LE(0);
+  }
+  input
+    .writeUInt32LE(filterBitmaps.length)
+    .writeUInt32LE(filterBitmaps.length ? allocateArrays(filterBitmaps) : 0);
+  input
+    .writeUInt32LE(ranges.length)
+    .writeUInt32LE(ranges.length ? allocateRanges(ranges) : 0);
+  input
+    .writeUInt32LE(prefixBitmaps.length)
+    .writeUInt32LE(prefixBitmaps.length ? allocateArrays(prefixBitmaps) : 0);
+  if (scope.length) {
+    const scopePtr = queryRunner.malloc(scope.length * 4);
+    queryRunnerMemory.forkAndJump(scopePtr).writeAll(new Uint8Array(new Uint32Array(scope).buffer));
+    input
+      .writeUInt32LE(scope.length / 2)
+      .writeUInt32LE(scopePtr);
+  } else {
+    input
+      .writeUInt32LE(0)
+      .writeUInt32LE(0);
+  }
+  if (bm25Table && bm25Terms.length) {
+    const tablePtr = queryRunner.malloc(bm25Table.byteLength);
+    queryRunnerMemory.forkAndJump(tablePtr).writeAll(new Uint8Array(bm25Table.buffer, bm25Table.byteOffset, bm25Table.byteLength));
+    const termsPtr = allocateArrays(bm25Terms.map(([, bitmap]) => bitmap));
+    const terms = queryRunnerMemory.forkAndJump(termsPtr);
+    const idfs = new Float32Array(bm25Terms.map(([term]) => {
+      const len = terms.readUInt32LE();
+      const ptr = terms.readUInt32LE();
+      // Postings lists of approximate terms are samples, so use the amount of documents actually with the term.
+      const approximateCount = APPROXIMATE_TERMS.get(term);
+      return bm25Idf(approximateCount !== undefined ? approximateCount : queryRunner.postings_list_cardinality(ptr, len));
+    }));
+    const idfsPtr = queryRunner.malloc(idfs.byteLength);
+    queryRunnerMemory.forkAndJump(idfsPtr).writeAll(new Uint8Array(idfs.buffer));
+    input
+      .writeUInt32LE(tablePtr)
+      .writeUInt32LE(bm25Terms.length)
+      .writeUInt32LE(termsPtr)
+      .writeUInt32LE(idfsPtr);
+  } else {
+    input
+      .writeUInt32LE(0)
+      .writeUInt32LE(0)
+      .writeUInt32LE(0)
+      .writeUInt32LE(0);
+  }
+  // Each mode and then each group is terminated by NULL.
+  for (const terms of [...modeTermBitmaps, ...groupTermBitmaps]) {
+    for (const bitmap of terms) {
       const ptr = queryRunner.malloc(bitmap.byteLength);
       queryRunnerMemory.forkAndJump(ptr).writeAll(new Uint8Array(bitmap));
       // WASM is LE.
@@ -419,10 +1247,38 @@ const buildIndexQuery = async (firstRank: number, modeTermBitmaps: ArrayBuffer[]
     }
     input.writeUInt32LE(0);
   }
+  // Groups are terminated by an additional NULL.
+  input.writeUInt32LE(0);
 
   return new Uint8Array(input.buffer);
 };
 
+// Apply MAX_EXPANDED_TERMS to the terms that will be sent to the runner, across all modes and groups, and the expansions of the prefix.
+// The prefix is a required term, so its expansions are counted after the other required terms.
+// Returns false if there are too many terms and they shouldn't be truncated; otherwise, excess terms are removed in place.
+const limitExpandedTerms = (modeTermBitmaps: ArrayBuffer[][], groupTermBitmaps: ArrayBuffer[][], prefixExpansionBitmaps: ArrayBuffer[]): boolean => {
+  if (MAX_EXPANDED_TERMS === undefined) {
+    return true;
+  }
+  let remaining = MAX_EXPANDED_TERMS;
+  for (const terms of [modeTermBitmaps[0], prefixExpansionBitmaps, ...modeTermBitmaps.slice(1), ...groupTermBitmaps]) {
+    if (terms.length > remaining) {
+      if (!EXPANDED_TERMS_OVERFLOW_TRUNCATE) {
+        return false;
+      }
+      terms.splice(remaining);
+    }
+    remaining -= terms.length;
+  }
+  // Truncated groups that became empty would otherwise match everything.
+  for (let i = groupTermBitmaps.length - 1; i >= 0; i--) {
+    if (!groupTermBitmaps[i].length) {
+      groupTermBitmaps.splice(i, 1);
+    }
+  }
+  return true;
+};
+
 const executePostingsListQuery = (queryData: Uint8Array): QueryResult | undefined => {
   const inputPtr = queryRunner.index_query_malloc();
   queryRunnerMemory.forkAndJump(inputPtr).writeAll(queryData);
@@ -430,48 +1286,316 @@ const executePostingsListQuery = (queryData: Uint8Array): QueryResult | undefine
   return outputPtr == 0 ? undefined : readResult(queryRunnerMemory.forkAndJump(outputPtr));
 };
 
+type Explanation = {
+  // Amount of documents each known query term is in.
+  terms: { [term: string]: number };
+  // Query terms each result contains and, if results were boosted, its proximity score, which only boosts results within PROXIMITY_SCAN_LIMIT.
+  results: { terms: string[]; proximity?: number }[];
+};
+
+// Explain which known query terms each document contains, using the runner so that bitmaps don't need to be deserialised in JS.
+// Postings lists of approximate terms are samples, so they may appear to be missing from documents that contain them.
+// `termBlockTables` should be the block tables the query was boosted with, or undefined if it wasn't.
+const explainResults = (documents: number[], knownTermBitmaps: Map<string, ArrayBuffer>, termBlockTables: ArrayBuffer[] | undefined): Explanation => {
+  queryRunner.reset();
+  const documentsPtr = queryRunner.malloc(documents.length * 4);
+  const documentsMemory = queryRunnerMemory.forkAndJump(documentsPtr);
+  for (const docId of documents) {
+    documentsMemory.writeUInt32LE(docId);
+  }
+  const containsPtr = queryRunner.malloc(documents.length);
+  const explanation: Explanation = {terms: {}, results: documents.map(() => ({terms: []}))};
+  for (const [term, bitmap] of knownTermBitmaps) {
+    const ptr = queryRunner.malloc(bitmap.byteLength);
+    queryRunnerMemory.forkAndJump(ptr).writeAll(new Uint8Array(bitmap));
+    const approximateCount = APPROXIMATE_TERMS.get(term);
+    explanation.terms[term] = approximateCount !== undefined ? approximateCount : queryRunner.postings_list_cardinality(ptr, bitmap.byteLength);
+    if (!queryRunner.postings_list_contains_each(ptr, bitmap.byteLength, documentsPtr, documents.length, containsPtr)) {
+      throw new Error(`Failed to deserialise postings list of ${term}`);
+    }
+    const contains = queryRunnerMemory.forkAndJump(containsPtr);
+    for (const result of explanation.results) {
+      if (contains.readUInt8()) {
+        result.terms.push(term);
+      }
+    }
+  }
+  if (termBlockTables) {
+    const tablesPtr = allocateArrays(termBlockTables);
+    documents.forEach((docId, i) => explanation.results[i].proximity = queryRunner.index_explain_proximity(termBlockTables.length, tablesPtr, docId));
+  }
+  return explanation;
+};
+
+// Keep only the explanations of results at `indices`, so that they line up with the results in the response.
+const explanationJson = (explanation: Explanation, indices: number[]): string => JSON.stringify({
+  terms: explanation.terms,
+  results: indices.map(i => explanation.results[i]),
+});
+
+// Default results collapsed by group key, in the same way as index_collapse_groups in wasm/index.c.
+const collapseDefaultResults = (firstRank: number, sort: Sort | undefined, groupKeyIds: Uint32Array): QueryResult => {
+  const seenGroups = new Set<number>();
+  const documents: number[] = [];
+  const scanCount = Math.min(DOCUMENT_COUNT, GROUP_SCAN_LIMIT!);
+  let collapsedRank = 0;
+  let more = false;
+  for (let rank = 0; rank < scanCount && !more; rank++) {
+    const docId = !sort ? rank : sort.order[sort.descending ? DOCUMENT_COUNT - 1 - rank : rank];
+    const group = groupKeyIds[docId];
+    if (seenGroups.has(group)) {
+      continue;
+    }
+    seenGroups.add(group);
+    if (collapsedRank >= firstRank) {
+      if (documents.length == MAX_RESULTS) {
+        more = true;
+        break;
+      }
+      documents.push(docId);
+    }
+    collapsedRank++;
+  }
+  return {continuation: more ? firstRank + documents.length : null, documents, total: DOCUMENT_COUNT};
+};
+
+// Keep only the fields of RESULT_PROJECTION in a document that is a JSON object. Other documents are returned as is.
+// This parses and serialises every result, so it costs CPU time that streaming documents as stored avoids.
+const projectDocument = (document: Uint8Array): Uint8Array => {
+  const value = JSON.parse(textDecoder.decode(document));
+  if (value === null || typeof value != 'object' || Array.isArray(value)) {
+    return document;
+  }
+  const projected: { [field: string]: unknown } = {};
+  for (const field of RESULT_PROJECTION!) {
+    if (Object.prototype.hasOwnProperty.call(value, field)) {
+      projected[field] = value[field];
+    }
+  }
+  return textEncoder.encode(JSON.stringify(projected));
+};
+
 const getAsciiBytes = (str: string) => new Uint8Array(str.split('').map(c => c.charCodeAt(0)));
 
 const COMMA = getAsciiBytes(',');
 
-const handleSearch = async (url: URL) => {
+// Respond as soon as the query has run, and write each result as soon as it and every result before it have been fetched, instead of buffering the whole response.
+// The status and start of the response are sent before any document is fetched, so `degraded` is at the end of the response instead,
+// and a failure to fetch documents can only be signalled by aborting the response.
+const streamSearchResults = (ctx: RequestContext, result: QueryResult, explanation: Explanation | undefined): Response => {
+  const stream = new TransformStream();
+  const writer = stream.writable.getWriter();
+  // Indices of results that have been written, in order, for matching them with their original IDs.
+  const written: number[] = [];
+  const settled = result.documents.map(() => false);
+  let nextUnwritten = 0;
+  const writeSettled = async (values: (ArrayBuffer | undefined)[]) => {
+    const start = nextUnwritten;
+    while (nextUnwritten < settled.length && settled[nextUnwritten]) {
+      nextUnwritten++;
+    }
+    const ready = [];
+    for (let i = start; i < nextUnwritten; i++) {
+      if (exists(values[i])) {
+        ready.push(i);
+      }
+    }
+    const documents = await decodeDocuments(ready.map(i => values[i]!));
+    for (let j = 0; j < documents.length; j++) {
+      if (written.length) {
+        writer.write(COMMA);
+      }
+      written.push(ready[j]);
+      writer.write(RESULT_PROJECTION === undefined ? documents[j] : projectDocument(documents[j]));
+    }
+  };
+
+  const writeResponse = async () => {
+    writer.write(getAsciiBytes(`{"total":${result.total},"continuation":${result.continuation},"results":[`));
+    // Results are written in order, so each batch waits for the previous one.
+    let writing = Promise.resolve();
+    const onSettled = (values: (ArrayBuffer | undefined)[], resultIdxs: number[]) => {
+      for (const i of resultIdxs) {
+        settled[i] = true;
+      }
+      writing = writing.then(() => writeSettled(values));
+    };
+    const [, originalIds] = await Promise.all([
+      DOCUMENTS_STORED
+        ? findAllInChunks(ctx, 'documents/', result.documents, false, onSettled)
+        // Without stored documents, each result is its internal ID instead, which is already JSON.
+        : onSettled(result.documents.map(docId => getAsciiBytes(`${docId}`).buffer), result.documents.map((_, i) => i)),
+      DOCUMENT_ID_CHUNKS === undefined ? undefined : findOriginalDocumentIds(ctx, result.documents),
+    ]);
+    await writing;
+    writer.write(textEncoder.encode(`]${ctx.degraded ? `,"degraded":true` : ''}${originalIds === undefined
+      ? ''
+      : `,"ids":${JSON.stringify(written.map(i => originalIds[i] === undefined ? null : originalIds[i]))}`}${explanation === undefined
+      ? ''
+      : `,"explain":${explanationJson(explanation, written)}`}}`));
+    await writer.close();
+  };
+  writeResponse().catch(err => writer.abort(err));
+
+  return new Response(stream.readable, {
+    status: result.total ? 200 : EMPTY_RESULT_STATUS,
+    headers: JSON_HEADERS,
+  });
+};
+
+const handleSearch = async (ctx: RequestContext, url: URL) => {
   // NOTE: Just because there are no valid words does not mean that there are no valid results.
   // For example, excluding an invalid word actually results in all entries matching.
-  const query = parseQuery(url.searchParams.getAll('t'));
-  if (!query) {
-    return responseError('Malformed query');
+  const termsRaw = url.searchParams.getAll('t');
+  let prefix: string | undefined;
+  if (url.searchParams.get('p') == '1') {
+    if (!MAX_PREFIX_EXPANSIONS) {
+      return responseError('invalid_query', 'Prefix searches are not enabled');
+    }
+    // Only the last term is a prefix, so it's parsed separately and the earlier terms stay exact.
+    prefix = parsePrefix(termsRaw.pop());
+    if (prefix === undefined) {
+      return responseError('invalid_query', 'The last term must be a required term to be a prefix');
+    }
+  }
+  const query = parseQuery(termsRaw);
+  const groups = parseGroups(url.searchParams.getAll('g'));
+  const parsedRanges = parseRanges(url.searchParams.getAll('r'));
+  if (!query || !groups || !parsedRanges) {
+    return responseError('invalid_query', 'Malformed query');
   }
   const continuation = Math.max(0, Number.parseInt(url.searchParams.get('c') || '', 10) || 0);
+  const sortName = url.searchParams.get('s');
+  const sortDirection = url.searchParams.get('o') || 'asc';
+  if (sortDirection != 'asc' && sortDirection != 'desc') {
+    return responseError('invalid_query', 'Invalid sort direction');
+  }
+  const collapse = url.searchParams.get('u') == '1';
+  if (collapse && GROUP_SCAN_LIMIT === undefined) {
+    return responseError('invalid_query', 'Results cannot be collapsed as documents have no group keys');
+  }
+  const explain = url.searchParams.get('e') == '1';
+  if (explain && !EXPLAIN) {
+    return responseError('invalid_query', 'Explaining results is not enabled');
+  }
+  // Filter values are matched exactly, so unlike terms they aren't analysed.
+  const filters = [...new Set(url.searchParams.getAll('f'))];
+  if (filters.length && !FILTERS) {
+    return responseError('invalid_query', 'Documents have no filters');
+  }
+  if (filters.length > MAX_QUERY_TERMS) {
+    return responseError('too_many_terms', 'Too many filters');
+  }
+  if (parsedRanges.length > MAX_QUERY_TERMS) {
+    return responseError('too_many_terms', 'Too many ranges');
+  }
+  const scopeRaw = url.searchParams.get('i');
+  if (scopeRaw != null && !SCOPED_SEARCH) {
+    return responseError('invalid_query', 'Scoped searches are not enabled');
+  }
+  const scope = scopeRaw == null ? undefined : parseScope(scopeRaw);
+  if (scopeRaw != null && !scope) {
+    return responseError('invalid_query', 'Malformed scope');
+  }
+  // A scope without any documents, including one with only IDs past the last document, matches nothing.
+  if (scope && !scope.length) {
+    return responseNoResults(ctx);
+  }
 
-  const termCount = query.reduce((count, modeTerms) => count + modeTerms.length, 0);
+  const termCount = [...query, ...groups].reduce((count, terms) => count + terms.length, prefix === undefined ? 0 : 1);
+  ctx.termCount = termCount;
   if (termCount > MAX_QUERY_TERMS) {
-    return responseError('Too many terms', 413);
+    return responseError('too_many_terms', 'Too many terms');
+  }
+  // Queries without terms are cheap as they return default results, so they're always allowed.
+  if (termCount && termCount < MIN_QUERY_TERMS) {
+    return responseError('invalid_query', `Queries must have at least ${MIN_QUERY_TERMS} terms`);
+  }
+  if ([...query, ...groups, prefix === undefined ? [] : [prefix]].some(terms => terms.some(term => textEncoder.encode(term).length < MIN_QUERY_BYTES))) {
+    return responseError('invalid_query', `Query terms must be at least ${MIN_QUERY_BYTES} bytes long`);
   }
 
-  const modeTermBitmaps = await findSerialisedTermBitmaps(query);
+  query[0] = withoutApproximateTerms(query[0]);
+  groups.forEach((groupTerms, i) => groups[i] = withoutApproximateTerms(groupTerms));
+
+  // Documents are only boosted when ordered by relevance, as an explicit sort key should be followed exactly.
+  const proximityTerms = PROXIMITY_SCAN_LIMIT === undefined || sortName != null ? [] : [...new Set([...query[0], ...query[1]])];
+  const [sort, groupKeyIds, termBitmaps, termBlockTables, maybeFilterBitmaps, maybeRanges, prefixExpansions, bm25Table] = await Promise.all([
+    sortName == null ? undefined : fetchSort(ctx, sortName, sortDirection == 'desc'),
+    collapse ? fetchGroupKeyIds(ctx) : undefined,
+    findSerialisedTermBitmaps(ctx, query, groups),
+    proximityTerms.length >= 2 ? findAllInChunks(ctx, 'blocks/', proximityTerms) : [],
+    findAllInChunks(ctx, 'filters/', filters, true),
+    Promise.all(parsedRanges.map(range => fetchRange(ctx, range))),
+    prefix === undefined ? {terms: [], bitmaps: []} : findPrefixExpansions(ctx, prefix),
+    // Like boosting, an explicit sort key should be followed exactly.
+    BM25 && sortName == null ? fetchBm25Table(ctx) : undefined,
+  ]);
+  if (sortName != null && !sort) {
+    return responseError('invalid_query', 'Unknown sort key');
+  }
+  if (!maybeRanges.every(exists)) {
+    return responseError('invalid_query', 'Unknown range field');
+  }
+  const ranges = maybeRanges as Range[];
+  if (!termBitmaps) {
+    return responseNoResults(ctx);
+  }
+  // No document has a filter value that was never indexed, whatever ON_UNKNOWN_TERM is, as filters must match exactly.
+  if (!maybeFilterBitmaps.every(exists)) {
+    return responseNoResults(ctx);
+  }
+  const filterBitmaps = maybeFilterBitmaps as ArrayBuffer[];
+  if (ranges.some(range => range.start >= range.end)) {
+    return responseNoResults(ctx);
+  }
+  // Like a required term that doesn't exist, a prefix that no indexed term starts with matches nothing.
+  if (prefix !== undefined && !prefixExpansions.bitmaps.length) {
+    return responseNoResults(ctx);
+  }
+  const {modeTermBitmaps, groupTermBitmaps: allGroupTermBitmaps, knownTermBitmaps} = termBitmaps;
   console.log('Bit sets retrieved');
+  // A group with a non-existent term matches nothing, so it can be omitted. If groups were provided but all are omitted, then there are zero results.
+  const groupTermBitmaps = allGroupTermBitmaps.filter(groupTerms => groupTerms.every(bm => bm));
+  if (groups.length && !groupTermBitmaps.length) {
+    return responseNoResults(ctx);
+  }
   // Handling non-existent terms:
-  // - If REQUIRE, then immediately return zero results, regardless of other terms of any mode.
+  // - If REQUIRE, then immediately return zero results, regardless of other terms of any mode. This is handled by findSerialisedTermBitmaps.
   // - If CONTAIN, then simply omit.
   // - If EXCLUDE, then it depends; if there are other terms of any mode, then simply omit. If there are no other terms of any mode, then return default results.
-  if (modeTermBitmaps[0].some(bm => !bm)) {
-    return responseNoResults();
-  }
   modeTermBitmaps[1] = modeTermBitmaps[1].filter(bm => bm);
   modeTermBitmaps[2] = modeTermBitmaps[2].filter(bm => bm);
+  if (!limitExpandedTerms(modeTermBitmaps as ArrayBuffer[][], groupTermBitmaps as ArrayBuffer[][], prefixExpansions.bitmaps)) {
+    return responseError('too_many_terms', 'Too many terms after expansion');
+  }
+  // Expansions dropped by the limit aren't part of the query, so they aren't explained either.
+  prefixExpansions.bitmaps.forEach((bitmap, i) => knownTermBitmaps.set(prefixExpansions.terms[i], bitmap));
 
   let result: QueryResult;
-  if (modeTermBitmaps.every(modeTerms => !modeTerms.length)) {
+  // Terms without a block table, such as very common terms, are not considered when boosting.
+  const boostingTermBlockTables = termBlockTables.filter(exists);
+  const useDefaultResults = modeTermBitmaps.every(modeTerms => !modeTerms.length) && !groupTermBitmaps.length && !filterBitmaps.length && !ranges.length && !prefixExpansions.bitmaps.length && !scope;
+  if (useDefaultResults && groupKeyIds) {
+    console.log('Using collapsed default results');
+    result = collapseDefaultResults(continuation, sort, groupKeyIds);
+  } else if (useDefaultResults) {
     console.log('Using default results');
     const after = continuation + MAX_RESULTS;
     result = {
       continuation: DOCUMENT_COUNT > after ? after : null,
-      documents: Array.from({length: MAX_RESULTS}, (_, i) => continuation + i).filter(docId => docId >= 0 && docId < DOCUMENT_COUNT),
+      documents: Array.from({length: MAX_RESULTS}, (_, i) => continuation + i)
+        .filter(rank => rank >= 0 && rank < DOCUMENT_COUNT)
+        .map(rank => !sort ? rank : sort.order[sort.descending ? DOCUMENT_COUNT - 1 - rank : rank]),
       total: DOCUMENT_COUNT,
     };
   } else {
     queryRunner.reset();
-    const indexQueryData = await buildIndexQuery(continuation, modeTermBitmaps as ArrayBuffer[][]);
+    // Excluded terms are never in matching documents, and prefix expansions are alternatives of one term, so only the other known terms are scored.
+    const bm25Terms = !bm25Table ? [] : [...new Set([...query[0], ...query[1], ...([] as string[]).concat(...groups)])]
+      .filter(term => knownTermBitmaps.has(term))
+      .map((term): [string, ArrayBuffer] => [term, knownTermBitmaps.get(term)!]);
+    const indexQueryData = await buildIndexQuery(continuation, sort, groupKeyIds, boostingTermBlockTables, filterBitmaps, ranges, prefixExpansions.bitmaps, scope ?? [], bm25Table, bm25Terms, modeTermBitmaps as ArrayBuffer[][], groupTermBitmaps as ArrayBuffer[][]);
     console.log('Query built');
     const maybeResult = await executePostingsListQuery(indexQueryData);
     if (!maybeResult) {
@@ -480,17 +1604,43 @@ const handleSearch = async (url: URL) => {
     result = maybeResult;
     console.log('Query executed');
   }
+  ctx.resultCount = result.total;
+
+  const explanation = !explain
+    ? undefined
+    : explainResults(result.documents, knownTermBitmaps, !useDefaultResults && boostingTermBlockTables.length >= 2 ? boostingTermBlockTables : undefined);
+
+  if (STREAM_RESULTS) {
+    return streamSearchResults(ctx, result, explanation);
+  }
 
   // We want to avoid JSON.{parse,stringify} as they take up a lot of CPU time and often cause timeout exceptions in CF Workers for large payloads.
   // So, we manually build our response with buffers, as that's how documents are stored.
   // The buffers represent parts of the UTF-8 encoded JSON serialised response bytes.
-  const jsonResPrefix = getAsciiBytes(`{"total":${result.total},"continuation":${result.continuation},"results":[`);
-  const jsonResSuffix = getAsciiBytes(`]}`);
   // Each document should be a JSON serialised value encoded in UTF-8.
-  const documents = (await findAllInChunks('documents/', result.documents))
-    .filter(exists)
-    .map(d => new Uint8Array(d));
+  // Without stored documents, each result is its internal ID instead, which is already JSON.
+  const fetchedDocuments = DOCUMENTS_STORED
+    ? await findAllInChunks(ctx, 'documents/', result.documents)
+    : result.documents.map(docId => getAsciiBytes(`${docId}`).buffer);
+  // Original IDs are only available if they were provided when building.
+  const originalIds = DOCUMENT_ID_CHUNKS === undefined ? undefined : await findOriginalDocumentIds(ctx, result.documents);
+  const found = result.documents.map((_, i) => i).filter(i => exists(fetchedDocuments[i]));
+  // Only the returned documents are decompressed.
+  const decodedDocuments = await decodeDocuments(found.map(i => fetchedDocuments[i]!));
+  const documents = RESULT_PROJECTION === undefined ? decodedDocuments : decodedDocuments.map(projectDocument);
   console.log('Documents fetched');
+  const jsonResPrefix = getAsciiBytes(`{"total":${result.total},"continuation":${result.continuation},${ctx.degraded ? `"degraded":true,` : ''}"results":[`);
+  // IDs and terms can contain any character, so they are encoded as UTF-8 instead of ASCII.
+  const jsonResSuffix = textEncoder.encode(`]${originalIds === undefined
+    ? ''
+    : `,"ids":${JSON.stringify(found.map(i => originalIds[i] === undefined ? null : originalIds[i]))}`}${explanation === undefined
+    ? ''
+    : `,"explain":${explanationJson(explanation, found)}`}}`);
+
+  const status = result.total ? 200 : EMPTY_RESULT_STATUS;
+  if (RESPONSE_PRETTY) {
+    return responseRawJson(textDecoder.decode(jsonResPrefix) + documents.map(d => textDecoder.decode(d)).join(',') + textDecoder.decode(jsonResSuffix), status);
+  }
 
   const stream = new TransformStream();
   const writer = stream.writable.getWriter();
@@ -505,27 +1655,156 @@ const handleSearch = async (url: URL) => {
   writer.releaseLock();
 
   return new Response(stream.readable, {
+    status,
+    headers: JSON_HEADERS,
+  });
+};
+
+const handleDocument = async (ctx: RequestContext, rawId: string) => {
+  if (!/^[0-9]+$/.test(rawId)) {
+    return responseError('invalid_query', 'Invalid document ID');
+  }
+  const docId = Number.parseInt(rawId, 10);
+  if (docId >= DOCUMENT_COUNT) {
+    return responseError('not_found', 'Document not found');
+  }
+  queryRunner.reset();
+  const [document] = await findAllInChunks(ctx, 'documents/', [docId]);
+  if (!document) {
+    // The document exists, so it can only be missing if its chunk was skipped.
+    return ctx.degraded ? responseError('timeout', 'Timed out fetching data') : responseError('not_found', 'Document not found');
+  }
+  const [decoded] = await decodeDocuments([document]);
+  if (RESPONSE_PRETTY) {
+    return responseRawJson(textDecoder.decode(decoded));
+  }
+  return new Response(decoded, {
     status: 200,
-    headers: {
-      'Content-Type': 'application/json',
-      ...CORS_HEADERS,
-    },
+    headers: JSON_HEADERS,
   });
 };
 
-const requestHandler = async (request: Request) => {
+const handleTermStats = async (ctx: RequestContext, rawTerm: string) => {
+  let term;
+  try {
+    term = analyseTerm(decodeURIComponent(rawTerm));
+  } catch {
+    return responseError('invalid_query', 'Malformed term');
+  }
+  if (term === undefined) {
+    // Terms dropped by the analyser, such as stopwords, are never indexed.
+    return responseError('not_found', 'Unknown term');
+  }
+  if (!term) {
+    return responseError('invalid_query', 'Malformed term');
+  }
+  // The postings list of an approximate term is a sample, so its size isn't the amount of documents with the term.
+  const approximateDocuments = APPROXIMATE_TERMS.get(term);
+  if (approximateDocuments !== undefined) {
+    return responseRawJson(JSON.stringify({term, documents: approximateDocuments}));
+  }
+  queryRunner.reset();
+  const [bitmap] = await findAllInChunks(ctx, 'terms/', [term]);
+  if (!bitmap) {
+    return ctx.degraded ? responseError('timeout', 'Timed out fetching data') : responseError('not_found', 'Unknown term');
+  }
+  // There must be no awaits from here on, as other requests could reset the runner in between.
+  const ptr = queryRunner.malloc(bitmap.byteLength);
+  queryRunnerMemory.forkAndJump(ptr).writeAll(new Uint8Array(bitmap));
+  const documents = queryRunner.postings_list_cardinality(ptr, bitmap.byteLength);
+  return responseRawJson(JSON.stringify({term, documents}));
+};
+
+// Lets work such as sending a record of a search continue after the response is sent.
+type WaitUntil = (promise: Promise<unknown>) => void;
+
+// Record a search to QUERY_LOG, if enabled and the search is sampled.
+// Documents fetched while streaming results are not counted, as the search is recorded once the response starts.
+// Keep in sync with build::QueryLogSink.
+const logQuery = (ctx: RequestContext, status: number, durationMs: number, waitUntil: WaitUntil) => {
+  if (QUERY_LOG === undefined || Math.random() >= QUERY_LOG_SAMPLE_RATE) {
+    return;
+  }
+  const {chunkReads, degraded, resultCount, termCount} = ctx;
+  const record = {event: 'search', status, terms: termCount, results: resultCount, chunkReads, durationMs, degraded};
+  switch (QUERY_LOG) {
+  case 'console':
+    console.log(JSON.stringify(record));
+    break;
+  case 'analytics':
+    // Analytics Engine only stores strings and numbers by position, so the order of doubles is part of the format.
+    QUERY_ANALYTICS.writeDataPoint({
+      blobs: [record.event],
+      doubles: [status, termCount, resultCount, chunkReads, durationMs, degraded ? 1 : 0],
+    });
+    break;
+  case 'endpoint':
+    waitUntil(fetch(QUERY_LOG_ENDPOINT!, {
+      method: 'POST',
+      headers: {'Content-Type': 'application/json'},
+      body: JSON.stringify(record),
+    }).catch(err => console.error(err)));
+    break;
+  }
+};
+
+const handleRoute = async (ctx: RequestContext, url: URL, route: string, isDocumentRoute: boolean, termStatsRoute: RegExpExecArray | null) => {
+  try {
+    if (termStatsRoute) {
+      return await handleTermStats(ctx, termStatsRoute[1]);
+    }
+    return await (isDocumentRoute ? handleDocument(ctx, route.slice('/doc/'.length)) : handleSearch(ctx, url));
+  } catch (err) {
+    if (err instanceof ChunkReadTimeoutError) {
+      return responseError('timeout', 'Timed out fetching data');
+    }
+    if (err instanceof UnknownTermError) {
+      return responseError('unknown_term', err.message);
+    }
+    // Such as the data store failing after retries, or a bug. Details are only logged, as they can reveal internals.
+    console.error(err);
+    return responseError('internal_error', 'Internal error');
+  }
+};
+
+const routeRequest = async (request: Request, waitUntil: WaitUntil) => {
   if (request.method == 'OPTIONS') {
     return responsePreflight();
   }
 
   const url = new URL(request.url);
+  if (!url.pathname.startsWith(`${ROUTE_PREFIX}/`)) {
+    return responseError('not_found', 'Not found');
+  }
+  const route = url.pathname.slice(ROUTE_PREFIX.length);
 
-  return url.pathname === '/search'
-    ? handleSearch(url)
-    : new Response(null, {status: 404});
+  const isDocumentRoute = DOCUMENT_ROUTE && route.startsWith('/doc/');
+  const termStatsRoute = TERM_STATS_ROUTE ? /^\/term\/([^/]+)\/stats$/.exec(route) : null;
+  if (route !== '/search' && !isDocumentRoute && !termStatsRoute) {
+    return responseError('not_found', 'Not found');
+  }
+  const ctx = newRequestContext();
+  const started = Date.now();
+  const response = await handleRoute(ctx, url, route, isDocumentRoute, termStatsRoute);
+  if (route === '/search') {
+    logQuery(ctx, response.status, Date.now() - started, waitUntil);
+  }
+  return response;
 };
 
-// See https://github.com/Microsoft/TypeScript/issues/14877.
-(self as unknown as ServiceWorkerGlobalScope).addEventListener('fetch', event => {
-  event.respondWith(requestHandler(event.request));
-});
+// Without a way to extend the request, such as in the browser, pending work just continues in the background.
+const requestHandler = async (request: Request, waitUntil: WaitUntil = () => undefined) => {
+  const preludeResponse = WORKER_PRELUDE && await WORKER_PRELUDE(request);
+  if (preludeResponse) {
+    return preludeResponse;
+  }
+  const response = await routeRequest(request, waitUntil);
+  return WORKER_MIDDLEWARE && await WORKER_MIDDLEWARE(request, response) || response;
+};
+
+if (FETCH_EVENT_LISTENER) {
+  // See https://github.com/Microsoft/TypeScript/issues/14877.
+  (self as unknown as ServiceWorkerGlobalScope).addEventListener('fetch', event => {
+    event.respondWith(requestHandler(event.request, promise => event.waitUntil(promise)));
+  });
+}
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::info;
+
+use crate::{DocumentId, Term};
+use crate::build::chunks::ChunkStrKey;
+use crate::build::chunks::bst::{BstChunks, OversizedValue};
+use crate::build::error::BuildError;
+use crate::util::format::number;
+
+/**
+ * Pack the block table of every term, which the runner uses to boost documents where several query terms are in the same block.
+ * Each table contains the IDs of the documents the term has a block in, in ascending order, followed by the block ID in each of those documents.
+ * Tables of very common terms can be too large for a chunk. They are skipped, as such terms say little about whether a document is relevant.
+ * Keep in sync with index_query_t in wasm/index.c.
+ */
+pub fn pack_block_tables(blocks: HashMap<Term, Vec<(DocumentId, u32)>>, chunk_size: usize) -> Result<BstChunks<ChunkStrKey>, BuildError> {
+    let mut builder = BstChunks::<ChunkStrKey>::new(chunk_size);
+    let mut terms_sorted = blocks.into_iter().collect::<Vec<(Term, Vec<(DocumentId, u32)>)>>();
+    terms_sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut oversized_tables = 0;
+    for (term, entries) in terms_sorted {
+        let mut table = Vec::<u8>::with_capacity(entries.len() * 8);
+        for (document_id, _) in entries.iter() {
+            table.write_u32::<LittleEndian>(*document_id).expect("write block table");
+        };
+        for (_, block_id) in entries.iter() {
+            table.write_u32::<LittleEndian>(*block_id).expect("write block table");
+        };
+        if let Err(OversizedValue { .. }) = builder.insert(ChunkStrKey::new(&term)?, table) {
+            oversized_tables += 1;
+        };
+    };
+    if oversized_tables > 0 {
+        info!("Skipped {} terms whose block tables do not fit in a chunk", number(oversized_tables));
+    };
+    Ok(builder)
+}
@@ -0,0 +1,161 @@
+use std::fs::{File, read, read_dir, remove_dir_all, remove_file};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::arg_enum;
+use log::info;
+
+use crate::build::error::{BuildError, IoContext};
+
+// Files and folders in the output folder needed to deploy or run the worker. Other files are intermediate build state.
+const BUNDLE_ENTRIES: &[&str] = &["blocks", "blocks.lookup", "dictionaries", "documents", "documents.lookup", "edgesearch.mjs", "filters", "filters.lookup", "format.json", "functions", "groups", "ids", "openapi.json", "ranges", "ranking", "runner.wasm", "runner.wasm.br", "runner.wasm.gz", "sorts", "terms", "terms.lookup", "worker.js"];
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum BundleCompression {
+        None,
+        // Compressed with the system `gzip` command.
+        Gzip,
+    }
+}
+
+fn collect_files(output_dir: &Path, rel: PathBuf, files: &mut Vec<PathBuf>) -> Result<(), BuildError> {
+    let path = output_dir.join(&rel);
+    if path.is_dir() {
+        let mut entries = read_dir(&path).context("read output folder")?.map(|e| e.map(|e| e.file_name())).collect::<Result<Vec<_>, _>>().context("read output folder")?;
+        entries.sort();
+        for entry in entries {
+            collect_files(output_dir, rel.join(entry), files)?;
+        };
+    } else if path.exists() {
+        files.push(rel);
+    };
+    Ok(())
+}
+
+// Write a ustar header for a regular file with a relative path and size.
+fn write_tar_header<W: Write>(out: &mut W, name: &str, size: usize) -> Result<(), BuildError> {
+    if name.len() > 100 {
+        return Err(BuildError::InvalidInput(format!("Path {} is too long to bundle", name)));
+    };
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with the checksum field set to spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    out.write_all(&header).context("write bundle")
+}
+
+fn write_tar<W: Write>(out: &mut W, output_dir: &Path, files: &[PathBuf]) -> Result<(), BuildError> {
+    for rel in files {
+        // Tar paths always use forward slashes.
+        let name = rel.iter().map(|c| c.to_str()).collect::<Option<Vec<&str>>>()
+            .ok_or_else(|| BuildError::InvalidInput(format!("Path {} is not valid UTF-8 and cannot be bundled", rel.display())))?
+            .join("/");
+        let data = read(output_dir.join(rel)).with_context(|| format!("read {} to bundle", name))?;
+        write_tar_header(out, &name, data.len())?;
+        out.write_all(&data).context("write bundle")?;
+        out.write_all(&vec![0u8; (512 - data.len() % 512) % 512]).context("write bundle")?;
+    };
+    // End of archive is marked by two empty records.
+    out.write_all(&[0u8; 1024]).context("write bundle")
+}
+
+/**
+ * Write the deployable contents of the output folder to a single tar archive at `bundle_path`.
+ * If `bundle_only` is true, the bundled files are then removed from the output folder.
+ */
+pub fn write_bundle(output_dir: &PathBuf, bundle_path: &PathBuf, compression: BundleCompression, bundle_only: bool) -> Result<(), BuildError> {
+    let mut files = Vec::<PathBuf>::new();
+    for entry in BUNDLE_ENTRIES {
+        collect_files(output_dir, PathBuf::from(entry), &mut files)?;
+    };
+
+    let bundle = File::create(bundle_path).context("create bundle file")?;
+    match compression {
+        BundleCompression::None => {
+            let mut out = BufWriter::new(bundle);
+            write_tar(&mut out, output_dir, &files)?;
+            out.flush().context("write bundle")?;
+        }
+        BundleCompression::Gzip => {
+            // Omit the timestamp from the header so that bundles of the same build are identical.
+            let mut gzip = Command::new("gzip")
+                .args(["-c", "-n"])
+                .stdin(Stdio::piped())
+                .stdout(bundle)
+                .spawn()
+                .map_err(|err| BuildError::Command(format!("Failed to run gzip: {}", err)))?;
+            // Wait for gzip even if writing to it failed, so that it doesn't outlive the build.
+            let written = write_tar(&mut BufWriter::new(gzip.stdin.take().unwrap()), output_dir, &files);
+            let status = gzip.wait().map_err(|err| BuildError::Command(format!("Failed to run gzip: {}", err)))?;
+            written?;
+            if !status.success() {
+                return Err(BuildError::Command("Failed to compress bundle with gzip".to_string()));
+            };
+        }
+    };
+    info!("Bundled {} files into {}", files.len(), bundle_path.display());
+
+    if bundle_only {
+        for entry in BUNDLE_ENTRIES {
+            let path = output_dir.join(entry);
+            if path.is_dir() {
+                remove_dir_all(&path).with_context(|| format!("remove bundled folder {}", path.display()))?;
+            } else if path.exists() {
+                remove_file(&path).with_context(|| format!("remove bundled file {}", path.display()))?;
+            };
+        };
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::build::js::tests::test_output_dir;
+
+    #[test]
+    fn bundles_of_the_same_output_are_identical() {
+        let output_dir = test_output_dir("bundle");
+        create_dir_all(output_dir.join("terms")).unwrap();
+        write(output_dir.join("worker.js"), "addEventListener('fetch', () => {});").unwrap();
+        write(output_dir.join("terms").join("0"), vec![7u8; 1000]).unwrap();
+        for compression in &[BundleCompression::None, BundleCompression::Gzip] {
+            let bundle = |name: &str| {
+                let bundle_path = output_dir.join(name);
+                write_bundle(&output_dir, &bundle_path, *compression, false).map(|_| read(bundle_path).unwrap())
+            };
+            let first = match bundle("first") {
+                Err(BuildError::Command(err)) => {
+                    eprintln!("{}, skipping {} bundle", err, compression);
+                    continue;
+                }
+                first => first.unwrap(),
+            };
+            if let BundleCompression::Gzip = compression {
+                // Some versions of gzip set the modification time in the header to the current time when compressing a stream.
+                assert_eq!(first[4..8], [0u8; 4], "gzip bundle has a timestamp");
+            };
+            // Timestamps are in whole seconds, so wait for a different one, and rewrite a file so that its modification time changes too.
+            sleep(Duration::from_millis(1100));
+            write(output_dir.join("worker.js"), "addEventListener('fetch', () => {});").unwrap();
+            assert!(first == bundle("second").unwrap(), "{} bundles differ", compression);
+        };
+        remove_dir_all(&output_dir).unwrap();
+    }
+}
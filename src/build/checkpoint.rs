@@ -0,0 +1,263 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::fs::{create_dir_all, File, read, read_to_string, remove_dir_all, write};
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
+
+use crate::{Term, TermId};
+use crate::data::corpus::CorpusSource;
+use crate::build::dictionary::TermDictionary;
+use crate::build::error::{BuildError, IoContext};
+
+// Phases of a build that can be resumed from, in the order they complete.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum BuildPhase {
+    TermsRead = 1,
+    TermsChunksWritten = 2,
+    DocumentsChunksWritten = 3,
+}
+
+impl BuildPhase {
+    fn from_u8(raw: u8) -> Option<BuildPhase> {
+        match raw {
+            1 => Some(BuildPhase::TermsRead),
+            2 => Some(BuildPhase::TermsChunksWritten),
+            3 => Some(BuildPhase::DocumentsChunksWritten),
+            _ => None,
+        }
+    }
+}
+
+// Compute a fingerprint of the build inputs from the size and modification time of each source file, as well as any other values that affect the intermediate state.
+// This avoids reading the entire inputs again just to check whether they have changed. In-memory corpora are hashed directly.
+pub fn fingerprint_inputs<T: Hash>(corpus: &CorpusSource, config: T) -> Result<u64, BuildError> {
+    let mut hasher = DefaultHasher::new();
+    match corpus {
+        CorpusSource::Files { document_terms, documents } => for source in &[document_terms, documents] {
+            let metadata = source.metadata().context("read source file metadata")?;
+            metadata.len().hash(&mut hasher);
+            metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).hash(&mut hasher);
+        },
+        CorpusSource::Combined { file, .. } => {
+            let metadata = file.metadata().context("read source file metadata")?;
+            metadata.len().hash(&mut hasher);
+            metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).hash(&mut hasher);
+        }
+        CorpusSource::Memory(corpus) => {
+            corpus.documents.hash(&mut hasher);
+            corpus.document_terms.hash(&mut hasher);
+        }
+    };
+    config.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn corrupt(file: &str) -> BuildError {
+    BuildError::InvalidInput(format!("Checkpoint {} are corrupt; delete the checkpoint folder to build from the start", file))
+}
+
+/**
+ * Intermediate state of a build, stored in `{output_dir}/checkpoint` so that an interrupted build can resume from the last completed phase.
+ * State is only used if it was created from inputs with the same fingerprint.
+ */
+pub struct Checkpoint {
+    dir: PathBuf,
+    fingerprint: u64,
+}
+
+impl Checkpoint {
+    pub fn new(output_dir: &PathBuf, fingerprint: u64) -> Checkpoint {
+        Checkpoint {
+            dir: output_dir.join("checkpoint"),
+            fingerprint,
+        }
+    }
+
+    // Get the last completed phase, if there is a checkpoint for the same inputs.
+    pub fn completed_phase(&self) -> Option<BuildPhase> {
+        let raw = read_to_string(self.dir.join("phase")).ok()?;
+        let mut parts = raw.trim().split(' ');
+        let fingerprint = parts.next()?.parse::<u64>().ok()?;
+        let phase = parts.next()?.parse::<u8>().ok()?;
+        if fingerprint != self.fingerprint {
+            info!("Ignoring checkpoint as inputs have changed");
+            return None;
+        };
+        BuildPhase::from_u8(phase)
+    }
+
+    // Discard state from any previous build.
+    pub fn reset(&self) -> Result<(), BuildError> {
+        self.clear();
+        create_dir_all(&self.dir).context("create checkpoint folder")
+    }
+
+    pub fn complete_phase(&self, phase: BuildPhase) -> Result<(), BuildError> {
+        write(self.dir.join("phase"), format!("{} {}", self.fingerprint, phase as u8)).context("write checkpoint phase")
+    }
+
+    pub fn save_terms(&self, terms: &TermDictionary, terms_by_document: &[Vec<TermId>]) -> Result<(), BuildError> {
+        let mut out = BufWriter::new(File::create(self.dir.join("terms.tmp")).context("create checkpoint terms file")?);
+        out.write_u32::<LittleEndian>(terms.len().try_into().expect("too many terms")).context("write checkpoint terms")?;
+        for term_id in 0..terms.len() {
+            let term = terms.term(term_id).as_bytes();
+            out.write_u32::<LittleEndian>(term.len().try_into().expect("term is too long")).context("write checkpoint terms")?;
+            out.write_all(term).context("write checkpoint terms")?;
+        };
+        out.write_u32::<LittleEndian>(terms_by_document.len().try_into().map_err(|_| BuildError::TooManyDocuments)?).context("write checkpoint terms")?;
+        for document_terms in terms_by_document {
+            out.write_u32::<LittleEndian>(document_terms.len().try_into().expect("too many terms")).context("write checkpoint terms")?;
+            for term_id in document_terms {
+                out.write_u32::<LittleEndian>((*term_id).try_into().expect("too many terms")).context("write checkpoint terms")?;
+            };
+        };
+        out.flush().context("write checkpoint terms")
+    }
+
+    pub fn load_terms(&self) -> Result<(TermDictionary, Vec<Vec<TermId>>), BuildError> {
+        let mut input = BufReader::new(File::open(self.dir.join("terms.tmp")).context("open checkpoint terms file")?);
+        // Interning in ID order reproduces the same IDs.
+        let mut terms = TermDictionary::new();
+        let term_count = input.read_u32::<LittleEndian>().context("read checkpoint terms")?;
+        for _ in 0..term_count {
+            let len = input.read_u32::<LittleEndian>().context("read checkpoint terms")?;
+            let mut term = vec![0u8; len as usize];
+            input.read_exact(&mut term).context("read checkpoint terms")?;
+            terms.intern(std::str::from_utf8(&term).map_err(|_| corrupt("terms"))?);
+        };
+        let document_count = input.read_u32::<LittleEndian>().context("read checkpoint terms")?;
+        let mut terms_by_document = Vec::<Vec<TermId>>::with_capacity(document_count as usize);
+        for _ in 0..document_count {
+            let len = input.read_u32::<LittleEndian>().context("read checkpoint terms")?;
+            let mut document_terms = Vec::<TermId>::with_capacity(len as usize);
+            for _ in 0..len {
+                document_terms.push(input.read_u32::<LittleEndian>().context("read checkpoint terms")? as TermId);
+            };
+            terms_by_document.push(document_terms);
+        };
+        Ok((terms, terms_by_document))
+    }
+
+    // Save the raw C lookup and amount of chunks for a dataset whose chunks have been written.
+    pub fn save_chunks_lookup(&self, dataset: &str, raw_lookup: &str, chunk_count: usize) -> Result<(), BuildError> {
+        write(self.dir.join(format!("{}.count", dataset)), format!("{}", chunk_count)).context("write checkpoint chunks count")?;
+        write(self.dir.join(format!("{}.lookup", dataset)), raw_lookup).context("write checkpoint chunks lookup")
+    }
+
+    pub fn load_chunks_lookup(&self, dataset: &str) -> Result<(String, usize), BuildError> {
+        let chunk_count = read_to_string(self.dir.join(format!("{}.count", dataset))).context("read checkpoint chunks count")?
+            .trim().parse::<usize>().map_err(|_| corrupt("chunks count"))?;
+        let raw_lookup = String::from_utf8(read(self.dir.join(format!("{}.lookup", dataset))).context("read checkpoint chunks lookup")?)
+            .map_err(|_| corrupt("chunks lookup"))?;
+        Ok((raw_lookup, chunk_count))
+    }
+
+    // Save the size of the zstd dictionary, or 0 if there isn't one, and of the largest document before encoding.
+    pub fn save_document_sizes(&self, dictionary_bytes: usize, max_document_bytes: usize) -> Result<(), BuildError> {
+        write(self.dir.join("documents.sizes"), format!("{} {}", dictionary_bytes, max_document_bytes)).context("write checkpoint document sizes")
+    }
+
+    pub fn load_document_sizes(&self) -> Result<(usize, usize), BuildError> {
+        let raw = read_to_string(self.dir.join("documents.sizes")).context("read checkpoint document sizes")?;
+        let (dictionary_bytes, max_document_bytes) = raw.trim().split_once(' ').ok_or_else(|| corrupt("document sizes"))?;
+        Ok((
+            dictionary_bytes.parse().map_err(|_| corrupt("document sizes"))?,
+            max_document_bytes.parse().map_err(|_| corrupt("document sizes"))?,
+        ))
+    }
+
+    // Save the terms whose postings lists were sampled when packing terms chunks, with the amount of documents each is actually in.
+    pub fn save_approximate_terms(&self, approximate_terms: &[(Term, usize)]) -> Result<(), BuildError> {
+        let mut out = BufWriter::new(File::create(self.dir.join("approximate-terms.tmp")).context("create checkpoint approximate terms file")?);
+        out.write_u32::<LittleEndian>(approximate_terms.len().try_into().expect("too many terms")).context("write checkpoint approximate terms")?;
+        for (term, document_count) in approximate_terms {
+            out.write_u32::<LittleEndian>((*document_count).try_into().map_err(|_| BuildError::TooManyDocuments)?).context("write checkpoint approximate terms")?;
+            out.write_u32::<LittleEndian>(term.len().try_into().expect("term is too long")).context("write checkpoint approximate terms")?;
+            out.write_all(term.as_bytes()).context("write checkpoint approximate terms")?;
+        };
+        out.flush().context("write checkpoint approximate terms")
+    }
+
+    pub fn load_approximate_terms(&self) -> Result<Vec<(Term, usize)>, BuildError> {
+        let mut input = BufReader::new(File::open(self.dir.join("approximate-terms.tmp")).context("open checkpoint approximate terms file")?);
+        let count = input.read_u32::<LittleEndian>().context("read checkpoint approximate terms")?;
+        let mut approximate_terms = Vec::<(Term, usize)>::with_capacity(count as usize);
+        for _ in 0..count {
+            let document_count = input.read_u32::<LittleEndian>().context("read checkpoint approximate terms")?;
+            let len = input.read_u32::<LittleEndian>().context("read checkpoint approximate terms")?;
+            let mut term = vec![0u8; len as usize];
+            input.read_exact(&mut term).context("read checkpoint approximate terms")?;
+            approximate_terms.push((String::from_utf8(term).map_err(|_| corrupt("approximate terms"))?, document_count as usize));
+        };
+        Ok(approximate_terms)
+    }
+
+    pub fn clear(&self) -> () {
+        let _ = remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::js::tests::test_output_dir;
+    use crate::data::MissingTerminatorHandling;
+    use crate::data::corpus::CorpusBuilder;
+
+    fn corpus() -> CorpusSource {
+        let mut builder = CorpusBuilder::new();
+        builder.add_document(0, "{}", &["a", "b"]).add_document(1, "{}", &["b"]);
+        CorpusSource::Memory(builder)
+    }
+
+    #[test]
+    fn build_resumes_from_a_checkpoint_of_the_same_inputs() {
+        let output_dir = test_output_dir("checkpoint-resume");
+        let fingerprint = fingerprint_inputs(&corpus(), MissingTerminatorHandling::Strict as u8).unwrap();
+        let checkpoint = Checkpoint::new(&output_dir, fingerprint);
+        checkpoint.reset().unwrap();
+        assert!(checkpoint.completed_phase().is_none());
+        let mut terms = TermDictionary::new();
+        let a = terms.intern("a").0;
+        let b = terms.intern("b").0;
+        checkpoint.save_terms(&terms, &[vec![a, b], vec![b]]).unwrap();
+        checkpoint.complete_phase(BuildPhase::TermsRead).unwrap();
+
+        let resumed = Checkpoint::new(&output_dir, fingerprint_inputs(&corpus(), MissingTerminatorHandling::Strict as u8).unwrap());
+        assert!(resumed.completed_phase() == Some(BuildPhase::TermsRead));
+        let (loaded_terms, terms_by_document) = resumed.load_terms().unwrap();
+        assert_eq!((loaded_terms.term(a), loaded_terms.term(b)), ("a", "b"));
+        assert_eq!(terms_by_document, vec![vec![a, b], vec![b]]);
+
+        checkpoint.save_chunks_lookup("documents", "{0}", 1).unwrap();
+        checkpoint.save_document_sizes(112640, 2).unwrap();
+        checkpoint.complete_phase(BuildPhase::DocumentsChunksWritten).unwrap();
+        assert!(resumed.completed_phase() == Some(BuildPhase::DocumentsChunksWritten));
+        assert_eq!(resumed.load_chunks_lookup("documents").unwrap(), ("{0}".to_string(), 1));
+        assert_eq!(resumed.load_document_sizes().unwrap(), (112640, 2));
+        remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_is_ignored_when_inputs_or_config_change() {
+        let output_dir = test_output_dir("checkpoint-invalidate");
+        let fingerprint = fingerprint_inputs(&corpus(), MissingTerminatorHandling::Strict as u8).unwrap();
+        let checkpoint = Checkpoint::new(&output_dir, fingerprint);
+        checkpoint.reset().unwrap();
+        checkpoint.complete_phase(BuildPhase::TermsChunksWritten).unwrap();
+
+        let lenient = fingerprint_inputs(&corpus(), MissingTerminatorHandling::Lenient as u8).unwrap();
+        assert!(Checkpoint::new(&output_dir, lenient).completed_phase().is_none());
+        let mut changed_corpus = CorpusBuilder::new();
+        changed_corpus.add_document(0, "{}", &["a"]);
+        let changed = fingerprint_inputs(&CorpusSource::Memory(changed_corpus), MissingTerminatorHandling::Strict as u8).unwrap();
+        assert!(Checkpoint::new(&output_dir, changed).completed_phase().is_none());
+        assert!(Checkpoint::new(&output_dir, fingerprint).completed_phase() == Some(BuildPhase::TermsChunksWritten));
+        remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -1,12 +1,31 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryInto;
+use std::hash::Hasher;
 use std::io::Write;
 
 use byteorder::{WriteBytesExt, LittleEndian};
 
 use crate::build::chunks::ChunkEntryKey;
 
+// Hash values so that identical values can be found without comparing every pair.
+fn hash_value(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(value);
+    hasher.finish()
+}
+
+// Byte length of a node excluding its key and value.
+// Keep in sync with BST::_serialise_node.
+pub const NODE_HEADER_LEN: usize = 4 + 4 + 4 + 4;
+
 struct BST<K: ChunkEntryKey> {
-    values: Vec<(K, Vec<u8>)>,
+    // (key, index of value in `unique_values`).
+    values: Vec<(K, usize)>,
+    // Each distinct value is stored once per chunk, and nodes with the same value point to the same bytes.
+    unique_values: Vec<Vec<u8>>,
+    // hash(value) => indices in `unique_values` with that hash.
+    unique_values_by_hash: HashMap<u64, Vec<usize>>,
     serialised_len: usize,
 }
 
@@ -14,13 +33,23 @@ impl<K: ChunkEntryKey> BST<K> {
     fn new() -> BST<K> {
         BST {
             values: Vec::new(),
+            unique_values: Vec::new(),
+            unique_values_by_hash: HashMap::new(),
             serialised_len: 0,
         }
     }
 
-    fn insertion_cost(key: &K, value: &[u8]) -> usize {
-        // Keep in sync with BSTNode::serialise.
-        key.bytes().len() + 4 + 4 + 4 + value.len()
+    fn find_unique_value(&self, value_hash: u64, value: &[u8]) -> Option<usize> {
+        self.unique_values_by_hash.get(&value_hash)?.iter().find(|i| self.unique_values[**i] == value).copied()
+    }
+
+    fn insertion_cost(&self, key: &K, value: &[u8]) -> usize {
+        // Keep in sync with BST::insert.
+        let value_cost = match self.find_unique_value(hash_value(value), value) {
+            Some(_) => 0,
+            None => value.len(),
+        };
+        key.bytes().len() + NODE_HEADER_LEN + value_cost
     }
 
     fn first_key(&self) -> Option<&K> {
@@ -29,63 +58,151 @@ impl<K: ChunkEntryKey> BST<K> {
 
     /**
      * WARNING: Key must be greater than any previously inserted key.
+     * Returns true if the value is a duplicate of one already in this chunk and so was not stored again.
+     */
+    fn insert(&mut self, key: K, value: Vec<u8>) -> bool {
+        let value_hash = hash_value(&value);
+        let (value_idx, duplicate) = match self.find_unique_value(value_hash, &value) {
+            Some(i) => {
+                self.serialised_len += key.bytes().len() + NODE_HEADER_LEN;
+                (i, true)
+            }
+            None => {
+                self.serialised_len += key.bytes().len() + NODE_HEADER_LEN + value.len();
+                let i = self.unique_values.len();
+                self.unique_values.push(value);
+                self.unique_values_by_hash.entry(value_hash).or_default().push(i);
+                (i, false)
+            }
+        };
+        self.values.push((key, value_idx));
+        duplicate
+    }
+
+    // Get the serialised length of this chunk if `other` was appended to it, as values in both would only be stored once.
+    fn appended_len(&self, other: &BST<K>) -> usize {
+        let shared_value_bytes: usize = other.unique_values.iter()
+            .filter(|value| self.find_unique_value(hash_value(value), value).is_some())
+            .map(|value| value.len())
+            .sum();
+        self.serialised_len + other.serialised_len - shared_value_bytes
+    }
+
+    /**
+     * WARNING: Every key in `other` must be greater than every key in this chunk.
+     * Returns the amount of nodes in `other` whose value was already in this chunk, and the bytes they would have used.
      */
-    fn insert(&mut self, key: K, value: Vec<u8>) -> () {
-        self.serialised_len += BST::<K>::insertion_cost(&key, &value);
-        self.values.push((key, value));
+    fn append(&mut self, other: BST<K>) -> (usize, usize) {
+        self.serialised_len = self.appended_len(&other);
+        let mut value_dup_lens = Vec::<Option<usize>>::with_capacity(other.unique_values.len());
+        let mut value_indices = Vec::<usize>::with_capacity(other.unique_values.len());
+        for value in other.unique_values {
+            let value_hash = hash_value(&value);
+            match self.find_unique_value(value_hash, &value) {
+                Some(i) => {
+                    value_dup_lens.push(Some(value.len()));
+                    value_indices.push(i);
+                }
+                None => {
+                    let i = self.unique_values.len();
+                    value_dup_lens.push(None);
+                    value_indices.push(i);
+                    self.unique_values.push(value);
+                    self.unique_values_by_hash.entry(value_hash).or_default().push(i);
+                }
+            };
+        };
+        let mut duplicates = (0, 0);
+        for (key, value_idx) in other.values {
+            if let Some(len) = value_dup_lens[value_idx] {
+                duplicates.0 += 1;
+                duplicates.1 += len;
+            };
+            self.values.push((key, value_indices[value_idx]));
+        };
+        duplicates
     }
 
-    fn _serialise_node(out: &mut Vec<u8>, left_pos: i32, right_pos: i32, key: &K, value: &[u8]) -> i32 {
+    // `value_positions` holds the position of each unique value that has already been written to `out`.
+    fn _serialise_node(&self, out: &mut Vec<u8>, value_positions: &mut [Option<u32>], left_pos: i32, right_pos: i32, key: &K, value_idx: usize) -> i32 {
         let pos: i32 = out.len().try_into().expect("too much data");
+        let value = &self.unique_values[value_idx];
         let value_len: u32 = value.len().try_into().expect("value is too long");
+        // The first node with a value has the value right after it; later nodes with the same value point to that.
+        let (value_pos, write_value) = match value_positions[value_idx] {
+            Some(p) => (p, false),
+            None => ((out.len() + key.bytes().len() + NODE_HEADER_LEN).try_into().expect("too much data"), true),
+        };
+        value_positions[value_idx] = Some(value_pos);
         out.write_all(key.bytes()).expect("write package data");
         out.write_i32::<LittleEndian>(left_pos).expect("write package data");
         out.write_i32::<LittleEndian>(right_pos).expect("write package data");
         out.write_u32::<LittleEndian>(value_len).expect("write package data");
-        out.write_all(value).expect("write package data");
+        out.write_u32::<LittleEndian>(value_pos).expect("write package data");
+        if write_value {
+            out.write_all(value).expect("write package data");
+        };
         pos
     }
 
     // Serialise nodes with indices in the range [lo, hi] (inclusive).
     // Return the position of the first byte of the serialised middle node.
-    fn _serialise_area(&self, out: &mut Vec<u8>, lo: usize, hi: usize) -> i32 {
+    fn _serialise_area(&self, out: &mut Vec<u8>, value_positions: &mut [Option<u32>], lo: usize, hi: usize) -> i32 {
         // Add first to prevent underflow.
         match hi + 1 - lo {
             0 => unreachable!(),
             1 => {
-                let (key, value) = &self.values[lo];
-                BST::_serialise_node(out, -1, -1, key, value)
+                let (key, value_idx) = &self.values[lo];
+                self._serialise_node(out, value_positions, -1, -1, key, *value_idx)
             }
             2 => {
-                let (left_key, left_value) = &self.values[lo];
-                let (right_key, right_value) = &self.values[hi];
-                let left_pos = BST::_serialise_node(out, -1, -1, left_key, left_value);
-                BST::_serialise_node(out, left_pos, -1, right_key, right_value)
+                let (left_key, left_value_idx) = &self.values[lo];
+                let (right_key, right_value_idx) = &self.values[hi];
+                let left_pos = self._serialise_node(out, value_positions, -1, -1, left_key, *left_value_idx);
+                self._serialise_node(out, value_positions, left_pos, -1, right_key, *right_value_idx)
             }
             dist => {
                 let mid = lo + (dist / 2);
-                let (key, value) = &self.values[mid];
-                let left_pos = self._serialise_area(out, lo, mid - 1);
-                let right_pos = self._serialise_area(out, mid + 1, hi);
-                BST::_serialise_node(out, left_pos, right_pos, key, value)
+                let (key, value_idx) = &self.values[mid];
+                let left_pos = self._serialise_area(out, value_positions, lo, mid - 1);
+                let right_pos = self._serialise_area(out, value_positions, mid + 1, hi);
+                self._serialise_node(out, value_positions, left_pos, right_pos, key, *value_idx)
             }
         }
     }
 
     fn serialise(&self) -> (u32, Vec<u8>) {
         let mut out = Vec::<u8>::new();
-        let centre_pos = self._serialise_area(&mut out, 0, self.values.len() - 1);
+        let mut value_positions = vec![None; self.unique_values.len()];
+        let centre_pos = self._serialise_area(&mut out, &mut value_positions, 0, self.values.len() - 1);
         (centre_pos.try_into().unwrap(), out)
     }
 
+    // Drop the values once the chunk has been serialised, keeping the keys and length for statistics.
+    fn release_values(&mut self) -> () {
+        self.unique_values = Vec::new();
+        self.unique_values_by_hash = HashMap::new();
+    }
+
     fn serialised_len(&self) -> usize {
         self.serialised_len
     }
 }
 
+// A value that can't fit in any chunk, even on its own.
+pub struct OversizedValue {
+    pub len: usize,
+    pub max_len: usize,
+}
+
 pub struct BstChunks<K: ChunkEntryKey> {
     chunks: Vec<BST<K>>,
     max_chunk_size: usize,
+    // Amount of values that were identical to an earlier value in the same chunk, and the total bytes they would have used.
+    duplicate_values: usize,
+    duplicate_value_bytes: usize,
+    // Amount of chunks removed by merging them into the previous chunk when serialising.
+    merged_chunks: usize,
 }
 
 impl<K: ChunkEntryKey> BstChunks<K> {
@@ -93,27 +210,111 @@ impl<K: ChunkEntryKey> BstChunks<K> {
         BstChunks {
             chunks: Vec::new(),
             max_chunk_size,
+            duplicate_values: 0,
+            duplicate_value_bytes: 0,
+            merged_chunks: 0,
         }
     }
 
-    pub fn insert(&mut self, key: K, value: Vec<u8>) -> () {
-        if self.chunks.last().filter(|p| p.serialised_len() + BST::<K>::insertion_cost(&key, &value) <= self.max_chunk_size).is_none() {
+    // Get the length of the largest value that fits in a chunk with `key`. Larger values would create a chunk exceeding the maximum size.
+    pub fn max_value_len(&self, key: &K) -> usize {
+        self.max_chunk_size.saturating_sub(key.bytes().len() + NODE_HEADER_LEN)
+    }
+
+    // Values that are too large are rejected before anything is changed, as otherwise they would create a chunk exceeding the maximum size.
+    pub fn insert(&mut self, key: K, value: Vec<u8>) -> Result<(), OversizedValue> {
+        // Compared as the whole entry, as the key and header alone can be larger than a chunk.
+        if key.bytes().len() + NODE_HEADER_LEN + value.len() > self.max_chunk_size {
+            return Err(OversizedValue { len: value.len(), max_len: self.max_value_len(&key) });
+        };
+        if self.chunks.last().filter(|p| p.serialised_len() + p.insertion_cost(&key, &value) <= self.max_chunk_size).is_none() {
             self.chunks.push(BST::new());
         };
 
-        self.chunks.last_mut().unwrap().insert(key, value);
+        let value_len = value.len();
+        if self.chunks.last_mut().unwrap().insert(key, value) {
+            self.duplicate_values += 1;
+            self.duplicate_value_bytes += value_len;
+        };
+        Ok(())
     }
 
     pub fn chunk_count(&self) -> usize {
         self.chunks.len()
     }
 
-    pub fn serialise(&self) -> (String, Vec<Vec<u8>>) {
+    pub fn merged_chunks(&self) -> usize {
+        self.merged_chunks
+    }
+
+    /**
+     * Merge adjacent chunks that fit within the maximum chunk size together.
+     * Entries are inserted in key order and a new chunk is only started when an entry doesn't fit,
+     * but a merged chunk stores values shared by both chunks once, so it can be smaller than the two chunks were apart.
+     * Chunks stay in key order, so the lookup built afterwards remains sorted.
+     */
+    fn merge_underfilled_chunks(&mut self) -> () {
+        let mut i = 0;
+        while i + 1 < self.chunks.len() {
+            if self.chunks[i].appended_len(&self.chunks[i + 1]) <= self.max_chunk_size {
+                let next = self.chunks.remove(i + 1);
+                let (duplicate_values, duplicate_value_bytes) = self.chunks[i].append(next);
+                self.duplicate_values += duplicate_values;
+                self.duplicate_value_bytes += duplicate_value_bytes;
+                self.merged_chunks += 1;
+            } else {
+                i += 1;
+            };
+        };
+    }
+
+    // Get the amount of values that were deduplicated, and the bytes saved by doing so.
+    pub fn deduplication_savings(&self) -> (usize, usize) {
+        (self.duplicate_values, self.duplicate_value_bytes)
+    }
+
+    fn entry_count(&self) -> usize {
+        self.chunks.iter().map(|c| c.values.len()).sum()
+    }
+
+    fn total_serialised_len(&self) -> usize {
+        self.chunks.iter().map(|c| c.serialised_len()).sum()
+    }
+
+    // Get the average size of chunks relative to the maximum chunk size. Lower ratios mean more chunk reads for the same data.
+    pub fn fill_ratio(&self) -> f64 {
+        if self.chunks.is_empty() {
+            return 0.0;
+        };
+        self.total_serialised_len() as f64 / (self.chunks.len() * self.max_chunk_size) as f64
+    }
+
+    pub fn max_entries_per_chunk(&self) -> usize {
+        self.chunks.iter().map(|c| c.values.len()).max().unwrap_or(0)
+    }
+
+    // Get the average bytes used by each entry, including its key and node header. Deduplicated values aren't counted again.
+    pub fn average_entry_size(&self) -> f64 {
+        match self.entry_count() {
+            0 => 0.0,
+            entries => self.total_serialised_len() as f64 / entries as f64,
+        }
+    }
+
+    /**
+     * Returns the lookup as C initialisers for runner.wasm, the same lookup as bytes for `write_chunks_lookup`, and the chunks.
+     * Each chunk's values are dropped as soon as it's serialised, so the data is only held about once in memory instead of twice.
+     * Nothing can be inserted afterwards, but statistics such as `fill_ratio` remain available.
+     */
+    pub fn serialise(&mut self) -> (String, Vec<u8>, Vec<Vec<u8>>) {
+        self.merge_underfilled_chunks();
         let mut lookup = String::new();
+        let mut lookup_bytes = Vec::new();
         let mut serialised_chunks = Vec::new();
 
-        for (package_id, package) in self.chunks.iter().enumerate() {
+        for (package_id, package) in self.chunks.iter_mut().enumerate() {
             let (mid_pos, serialised) = package.serialise();
+            package.release_values();
             let lookup_entry = format!(r#"{{
                 .id = {package_id},
                 .mid_pos = {middle},
@@ -124,9 +325,51 @@ impl<K: ChunkEntryKey> BstChunks<K> {
                 middle = mid_pos,
             );
             lookup.push_str(lookup_entry.as_str());
+            lookup_bytes.write_u32::<LittleEndian>(package_id.try_into().expect("too many chunks")).unwrap();
+            lookup_bytes.write_u32::<LittleEndian>(mid_pos).unwrap();
+            lookup_bytes.write_all(package.first_key().unwrap().bytes()).unwrap();
             serialised_chunks.push(serialised);
         };
 
-        (lookup, serialised_chunks)
+        (lookup, lookup_bytes, serialised_chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::chunks::{ChunkStrKey, ChunkU32Key};
+
+    #[test]
+    fn oversized_value_is_rejected_without_changing_chunks() {
+        let mut chunks = BstChunks::<ChunkU32Key>::new(64);
+        assert!(chunks.insert(ChunkU32Key::new(0), vec![1; 8]).is_ok());
+        let max_len = chunks.max_value_len(&ChunkU32Key::new(1));
+        match chunks.insert(ChunkU32Key::new(1), vec![2; max_len + 1]) {
+            Err(OversizedValue { len, max_len: reported_max_len }) => assert_eq!((len, reported_max_len), (max_len + 1, max_len)),
+            Ok(()) => panic!("inserted a value larger than a chunk"),
+        };
+        assert_eq!((chunks.chunk_count(), chunks.entry_count()), (1, 1));
+        // The largest value that fits is still accepted, in a chunk of its own that is exactly the maximum size.
+        assert!(chunks.insert(ChunkU32Key::new(1), vec![2; max_len]).is_ok());
+        let (_, _, serialised) = chunks.serialise();
+        assert_eq!(serialised.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![8 + 4 + NODE_HEADER_LEN, 64]);
+    }
+
+    #[test]
+    fn entry_is_rejected_if_its_key_alone_is_larger_than_a_chunk() {
+        let mut chunks = BstChunks::<ChunkStrKey>::new(NODE_HEADER_LEN + 4);
+        let key = ChunkStrKey::new("long key").unwrap();
+        assert_eq!(chunks.max_value_len(&key), 0);
+        assert!(chunks.insert(key, Vec::new()).is_err());
+        assert_eq!(chunks.chunk_count(), 0);
+    }
+
+    #[test]
+    fn string_key_longer_than_runner_supports_is_rejected() {
+        assert!(ChunkStrKey::new(&"a".repeat(255)).is_ok());
+        assert!(ChunkStrKey::new(&"a".repeat(256)).is_err());
+        // The limit is in bytes, not characters.
+        assert!(ChunkStrKey::new(&"é".repeat(128)).is_err());
     }
 }
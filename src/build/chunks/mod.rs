@@ -1,11 +1,158 @@
 use std::convert::TryInto;
-use std::io::Write;
+use std::fs::{create_dir, create_dir_all, File, read, read_dir, remove_dir_all, write};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::arg_enum;
+
+use crate::build::error::{BuildError, IoContext};
+use crate::build::precompress::is_precompressed;
 
 pub mod bst;
 
+// Folders of chunks that the worker fetches, which are also the first segment of their KV keys.
+pub const CHUNK_DATASETS: &[&str] = &["blocks", "dictionaries", "documents", "filters", "groups", "ids", "ranges", "ranking", "sorts", "terms"];
+
+// Largest value a Cloudflare Workers KV entry can hold, so chunks stored in KV can't be larger.
+pub const KV_VALUE_MAX_SIZE: usize = 25 * 1024 * 1024;
+
+// Amount of chunks in each subdirectory when using ChunkLayout::Nested.
+// Keep in sync with chunkPath in script/src/main.ts.
+pub const NESTED_CHUNKS_PER_DIR: usize = 1000;
+
+arg_enum! {
+    // How chunk files are laid out within each dataset's folder.
+    // Flat stores `{dataset}/{id}`; Nested stores `{dataset}/{id / NESTED_CHUNKS_PER_DIR}/{id}` to keep folders small.
+    #[derive(Clone, Copy)]
+    pub enum ChunkLayout {
+        Flat,
+        Nested,
+    }
+}
+
+impl ChunkLayout {
+    pub fn relative_path(&self, dataset: &str, chunk_id: usize) -> PathBuf {
+        let dir = PathBuf::from(dataset);
+        match self {
+            ChunkLayout::Flat => dir.join(format!("{}", chunk_id)),
+            ChunkLayout::Nested => dir.join(format!("{}", chunk_id / NESTED_CHUNKS_PER_DIR)).join(format!("{}", chunk_id)),
+        }
+    }
+}
+
+/**
+ * Check a namespace that prefixes every KV key, such as `staging`, so that builds for different environments can share a KV namespace.
+ * Keys become `{namespace}/{dataset}/{id}`, so a namespace can't contain a slash or be a dataset name,
+ * as listing or deleting the keys of one build by prefix would otherwise include those of another.
+ */
+pub fn validate_kv_key_namespace(namespace: &str) -> Result<(), String> {
+    if namespace.is_empty() {
+        return Err("it must not be empty".to_string());
+    };
+    if !namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("{:?} must only contain ASCII letters, digits, hyphens, and underscores", namespace));
+    };
+    if CHUNK_DATASETS.contains(&namespace) {
+        return Err(format!("{:?} is reserved as a dataset name", namespace));
+    };
+    Ok(())
+}
+
+// Replace the folder for `dataset` in `output_dir` with the serialised chunks.
+// Chunks are always packed in key order and never in the iteration order of a HashMap, so the same input produces the same chunk IDs and bytes, and unchanged chunks don't need uploading again.
+pub fn write_chunks(output_dir: &PathBuf, dataset: &str, layout: ChunkLayout, chunks: &[Vec<u8>]) -> Result<(), BuildError> {
+    let _ = remove_dir_all(output_dir.join(dataset));
+    create_dir(output_dir.join(dataset)).with_context(|| format!("create {} chunks folder", dataset))?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let path = output_dir.join(layout.relative_path(dataset, i));
+        create_dir_all(path.parent().unwrap()).with_context(|| format!("create {} chunks subfolder", dataset))?;
+        let mut f = File::create(&path).with_context(|| format!("create chunk file {}", path.display()))?;
+        f.write_all(chunk).with_context(|| format!("write chunk file {}", path.display()))?;
+    };
+    Ok(())
+}
+
+// Precompressed copies of chunks are not counted, as they are written alongside the chunks after the build.
+fn count_files(dir: &Path) -> Result<usize, BuildError> {
+    let mut count = 0;
+    for entry in read_dir(dir).context("read chunks folder")? {
+        let path = entry.context("read chunks folder")?.path();
+        count += if path.is_dir() { count_files(&path)? } else if is_precompressed(&path) { 0 } else { 1 };
+    };
+    Ok(count)
+}
+
+// Check that the chunks written for `dataset` are exactly the `expected` amount that the worker and runner were generated with.
+// A mismatch is a bug rather than a problem with the input, so it still panics.
+pub fn verify_chunks(output_dir: &PathBuf, dataset: &str, layout: ChunkLayout, expected: usize) -> Result<(), BuildError> {
+    let written = count_files(&output_dir.join(dataset))?;
+    if written != expected || (0..expected).any(|i| !output_dir.join(layout.relative_path(dataset, i)).is_file()) {
+        panic!("Package count mismatch: the worker expects {} {} chunks but {} were written", expected, dataset, written);
+    };
+    Ok(())
+}
+
+// Keep in sync with bst_key_t in wasm/chunks.c.
+pub const CHUNK_KEY_U32: u8 = 0;
+pub const CHUNK_KEY_STR: u8 = 1;
+
+/**
+ * Write the lookup of the chunks of `dataset` to `{dataset}.lookup` in `output_dir`, for runtimes other than the generated worker and runner.
+ * It has the same entries as the lookup compiled into runner.wasm: a key type byte, the amount of chunks as u32le, and then for each chunk its ID as u32le, the position of its root node as u32le, and its first key.
+ * `entries` are as serialised by `BstChunks::serialise`, and are empty if the dataset has no chunks.
+ * Keep in sync with the chunkLookup of format.json.
+ */
+pub fn write_chunks_lookup<K: ChunkEntryKey>(output_dir: &PathBuf, dataset: &str, chunk_count: usize, entries: &[u8]) -> Result<(), BuildError> {
+    let mut out = Vec::with_capacity(5 + entries.len());
+    out.write_u8(K::KEY_TYPE).unwrap();
+    out.write_u32::<LittleEndian>(chunk_count.try_into().expect("too many chunks")).unwrap();
+    out.extend_from_slice(entries);
+    write(output_dir.join(format!("{}.lookup", dataset)), out).with_context(|| format!("write {}.lookup", dataset))
+}
+
+pub enum ChunkLookupKey {
+    U32(u32),
+    Str(String),
+}
+
+// A chunk listed in a `{dataset}.lookup` file. The chunk has every key from `first_key` up to but excluding the first key of the next chunk.
+pub struct ChunkLookupEntry {
+    pub id: u32,
+    // Position of the root node of the chunk's binary search tree.
+    pub mid_pos: u32,
+    pub first_key: ChunkLookupKey,
+}
+
+/**
+ * Read the `{dataset}.lookup` file written by a build to `output_dir`, to find which chunk of `dataset` has a key without the worker.
+ * Entries are in key order, so the chunk with a key is the last one whose first key is not greater than it.
+ */
+pub fn read_chunks_lookup(output_dir: &Path, dataset: &str) -> Result<Vec<ChunkLookupEntry>, BuildError> {
+    let raw = read(output_dir.join(format!("{}.lookup", dataset))).with_context(|| format!("read {}.lookup", dataset))?;
+    let mut rd = Cursor::new(raw);
+    let key_type = rd.read_u8().context("read chunks lookup key type")?;
+    let chunk_count = rd.read_u32::<LittleEndian>().context("read chunks lookup length")?;
+    (0..chunk_count).map(|_| {
+        let id = rd.read_u32::<LittleEndian>().context("read chunks lookup entry")?;
+        let mid_pos = rd.read_u32::<LittleEndian>().context("read chunks lookup entry")?;
+        let first_key = match key_type {
+            CHUNK_KEY_U32 => ChunkLookupKey::U32(rd.read_u32::<LittleEndian>().context("read chunks lookup key")?),
+            CHUNK_KEY_STR => {
+                let mut key = vec![0u8; rd.read_u8().context("read chunks lookup key length")? as usize];
+                rd.read_exact(&mut key).context("read chunks lookup key")?;
+                ChunkLookupKey::Str(String::from_utf8(key).map_err(|_| BuildError::InvalidInput(format!("Key of chunk {} in {}.lookup is not valid UTF-8", id, dataset)))?)
+            }
+            _ => return Err(BuildError::InvalidInput(format!("Unknown key type {} in {}.lookup", key_type, dataset))),
+        };
+        Ok(ChunkLookupEntry { id, mid_pos, first_key })
+    }).collect()
+}
+
 pub trait ChunkEntryKey {
+    // One of the CHUNK_KEY_* values.
+    const KEY_TYPE: u8;
+
     fn bytes(&self) -> &[u8];
     fn c(&self) -> &str;
 }
@@ -29,6 +176,8 @@ impl ChunkU32Key {
 }
 
 impl ChunkEntryKey for ChunkU32Key {
+    const KEY_TYPE: u8 = CHUNK_KEY_U32;
+
     fn bytes(&self) -> &[u8] {
         &self.bytes
     }
@@ -44,12 +193,18 @@ pub struct ChunkStrKey {
     c: String,
 }
 
+// The longest string key in bytes, as the runner stores the length of keys in a uint8_t.
+pub const CHUNK_STR_KEY_MAX_LEN: usize = u8::MAX as usize;
+
 impl ChunkStrKey {
-    pub fn new(key: &str) -> ChunkStrKey {
+    pub fn new(key: &str) -> Result<ChunkStrKey, BuildError> {
+        if key.len() > CHUNK_STR_KEY_MAX_LEN {
+            return Err(BuildError::InvalidInput(format!("Key {:?} is {} bytes but keys can be at most {} bytes", key, key.len(), CHUNK_STR_KEY_MAX_LEN)));
+        };
         let mut bytes = Vec::new();
-        bytes.write_u8(key.len().try_into().expect("key is too long")).unwrap();
+        bytes.write_u8(key.len() as u8).unwrap();
         bytes.write_all(key.as_bytes()).unwrap();
-        ChunkStrKey {
+        Ok(ChunkStrKey {
             bytes,
             c: format!(r#"{{
                 .strval = {{
@@ -60,11 +215,13 @@ impl ChunkStrKey {
                 VAL = key.replace("\n", "\\n").replace("\"", "\\\""),
                 LEN = key.len(),
             ),
-        }
+        })
     }
 }
 
 impl ChunkEntryKey for ChunkStrKey {
+    const KEY_TYPE: u8 = CHUNK_KEY_STR;
+
     fn bytes(&self) -> &[u8] {
         &self.bytes
     }
@@ -0,0 +1,124 @@
+use std::fs::{create_dir_all, File, read, remove_dir_all, remove_file};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+
+use clap::arg_enum;
+
+use crate::build::chunks::ChunkLayout;
+use crate::build::error::{BuildError, IoContext};
+
+arg_enum! {
+    // How each document is compressed before being packed, so that the worker only decompresses the documents it returns.
+    // Keep in sync with DOCUMENT_COMPRESSION in script/src/main.ts.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum DocumentCompression {
+        None,
+        // Compressed with the system `gzip` command, and decompressed by the worker using DecompressionStream.
+        Gzip,
+        // Compressed with the system `zstd` command against a dictionary trained on the documents, and decompressed by the runner.
+        Zstd,
+    }
+}
+
+// When documents are compressed, each one is prefixed with a tag byte describing how it is stored.
+// Keep in sync with decodeDocuments in script/src/main.ts.
+pub const DOCUMENT_ENCODING_RAW: u8 = 0;
+pub const DOCUMENT_ENCODING_COMPRESSED: u8 = 1;
+
+// The ID and encoded bytes of each document, and the zstd dictionary if one was trained.
+pub type EncodedDocuments = (Vec<(usize, Vec<u8>)>, Option<Vec<u8>>);
+
+// Maximum amount of documents, spread evenly across the corpus, to train the zstd dictionary with.
+const ZSTD_DICTIONARY_TRAINING_SAMPLES: usize = 10000;
+// Maximum size of the zstd dictionary, which the worker fetches once per instance. This is the `zstd` default.
+const ZSTD_DICTIONARY_MAX_SIZE: usize = 112640;
+
+// Bytes added to every document by its encoding tag.
+pub fn document_encoding_overhead(compression: DocumentCompression) -> usize {
+    match compression {
+        DocumentCompression::None => 0,
+        DocumentCompression::Gzip | DocumentCompression::Zstd => 1,
+    }
+}
+
+// Prefix the smaller of the raw and compressed forms of a document with its encoding tag.
+fn tag_document(raw: Vec<u8>, compressed: Vec<u8>) -> Vec<u8> {
+    let (tag, data) = if compressed.len() < raw.len() {
+        (DOCUMENT_ENCODING_COMPRESSED, compressed)
+    } else {
+        (DOCUMENT_ENCODING_RAW, raw)
+    };
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(tag);
+    out.extend(data);
+    out
+}
+
+fn run(cmd: &mut Command, name: &str) -> Result<(), BuildError> {
+    let status = cmd.status().map_err(|err| BuildError::Command(format!("Failed to run {}: {}", name, err)))?;
+    if !status.success() {
+        return Err(BuildError::Command(format!("Failed to compress documents with {}", name)));
+    };
+    Ok(())
+}
+
+/**
+ * Encode each document as it should be stored in its chunk, and return the zstd dictionary if one was trained.
+ * Compression writes every document to a temporary folder and compresses them all using one process,
+ * as running a process per document would be far too slow.
+ */
+pub fn encode_documents<I: Iterator<Item = Result<(usize, String), BuildError>>>(output_dir: &PathBuf, compression: DocumentCompression, documents: I) -> Result<EncodedDocuments, BuildError> {
+    if compression == DocumentCompression::None {
+        let encoded = documents.map(|document| document.map(|(document_id, document)| (document_id, document.into_bytes()))).collect::<Result<_, _>>()?;
+        return Ok((encoded, None));
+    };
+
+    let dir = output_dir.join("documents.tmp");
+    // Keep folders small, as there is a file for every document.
+    let document_path = |document_id: usize| dir.join(ChunkLayout::Nested.relative_path("", document_id));
+    let _ = remove_dir_all(&dir);
+    let mut document_ids = Vec::<usize>::new();
+    for document in documents {
+        let (document_id, document) = document?;
+        let path = document_path(document_id);
+        create_dir_all(path.parent().unwrap()).context("create temporary documents folder")?;
+        File::create(&path).context("create temporary document file")?.write_all(document.as_bytes()).context("write temporary document file")?;
+        document_ids.push(document_id);
+    };
+    if document_ids.is_empty() {
+        return Ok((Vec::new(), None));
+    };
+
+    let (extension, dictionary) = match compression {
+        DocumentCompression::None => unreachable!(),
+        DocumentCompression::Gzip => {
+            // Omit the name and timestamp from the header and keep the original files.
+            run(Command::new("gzip").args(["-r", "-k", "-n", "-9"]).arg(&dir), "gzip")?;
+            ("gz", None)
+        }
+        DocumentCompression::Zstd => {
+            let dictionary_path = output_dir.join("documents.dict");
+            let sample_interval = document_ids.len().div_ceil(ZSTD_DICTIONARY_TRAINING_SAMPLES);
+            run(Command::new("zstd")
+                .arg("--train")
+                .arg(format!("--maxdict={}", ZSTD_DICTIONARY_MAX_SIZE))
+                .arg("-q")
+                .args(document_ids.iter().step_by(sample_interval).map(|id| document_path(*id)))
+                .arg("-o").arg(&dictionary_path), "zstd")?;
+            // The content size is written to each frame by default, which the runner needs to allocate the output.
+            run(Command::new("zstd").args(["-r", "-q", "-19"]).arg("-D").arg(&dictionary_path).arg(&dir), "zstd")?;
+            let dictionary = read(&dictionary_path).context("read zstd dictionary")?;
+            let _ = remove_file(&dictionary_path);
+            ("zst", Some(dictionary))
+        }
+    };
+    let encoded = document_ids.into_iter().map(|document_id| {
+        let path = document_path(document_id);
+        let raw = read(&path).context("read temporary document file")?;
+        let compressed = read(path.with_extension(extension)).context("read compressed document file")?;
+        Ok((document_id, tag_document(raw, compressed)))
+    }).collect::<Result<_, BuildError>>()?;
+    let _ = remove_dir_all(&dir);
+    Ok((encoded, dictionary))
+}
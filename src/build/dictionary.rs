@@ -0,0 +1,214 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::fs::{read, write};
+use std::hash::Hasher;
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::TermId;
+use crate::build::error::{BuildError, IoContext};
+
+const EMPTY_SLOT: TermId = TermId::MAX;
+
+fn hash_term(term: &str) -> u64 {
+    // DefaultHasher::new always uses the same keys, so slot assignment is deterministic across builds.
+    let mut hasher = DefaultHasher::new();
+    hasher.write(term.as_bytes());
+    hasher.finish()
+}
+
+/**
+ * Interns terms into one contiguous arena, assigning term IDs in order of first insertion.
+ * Each unique term is stored exactly once; the hash table only holds term IDs and compares against slices of the arena.
+ */
+pub struct TermDictionary {
+    // All unique terms, concatenated.
+    arena: String,
+    // term_id => (start, end) of term in arena.
+    ranges: Vec<(usize, usize)>,
+    // Open addressing hash table of term IDs with linear probing. Length is always a power of two.
+    slots: Vec<TermId>,
+}
+
+impl TermDictionary {
+    pub fn new() -> TermDictionary {
+        TermDictionary {
+            arena: String::new(),
+            ranges: Vec::new(),
+            slots: vec![EMPTY_SLOT; 1024],
+        }
+    }
+
+    /**
+     * Load a dictionary written by `save`. The file contains every term in ID order, each terminated by '\0',
+     * so the ID of a term is its position in the file.
+     */
+    pub fn load(path: &PathBuf) -> Result<TermDictionary, BuildError> {
+        let raw = String::from_utf8(read(path).context("read term dictionary")?)
+            .map_err(|_| BuildError::InvalidInput("Term dictionary is not valid UTF-8".to_string()))?;
+        let mut terms = TermDictionary::new();
+        for term in raw.split_terminator('\0') {
+            if !terms.intern(term).1 {
+                return Err(BuildError::InvalidInput(format!("Term dictionary contains duplicate term {:?}", term)));
+            };
+        };
+        Ok(terms)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> Result<(), BuildError> {
+        let mut raw = String::with_capacity(self.arena.len() + self.ranges.len());
+        for term_id in 0..self.len() {
+            raw.push_str(self.term(term_id));
+            raw.push('\0');
+        };
+        write(path, raw).context("write term dictionary")
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn term(&self, term_id: TermId) -> &str {
+        let (start, end) = self.ranges[term_id];
+        &self.arena[start..end]
+    }
+
+    // Return the slot that contains `term`, or the empty slot where it would be inserted.
+    fn find_slot(&self, term: &str) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut slot = (hash_term(term) as usize) & mask;
+        loop {
+            let term_id = self.slots[slot];
+            if term_id == EMPTY_SLOT || self.term(term_id) == term {
+                return slot;
+            };
+            slot = (slot + 1) & mask;
+        };
+    }
+
+    fn grow(&mut self) -> () {
+        let mask = self.slots.len() * 2 - 1;
+        let mut slots = vec![EMPTY_SLOT; self.slots.len() * 2];
+        for term_id in 0..self.ranges.len() {
+            let mut slot = (hash_term(self.term(term_id)) as usize) & mask;
+            while slots[slot] != EMPTY_SLOT {
+                slot = (slot + 1) & mask;
+            };
+            slots[slot] = term_id;
+        };
+        self.slots = slots;
+    }
+
+    // Get the ID of `term`, inserting it if it doesn't exist. The second value is true if the term was inserted.
+    pub fn intern(&mut self, term: &str) -> (TermId, bool) {
+        let slot = self.find_slot(term);
+        if self.slots[slot] != EMPTY_SLOT {
+            return (self.slots[slot], false);
+        };
+        let term_id = self.ranges.len();
+        let start = self.arena.len();
+        self.arena.push_str(term);
+        self.ranges.push((start, self.arena.len()));
+        self.slots[slot] = term_id;
+        // Keep load factor at or below 0.5 so probe sequences stay short.
+        if self.ranges.len() * 2 > self.slots.len() {
+            self.grow();
+        };
+        (term_id, true)
+    }
+}
+
+/**
+ * Write every indexed term and its ID to `dictionary.bin`, in term order, so that other tools can translate between terms and IDs without reading the index.
+ * Terms are sorted by their UTF-8 bytes, so a term can be found using a binary search of the offsets.
+ * The file contains, with every integer as u32le:
+ * - the amount of terms;
+ * - the offset of each term in the terms area, followed by the length of the terms area;
+ * - the ID of each term;
+ * - the terms area, which is every term in UTF-8 concatenated.
+ */
+pub(crate) fn write_sorted_dictionary(path: &PathBuf, mut entries: Vec<(&str, TermId)>) -> Result<(), BuildError> {
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    let terms_len: usize = entries.iter().map(|(term, _)| term.len()).sum();
+    let mut out = Vec::<u8>::with_capacity(4 + (entries.len() * 2 + 1) * 4 + terms_len);
+    out.write_u32::<LittleEndian>(entries.len().try_into().expect("too many terms")).unwrap();
+    let mut offset = 0;
+    for (term, _) in entries.iter() {
+        out.write_u32::<LittleEndian>(offset).unwrap();
+        offset += term.len() as u32;
+    };
+    out.write_u32::<LittleEndian>(terms_len.try_into().expect("terms are too large for dictionary.bin")).unwrap();
+    for (_, term_id) in entries.iter() {
+        out.write_u32::<LittleEndian>((*term_id).try_into().expect("too many terms")).unwrap();
+    };
+    for (term, _) in entries.iter() {
+        out.extend_from_slice(term.as_bytes());
+    };
+    write(path, out).context("write dictionary.bin")
+}
+
+// The terms and IDs of a build, loaded from the `dictionary.bin` written with `BuildConfig::write_dictionary`.
+pub struct SortedTermDictionary {
+    raw: Vec<u8>,
+    len: usize,
+    terms_start: usize,
+}
+
+impl SortedTermDictionary {
+    pub fn load(path: &PathBuf) -> Result<SortedTermDictionary, BuildError> {
+        let raw = read(path).context("read dictionary.bin")?;
+        let truncated = || BuildError::InvalidInput("dictionary.bin is truncated".to_string());
+        if raw.len() < 8 {
+            return Err(truncated());
+        };
+        let len = LittleEndian::read_u32(&raw) as usize;
+        let terms_start = 4 + (len * 2 + 1) * 4;
+        if raw.len() < terms_start || raw.len() != terms_start + LittleEndian::read_u32(&raw[4 + len * 4..]) as usize {
+            return Err(truncated());
+        };
+        let dictionary = SortedTermDictionary { raw, len, terms_start };
+        for i in 0..len {
+            if std::str::from_utf8(dictionary.term_bytes(i)).is_err() {
+                return Err(BuildError::InvalidInput("dictionary.bin contains a term that is not valid UTF-8".to_string()));
+            };
+        };
+        Ok(dictionary)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn u32_at(&self, index: usize) -> usize {
+        LittleEndian::read_u32(&self.raw[4 + index * 4..]) as usize
+    }
+
+    fn term_bytes(&self, i: usize) -> &[u8] {
+        &self.raw[self.terms_start + self.u32_at(i)..self.terms_start + self.u32_at(i + 1)]
+    }
+
+    // Get the term and ID of the `i`-th term in term order.
+    pub fn entry(&self, i: usize) -> (&str, TermId) {
+        // Terms were checked to be valid UTF-8 when loading.
+        (std::str::from_utf8(self.term_bytes(i)).unwrap(), self.u32_at(self.len + 1 + i))
+    }
+
+    // Get the ID of a term, or None if it isn't indexed.
+    pub fn id(&self, term: &str) -> Option<TermId> {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.term_bytes(mid).cmp(term.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(self.u32_at(self.len + 1 + mid)),
+            };
+        };
+        None
+    }
+}
@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Why a build failed, which `build` returns instead of panicking so that library users can handle failures.
+#[derive(Debug)]
+pub enum BuildError {
+    // Reading or writing a file failed. `context` describes what was being done, such as "write worker.data".
+    Io { context: String, source: io::Error },
+    // A term isn't valid UTF-8 and invalid terms are handled as errors.
+    InvalidTerm { document_id: usize },
+    // An input file is malformed, or disagrees with another input, such as having values for a different amount of documents.
+    InvalidInput(String),
+    // There are more documents than IDs can represent, as JavaScript and Roaring Bitmaps only support 32-bit integers.
+    TooManyDocuments,
+    // Options that can't be used together or are out of range.
+    InvalidConfig(String),
+    // Compiling the runner to WASM failed, with the compiler's diagnostics.
+    WasmCompilation(String),
+    // An external program, such as `gzip` or `tar`, couldn't be run or exited unsuccessfully.
+    Command(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Io { context, source } => write!(f, "Failed to {}: {}", context, source),
+            BuildError::InvalidTerm { document_id } => write!(f, "Term in document {} is not valid UTF-8", document_id),
+            BuildError::InvalidInput(msg) => write!(f, "{}", msg),
+            BuildError::TooManyDocuments => write!(f, "There are too many documents, as at most 2^32 are supported"),
+            BuildError::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
+            BuildError::WasmCompilation(diagnostics) => write!(f, "Failed to compile WASM:\n{}", diagnostics),
+            BuildError::Command(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Describe what was being done when an I/O operation failed, such as `.context("write worker.data")?`.
+pub trait IoContext<T> {
+    fn context(self, context: &str) -> Result<T, BuildError>;
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T, BuildError>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, context: &str) -> Result<T, BuildError> {
+        self.map_err(|source| BuildError::Io { context: context.to_string(), source })
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T, BuildError> {
+        self.map_err(|source| BuildError::Io { context: context(), source })
+    }
+}
+
+// Check a condition of the configuration, like `assert!` but returning an error.
+pub fn check_config(condition: bool, message: &str) -> Result<(), BuildError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(BuildError::InvalidConfig(message.to_string()))
+    }
+}
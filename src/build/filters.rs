@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+use crate::{DocumentId, Term};
+use crate::build::chunks::ChunkStrKey;
+use crate::build::chunks::bst::BstChunks;
+use crate::build::error::BuildError;
+use crate::build::postings::{Postings, PostingsList};
+
+/**
+ * Pack the postings list of every filter value, which the runner intersects with the documents matching the query terms.
+ * Filters are their own dataset, so a filter value never matches a term with the same text, and they never affect ranking.
+ * Postings lists of filter values are never sampled, as filters must match exactly.
+ * Keep in sync with index_query_t in wasm/index.c.
+ */
+pub fn pack_filters<L: PostingsList>(filters: HashMap<Term, Vec<DocumentId>>, document_count: usize, chunk_size: usize) -> Result<BstChunks<ChunkStrKey>, BuildError> {
+    let mut values = Vec::<Term>::with_capacity(filters.len());
+    let mut lists = Vec::<L>::with_capacity(filters.len());
+    for (value, documents) in filters {
+        let mut list = L::create();
+        for document_id in documents {
+            list.add(document_id);
+        };
+        values.push(value);
+        lists.push(list);
+    };
+    let (builder, _) = Postings::new(values, lists, document_count)?.pack(chunk_size, None)?;
+    Ok(builder)
+}
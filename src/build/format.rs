@@ -0,0 +1,199 @@
+use std::fs::write;
+use std::path::PathBuf;
+
+use crate::DocumentId;
+use crate::build::chunks::{CHUNK_KEY_STR, CHUNK_KEY_U32, ChunkLayout, NESTED_CHUNKS_PER_DIR};
+use crate::build::chunks::bst::NODE_HEADER_LEN;
+use crate::build::compress::{DOCUMENT_ENCODING_COMPRESSED, DOCUMENT_ENCODING_RAW, DocumentCompression};
+use crate::build::error::{BuildError, IoContext};
+use crate::build::js::js_string;
+
+// Increment when any structure described in format.json changes incompatibly.
+pub const FORMAT_VERSION: u32 = 2;
+
+pub struct FormatArgs<'o> {
+    pub output_dir: &'o PathBuf,
+    pub blocks_chunk_count: usize,
+    pub chunk_layout: ChunkLayout,
+    pub document_id_chunk_starts: Option<&'o [DocumentId]>,
+    pub documents_chunk_count: usize,
+    pub documents_stored: bool,
+    pub filters_chunk_count: usize,
+    pub has_bm25: bool,
+    pub has_groups: bool,
+    pub kv_key_namespace: Option<&'o str>,
+    pub per_document_compression: DocumentCompression,
+    pub range_field_names: &'o [String],
+    pub sort_key_names: &'o [String],
+    pub terms_chunk_count: usize,
+}
+
+/**
+ * Write a machine-readable description of the output data to `format.json`, so that other tools can read chunks without the worker.
+ * Keep in sync with build::blocks, build::chunks, build::chunks::bst, build::compress, build::filters, build::groups, build::ids, build::range, build::ranking, and build::sort.
+ */
+pub fn write_format_json(FormatArgs {
+    output_dir,
+    blocks_chunk_count,
+    chunk_layout,
+    document_id_chunk_starts,
+    documents_chunk_count,
+    documents_stored,
+    filters_chunk_count,
+    has_bm25,
+    has_groups,
+    kv_key_namespace,
+    per_document_compression,
+    range_field_names,
+    sort_key_names,
+    terms_chunk_count,
+}: FormatArgs) -> Result<(), BuildError> {
+    let json = format!(r#"{{
+  "version": {VERSION},
+  "chunkLayout": {{
+    "type": {LAYOUT_TYPE},
+    "chunksPerDir": {CHUNKS_PER_DIR},
+    "path": {LAYOUT_PATH},
+    "kvKey": "{KV_NAMESPACE}{{dataset}}/{{id}}"
+  }},
+  "keyEncodings": {{
+    "str": [
+      {{"name": "length", "type": "u8"}},
+      {{"name": "value", "type": "utf8", "length": "length"}}
+    ],
+    "u32": [
+      {{"name": "value", "type": "u32le"}}
+    ]
+  }},
+  "bstChunk": {{
+    "description": "Binary search tree of nodes; the position of the root node is in the chunk lookup of the dataset. Nodes with identical values share the same value bytes.",
+    "nodeHeaderLength": {NODE_HEADER_LEN},
+    "node": [
+      {{"name": "key", "type": "key"}},
+      {{"name": "leftPos", "type": "i32le", "description": "Position of the left child node in the chunk, or -1 if none."}},
+      {{"name": "rightPos", "type": "i32le", "description": "Position of the right child node in the chunk, or -1 if none."}},
+      {{"name": "valueLength", "type": "u32le"}},
+      {{"name": "valuePos", "type": "u32le", "description": "Position of the value bytes in the chunk."}}
+    ]
+  }},
+  "chunkLookup": {{
+    "description": "The first key and root node position of each chunk of a bstChunk dataset in key order, written to the lookup path of the dataset. The chunk with a key is the last one whose first key is not greater than it.",
+    "fields": [
+      {{"name": "keyType", "type": "u8", "description": "{KEY_U32} if keys are u32 or {KEY_STR} if keys are str."}},
+      {{"name": "chunkCount", "type": "u32le"}},
+      {{"name": "entries", "type": "entry[]", "length": "chunkCount"}}
+    ],
+    "entry": [
+      {{"name": "id", "type": "u32le", "description": "ID of the chunk."}},
+      {{"name": "midPos", "type": "u32le", "description": "Position of the root node in the chunk."}},
+      {{"name": "firstKey", "type": "key"}}
+    ]
+  }},
+  "encodedDocument": {{
+    "description": "A document prefixed with a tag byte: {ENCODING_RAW} if the document is stored as is, or {ENCODING_COMPRESSED} if it is compressed using the compression of the dataset.",
+    "fields": [
+      {{"name": "encoding", "type": "u8"}},
+      {{"name": "document", "type": "bytes"}}
+    ]
+  }},
+  "sortTable": {{
+    "description": "Documents ranked by ascending sort key value, with ties broken by ascending document ID.",
+    "fields": [
+      {{"name": "ranks", "type": "u32le[]", "length": "documents", "description": "Rank of each document, in document ID order."}},
+      {{"name": "order", "type": "u32le[]", "length": "documents", "description": "Document ID of each rank, in rank order."}}
+    ]
+  }},
+  "rangeTable": {{
+    "description": "A sortTable of the values of a numeric field, followed by the values in rank order, so ascending.",
+    "fields": [
+      {{"name": "ranks", "type": "u32le[]", "length": "documents", "description": "Rank of each document, in document ID order."}},
+      {{"name": "order", "type": "u32le[]", "length": "documents", "description": "Document ID of each rank, in rank order."}},
+      {{"name": "values", "type": "f64le[]", "length": "documents", "description": "Value of each rank, in rank order."}}
+    ]
+  }},
+  "groupTable": {{
+    "description": "Documents with the same group key have the same group ID, assigned in order of first occurrence.",
+    "fields": [
+      {{"name": "groups", "type": "u32le[]", "length": "documents", "description": "Group ID of each document, in document ID order."}}
+    ]
+  }},
+  "bm25Table": {{
+    "description": "BM25 length normalisation of each document, (k1 + 1) / (1 + k1 * (1 - b + b * length / averageLength)) where length is its amount of terms. A document's BM25 score is this multiplied by the sum of the IDFs of the query terms it contains.",
+    "fields": [
+      {{"name": "normalisations", "type": "f32le[]", "length": "documents", "description": "Normalisation of each document, in document ID order."}}
+    ]
+  }},
+  "blockTable": {{
+    "description": "Block, such as the paragraph, that a term is first in within each document that has it.",
+    "fields": [
+      {{"name": "documents", "type": "u32le[]", "length": "entries", "description": "IDs of documents with a block for the term, in ascending order."}},
+      {{"name": "blocks", "type": "u32le[]", "length": "entries", "description": "Block ID of the term in each document, in the same order."}}
+    ]
+  }},
+  "documentIdTable": {{
+    "description": "Original IDs of consecutive documents, starting from the document in firstDocuments at the index of the chunk.",
+    "fields": [
+      {{"name": "count", "type": "u32le"}},
+      {{"name": "ends", "type": "u32le[]", "length": "count", "description": "End offset of each ID within ids; each ID starts at the end of the previous one."}},
+      {{"name": "ids", "type": "utf8"}}
+    ]
+  }},
+  "datasets": [
+    {{"name": "terms", "format": "bstChunk", "lookup": "terms.lookup", "key": "str", "value": "roaringPortableBitmap", "chunks": {TERMS_CHUNK_COUNT}}},
+    {{"name": "documents", "format": "bstChunk", "lookup": "documents.lookup", "key": "u32", "value": {DOCUMENTS_VALUE}, "compression": {DOCUMENTS_COMPRESSION}, "stored": {DOCUMENTS_STORED}, "chunks": {DOCUMENTS_CHUNK_COUNT}}},
+    {{"name": "dictionaries", "format": "zstdDictionary", "chunks": {DICTIONARIES_CHUNK_COUNT}, "description": "Dictionary that documents compressed with zstd were compressed against."}},
+    {{"name": "sorts", "format": "sortTable", "chunks": {SORTS_CHUNK_COUNT}, "names": [{SORT_KEY_NAMES}]}},
+    {{"name": "ranges", "format": "rangeTable", "chunks": {RANGES_CHUNK_COUNT}, "names": [{RANGE_FIELD_NAMES}]}},
+    {{"name": "ids", "format": "documentIdTable", "chunks": {IDS_CHUNK_COUNT}, "firstDocuments": [{IDS_FIRST_DOCUMENTS}]}},
+    {{"name": "groups", "format": "groupTable", "chunks": {GROUPS_CHUNK_COUNT}}},
+    {{"name": "ranking", "format": "bm25Table", "chunks": {RANKING_CHUNK_COUNT}}},
+    {{"name": "blocks", "format": "bstChunk", "lookup": "blocks.lookup", "key": "str", "value": "blockTable", "chunks": {BLOCKS_CHUNK_COUNT}}},
+    {{"name": "filters", "format": "bstChunk", "lookup": "filters.lookup", "key": "str", "value": "roaringPortableBitmap", "chunks": {FILTERS_CHUNK_COUNT}, "description": "Documents with each filter value."}}
+  ]
+}}
+"#,
+        VERSION = FORMAT_VERSION,
+        LAYOUT_TYPE = match chunk_layout {
+            ChunkLayout::Flat => r#""flat""#,
+            ChunkLayout::Nested => r#""nested""#,
+        },
+        CHUNKS_PER_DIR = match chunk_layout {
+            ChunkLayout::Flat => "null".to_string(),
+            ChunkLayout::Nested => format!("{}", NESTED_CHUNKS_PER_DIR),
+        },
+        LAYOUT_PATH = match chunk_layout {
+            ChunkLayout::Flat => r#""{dataset}/{id}""#,
+            ChunkLayout::Nested => r#""{dataset}/{floor(id / chunksPerDir)}/{id}""#,
+        },
+        KV_NAMESPACE = kv_key_namespace.map_or(String::new(), |ns| format!("{}/", ns)),
+        NODE_HEADER_LEN = NODE_HEADER_LEN,
+        KEY_U32 = CHUNK_KEY_U32,
+        KEY_STR = CHUNK_KEY_STR,
+        TERMS_CHUNK_COUNT = terms_chunk_count,
+        ENCODING_RAW = DOCUMENT_ENCODING_RAW,
+        ENCODING_COMPRESSED = DOCUMENT_ENCODING_COMPRESSED,
+        DOCUMENTS_VALUE = match per_document_compression {
+            DocumentCompression::None => r#""utf8Json""#,
+            _ => r#""encodedDocument""#,
+        },
+        DOCUMENTS_COMPRESSION = match per_document_compression {
+            DocumentCompression::None => "null",
+            DocumentCompression::Gzip => r#""gzip""#,
+            DocumentCompression::Zstd => r#""zstd""#,
+        },
+        DOCUMENTS_CHUNK_COUNT = documents_chunk_count,
+        DOCUMENTS_STORED = documents_stored,
+        DICTIONARIES_CHUNK_COUNT = if per_document_compression == DocumentCompression::Zstd { 1 } else { 0 },
+        SORTS_CHUNK_COUNT = sort_key_names.len(),
+        RANGES_CHUNK_COUNT = range_field_names.len(),
+        GROUPS_CHUNK_COUNT = if has_groups { 1 } else { 0 },
+        RANKING_CHUNK_COUNT = if has_bm25 { 1 } else { 0 },
+        BLOCKS_CHUNK_COUNT = blocks_chunk_count,
+        FILTERS_CHUNK_COUNT = filters_chunk_count,
+        IDS_CHUNK_COUNT = document_id_chunk_starts.map_or(0, |starts| starts.len()),
+        IDS_FIRST_DOCUMENTS = document_id_chunk_starts.unwrap_or(&[]).iter().map(|s| s.to_string()).collect::<Vec<String>>().join(", "),
+        RANGE_FIELD_NAMES = range_field_names.iter().map(|n| js_string(n)).collect::<Vec<String>>().join(", "),
+        SORT_KEY_NAMES = sort_key_names.iter().map(|n| js_string(n)).collect::<Vec<String>>().join(", "),
+    );
+    write(output_dir.join("format.json"), json).context("write format.json")
+}
@@ -0,0 +1,21 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/**
+ * Serialise the table the runner uses to collapse results by group.
+ * Each distinct key is given a group ID in order of first occurrence, and the table contains the group ID of each document in document ID order.
+ * Returns the amount of groups, and the serialised table.
+ * Keep in sync with index_query_t in wasm/index.c.
+ */
+pub fn serialise_group_table(keys: &[String]) -> (usize, Vec<u8>) {
+    let mut group_ids = HashMap::<&str, u32>::new();
+    let mut out = Vec::<u8>::with_capacity(keys.len() * 4);
+    for key in keys {
+        let next_id = group_ids.len().try_into().expect("too many groups");
+        let group_id = *group_ids.entry(key.as_str()).or_insert(next_id);
+        out.write_u32::<LittleEndian>(group_id).expect("write group table");
+    };
+    (group_ids.len(), out)
+}
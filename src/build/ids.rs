@@ -0,0 +1,44 @@
+use std::convert::TryInto;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::DocumentId;
+use crate::build::error::BuildError;
+
+/**
+ * Pack the original ID of each document into chunks of consecutive documents, so the chunk containing a document can be found from its internal ID alone.
+ * Each chunk contains the amount of IDs, then the end offset of each ID within the ID bytes, then the UTF-8 bytes of every ID, all concatenated.
+ * Returns the internal ID of the first document in each chunk, and the serialised chunks.
+ * Keep in sync with findOriginalDocumentIds in script/src/main.ts.
+ */
+pub fn serialise_document_id_chunks(ids: &[String], chunk_size: usize) -> Result<(Vec<DocumentId>, Vec<Vec<u8>>), BuildError> {
+    let mut chunk_starts = Vec::<DocumentId>::new();
+    let mut chunks = Vec::<Vec<u8>>::new();
+    let mut start = 0;
+    while start < ids.len() {
+        let mut end = start;
+        let mut size = 4;
+        while end < ids.len() && size + 4 + ids[end].len() <= chunk_size {
+            size += 4 + ids[end].len();
+            end += 1;
+        };
+        if end == start {
+            return Err(BuildError::InvalidInput(format!("ID of document {} does not fit in a chunk", start)));
+        };
+
+        let mut chunk = Vec::<u8>::with_capacity(size);
+        chunk.write_u32::<LittleEndian>((end - start).try_into().expect("too many documents")).expect("write document IDs chunk");
+        let mut offset = 0;
+        for id in &ids[start..end] {
+            offset += id.len();
+            chunk.write_u32::<LittleEndian>(offset.try_into().expect("document IDs chunk is too large")).expect("write document IDs chunk");
+        };
+        for id in &ids[start..end] {
+            chunk.extend_from_slice(id.as_bytes());
+        };
+        chunk_starts.push(start.try_into().map_err(|_| BuildError::TooManyDocuments)?);
+        chunks.push(chunk);
+        start = end;
+    };
+    Ok((chunk_starts, chunks))
+}
@@ -1,38 +1,654 @@
-use std::fs::File;
+use std::fs::{create_dir_all, File};
 use std::io::Write;
 use std::path::PathBuf;
-use crate::build::DataStore;
+
+use clap::arg_enum;
+
+use crate::{DocumentId, Term};
+use crate::build::{DataStore, EmptyResultBehaviour, ExpandedTermsOverflow, OnUnknownTerm, ReadTimeoutBehaviour};
+use crate::build::chunks::{ChunkLayout, NESTED_CHUNKS_PER_DIR};
+use crate::build::compress::DocumentCompression;
+use crate::build::error::{BuildError, IoContext};
+use crate::data::normalise::{Analyser, AnalyserStage, CaseFolding, UnicodeNormalisation};
 
 const WORKER_JS_MAIN_TEMPLATE: &'static str = include_str!("../../script/dist/main.js");
 const WORKER_JS_DATA_SOURCE_KV: &'static str = include_str!("../../script/dist/datastore/kv.js");
 const WORKER_JS_DATA_SOURCE_URL: &'static str = include_str!("../../script/dist/datastore/url.js");
 
-pub fn generate_worker_js(
-    output_dir: &PathBuf,
-    data_store: DataStore,
-    data_store_url_prefix: Option<String>,
-    document_count: usize,
-    max_query_terms: usize,
-    max_results: usize,
-) -> () {
+arg_enum! {
+    // What runs the generated script. The data format and runner are the same for every target.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum DeployTarget {
+        // A Cloudflare worker script, `worker.js`, that handles fetch events.
+        Worker,
+        // An ES module, `edgesearch.mjs`, that runs queries in the browser by fetching `runner.wasm` and chunks from static hosting.
+        Browser,
+        // A Cloudflare Pages Function, `functions{route_prefix}/[[path]].js`, for a Pages project whose root is the output folder.
+        PagesFunctions,
+    }
+}
+
+arg_enum! {
+    // How the worker formats error responses, which have an HTTP status for their category of error.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum ErrorFormat {
+        // `{"error": "..."}` with the message, using the response content type.
+        Message,
+        // `{"error": {"code": "...", "message": "..."}}`, using the response content type, so that clients can handle errors by code.
+        Envelope,
+        // Only the message, as plain text.
+        Text,
+    }
+}
+
+arg_enum! {
+    // Where the worker sends a record of each search, with its status, term count, result count, chunks read, and duration.
+    // Keep in sync with logQuery in script/src/main.ts.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum QueryLogSink {
+        None,
+        // A JSON line written using console.log, which Cloudflare Logpush and `wrangler tail` collect.
+        Console,
+        // A data point written to the Workers Analytics Engine dataset bound to the worker as QUERY_ANALYTICS.
+        AnalyticsEngine,
+        // A JSON object POSTed to the endpoint without delaying the response.
+        Endpoint,
+    }
+}
+
+impl DeployTarget {
+    // Get the path of the generated script relative to the output folder.
+    pub fn script_path(self, route_prefix: &str) -> PathBuf {
+        match self {
+            DeployTarget::Worker => PathBuf::from("worker.js"),
+            DeployTarget::Browser => PathBuf::from("edgesearch.mjs"),
+            // Pages routes requests by file path, so the function handles every path under the route prefix.
+            DeployTarget::PagesFunctions => PathBuf::from(format!("functions{}/[[path]].js", route_prefix)),
+        }
+    }
+}
+
+// Escape a value as a JS string literal, which is also a valid JSON string.
+pub fn js_string(val: &str) -> String {
+    let mut out = String::from("\"");
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 || c == '\u{2028}' || c == '\u{2029}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        };
+    };
+    out.push('"');
+    out
+}
+
+// How the worker formats its JSON responses.
+pub struct ResponseFormatConfig {
+    // Indent JSON for readability when debugging. This requires parsing every response, so it is slower and uses more bytes.
+    pub pretty: bool,
+    pub content_type: String,
+    // Fields to keep in each search result that is a JSON object, dropping the rest. Empty to return results as stored, which avoids parsing them.
+    pub result_projection: Vec<String>,
+    // Start sending search responses before documents are fetched, writing each result once it's available, to reduce time to first byte and memory use.
+    pub stream_results: bool,
+    // Allow search requests to ask for which query terms each result matched and why it ranked where it did. Intended for debugging relevance.
+    pub explain: bool,
+    pub error_format: ErrorFormat,
+}
+
+impl Default for ResponseFormatConfig {
+    fn default() -> ResponseFormatConfig {
+        ResponseFormatConfig {
+            pretty: false,
+            content_type: "application/json".to_string(),
+            result_projection: Vec::new(),
+            stream_results: false,
+            explain: false,
+            error_format: ErrorFormat::Message,
+        }
+    }
+}
+
+// How the worker records searches, for monitoring query volume, latency, and results.
+pub struct QueryLogConfig {
+    pub sink: QueryLogSink,
+    // URL to POST records to, which is required by and only allowed with the endpoint sink.
+    pub endpoint: Option<String>,
+    // Fraction of searches to record, from 0 exclusive to 1 inclusive, to limit logging costs on busy workers.
+    pub sample_rate: f64,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> QueryLogConfig {
+        QueryLogConfig {
+            sink: QueryLogSink::None,
+            endpoint: None,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+impl QueryLogConfig {
+    pub fn validate(&self, deploy_target: DeployTarget) -> Result<(), String> {
+        if !(self.sample_rate > 0.0 && self.sample_rate <= 1.0) {
+            return Err(format!("sample rate {} is not greater than 0 and at most 1", self.sample_rate));
+        };
+        match (self.sink, &self.endpoint) {
+            (QueryLogSink::Endpoint, None) => return Err("the endpoint sink requires an endpoint".to_string()),
+            (QueryLogSink::Endpoint, Some(endpoint)) if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") => return Err(format!("endpoint {:?} is not an HTTP URL", endpoint)),
+            (QueryLogSink::Endpoint, Some(_)) => {}
+            (_, Some(_)) => return Err("an endpoint is only used by the endpoint sink".to_string()),
+            (_, None) => {}
+        };
+        // Browsers have no Analytics Engine binding.
+        if self.sink == QueryLogSink::AnalyticsEngine && deploy_target == DeployTarget::Browser {
+            return Err("the browser deploy target cannot write to Analytics Engine".to_string());
+        };
+        Ok(())
+    }
+}
+
+pub struct WorkerJsArgs<'o> {
+    pub output_dir: &'o PathBuf,
+    // Terms whose postings lists were sampled, with the amount of documents each is actually in.
+    pub approximate_terms: Vec<(Term, usize)>,
+    // Whether to rank results of searches without a sort key by BM25, using the BM25 table.
+    pub bm25: bool,
+    pub chunk_layout: ChunkLayout,
+    pub chunk_read_concurrency: usize,
+    pub chunk_read_retries: usize,
+    // Milliseconds before the first retry, which doubles after each retry.
+    pub chunk_read_retry_delay: u64,
+    pub chunk_read_timeout: Option<u64>,
+    pub chunk_read_timeout_behaviour: ReadTimeoutBehaviour,
+    pub data_store: DataStore,
+    pub data_store_url_prefix: Option<String>,
+    pub deploy_target: DeployTarget,
+    pub document_count: usize,
+    // Internal ID of the first document in each chunk of original document IDs, if documents have original IDs.
+    pub document_id_chunk_starts: Option<Vec<DocumentId>>,
+    pub document_route: bool,
+    // Whether documents were packed. If not, searches return the internal ID of each result instead.
+    pub documents_stored: bool,
+    pub empty_result_behaviour: EmptyResultBehaviour,
+    pub expanded_terms_overflow: ExpandedTermsOverflow,
+    // Whether documents have filter values, which searches can then require.
+    pub filters: bool,
+    // Maximum amount of matching documents to scan when collapsing results by group, or None if documents have no group keys.
+    pub group_scan_limit: Option<usize>,
+    // Namespace that every KV key is prefixed with, if any.
+    pub kv_key_namespace: Option<String>,
+    pub max_expanded_terms: Option<usize>,
+    // Maximum amount of indexed terms a prefix is expanded to, or zero if searches can't mark their last term as a prefix.
+    pub max_prefix_expansions: usize,
+    pub max_query_terms: usize,
+    pub max_results: usize,
+    pub min_query_bytes: usize,
+    pub min_query_terms: usize,
+    pub on_unknown_term: OnUnknownTerm,
+    pub per_document_compression: DocumentCompression,
+    // Whether `runner.wasm` and every chunk have gzip copies, which the browser deploy target then fetches instead.
+    pub precompressed_gzip: bool,
+    // Maximum amount of matching documents to scan when boosting by proximity, or None if terms have no block tables.
+    pub proximity_scan_limit: Option<usize>,
+    pub query_log: QueryLogConfig,
+    // Names of range fields, where the chunk ID of each range table is its index.
+    pub range_field_names: Vec<String>,
+    pub response: ResponseFormatConfig,
+    // Path that all routes are under, without a trailing slash. Empty for the root.
+    pub route_prefix: String,
+    // Size of the memory given to the runner, which every query allocates from.
+    pub runner_memory_pages: usize,
+    // Whether searches can be scoped to documents provided by the client.
+    pub scoped_search: bool,
+    // Names of sort keys, where the chunk ID of each sort table is its index.
+    pub sort_key_names: Vec<String>,
+    pub term_analyser: Analyser,
+    // Length in bytes that long terms were truncated to when building, so that query terms are truncated identically.
+    pub term_truncation_bytes: Option<usize>,
+    pub term_stats_route: bool,
+    pub worker_middleware: Option<String>,
+    pub worker_prelude: Option<String>,
+}
+
+// Wrap user JavaScript from `WorkerConfig` in an async function with the given parameters, or use undefined if there is none.
+// The code is on its own lines so that a trailing line comment can't swallow the closing brace.
+fn worker_hook_js(code: Option<String>, params: &str) -> String {
+    code.map_or("undefined".to_string(), |code| format!("async ({}) => {{\n{}\n}}", params, code))
+}
+
+pub fn generate_worker_js(WorkerJsArgs {
+    output_dir,
+    approximate_terms,
+    bm25,
+    chunk_layout,
+    chunk_read_concurrency,
+    chunk_read_retries,
+    chunk_read_retry_delay,
+    chunk_read_timeout,
+    chunk_read_timeout_behaviour,
+    data_store,
+    data_store_url_prefix,
+    deploy_target,
+    document_count,
+    document_id_chunk_starts,
+    document_route,
+    documents_stored,
+    empty_result_behaviour,
+    expanded_terms_overflow,
+    filters,
+    group_scan_limit,
+    kv_key_namespace,
+    max_expanded_terms,
+    max_prefix_expansions,
+    max_query_terms,
+    max_results,
+    min_query_bytes,
+    min_query_terms,
+    on_unknown_term,
+    per_document_compression,
+    precompressed_gzip,
+    proximity_scan_limit,
+    query_log,
+    range_field_names,
+    response,
+    route_prefix,
+    runner_memory_pages,
+    scoped_search,
+    sort_key_names,
+    term_analyser,
+    term_truncation_bytes,
+    term_stats_route,
+    worker_middleware,
+    worker_prelude,
+}: WorkerJsArgs) -> Result<(), BuildError> {
     let js = WORKER_JS_MAIN_TEMPLATE
         // Keep in sync with variables declared in script/src/**/*.ts.
         .replace(r#""use strict";"#, format!(r#"
+            const APPROXIMATE_TERMS = {APPROXIMATE_TERMS};
+            const BM25 = {BM25};
+            const CHUNK_READ_CONCURRENCY = {CHUNK_READ_CONCURRENCY};
+            const CHUNK_READ_RETRIES = {CHUNK_READ_RETRIES};
+            const CHUNK_READ_RETRY_DELAY = {CHUNK_READ_RETRY_DELAY};
+            const CHUNK_READ_TIMEOUT = {CHUNK_READ_TIMEOUT};
+            const CHUNK_READ_TIMEOUT_DEGRADE = {CHUNK_READ_TIMEOUT_DEGRADE};
+            const CHUNKS_PER_DIR = {CHUNKS_PER_DIR};
             const DATASTORE_URL_PREFIX = {DATASTORE_URL_PREFIX};
+            const DOCUMENT_COMPRESSION = {DOCUMENT_COMPRESSION};
             const DOCUMENT_COUNT = {DOCUMENT_COUNT};
+            const DOCUMENT_ID_CHUNKS = {DOCUMENT_ID_CHUNKS};
+            const DOCUMENT_ROUTE = {DOCUMENT_ROUTE};
+            const DOCUMENTS_STORED = {DOCUMENTS_STORED};
+            const EMPTY_RESULT_STATUS = {EMPTY_RESULT_STATUS};
+            const ERROR_FORMAT = {ERROR_FORMAT};
+            const EXPANDED_TERMS_OVERFLOW_TRUNCATE = {EXPANDED_TERMS_OVERFLOW_TRUNCATE};
+            const EXPLAIN = {EXPLAIN};
+            const FETCH_EVENT_LISTENER = {FETCH_EVENT_LISTENER};
+            const FILTERS = {FILTERS};
+            const GROUP_SCAN_LIMIT = {GROUP_SCAN_LIMIT};
+            const KV_KEY_PREFIX = {KV_KEY_PREFIX};
+            const MAX_EXPANDED_TERMS = {MAX_EXPANDED_TERMS};
+            const MAX_PREFIX_EXPANSIONS = {MAX_PREFIX_EXPANSIONS};
             const MAX_QUERY_TERMS = {MAX_QUERY_TERMS};
             const MAX_RESULTS = {MAX_RESULTS};
+            const MIN_QUERY_BYTES = {MIN_QUERY_BYTES};
+            const MIN_QUERY_TERMS = {MIN_QUERY_TERMS};
+            const ON_UNKNOWN_TERM = {ON_UNKNOWN_TERM};
+            const PRECOMPRESSED_GZIP = {PRECOMPRESSED_GZIP};
+            const PROXIMITY_SCAN_LIMIT = {PROXIMITY_SCAN_LIMIT};
+            const QUERY_LOG = {QUERY_LOG};
+            const QUERY_LOG_ENDPOINT = {QUERY_LOG_ENDPOINT};
+            const QUERY_LOG_SAMPLE_RATE = {QUERY_LOG_SAMPLE_RATE};
+            const RANGE_FIELDS = {RANGE_FIELDS};
+            const RESPONSE_CONTENT_TYPE = {RESPONSE_CONTENT_TYPE};
+            const RESPONSE_PRETTY = {RESPONSE_PRETTY};
+            const RESULT_PROJECTION = {RESULT_PROJECTION};
+            const ROUTE_PREFIX = {ROUTE_PREFIX};
+            const RUNNER_MEMORY_PAGES = {RUNNER_MEMORY_PAGES};
+            const SCOPED_SEARCH = {SCOPED_SEARCH};
+            const SORT_KEYS = {SORT_KEYS};
+            const STREAM_RESULTS = {STREAM_RESULTS};
+            const TERM_ANALYSER = {TERM_ANALYSER};
+            const TERM_STATS_ROUTE = {TERM_STATS_ROUTE};
+            const TERM_TRUNCATION_BYTES = {TERM_TRUNCATION_BYTES};
+            const WORKER_MIDDLEWARE = {WORKER_MIDDLEWARE};
+            const WORKER_PRELUDE = {WORKER_PRELUDE};
             {DATA_SOURCE_JS}
         "#,
-            DATASTORE_URL_PREFIX = data_store_url_prefix.map_or("undefined".to_string(), |prefix| format!("`{}`", prefix)),
+            APPROXIMATE_TERMS = format_args!("new Map([{}])", approximate_terms.iter().map(|(term, count)| format!("[{}, {}]", js_string(term), count)).collect::<Vec<String>>().join(", ")),
+            BM25 = bm25,
+            CHUNK_READ_CONCURRENCY = chunk_read_concurrency,
+            CHUNK_READ_RETRIES = chunk_read_retries,
+            CHUNK_READ_RETRY_DELAY = chunk_read_retry_delay,
+            CHUNK_READ_TIMEOUT = chunk_read_timeout.map_or("undefined".to_string(), |ms| format!("{}", ms)),
+            CHUNK_READ_TIMEOUT_DEGRADE = match chunk_read_timeout_behaviour {
+                ReadTimeoutBehaviour::Degrade => true,
+                ReadTimeoutBehaviour::Fail => false,
+            },
+            CHUNKS_PER_DIR = match chunk_layout {
+                ChunkLayout::Flat => "undefined".to_string(),
+                ChunkLayout::Nested => format!("{}", NESTED_CHUNKS_PER_DIR),
+            },
+            DATASTORE_URL_PREFIX = match (data_store_url_prefix, deploy_target) {
+                (Some(prefix), _) => format!("`{}`", prefix),
+                // Fetch chunks from alongside the module by default, wherever it is hosted.
+                (None, DeployTarget::Browser) => "new URL('./', import.meta.url).href".to_string(),
+                (None, DeployTarget::Worker) | (None, DeployTarget::PagesFunctions) => "undefined".to_string(),
+            },
+            DOCUMENT_COMPRESSION = match per_document_compression {
+                DocumentCompression::None => "undefined",
+                DocumentCompression::Gzip => r#""gzip""#,
+                DocumentCompression::Zstd => r#""zstd""#,
+            },
             DOCUMENT_COUNT = document_count,
+            DOCUMENT_ID_CHUNKS = document_id_chunk_starts.map_or("undefined".to_string(), |starts| format!("[{}]", starts.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(", "))),
+            DOCUMENT_ROUTE = document_route,
+            DOCUMENTS_STORED = documents_stored,
+            EMPTY_RESULT_STATUS = match empty_result_behaviour {
+                EmptyResultBehaviour::Empty => 200,
+                EmptyResultBehaviour::NotFound => 404,
+            },
+            ERROR_FORMAT = match response.error_format {
+                ErrorFormat::Message => r#""message""#,
+                ErrorFormat::Envelope => r#""envelope""#,
+                ErrorFormat::Text => r#""text""#,
+            },
+            EXPANDED_TERMS_OVERFLOW_TRUNCATE = match expanded_terms_overflow {
+                ExpandedTermsOverflow::Error => false,
+                ExpandedTermsOverflow::Truncate => true,
+            },
+            EXPLAIN = response.explain,
+            // Pages Functions are modules that export their handler instead.
+            FETCH_EVENT_LISTENER = deploy_target != DeployTarget::PagesFunctions,
+            FILTERS = filters,
+            GROUP_SCAN_LIMIT = group_scan_limit.map_or("undefined".to_string(), |l| format!("{}", l)),
+            // Keep in sync with the kvKey of format.json.
+            KV_KEY_PREFIX = js_string(&kv_key_namespace.map_or(String::new(), |ns| format!("{}/", ns))),
+            MAX_EXPANDED_TERMS = max_expanded_terms.map_or("undefined".to_string(), |m| format!("{}", m)),
+            MAX_PREFIX_EXPANSIONS = max_prefix_expansions,
             MAX_QUERY_TERMS = max_query_terms,
             MAX_RESULTS = max_results,
+            MIN_QUERY_BYTES = min_query_bytes,
+            MIN_QUERY_TERMS = min_query_terms,
+            ON_UNKNOWN_TERM = match on_unknown_term {
+                OnUnknownTerm::Empty => r#""empty""#,
+                OnUnknownTerm::Ignore => r#""ignore""#,
+                OnUnknownTerm::Error => r#""error""#,
+            },
+            // Only the browser fetches the copies, as workers are charged for the CPU time to decompress them.
+            PRECOMPRESSED_GZIP = precompressed_gzip && deploy_target == DeployTarget::Browser,
+            PROXIMITY_SCAN_LIMIT = proximity_scan_limit.map_or("undefined".to_string(), |l| format!("{}", l)),
+            QUERY_LOG = match query_log.sink {
+                QueryLogSink::None => "undefined",
+                QueryLogSink::Console => r#""console""#,
+                QueryLogSink::AnalyticsEngine => r#""analytics""#,
+                QueryLogSink::Endpoint => r#""endpoint""#,
+            },
+            QUERY_LOG_ENDPOINT = query_log.endpoint.as_ref().map_or("undefined".to_string(), |e| js_string(e)),
+            QUERY_LOG_SAMPLE_RATE = query_log.sample_rate,
+            RANGE_FIELDS = format_args!("[{}]", range_field_names.iter().map(|n| js_string(n)).collect::<Vec<String>>().join(", ")),
+            RESPONSE_CONTENT_TYPE = js_string(&response.content_type),
+            RESPONSE_PRETTY = response.pretty,
+            RESULT_PROJECTION = if response.result_projection.is_empty() {
+                "undefined".to_string()
+            } else {
+                format!("[{}]", response.result_projection.iter().map(|f| js_string(f)).collect::<Vec<String>>().join(", "))
+            },
+            ROUTE_PREFIX = js_string(&route_prefix),
+            RUNNER_MEMORY_PAGES = runner_memory_pages,
+            SCOPED_SEARCH = scoped_search,
+            SORT_KEYS = format_args!("[{}]", sort_key_names.iter().map(|n| js_string(n)).collect::<Vec<String>>().join(", ")),
+            STREAM_RESULTS = response.stream_results,
+            TERM_ANALYSER = format_args!("[{}]", term_analyser.stages.iter().map(|stage| match stage {
+                AnalyserStage::Lowercase(case_folding) => format!(r#"{{stage: "lowercase", caseFolding: "{}"}}"#, match case_folding {
+                    CaseFolding::Unicode => "unicode",
+                    CaseFolding::Ascii => "ascii",
+                    CaseFolding::Turkic => "turkic",
+                }),
+                AnalyserStage::Normalise(form) => format!(r#"{{stage: "normalise", form: "{}"}}"#, match form {
+                    UnicodeNormalisation::NFC => "NFC",
+                    UnicodeNormalisation::NFKC => "NFKC",
+                }),
+                AnalyserStage::FoldDiacritics => r#"{stage: "foldDiacritics"}"#.to_string(),
+                AnalyserStage::StripStopwords(stopwords) => format!(r#"{{stage: "stopwords", words: new Set([{}])}}"#, stopwords.iter().map(|w| js_string(w)).collect::<Vec<String>>().join(", ")),
+            }).collect::<Vec<String>>().join(", ")),
+            TERM_STATS_ROUTE = term_stats_route,
+            TERM_TRUNCATION_BYTES = term_truncation_bytes.map_or("undefined".to_string(), |b| b.to_string()),
+            WORKER_MIDDLEWARE = worker_hook_js(worker_middleware, "request, response"),
+            WORKER_PRELUDE = worker_hook_js(worker_prelude, "request"),
             DATA_SOURCE_JS = match data_store {
                 DataStore::KV => WORKER_JS_DATA_SOURCE_KV,
                 DataStore::URL => WORKER_JS_DATA_SOURCE_URL,
             },
         ).as_str());
 
-    File::create(output_dir.join("worker.js")).expect("create worker.js file").write_all(js.as_bytes()).expect("write worker.js");
+    let js = match deploy_target {
+        DeployTarget::Worker => js,
+        // Workers get the runner as a binding, but the browser must fetch and compile it, which top-level await in a module allows.
+        // The fetch event listener is left in place as it never fires outside a service worker.
+        DeployTarget::Browser => format!(r#"
+            const QUERY_RUNNER_WASM = await WebAssembly.compile(await {RUNNER_RESPONSE}.arrayBuffer());
+            {MAIN_JS}
+            // Handle a request for a route, such as `/search?t=0_hello`, as the worker would, resolving relative to the page.
+            export const handleRequest = (url) => requestHandler(new Request(new URL(url, location.href)));
+        "#,
+            // The script's constants aren't declared yet, so whether to fetch the gzip copy is decided here.
+            RUNNER_RESPONSE = if precompressed_gzip {
+                "new Response((await fetch(new URL('runner.wasm.gz', import.meta.url))).body.pipeThrough(new DecompressionStream('gzip')))"
+            } else {
+                "(await fetch(new URL('runner.wasm', import.meta.url)))"
+            },
+            MAIN_JS = js,
+        ),
+        // Pages bundles the imported runner with the function, and passes bindings such as KV with each request instead of as globals.
+        DeployTarget::PagesFunctions => format!(r#"
+            import QUERY_RUNNER_WASM from '{RUNNER_PATH}';
+            let KV;
+            let QUERY_ANALYTICS;
+            {MAIN_JS}
+            export const onRequest = (context) => {{
+              KV = context.env.KV;
+              QUERY_ANALYTICS = context.env.QUERY_ANALYTICS;
+              return requestHandler(context.request, (promise) => context.waitUntil(promise));
+            }};
+        "#,
+            // The function is in a subfolder of `functions` for each segment of the route prefix, and the runner is in the output folder.
+            RUNNER_PATH = format_args!("{}runner.wasm", "../".repeat(1 + route_prefix.split('/').filter(|s| !s.is_empty()).count())),
+            MAIN_JS = js,
+        ),
+    };
+
+    let worker_js_path = output_dir.join(deploy_target.script_path(&route_prefix));
+    create_dir_all(worker_js_path.parent().unwrap()).context("create worker script folder")?;
+    File::create(&worker_js_path).context("create worker script file")?.write_all(js.as_bytes()).context("write worker script")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::env::temp_dir;
+    use std::ffi::OsStr;
+    use std::fs::{copy, read_to_string, remove_dir_all};
+    use std::io::ErrorKind;
+    use std::process::{Command, Output};
+
+    use super::*;
+    use crate::build::{DataStore, EmptyResultBehaviour, ExpandedTermsOverflow, OnUnknownTerm, ReadTimeoutBehaviour};
+
+    // Declarations that must appear in every generated worker script.
+    // Keep in sync with the template in generate_worker_js and script/src/main.ts.
+    const WORKER_JS_REQUIRED_DECLARATIONS: &[&str] = &[
+        "const APPROXIMATE_TERMS = ",
+        "const BM25 = ",
+        "const CHUNK_READ_CONCURRENCY = ",
+        "const CHUNK_READ_RETRIES = ",
+        "const CHUNK_READ_RETRY_DELAY = ",
+        "const CHUNK_READ_TIMEOUT = ",
+        "const CHUNK_READ_TIMEOUT_DEGRADE = ",
+        "const CHUNKS_PER_DIR = ",
+        "const DATASTORE_URL_PREFIX = ",
+        "const DOCUMENT_COMPRESSION = ",
+        "const DOCUMENT_COUNT = ",
+        "const DOCUMENT_ID_CHUNKS = ",
+        "const DOCUMENT_ROUTE = ",
+        "const DOCUMENTS_STORED = ",
+        "const EMPTY_RESULT_STATUS = ",
+        "const ERROR_FORMAT = ",
+        "const EXPANDED_TERMS_OVERFLOW_TRUNCATE = ",
+        "const EXPLAIN = ",
+        "const FETCH_EVENT_LISTENER = ",
+        "const FILTERS = ",
+        "const GROUP_SCAN_LIMIT = ",
+        "const KV_KEY_PREFIX = ",
+        "const MAX_EXPANDED_TERMS = ",
+        "const MAX_PREFIX_EXPANSIONS = ",
+        "const MAX_QUERY_TERMS = ",
+        "const MAX_RESULTS = ",
+        "const MIN_QUERY_BYTES = ",
+        "const MIN_QUERY_TERMS = ",
+        "const ON_UNKNOWN_TERM = ",
+        "const PRECOMPRESSED_GZIP = ",
+        "const PROXIMITY_SCAN_LIMIT = ",
+        "const QUERY_LOG = ",
+        "const QUERY_LOG_ENDPOINT = ",
+        "const QUERY_LOG_SAMPLE_RATE = ",
+        "const RANGE_FIELDS = ",
+        "const RESPONSE_CONTENT_TYPE = ",
+        "const RESPONSE_PRETTY = ",
+        "const RESULT_PROJECTION = ",
+        "const ROUTE_PREFIX = ",
+        "const RUNNER_MEMORY_PAGES = ",
+        "const SCOPED_SEARCH = ",
+        "const SORT_KEYS = ",
+        "const STREAM_RESULTS = ",
+        "const TERM_ANALYSER = ",
+        "const TERM_STATS_ROUTE = ",
+        "const TERM_TRUNCATION_BYTES = ",
+        "const WORKER_MIDDLEWARE = ",
+        "const WORKER_PRELUDE = ",
+        "var fetchChunk = ",
+        ".addEventListener('fetch', ",
+    ];
+
+    // An empty folder for the output of a test, which is left behind for debugging if the test fails.
+    pub(crate) fn test_output_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("edgesearch-test-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Run Node.js, or return None if it isn't installed so that tests which need it can be skipped.
+    pub(crate) fn node<S: AsRef<OsStr>>(args: &[S]) -> Option<Output> {
+        match Command::new("node").args(args).output() {
+            Ok(output) => Some(output),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                eprintln!("Node.js not found, skipping");
+                None
+            }
+            Err(err) => panic!("Failed to run Node.js: {}", err),
+        }
+    }
+
+    // Arguments for generating a script for a small index with default options.
+    pub(crate) fn test_worker_js_args(output_dir: &PathBuf, deploy_target: DeployTarget, term_analyser: Analyser) -> WorkerJsArgs<'_> {
+        WorkerJsArgs {
+            output_dir,
+            approximate_terms: Vec::new(),
+            bm25: false,
+            chunk_layout: ChunkLayout::Flat,
+            chunk_read_concurrency: 8,
+            chunk_read_retries: 0,
+            chunk_read_retry_delay: 100,
+            chunk_read_timeout: None,
+            chunk_read_timeout_behaviour: ReadTimeoutBehaviour::Fail,
+            data_store: DataStore::KV,
+            data_store_url_prefix: None,
+            deploy_target,
+            document_count: 1,
+            document_id_chunk_starts: None,
+            document_route: false,
+            documents_stored: true,
+            empty_result_behaviour: EmptyResultBehaviour::Empty,
+            expanded_terms_overflow: ExpandedTermsOverflow::Error,
+            filters: false,
+            group_scan_limit: None,
+            kv_key_namespace: None,
+            max_expanded_terms: None,
+            max_prefix_expansions: 0,
+            max_query_terms: 10,
+            max_results: 10,
+            min_query_bytes: 1,
+            min_query_terms: 1,
+            on_unknown_term: OnUnknownTerm::Empty,
+            per_document_compression: DocumentCompression::None,
+            precompressed_gzip: false,
+            proximity_scan_limit: None,
+            query_log: QueryLogConfig::default(),
+            range_field_names: Vec::new(),
+            response: ResponseFormatConfig::default(),
+            route_prefix: String::new(),
+            runner_memory_pages: 1,
+            scoped_search: false,
+            sort_key_names: Vec::new(),
+            term_analyser,
+            term_truncation_bytes: None,
+            term_stats_route: false,
+            worker_middleware: None,
+            worker_prelude: None,
+        }
+    }
+
+    // Load a generated worker script and analyse each term read from a file, as the worker does with query terms.
+    // Terms are separated by NULL (ASCII 0), and dropped terms are written as SOH (ASCII 1).
+    const ANALYSE_TERMS_JS: &str = r#"
+        const fs = require('fs');
+        const [scriptPath, termsPath] = process.argv.slice(2);
+        // Terms are analysed without the runner, so an empty module stands in for it.
+        global.QUERY_RUNNER_WASM = new WebAssembly.Module(new Uint8Array([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]));
+        global.self = {addEventListener () {}};
+        const analyseTerm = Function(`${fs.readFileSync(scriptPath, 'utf8')}\nreturn analyseTerm;`)();
+        const terms = fs.readFileSync(termsPath, 'utf8').split('\0').slice(0, -1);
+        process.stdout.write(terms.map(term => `${analyseTerm(term) ?? '\x01'}\0`).join(''));
+    "#;
+
+    // Analyse terms with a worker script generated for `term_analyser`, or return None if Node.js isn't installed.
+    pub(crate) fn analyse_in_worker(name: &str, term_analyser: &Analyser, terms: &[&str]) -> Option<Vec<Option<Term>>> {
+        let output_dir = test_output_dir(name);
+        generate_worker_js(test_worker_js_args(&output_dir, DeployTarget::Worker, term_analyser.clone())).unwrap();
+        let harness_path = output_dir.join("analyse.js");
+        let terms_path = output_dir.join("terms");
+        File::create(&harness_path).unwrap().write_all(ANALYSE_TERMS_JS.as_bytes()).unwrap();
+        File::create(&terms_path).unwrap().write_all(terms.iter().map(|term| format!("{}\0", term)).collect::<String>().as_bytes()).unwrap();
+        let output = node(&[harness_path.as_os_str(), output_dir.join("worker.js").as_os_str(), terms_path.as_os_str()])?;
+        assert!(output.status.success(), "Failed to analyse terms in worker:\n{}", String::from_utf8_lossy(&output.stderr));
+        remove_dir_all(&output_dir).unwrap();
+        Some(String::from_utf8(output.stdout).unwrap()
+            .split_terminator('\0')
+            .map(|term| Some(term.to_string()).filter(|term| term != "\x01"))
+            .collect())
+    }
+
+    #[test]
+    fn generated_scripts_are_complete_and_parse() {
+        for deploy_target in &[DeployTarget::Worker, DeployTarget::Browser, DeployTarget::PagesFunctions] {
+            let output_dir = test_output_dir(&format!("worker-js-{}", deploy_target));
+            generate_worker_js(test_worker_js_args(&output_dir, *deploy_target, Analyser::default())).unwrap();
+            let script_path = output_dir.join(deploy_target.script_path(""));
+            let js = read_to_string(&script_path).unwrap();
+            for decl in WORKER_JS_REQUIRED_DECLARATIONS {
+                assert!(js.contains(decl), "{} script is missing `{}`", deploy_target, decl.trim());
+            };
+            // Node.js only checks `.mjs` files as ES modules, and the Pages function imports the runner.
+            let check_path = output_dir.join("check.mjs");
+            copy(&script_path, &check_path).unwrap();
+            let check_path = if *deploy_target == DeployTarget::Worker { script_path } else { check_path };
+            if let Some(output) = node(&[OsStr::new("--check"), check_path.as_os_str()]) {
+                assert!(output.status.success(), "{} script is not valid JavaScript:\n{}", deploy_target, String::from_utf8_lossy(&output.stderr));
+            };
+            remove_dir_all(&output_dir).unwrap();
+        };
+    }
 }
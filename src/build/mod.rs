@@ -1,25 +1,91 @@
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::fs::{create_dir, File, remove_dir_all};
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
 use std::path::PathBuf;
+use std::time::Instant;
 
 use clap::arg_enum;
-use croaring::Bitmap;
-
-use crate::{Term, TermId};
-use crate::build::chunks::{ChunkStrKey, ChunkU32Key};
-use crate::build::chunks::bst::BstChunks;
-use crate::build::js::generate_worker_js;
-use crate::build::wasm::generate_and_compile_runner_wasm;
-use crate::data::document_terms::DocumentTermsReader;
+use log::{info, warn};
+
+use crate::{DocumentId, Term, TermId};
+use crate::build::chunks::{ChunkEntryKey, ChunkStrKey, ChunkU32Key, KV_VALUE_MAX_SIZE, validate_kv_key_namespace, verify_chunks, write_chunks, write_chunks_lookup};
+use crate::build::checkpoint::{BuildPhase, Checkpoint, fingerprint_inputs};
+use crate::build::chunks::bst::{BstChunks, OversizedValue};
+use crate::build::blocks::pack_block_tables;
+use crate::build::bundle::write_bundle;
+use crate::build::compress::{document_encoding_overhead, encode_documents};
+use crate::build::dictionary::{TermDictionary, write_sorted_dictionary};
+use crate::build::error::check_config;
+use crate::build::filters::pack_filters;
+use crate::build::format::{FormatArgs, write_format_json};
+use crate::build::groups::serialise_group_table;
+use crate::build::ids::serialise_document_id_chunks;
+use crate::build::range::serialise_range_table;
+use crate::build::ranking::serialise_bm25_table;
+use crate::build::sort::serialise_sort_table;
+use crate::build::js::js_string;
+use crate::build::precompress::write_precompressed;
+use crate::build::worker::{WorkerData, write_worker};
+use crate::build::wasm::{generate_and_compile_runner_wasm, RunnerWasmArgs};
+use crate::data::document_terms::{DocumentTermsReader, read_document_terms_parallel};
 use crate::data::documents::DocumentsReader;
-use crate::util::format::{number, percent};
-use crate::util::log::status_log_interval;
+use crate::data::document_filters::read_document_filters;
+use crate::data::sort_keys::read_numeric_values;
+use crate::data::document_ids::read_document_ids;
+use crate::data::group_keys::read_group_keys;
+use crate::data::term_blocks::read_term_blocks;
+use crate::util::format::{number, percent, round2};
+
+pub use croaring::Bitmap;
+
+// For the readers in crate::data, which fail with the same errors as the rest of the build.
+pub(crate) use crate::build::chunks::CHUNK_STR_KEY_MAX_LEN;
+pub(crate) use crate::build::error::IoContext;
 
-mod js;
+pub use crate::build::bundle::BundleCompression;
+pub use crate::build::chunks::{ChunkLayout, ChunkLookupEntry, ChunkLookupKey, read_chunks_lookup};
+pub use crate::build::compress::DocumentCompression;
+pub use crate::build::dictionary::SortedTermDictionary;
+pub use crate::build::error::BuildError;
+pub use crate::build::js::{DeployTarget, ErrorFormat, QueryLogConfig, QueryLogSink, ResponseFormatConfig};
+pub use crate::build::postings::{Postings, PostingsList, read_serialised_postings};
+pub use crate::build::precompress::Precompression;
+pub use crate::build::range::RangeField;
+pub use crate::build::ranking::Ranking;
+pub use crate::build::scope::encode_scope;
+pub use crate::build::sort::SortKey;
+pub use crate::build::stats::{BuildProgress, BuildStats, ChunkStats, PhaseTiming, TermFrequencySummary};
+pub use crate::build::validate::{validate_inputs, ValidateConfig};
+pub use crate::build::wasm::{IntersectionStrategy, RunnerLimitError, validate_runner_limits};
+pub use crate::build::worker::{generate_worker, WorkerConfig};
+pub use crate::data::corpus::{CorpusBuilder, CorpusSource};
+pub use crate::data::MissingTerminatorHandling;
+pub use crate::data::document_terms::InvalidTermHandling;
+pub use crate::data::normalise::{AnalyseTerm, Analyser, AnalyserStage, CaseFolding, LongTermHandling, TermLengthLimit, UnicodeNormalisation};
+
+pub(crate) mod js;
+mod blocks;
+mod bundle;
+mod checkpoint;
 mod chunks;
+mod compress;
+mod dictionary;
+mod error;
+mod filters;
+mod format;
+mod groups;
+mod ids;
+mod openapi;
+mod postings;
+mod precompress;
+mod range;
+mod ranking;
+mod scope;
+mod sort;
+mod stats;
+mod validate;
 mod wasm;
+mod worker;
 
 arg_enum! {
     pub enum DataStore {
@@ -28,129 +94,1142 @@ arg_enum! {
     }
 }
 
-pub struct BuildConfig {
+arg_enum! {
+    // What the worker does when reading a chunk takes longer than the configured timeout.
+    #[derive(Clone, Copy)]
+    pub enum ReadTimeoutBehaviour {
+        // Skip the chunk and return partial results marked with `"degraded": true`.
+        Degrade,
+        // Respond with a 504 error.
+        Fail,
+    }
+}
+
+arg_enum! {
+    // How the worker responds to a search that matches no documents.
+    #[derive(Clone, Copy)]
+    pub enum EmptyResultBehaviour {
+        // Respond with 200 and an empty results array.
+        Empty,
+        // Respond with 404 and an empty results array, for clients that treat no results as an error.
+        NotFound,
+    }
+}
+
+arg_enum! {
+    // What the worker does when a query resolves to more terms than the configured maximum.
+    #[derive(Clone, Copy)]
+    pub enum ExpandedTermsOverflow {
+        // Respond with a 413 error.
+        Error,
+        // Keep the first terms in mode order (require, the expansions of a prefix, contain, exclude, then groups) and drop the rest.
+        Truncate,
+    }
+}
+
+arg_enum! {
+    // What the worker does with a required or group query term that was never indexed.
+    #[derive(Clone, Copy)]
+    pub enum OnUnknownTerm {
+        // Return no results, as no document can contain the term.
+        Empty,
+        // Drop the term from the query.
+        Ignore,
+        // Respond with a 400 error.
+        Error,
+    }
+}
+
+arg_enum! {
+    // What to do with a document that is too large to fit in a chunk.
+    #[derive(Clone, Copy)]
+    pub enum OversizedDocumentHandling {
+        Error,
+        // Replace the document with a JSON string of as much of its raw contents as fits, and log a warning.
+        Truncate,
+    }
+}
+
+// Replace a document with a JSON string of the longest prefix of its raw contents that fits in `max_len` bytes once serialised, so that it remains valid JSON.
+fn truncate_document(document: &str, max_len: usize) -> String {
+    let mut out = String::from("\"");
+    for c in document.chars() {
+        let escaped = js_string(c.encode_utf8(&mut [0; 4]));
+        let escaped = &escaped[1..escaped.len() - 1];
+        if out.len() + escaped.len() + 1 > max_len {
+            break;
+        };
+        out.push_str(escaped);
+    };
+    out.push('"');
+    out
+}
+
+fn record_chunk_utilisation<K: ChunkEntryKey>(stats: &mut BuildStats, dataset: &'static str, chunks: &BstChunks<K>) -> () {
+    info!(
+        "{} chunks are {} full on average, with up to {} entries per chunk and {} bytes per entry on average",
+        dataset,
+        percent(chunks.fill_ratio()),
+        number(chunks.max_entries_per_chunk()),
+        round2(chunks.average_entry_size()),
+    );
+    stats.chunks.push(ChunkStats {
+        dataset,
+        chunk_count: chunks.chunk_count(),
+        merged_chunks: chunks.merged_chunks(),
+        fill_ratio: chunks.fill_ratio(),
+        max_entries_per_chunk: chunks.max_entries_per_chunk(),
+        average_entry_size: chunks.average_entry_size(),
+    });
+}
+
+/**
+ * Inputs other than the corpus with a value or entries for each document, which are looked up by document ID.
+ * They are all read before anything is packed, so that they can be checked against the document count in one place.
+ */
+struct DocumentInputs {
+    sort_values: Vec<(String, Vec<f64>)>,
+    range_values: Vec<(String, Vec<f64>)>,
+    document_ids: Option<Vec<String>>,
+    group_keys: Option<Vec<String>>,
+    term_blocks: Option<HashMap<Term, Vec<(DocumentId, u32)>>>,
+    filters: Option<HashMap<Term, Vec<DocumentId>>>,
+}
+
+impl DocumentInputs {
+    /**
+     * Inputs with a value for each document must have exactly one value per document.
+     * Inputs with the structure of the document terms file can leave out documents at the end without entries, but can't have entries past the last document.
+     */
+    fn check_document_count(&self, document_count: usize) -> Result<(), BuildError> {
+        let value_counts = self.sort_values.iter().map(|(name, values)| (format!("Sort key {}", name), values.len()))
+            .chain(self.range_values.iter().map(|(name, values)| (format!("Range field {}", name), values.len())))
+            .chain(self.document_ids.iter().map(|ids| ("Document IDs".to_string(), ids.len())))
+            .chain(self.group_keys.iter().map(|keys| ("Group keys".to_string(), keys.len())));
+        for (input, count) in value_counts {
+            if count != document_count {
+                return Err(BuildError::InvalidInput(format!("{} has {} values but there are {} documents", input, count, document_count)));
+            };
+        };
+        // Entries are read in document ID order, so the last entry of each term or value has its largest document ID.
+        let last_documents = self.term_blocks.iter().map(|blocks| ("Term blocks", blocks.values().filter_map(|blocks| blocks.last()).map(|(document_id, _)| *document_id).max()))
+            .chain(self.filters.iter().map(|filters| ("Document filters", filters.values().filter_map(|documents| documents.last()).copied().max())));
+        for (input, last_document) in last_documents {
+            if let Some(document_id) = last_document.filter(|document_id| *document_id as usize >= document_count) {
+                return Err(BuildError::InvalidInput(format!("{} has entries for document {} but there are {} documents", input, document_id, document_count)));
+            };
+        };
+        Ok(())
+    }
+}
+
+pub struct BuildConfig<L: PostingsList = Bitmap> {
+    // Also write the deployable output to a single tar archive at this path.
+    pub bundle: Option<PathBuf>,
+    pub bundle_compression: BundleCompression,
+    // Remove the bundled files from the output folder after writing the bundle.
+    pub bundle_only: bool,
+    pub chunk_layout: ChunkLayout,
+    pub checkpoint: bool,
+    // Maximum amount of chunks the worker fetches at once when looking up terms or documents, to stay within subrequest limits.
+    pub chunk_read_concurrency: usize,
+    // Amount of times the worker retries a chunk read that fails, such as due to a transient KV error, before failing the request.
+    pub chunk_read_retries: usize,
+    // Milliseconds the worker waits before the first retry of a chunk read, doubling after each retry.
+    pub chunk_read_retry_delay: u64,
+    // Maximum milliseconds the worker waits for a chunk read, or None to wait indefinitely.
+    pub chunk_read_timeout: Option<u64>,
+    pub chunk_read_timeout_behaviour: ReadTimeoutBehaviour,
+    // Maximum bytes of each chunk. Smaller chunks make each read cheaper but lookups larger. At most KV_VALUE_MAX_SIZE with the KV data store.
     pub chunk_size: usize,
+    // clang used to compile the runner, as a command in PATH such as `clang-15` or a path such as `/opt/wasi-sdk/bin/clang`. Defaults to `clang`.
+    pub compiler: Option<PathBuf>,
+    // Directory to run clang in when compiling the runner, which must contain the `stubs` include directory. Defaults to the bundled `wasm` directory.
+    pub compile_dir: Option<PathBuf>,
+    pub corpus: CorpusSource,
     pub data_store: DataStore,
     pub data_store_url_prefix: Option<String>,
-    pub document_terms_source: File,
-    pub documents_source: File,
+    pub deploy_target: DeployTarget,
+    // Filter values of each document, such as `category=books`, which searches can require using exact matching in addition to their terms.
+    pub document_filters: Option<File>,
+    // Original ID of each document, such as a UUID or slug, to return with results. Documents are identified by their position if not provided.
+    pub document_ids: Option<File>,
+    // Serve documents by ID at `{route_prefix}/doc/{id}` in addition to searches.
+    pub document_route: bool,
+    pub empty_result_behaviour: EmptyResultBehaviour,
+    pub expanded_terms_overflow: ExpandedTermsOverflow,
+    // Key of each document, such as the article it is a page of, so that searches can return at most one document per key.
+    pub group_keys: Option<File>,
+    // Maximum amount of matching documents the runner scans for each search that collapses results by group.
+    pub group_scan_limit: usize,
+    pub include_dirs: Vec<PathBuf>,
+    pub intersection_strategy: IntersectionStrategy,
+    pub invalid_term_handling: InvalidTermHandling,
+    // Prefix every KV key with this and a slash, such as `staging`, so that builds for several environments can share a KV namespace.
+    pub kv_key_namespace: Option<String>,
+    // What to do with terms longer than `maximum_term_bytes`.
+    pub long_term_handling: LongTermHandling,
+    // Maximum amount of terms a query can resolve to after all expansions, applied before running the query.
+    pub maximum_expanded_terms: Option<usize>,
+    // Maximum amount of documents stored in a postings list. Lists of terms in more documents are sampled down to this size, and their terms treated as approximate by the worker.
+    pub maximum_postings_cardinality: Option<usize>,
+    // Maximum amount of indexed terms the last query term is expanded to when a search marks it as a prefix, such as for autocomplete. Zero disables prefix searches.
+    pub maximum_prefix_expansions: usize,
+    // Size of the runner's memory, which holds everything a single query uses, such as the postings lists of its terms. Rounded up to whole 64 KiB pages.
+    pub maximum_query_bytes: usize,
     pub maximum_query_results: usize,
     pub maximum_query_terms: usize,
+    // Maximum UTF-8 length of each document term and term block term after analysis, which can be at most 255. None uses 255, the longest the runner supports.
+    pub maximum_term_bytes: Option<usize>,
+    pub missing_terminator_handling: MissingTerminatorHandling,
+    // Minimum UTF-8 length of each query term, checked after normalisation.
+    pub minimum_query_bytes: usize,
+    // Minimum amount of terms a query must have across all modes. Queries without any terms still return default results.
+    pub minimum_query_terms: usize,
+    // Called as each phase of the build starts and completes, in addition to logging.
+    pub on_progress: Option<Box<dyn FnMut(BuildProgress) + Send>>,
+    pub on_unknown_term: OnUnknownTerm,
+    // Also write `openapi.json`, an OpenAPI description of the worker's routes, parameters, and responses.
+    pub openapi: bool,
     pub output_dir: PathBuf,
+    pub oversized_document_handling: OversizedDocumentHandling,
+    // Amount of threads to parse document terms with. Values above 1 use the parallel reader.
+    pub parse_threads: usize,
+    // Compress each document individually, so that the worker only decompresses the documents it returns.
+    pub per_document_compression: DocumentCompression,
+    // Prebuilt postings lists to pack instead of reading terms from the corpus, whose document terms are then ignored.
+    pub postings: Option<Postings<L>>,
+    // Amount of threads to build postings lists from document terms with, which produce the same postings lists as one thread.
+    pub postings_threads: usize,
+    // Also write a copy of `runner.wasm` and every chunk in each encoding, such as `runner.wasm.gz`, for static hosts to serve without compressing them on each request.
+    pub precompression: Vec<Precompression>,
+    // Maximum amount of matching documents, in result order, the runner scans for each search to boost those where query terms share a block.
+    pub proximity_scan_limit: usize,
+    // Record each search to a log or metrics sink, for monitoring the deployed worker.
+    pub query_log: QueryLogConfig,
+    // Each range field is stored as a `ranges/{index}` chunk in the order provided.
+    pub range_fields: Vec<RangeField>,
+    // How to order results of searches that don't use a sort key. BM25 needs the length of each document, so it can't be used with prebuilt postings lists.
+    pub ranking: Ranking,
+    // Extra flags passed to clang verbatim when compiling the runner, such as `-msimd128`. They are not validated.
+    pub raw_clang_flags: Vec<String>,
+    pub response: ResponseFormatConfig,
+    // Path to serve routes under, such as `/api`. Empty for the root.
+    pub route_prefix: String,
+    // Allow searches to be scoped to documents provided by the client in the `i` query parameter. See `encode_scope`.
+    pub scoped_search: bool,
+    // Compile the runner with WASM SIMD, which clang uses to vectorise loops in roaring. The bundled roaring has no hand-written WASM SIMD code.
+    pub simd: bool,
+    pub size_report: bool,
+    // Each sort key is stored as a `sorts/{index}` chunk in the order provided.
+    pub sort_keys: Vec<SortKey>,
+    // Also write the build statistics and resolved config to a JSON file at this path, such as for comparing builds in CI.
+    pub stats_json: Option<PathBuf>,
+    // Pack documents so that searches return them. If false, the documents are never read and searches only return the IDs of results.
+    pub store_documents: bool,
+    pub sysroot: Option<PathBuf>,
+    // Stages every term goes through, such as lowercasing, both when building and when the worker parses queries.
+    pub term_analyser: Analyser,
+    // Block, such as the paragraph, of each term of each document, so that documents where query terms share a block rank first.
+    pub term_blocks: Option<File>,
+    // Term dictionary to keep term IDs stable across builds. Existing IDs are preserved and new terms are appended.
+    pub term_dictionary: Option<PathBuf>,
+    // Serve the amount of documents containing a term at `{route_prefix}/term/{term}/stats`, such as for ordering autocomplete suggestions.
+    pub term_stats_route: bool,
+    // Binaryen's `wasm-opt`, to further optimise the runner with after compiling it, which usually makes it smaller. The runner is left as compiled if None.
+    pub wasm_opt: Option<PathBuf>,
+    // JavaScript run after the worker responds to a request, which can change or replace the response. See `WorkerConfig`.
+    pub worker_middleware: Option<String>,
+    // JavaScript run before the worker handles a request, which can respond instead. See `WorkerConfig`.
+    pub worker_prelude: Option<String>,
+    // Also write `dictionary.bin`, every indexed term and its ID in term order, for tools that translate between terms and IDs. See `SortedTermDictionary`.
+    pub write_dictionary: bool,
+    // The zstd single-file decoder (zstddeclib.c), compiled into the runner when documents are compressed with zstd.
+    pub zstd_decoder: Option<PathBuf>,
 }
 
-pub fn build(BuildConfig {
+/**
+ * Build the index, runner, and worker into the output folder, and return statistics about the build.
+ * Problems with the config, input files, or environment, such as invalid UTF-8 terms, I/O failures, or the runner failing to compile, are returned as a `BuildError`.
+ * Panics are reserved for bugs, such as the chunks written disagreeing with the lookups compiled into the runner.
+ */
+pub fn build<L: PostingsList>(BuildConfig {
+    bundle,
+    bundle_compression,
+    bundle_only,
+    checkpoint,
+    chunk_layout,
+    chunk_read_concurrency,
+    chunk_read_retries,
+    chunk_read_retry_delay,
+    chunk_read_timeout,
+    chunk_read_timeout_behaviour,
     chunk_size,
+    compiler,
+    compile_dir,
+    corpus,
     data_store,
     data_store_url_prefix,
-    document_terms_source,
-    documents_source,
+    deploy_target,
+    document_filters,
+    document_ids,
+    document_route,
+    empty_result_behaviour,
+    expanded_terms_overflow,
+    group_keys,
+    group_scan_limit,
+    include_dirs,
+    intersection_strategy,
+    invalid_term_handling,
+    kv_key_namespace,
+    long_term_handling,
+    maximum_expanded_terms,
+    maximum_postings_cardinality,
+    maximum_prefix_expansions,
+    maximum_query_bytes,
     maximum_query_results,
     maximum_query_terms,
+    maximum_term_bytes,
+    minimum_query_bytes,
+    missing_terminator_handling,
+    minimum_query_terms,
+    on_progress,
+    on_unknown_term,
+    openapi,
     output_dir,
-}: BuildConfig) -> () {
-    // term_id => term.
-    let mut terms = Vec::<Term>::new();
-    // term => term_id.
-    let mut term_ids = HashMap::<Term, TermId>::new();
-    // document_id => term_id[].
-    let mut terms_by_document = Vec::<Vec<TermId>>::new();
-    // term_id => bitmap.
-    let mut inverted_index = Vec::<Bitmap>::new();
-    // term_id => document_terms.filter(|d| d.contains(term_id)).count().
-    let mut term_frequency = HashMap::<TermId, usize>::new();
-
-    // - Each document must end with '\0', even if last.
-    // - Each term must be unique within its document.
-    // - Each term must end with '\0', even if last for document or entire index.
-    // - Each term must not be empty.
-    // - Each term must not contain '\0'.
-    for (document_id, term) in DocumentTermsReader::new(document_terms_source) {
-        // Some documents have no terms, so iteration could skip a few document IDs.
-        while terms_by_document.len() <= document_id {
-            terms_by_document.push(Vec::<TermId>::new());
+    oversized_document_handling,
+    parse_threads,
+    per_document_compression,
+    postings,
+    postings_threads,
+    precompression,
+    proximity_scan_limit,
+    query_log,
+    range_fields,
+    ranking,
+    raw_clang_flags,
+    response,
+    route_prefix,
+    scoped_search,
+    simd,
+    size_report,
+    sort_keys,
+    stats_json,
+    store_documents,
+    sysroot,
+    term_analyser,
+    term_blocks,
+    term_dictionary,
+    term_stats_route,
+    wasm_opt,
+    worker_middleware,
+    worker_prelude,
+    write_dictionary,
+    zstd_decoder,
+}: BuildConfig<L>) -> Result<BuildStats, BuildError> {
+    check_config(bundle.is_some() || !bundle_only, "bundle only requires a bundle path")?;
+    check_config(zstd_decoder.is_some() || per_document_compression != DocumentCompression::Zstd, "zstd document compression requires the zstd decoder source")?;
+    check_config(store_documents || (!document_route && per_document_compression == DocumentCompression::None), "the document route and per-document compression require stored documents")?;
+    check_config(postings.is_none() || (!checkpoint && term_dictionary.is_none()), "prebuilt postings cannot be used with checkpoints or a term dictionary")?;
+    check_config(deploy_target != DeployTarget::Browser || matches!(data_store, DataStore::URL), "the browser deploy target requires the URL data store")?;
+    // Otherwise the function would handle every path, hiding the static site of the Pages project.
+    check_config(deploy_target != DeployTarget::PagesFunctions || !route_prefix.trim_end_matches('/').is_empty(), "the Pages Functions deploy target requires a route prefix")?;
+    check_config(chunk_read_concurrency >= 1, "chunk read concurrency must be at least 1")?;
+    check_config(chunk_size >= 1, "chunk size must be at least 1")?;
+    check_config(!matches!(data_store, DataStore::KV) || chunk_size <= KV_VALUE_MAX_SIZE, &format!("chunk size must be at most {} bytes with the KV data store, as larger KV values are rejected", KV_VALUE_MAX_SIZE))?;
+    check_config(maximum_postings_cardinality != Some(0), "maximum postings cardinality must be at least 1")?;
+    check_config(maximum_term_bytes != Some(0), "maximum term bytes must be at least 1")?;
+    check_config(maximum_term_bytes.unwrap_or(0) <= CHUNK_STR_KEY_MAX_LEN, "maximum term bytes must be at most 255")?;
+    check_config(maximum_expanded_terms != Some(0), "maximum expanded terms must be at least 1")?;
+    // Checked before any data is read, as the runner is only compiled at the end of the build.
+    if let Err(err) = validate_runner_limits(maximum_query_results, maximum_query_terms) {
+        return Err(BuildError::InvalidConfig(format!("runner limits: {}", err)));
+    };
+    check_config(route_prefix.is_empty() || route_prefix.starts_with('/'), "route prefix must start with a slash")?;
+    check_config(!(response.pretty && response.stream_results), "pretty responses cannot be streamed")?;
+    if let Err(err) = query_log.validate(deploy_target) {
+        return Err(BuildError::InvalidConfig(format!("query log: {}", err)));
+    };
+    check_config(kv_key_namespace.is_none() || matches!(data_store, DataStore::KV), "a KV key namespace requires the KV data store")?;
+    check_config(postings.is_none() || !ranking.is_bm25(), "BM25 ranking cannot be used with prebuilt postings")?;
+    if let Err(err) = ranking.validate() {
+        return Err(BuildError::InvalidConfig(format!("ranking: {}", err)));
+    };
+    if let Some(namespace) = &kv_key_namespace {
+        if let Err(err) = validate_kv_key_namespace(namespace) {
+            return Err(BuildError::InvalidConfig(format!("KV key namespace: {}", err)));
         };
-        let document_terms = &mut terms_by_document[document_id];
-        let term_id = match term_ids.get(&term) {
-            Some(term_id) => *term_id,
-            None => {
-                assert_eq!(terms.len(), inverted_index.len());
-                let term_id = terms.len() as TermId;
-                term_ids.insert(term.clone(), term_id);
-                terms.push(term);
-                inverted_index.push(Bitmap::create());
-                term_id
+    };
+
+    let mut stats = BuildStats::new(on_progress);
+    // Captured before the config is consumed, as stats.json is written at the end.
+    let path_json = |path: Option<&PathBuf>| path.map_or("null".to_string(), |p| js_string(&p.to_string_lossy()));
+    let resolved_config: Vec<(&str, String)> = vec![
+        ("bundle", path_json(bundle.as_ref())),
+        ("bundleCompression", js_string(&bundle_compression.to_string())),
+        ("bundleOnly", bundle_only.to_string()),
+        ("checkpoint", checkpoint.to_string()),
+        ("chunkLayout", js_string(&chunk_layout.to_string())),
+        ("chunkReadConcurrency", chunk_read_concurrency.to_string()),
+        ("chunkReadRetries", chunk_read_retries.to_string()),
+        ("chunkReadRetryDelay", chunk_read_retry_delay.to_string()),
+        ("chunkReadTimeout", chunk_read_timeout.map_or("null".to_string(), |t| t.to_string())),
+        ("chunkReadTimeoutBehaviour", js_string(&chunk_read_timeout_behaviour.to_string())),
+        ("chunkSize", chunk_size.to_string()),
+        ("compiler", path_json(compiler.as_ref())),
+        ("compileDir", path_json(compile_dir.as_ref())),
+        ("corpus", js_string(match corpus {
+            CorpusSource::Files { .. } => "files",
+            CorpusSource::Combined { .. } => "combined",
+            CorpusSource::Memory(_) => "memory",
+        })),
+        ("dataStore", js_string(&data_store.to_string())),
+        ("dataStoreUrlPrefix", data_store_url_prefix.as_ref().map_or("null".to_string(), |p| js_string(p))),
+        ("deployTarget", js_string(&deploy_target.to_string())),
+        ("documentFilters", document_filters.is_some().to_string()),
+        ("documentIds", document_ids.is_some().to_string()),
+        ("documentRoute", document_route.to_string()),
+        ("emptyResultBehaviour", js_string(&empty_result_behaviour.to_string())),
+        ("errorFormat", js_string(&response.error_format.to_string())),
+        ("expandedTermsOverflow", js_string(&expanded_terms_overflow.to_string())),
+        ("explain", response.explain.to_string()),
+        ("groupKeys", group_keys.is_some().to_string()),
+        ("groupScanLimit", group_scan_limit.to_string()),
+        ("includeDirs", format!("[{}]", include_dirs.iter().map(|d| path_json(Some(d))).collect::<Vec<String>>().join(", "))),
+        ("intersectionStrategy", js_string(&intersection_strategy.to_string())),
+        ("invalidTermHandling", js_string(&invalid_term_handling.to_string())),
+        ("kvKeyNamespace", kv_key_namespace.as_ref().map_or("null".to_string(), |ns| js_string(ns))),
+        ("longTermHandling", js_string(&long_term_handling.to_string())),
+        ("maximumExpandedTerms", maximum_expanded_terms.map_or("null".to_string(), |m| m.to_string())),
+        ("maximumPostingsCardinality", maximum_postings_cardinality.map_or("null".to_string(), |m| m.to_string())),
+        ("maximumPrefixExpansions", maximum_prefix_expansions.to_string()),
+        ("maximumQueryBytes", maximum_query_bytes.to_string()),
+        ("maximumQueryResults", maximum_query_results.to_string()),
+        ("maximumQueryTerms", maximum_query_terms.to_string()),
+        ("maximumTermBytes", maximum_term_bytes.map_or("null".to_string(), |m| m.to_string())),
+        ("minimumQueryBytes", minimum_query_bytes.to_string()),
+        ("minimumQueryTerms", minimum_query_terms.to_string()),
+        ("missingTerminatorHandling", js_string(&missing_terminator_handling.to_string())),
+        ("onUnknownTerm", js_string(&on_unknown_term.to_string())),
+        ("openapi", openapi.to_string()),
+        ("outputDir", path_json(Some(&output_dir))),
+        ("oversizedDocumentHandling", js_string(&oversized_document_handling.to_string())),
+        ("parseThreads", parse_threads.to_string()),
+        ("perDocumentCompression", js_string(&per_document_compression.to_string())),
+        ("postingsThreads", postings_threads.to_string()),
+        ("prebuiltPostings", postings.is_some().to_string()),
+        ("precompression", format!("[{}]", precompression.iter().map(|p| js_string(&p.to_string())).collect::<Vec<String>>().join(", "))),
+        ("proximityScanLimit", proximity_scan_limit.to_string()),
+        ("queryLog", js_string(&query_log.sink.to_string())),
+        ("queryLogEndpoint", query_log.endpoint.as_ref().map_or("null".to_string(), |e| js_string(e))),
+        ("queryLogSampleRate", query_log.sample_rate.to_string()),
+        ("rangeFields", format!("[{}]", range_fields.iter().map(|f| js_string(&f.name)).collect::<Vec<String>>().join(", "))),
+        ("ranking", match ranking {
+            Ranking::BitmapOnly => r#""bitmapOnly""#.to_string(),
+            Ranking::Bm25 { k1, b } => format!(r#"{{"bm25": {{"k1": {}, "b": {}}}}}"#, k1, b),
+        }),
+        ("rawClangFlags", format!("[{}]", raw_clang_flags.iter().map(|f| js_string(f)).collect::<Vec<String>>().join(", "))),
+        ("responseContentType", js_string(&response.content_type)),
+        ("responsePretty", response.pretty.to_string()),
+        ("resultProjection", format!("[{}]", response.result_projection.iter().map(|f| js_string(f)).collect::<Vec<String>>().join(", "))),
+        ("routePrefix", js_string(&route_prefix)),
+        ("scopedSearch", scoped_search.to_string()),
+        ("simd", simd.to_string()),
+        ("sizeReport", size_report.to_string()),
+        ("sortKeys", format!("[{}]", sort_keys.iter().map(|k| js_string(&k.name)).collect::<Vec<String>>().join(", "))),
+        ("storeDocuments", store_documents.to_string()),
+        ("streamResults", response.stream_results.to_string()),
+        ("sysroot", path_json(sysroot.as_ref())),
+        ("termAnalyser", format!("[{}]", term_analyser.stages.iter().map(|stage| js_string(&stage.to_string())).collect::<Vec<String>>().join(", "))),
+        ("termBlocks", term_blocks.is_some().to_string()),
+        ("termDictionary", path_json(term_dictionary.as_ref())),
+        ("termStatsRoute", term_stats_route.to_string()),
+        ("wasmOpt", path_json(wasm_opt.as_ref())),
+        ("workerMiddleware", worker_middleware.is_some().to_string()),
+        ("workerPrelude", worker_prelude.is_some().to_string()),
+        ("writeDictionary", write_dictionary.to_string()),
+        ("zstdDecoder", path_json(zstd_decoder.as_ref())),
+    ];
+
+    // Terms are chunk keys, which the runner can only look up if they are at most CHUNK_STR_KEY_MAX_LEN bytes, so terms are always limited to that.
+    let term_length_limit = TermLengthLimit { max_bytes: maximum_term_bytes.unwrap_or(CHUNK_STR_KEY_MAX_LEN), handling: long_term_handling };
+
+    let checkpoint = if checkpoint {
+        Some(Checkpoint::new(&output_dir, fingerprint_inputs(&corpus, (
+            chunk_layout as u8,
+            chunk_size,
+            invalid_term_handling as u8,
+            maximum_postings_cardinality,
+            missing_terminator_handling as u8,
+            oversized_document_handling as u8,
+            per_document_compression as u8,
+            &term_analyser,
+            term_dictionary.is_some(),
+            term_length_limit,
+        ))?))
+    } else {
+        None
+    };
+    let resumed_phase = checkpoint.as_ref().and_then(|c| c.completed_phase());
+    let resumed = |phase: BuildPhase| resumed_phase.filter(|p| *p >= phase).is_some();
+    if let (Some(checkpoint), None) = (&checkpoint, resumed_phase) {
+        checkpoint.reset()?;
+    };
+
+    let prebuilt_postings = postings.is_some();
+    // Amount of terms in each document, if needed to rank by BM25.
+    let mut document_lengths = None::<Vec<usize>>;
+    let postings = match postings {
+        Some(postings) => Some(postings),
+        None => {
+            let (terms, terms_by_document) = if resumed(BuildPhase::TermsRead) {
+                info!("Resuming with terms from checkpoint");
+                checkpoint.as_ref().unwrap().load_terms()?
+            } else {
+                let started = stats.start_phase("Read terms");
+                let prior_terms = match term_dictionary.as_ref().filter(|p| p.exists()) {
+                    Some(path) => {
+                        let prior_terms = TermDictionary::load(path)?;
+                        info!("Loaded {} terms from term dictionary", number(prior_terms.len()));
+                        prior_terms
+                    }
+                    None => TermDictionary::new(),
+                };
+                let (terms, terms_by_document) = read_terms(prior_terms, &corpus, invalid_term_handling, missing_terminator_handling, parse_threads, &term_analyser, term_length_limit)?;
+                if let Some(path) = &term_dictionary {
+                    terms.save(path)?;
+                };
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.save_terms(&terms, &terms_by_document)?;
+                    checkpoint.complete_phase(BuildPhase::TermsRead)?;
+                };
+                stats.record_phase("Read terms", started);
+                (terms, terms_by_document)
+            };
+            stats.document_count = terms_by_document.len();
+            stats.term_count = terms.len();
+            if ranking.is_bm25() {
+                document_lengths = Some(terms_by_document.iter().map(|doc_terms| doc_terms.len()).collect());
+            };
+            if resumed(BuildPhase::TermsChunksWritten) {
+                None
+            } else {
+                let started = stats.start_phase("Build postings lists");
+                let postings = Postings::<L>::from_document_terms(terms, &terms_by_document, postings_threads)?;
+                stats.record_phase("Build postings lists", started);
+                Some(postings)
             }
+        }
+    };
+    if let Some(postings) = &postings {
+        stats.document_count = postings.document_count();
+        stats.term_count = postings.term_count();
+        stats.term_frequency = TermFrequencySummary::new(postings.term_frequencies());
+        // Resumed builds skip this, but the file was written before the checkpoint they resume from.
+        if write_dictionary {
+            write_sorted_dictionary(&output_dir.join("dictionary.bin"), postings.indexed_terms())?;
         };
+    };
+    let document_count = stats.document_count;
+    // Document IDs are only 32-bit, so this is checked once here and every later document ID is known to fit.
+    if document_count.saturating_sub(1) > DocumentId::MAX as usize {
+        return Err(BuildError::TooManyDocuments);
+    };
+    info!("There are {} documents with {} terms", number(document_count), number(stats.term_count));
 
-        document_terms.push(term_id);
-        term_frequency.insert(term_id, term_frequency.get(&term_id).unwrap_or(&0) + 1);
+    let started = stats.start_phase("Read document inputs");
+    let document_inputs = DocumentInputs {
+        sort_values: sort_keys.into_iter()
+            .map(|SortKey { name, values_source }| read_numeric_values("Sort key", &name, values_source, missing_terminator_handling).map(|values| (name, values)))
+            .collect::<Result<_, _>>()?,
+        range_values: range_fields.into_iter()
+            .map(|RangeField { name, values_source }| read_numeric_values("Range field", &name, values_source, missing_terminator_handling).map(|values| (name, values)))
+            .collect::<Result<_, _>>()?,
+        document_ids: document_ids.map(|ids| read_document_ids(ids, missing_terminator_handling)).transpose()?,
+        group_keys: group_keys.map(|keys| read_group_keys(keys, missing_terminator_handling)).transpose()?,
+        term_blocks: term_blocks.map(|blocks| read_term_blocks(blocks, invalid_term_handling, missing_terminator_handling, &term_analyser, term_length_limit)).transpose()?,
+        filters: document_filters.map(|filters| read_document_filters(filters, invalid_term_handling, missing_terminator_handling)).transpose()?,
     };
+    document_inputs.check_document_count(document_count)?;
+    stats.record_phase("Read document inputs", started);
+    let DocumentInputs { sort_values, range_values, document_ids, group_keys, term_blocks, filters } = document_inputs;
 
-    let document_count = terms_by_document.len();
+    let (terms_index_raw_lookup, terms_index_chunk_count, approximate_terms) = match postings {
+        None => {
+            info!("Resuming with terms chunks from checkpoint");
+            let checkpoint = checkpoint.as_ref().unwrap();
+            let (raw_lookup, chunk_count) = checkpoint.load_chunks_lookup("terms")?;
+            (raw_lookup, chunk_count, checkpoint.load_approximate_terms()?)
+        }
+        Some(postings) => {
+            let started = stats.start_phase("Pack terms");
+            let (mut terms_index_builder, approximate_terms) = postings.pack(chunk_size, maximum_postings_cardinality)?;
+            let (terms_index_raw_lookup, terms_index_lookup, terms_index_serialised_entries) = terms_index_builder.serialise();
+            info!("{} chunks contain terms, after merging {} chunks into their neighbours", number(terms_index_builder.chunk_count()), number(terms_index_builder.merged_chunks()));
+            let (duplicate_postings_lists, duplicate_postings_lists_bytes) = terms_index_builder.deduplication_savings();
+            info!("Deduplicated {} identical postings lists, saving {} bytes", number(duplicate_postings_lists), number(duplicate_postings_lists_bytes));
+            record_chunk_utilisation(&mut stats, "Terms", &terms_index_builder);
+            write_chunks(&output_dir, "terms", chunk_layout, &terms_index_serialised_entries)?;
+            write_chunks_lookup::<ChunkStrKey>(&output_dir, "terms", terms_index_serialised_entries.len(), &terms_index_lookup)?;
+            if let Some(checkpoint) = &checkpoint {
+                checkpoint.save_chunks_lookup("terms", &terms_index_raw_lookup, terms_index_serialised_entries.len())?;
+                checkpoint.save_approximate_terms(&approximate_terms)?;
+                checkpoint.complete_phase(BuildPhase::TermsChunksWritten)?;
+            };
+            stats.record_phase("Pack terms", started);
+            (terms_index_raw_lookup, terms_index_serialised_entries.len(), approximate_terms)
+        }
+    };
+    stats.approximate_term_count = approximate_terms.len();
 
-    let hash_log_interval = status_log_interval(document_count, 10);
-    for (document_id, doc_terms) in terms_by_document.iter().enumerate() {
-        interval_log!(hash_log_interval, document_id, document_count, "Processing documents ({})...");
-        for term_id in doc_terms {
-            // Add to the relevant postings list entry bitmap.
-            inverted_index[*term_id].add(document_id.try_into().expect("too many documents"));
+    let (documents_raw_lookup, documents_chunk_count, document_dictionary_bytes, max_document_bytes) = if !store_documents {
+        info!("Skipping documents as they are not stored");
+        // Clear any documents from previous builds, so that they aren't deployed.
+        write_chunks(&output_dir, "documents", chunk_layout, &[])?;
+        write_chunks_lookup::<ChunkU32Key>(&output_dir, "documents", 0, &[])?;
+        write_chunks(&output_dir, "dictionaries", chunk_layout, &[])?;
+        (String::new(), 0, 0, 0)
+    } else if resumed(BuildPhase::DocumentsChunksWritten) {
+        info!("Resuming with documents chunks from checkpoint");
+        let checkpoint = checkpoint.as_ref().unwrap();
+        let (documents_raw_lookup, documents_chunk_count) = checkpoint.load_chunks_lookup("documents")?;
+        let (document_dictionary_bytes, max_document_bytes) = checkpoint.load_document_sizes()?;
+        (documents_raw_lookup, documents_chunk_count, document_dictionary_bytes, max_document_bytes)
+    } else {
+        let started = stats.start_phase("Pack documents");
+        let mut documents_builder = BstChunks::<ChunkU32Key>::new(chunk_size);
+        let mut raw_documents_len = 0;
+        let mut max_document_bytes = 0;
+        let documents: Box<dyn Iterator<Item = Result<(usize, String), BuildError>>> = match &corpus {
+            CorpusSource::Files { documents, .. } => Box::new(DocumentsReader::new(documents.try_clone().context("open documents file")?, missing_terminator_handling)),
+            CorpusSource::Combined { file, document_count, .. } => {
+                let mut file = file.try_clone().context("open combined corpus file")?;
+                file.seek(SeekFrom::Start(0)).context("seek to documents")?;
+                Box::new(DocumentsReader::new(file, missing_terminator_handling).take(*document_count))
+            }
+            CorpusSource::Memory(corpus) => Box::new(corpus.documents.iter().cloned().enumerate().map(Ok)),
+        };
+        let documents = documents.map(|document| {
+            let (document_id, document) = document?;
+            // The document count was checked to fit above, and documents past it fail the build once they're all counted below.
+            let key = ChunkU32Key::new(document_id as DocumentId);
+            // Encoded documents are never larger than their raw contents plus the encoding tag.
+            let max_len = documents_builder.max_value_len(&key).saturating_sub(document_encoding_overhead(per_document_compression));
+            let document = if document.len() <= max_len {
+                document
+            } else {
+                match oversized_document_handling {
+                    OversizedDocumentHandling::Error => return Err(BuildError::InvalidInput(format!("Document {} is {} bytes which does not fit in a chunk", document_id, number(document.len())))),
+                    OversizedDocumentHandling::Truncate => {
+                        warn!("Truncating document {} as it is {} bytes which does not fit in a chunk", document_id, number(document.len()));
+                        truncate_document(&document, max_len)
+                    }
+                }
+            };
+            raw_documents_len += document.len();
+            max_document_bytes = max_document_bytes.max(document.len());
+            Ok((document_id, document))
+        });
+        let (encoded_documents, dictionary) = encode_documents(&output_dir, per_document_compression, documents)?;
+        if per_document_compression != DocumentCompression::None {
+            let encoded_documents_len = encoded_documents.iter().map(|(_, document)| document.len()).sum::<usize>();
+            info!("Compressed documents from {} to {} bytes ({})", number(raw_documents_len), number(encoded_documents_len), percent(encoded_documents_len as f64 / raw_documents_len.max(1) as f64));
+        };
+        if let Some(dictionary) = &dictionary {
+            info!("Trained a {} byte dictionary to compress documents with", number(dictionary.len()));
+        };
+        let document_dictionary_bytes = dictionary.as_ref().map_or(0, |dictionary| dictionary.len());
+        // The dictionary is stored as its own dataset so it's deployed like any other chunk.
+        write_chunks(&output_dir, "dictionaries", chunk_layout, &dictionary.into_iter().collect::<Vec<Vec<u8>>>())?;
+        // Documents are only counted as they're read, so unlike the other per-document inputs they're checked here.
+        if encoded_documents.len() != document_count {
+            let source = if prebuilt_postings { "the postings lists are for" } else { "document terms has" };
+            return Err(BuildError::InvalidInput(format!("There are {} documents but {} {} documents", encoded_documents.len(), source, document_count)));
         };
+        for (document_id, document) in encoded_documents {
+            // Oversized documents have already been handled, so this only fails if encoding made a document larger than expected.
+            if let Err(OversizedValue { len, max_len }) = documents_builder.insert(ChunkU32Key::new(document_id as DocumentId), document) {
+                return Err(BuildError::InvalidInput(format!("Document {} is {} bytes when encoded which does not fit in a chunk of up to {} bytes", document_id, number(len), number(max_len))));
+            };
+        };
+        let (documents_raw_lookup, documents_lookup, documents_serialised_entries) = documents_builder.serialise();
+        info!("{} chunks contain documents, after merging {} chunks into their neighbours", number(documents_builder.chunk_count()), number(documents_builder.merged_chunks()));
+        record_chunk_utilisation(&mut stats, "Documents", &documents_builder);
+        write_chunks(&output_dir, "documents", chunk_layout, &documents_serialised_entries)?;
+        write_chunks_lookup::<ChunkU32Key>(&output_dir, "documents", documents_serialised_entries.len(), &documents_lookup)?;
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.save_chunks_lookup("documents", &documents_raw_lookup, documents_serialised_entries.len())?;
+            checkpoint.save_document_sizes(document_dictionary_bytes, max_document_bytes)?;
+            checkpoint.complete_phase(BuildPhase::DocumentsChunksWritten)?;
+        };
+        stats.record_phase("Pack documents", started);
+        (documents_raw_lookup, documents_serialised_entries.len(), document_dictionary_bytes, max_document_bytes)
+    };
+
+    let started = stats.start_phase("Pack sort keys");
+    let sort_key_names = sort_values.iter().map(|(name, _)| name.clone()).collect::<Vec<String>>();
+    let mut sort_tables = Vec::<Vec<u8>>::new();
+    for (name, values) in sort_values {
+        let sort_table = serialise_sort_table(&values);
+        if sort_table.len() > chunk_size {
+            return Err(BuildError::InvalidInput(format!("Sort key {} takes {} bytes which does not fit in a chunk", name, number(sort_table.len()))));
+        };
+        sort_tables.push(sort_table);
+    };
+    write_chunks(&output_dir, "sorts", chunk_layout, &sort_tables)?;
+    stats.record_phase("Pack sort keys", started);
+
+    let started = stats.start_phase("Pack range fields");
+    let range_field_names = range_values.iter().map(|(name, _)| name.clone()).collect::<Vec<String>>();
+    let mut range_tables = Vec::<Vec<u8>>::new();
+    for (name, values) in range_values {
+        let range_table = serialise_range_table(&values);
+        if range_table.len() > chunk_size {
+            return Err(BuildError::InvalidInput(format!("Range field {} takes {} bytes which does not fit in a chunk", name, number(range_table.len()))));
+        };
+        range_tables.push(range_table);
     };
+    write_chunks(&output_dir, "ranges", chunk_layout, &range_tables)?;
+    stats.record_phase("Pack range fields", started);
 
-    println!("There are {} documents with {} terms", number(terms_by_document.len()), number(terms.len()));
+    let ranking_tables = match (ranking, document_lengths) {
+        (Ranking::Bm25 { k1, b }, Some(document_lengths)) => {
+            let bm25_table = serialise_bm25_table(&document_lengths, k1, b);
+            if bm25_table.len() > chunk_size {
+                return Err(BuildError::InvalidInput(format!("BM25 table takes {} bytes which does not fit in a chunk", number(bm25_table.len()))));
+            };
+            vec![bm25_table]
+        }
+        _ => Vec::new(),
+    };
+    write_chunks(&output_dir, "ranking", chunk_layout, &ranking_tables)?;
+    let has_bm25 = !ranking_tables.is_empty();
 
-    let mut terms_index_builder = BstChunks::<ChunkStrKey>::new(chunk_size);
-    let mut terms_sorted = (0..terms.len()).collect::<Vec<TermId>>();
-    terms_sorted.sort_by(|a, b| terms[*a].cmp(&terms[*b]));
-    for term_id in terms_sorted.iter() {
-        let postings_list_entry = &mut inverted_index[*term_id];
-        postings_list_entry.run_optimize();
-        let serialised = postings_list_entry.serialize();
-        terms_index_builder.insert(ChunkStrKey::new(&terms[*term_id]), serialised);
+    let (document_id_chunk_starts, document_id_chunks) = match document_ids {
+        None => (None, Vec::new()),
+        Some(ids) => {
+            let started = stats.start_phase("Pack document IDs");
+            let (chunk_starts, chunks) = serialise_document_id_chunks(&ids, chunk_size)?;
+            info!("{} chunks contain document IDs", number(chunks.len()));
+            stats.record_phase("Pack document IDs", started);
+            (Some(chunk_starts), chunks)
+        }
     };
-    let (terms_index_raw_lookup, terms_index_serialised_entries) = terms_index_builder.serialise();
-    println!("{} chunks contain terms", number(terms_index_builder.chunk_count()));
-    let _ = remove_dir_all(output_dir.join("terms"));
-    create_dir(output_dir.join("terms")).expect("create terms chunks folder");
-    for (i, chunk) in terms_index_serialised_entries.iter().enumerate() {
-        let mut f = File::create(
-            output_dir.join("terms").join(format!("{}", i))
-        ).expect("open terms chunk file for writing");
-        f.write_all(chunk).expect("write terms chunk");
+    write_chunks(&output_dir, "ids", chunk_layout, &document_id_chunks)?;
+
+    let group_tables = match group_keys {
+        None => Vec::new(),
+        Some(keys) => {
+            let started = stats.start_phase("Pack group keys");
+            let (group_count, group_table) = serialise_group_table(&keys);
+            if group_table.len() > chunk_size {
+                return Err(BuildError::InvalidInput(format!("Group table takes {} bytes which does not fit in a chunk", number(group_table.len()))));
+            };
+            info!("{} documents are in {} groups", number(document_count), number(group_count));
+            stats.record_phase("Pack group keys", started);
+            vec![group_table]
+        }
     };
+    write_chunks(&output_dir, "groups", chunk_layout, &group_tables)?;
+    let has_groups = !group_tables.is_empty();
 
-    let mut documents_builder = BstChunks::<ChunkU32Key>::new(chunk_size);
-    for (document_id, document) in DocumentsReader::new(documents_source) {
-        documents_builder.insert(ChunkU32Key::new(document_id.try_into().expect("too many documents")), document.as_bytes().to_vec());
+    let (blocks_raw_lookup, blocks_chunk_count) = match term_blocks {
+        None => {
+            write_chunks(&output_dir, "blocks", chunk_layout, &[])?;
+            write_chunks_lookup::<ChunkStrKey>(&output_dir, "blocks", 0, &[])?;
+            (String::new(), 0)
+        }
+        Some(blocks) => {
+            let started = stats.start_phase("Pack term blocks");
+            info!("{} terms have block IDs", number(blocks.len()));
+            let mut blocks_builder = pack_block_tables(blocks, chunk_size)?;
+            let (blocks_raw_lookup, blocks_lookup, blocks_serialised_entries) = blocks_builder.serialise();
+            info!("{} chunks contain block tables", number(blocks_builder.chunk_count()));
+            record_chunk_utilisation(&mut stats, "Blocks", &blocks_builder);
+            write_chunks(&output_dir, "blocks", chunk_layout, &blocks_serialised_entries)?;
+            write_chunks_lookup::<ChunkStrKey>(&output_dir, "blocks", blocks_serialised_entries.len(), &blocks_lookup)?;
+            stats.record_phase("Pack term blocks", started);
+            (blocks_raw_lookup, blocks_serialised_entries.len())
+        }
     };
-    let (documents_raw_lookup, documents_serialised_entries) = documents_builder.serialise();
-    println!("{} chunks contain documents", number(documents_builder.chunk_count()));
-    let _ = remove_dir_all(output_dir.join("documents"));
-    create_dir(output_dir.join("documents")).expect("create documents chunks folder");
-    for (i, chunk) in documents_serialised_entries.iter().enumerate() {
-        let mut f = File::create(
-            output_dir.join("documents").join(format!("{}", i))
-        ).expect("open documents chunk file for writing");
-        f.write_all(chunk).expect("write documents chunk");
+
+    let (filters_raw_lookup, filters_chunk_count) = match filters {
+        None => {
+            write_chunks(&output_dir, "filters", chunk_layout, &[])?;
+            write_chunks_lookup::<ChunkStrKey>(&output_dir, "filters", 0, &[])?;
+            (String::new(), 0)
+        }
+        Some(filters) => {
+            let started = stats.start_phase("Pack filters");
+            info!("There are {} filter values", number(filters.len()));
+            let mut filters_builder = pack_filters::<L>(filters, document_count, chunk_size)?;
+            let (filters_raw_lookup, filters_lookup, filters_serialised_entries) = filters_builder.serialise();
+            info!("{} chunks contain filters", number(filters_builder.chunk_count()));
+            record_chunk_utilisation(&mut stats, "Filters", &filters_builder);
+            write_chunks(&output_dir, "filters", chunk_layout, &filters_serialised_entries)?;
+            write_chunks_lookup::<ChunkStrKey>(&output_dir, "filters", filters_serialised_entries.len(), &filters_lookup)?;
+            stats.record_phase("Pack filters", started);
+            (filters_raw_lookup, filters_serialised_entries.len())
+        }
     };
 
-    generate_worker_js(
-        &output_dir,
+    let sort_key_count = sort_key_names.len();
+    let range_field_count = range_field_names.len();
+    let document_id_chunk_count = document_id_chunk_starts.as_ref().map_or(0, |starts| starts.len());
+    let started = stats.start_phase("Generate worker");
+    write_format_json(FormatArgs {
+        output_dir: &output_dir,
+        blocks_chunk_count,
+        chunk_layout,
+        document_id_chunk_starts: document_id_chunk_starts.as_deref(),
+        documents_chunk_count,
+        documents_stored: store_documents,
+        filters_chunk_count,
+        has_bm25,
+        has_groups,
+        kv_key_namespace: kv_key_namespace.as_deref(),
+        per_document_compression,
+        range_field_names: &range_field_names,
+        sort_key_names: &sort_key_names,
+        terms_chunk_count: terms_index_chunk_count,
+    })?;
+    let worker_data = WorkerData {
+        approximate_terms,
+        blocks_chunk_count,
+        chunk_layout,
+        document_count,
+        document_dictionary_bytes,
+        document_id_chunk_starts,
+        documents_stored: store_documents,
+        filters_chunk_count,
+        has_bm25,
+        has_groups,
+        kv_key_namespace,
+        max_document_bytes,
+        max_expanded_terms: maximum_expanded_terms,
+        max_query_terms: maximum_query_terms,
+        max_results: maximum_query_results,
+        per_document_compression,
+        precompression: precompression.clone(),
+        range_field_names,
+        sort_key_names,
+        term_analyser,
+        // Query terms only need the same treatment if long terms were kept.
+        term_truncation_bytes: Some(term_length_limit.max_bytes).filter(|_| term_length_limit.handling == LongTermHandling::Truncate),
+    };
+    worker_data.save(&output_dir)?;
+    write_worker(WorkerConfig {
+        chunk_read_concurrency,
+        chunk_read_retries,
+        chunk_read_retry_delay,
+        chunk_read_timeout,
+        chunk_read_timeout_behaviour,
         data_store,
         data_store_url_prefix,
-        terms_by_document.len(),
-        maximum_query_terms,
-        maximum_query_results,
-    );
-    generate_and_compile_runner_wasm(
-        &output_dir,
-        maximum_query_results,
-        maximum_query_terms,
-        terms_index_raw_lookup.as_str(),
-        terms_index_serialised_entries.len(),
-        documents_raw_lookup.as_str(),
-        documents_serialised_entries.len(),
-    );
-    println!("Build complete")
+        deploy_target,
+        document_route,
+        empty_result_behaviour,
+        expanded_terms_overflow,
+        group_scan_limit,
+        maximum_prefix_expansions,
+        maximum_query_bytes,
+        minimum_query_bytes,
+        minimum_query_terms,
+        on_unknown_term,
+        openapi,
+        output_dir: output_dir.clone(),
+        proximity_scan_limit,
+        query_log,
+        response,
+        route_prefix,
+        scoped_search,
+        term_stats_route,
+        worker_middleware,
+        worker_prelude,
+    }, worker_data)?;
+    stats.record_phase("Generate worker", started);
+    let started = stats.start_phase("Compile WASM");
+    generate_and_compile_runner_wasm(RunnerWasmArgs {
+        output_dir: &output_dir,
+        max_results: maximum_query_results,
+        // The worker rejects queries with more terms before expanding them, and the runner allocates prefix expansions per query,
+        // so the maximum expanded terms only ever lowers how many terms reach the runner's fixed-size term arrays.
+        max_query_terms: maximum_query_terms,
+        terms_chunks_raw: terms_index_raw_lookup.as_str(),
+        terms_chunks_len: terms_index_chunk_count,
+        documents_chunks_raw: documents_raw_lookup.as_str(),
+        documents_chunks_len: documents_chunk_count,
+        blocks_chunks_raw: blocks_raw_lookup.as_str(),
+        blocks_chunks_len: blocks_chunk_count,
+        filters_chunks_raw: filters_raw_lookup.as_str(),
+        filters_chunks_len: filters_chunk_count,
+        compiler: compiler.as_ref(),
+        compile_dir: compile_dir.as_ref(),
+        include_dirs: &include_dirs,
+        intersection_strategy,
+        raw_clang_flags: &raw_clang_flags,
+        simd,
+        sysroot: sysroot.as_ref(),
+        size_report,
+        wasm_opt: wasm_opt.as_ref(),
+        zstd_decoder: zstd_decoder.as_ref().filter(|_| per_document_compression == DocumentCompression::Zstd),
+    })?;
+    stats.record_phase("Compile WASM", started);
+    // The chunk counts are baked into the worker and runner, so queries would silently miss data if they drifted from what was written.
+    verify_chunks(&output_dir, "terms", chunk_layout, terms_index_chunk_count)?;
+    verify_chunks(&output_dir, "documents", chunk_layout, documents_chunk_count)?;
+    verify_chunks(&output_dir, "dictionaries", chunk_layout, if per_document_compression == DocumentCompression::Zstd { 1 } else { 0 })?;
+    verify_chunks(&output_dir, "sorts", chunk_layout, sort_key_count)?;
+    verify_chunks(&output_dir, "ranges", chunk_layout, range_field_count)?;
+    verify_chunks(&output_dir, "ids", chunk_layout, document_id_chunk_count)?;
+    verify_chunks(&output_dir, "groups", chunk_layout, if has_groups { 1 } else { 0 })?;
+    verify_chunks(&output_dir, "ranking", chunk_layout, if has_bm25 { 1 } else { 0 })?;
+    verify_chunks(&output_dir, "blocks", chunk_layout, blocks_chunk_count)?;
+    verify_chunks(&output_dir, "filters", chunk_layout, filters_chunk_count)?;
+    // Always run so that copies from a previous build with different settings are removed.
+    let started = if precompression.is_empty() { Instant::now() } else { stats.start_phase("Precompress") };
+    write_precompressed(&output_dir, &precompression)?;
+    if !precompression.is_empty() {
+        stats.record_phase("Precompress", started);
+    };
+    if let Some(bundle) = &bundle {
+        let started = stats.start_phase("Bundle");
+        write_bundle(&output_dir, bundle, bundle_compression, bundle_only)?;
+        stats.record_phase("Bundle", started);
+    };
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.clear();
+    };
+    info!("Build complete");
+    stats.log_phase_timings();
+    if let Some(path) = &stats_json {
+        stats.write_json(path, &resolved_config)?;
+    };
+    Ok(stats)
+}
+
+fn log_long_terms(long_terms: usize, term_length_limit: TermLengthLimit) -> () {
+    if long_terms > 0 {
+        info!("{} document terms were longer than {} bytes and were {}", number(long_terms), term_length_limit.max_bytes, match term_length_limit.handling {
+            LongTermHandling::Truncate => "truncated",
+            LongTermHandling::Drop => "dropped",
+        });
+    };
+}
+
+fn log_analysed_out_terms(analysed_out_terms: usize) -> () {
+    if analysed_out_terms > 0 {
+        info!("{} document terms were dropped by term analysis, such as stopwords", number(analysed_out_terms));
+    };
+}
+
+// Read all document terms, interning them into `terms` so terms already in it keep their IDs.
+fn read_terms(
+    mut terms: TermDictionary,
+    corpus: &CorpusSource,
+    invalid_term_handling: InvalidTermHandling,
+    missing_terminator_handling: MissingTerminatorHandling,
+    parse_threads: usize,
+    term_analyser: &Analyser,
+    term_length_limit: TermLengthLimit,
+) -> Result<(TermDictionary, Vec<Vec<TermId>>), BuildError> {
+    // document_id => term_id[].
+    let mut terms_by_document = Vec::<Vec<TermId>>::new();
+
+    // Analysis and truncation can turn distinct terms of a document into the same term, so duplicates must then be dropped.
+    let dedupe_document_terms = !term_analyser.is_identity() || term_length_limit.handling == LongTermHandling::Truncate;
+    // Get the term to index after analysis, or None if it's dropped, and whether it was too long.
+    let prepare_term = |term: Term| -> (Option<Term>, bool) {
+        match term_analyser.analyse(term) {
+            Some(term) => term_length_limit.apply(term),
+            None => (None, false),
+        }
+    };
+    // Occurrences of terms that analysis dropped, such as stopwords; documents of only such terms are still counted.
+    let mut analysed_out_terms = 0;
+    let mut long_terms = 0;
+    let mut on_long_term = |document_id: usize| -> () {
+        long_terms += 1;
+        if term_length_limit.handling == LongTermHandling::Drop {
+            warn!("Dropping term in document {} as it is longer than {} bytes", document_id, term_length_limit.max_bytes);
+        };
+    };
+    // Documents are read in order, so only the term IDs of the document being read are needed to drop duplicates.
+    let mut seen_document_id = None::<usize>;
+    let mut seen_term_ids = HashSet::<TermId>::new();
+    let mut add_document_term = |document_id: usize, term_id: TermId| -> () {
+        // Some documents have no terms, so iteration could skip a few document IDs.
+        while terms_by_document.len() <= document_id {
+            terms_by_document.push(Vec::<TermId>::new());
+        };
+        if dedupe_document_terms {
+            if seen_document_id != Some(document_id) {
+                seen_document_id = Some(document_id);
+                seen_term_ids.clear();
+            };
+            if !seen_term_ids.insert(term_id) {
+                return;
+            };
+        };
+        terms_by_document[document_id].push(term_id);
+    };
+
+    // - Each document must end with '\0', even if last, unless missing terminators are handled leniently.
+    // - Each term must be unique within its document.
+    // - Each term must end with '\0', even if last for document or entire index, unless missing terminators are handled leniently.
+    // - Each term must not be empty.
+    // - Each term must not contain '\0'.
+    let document_terms_source = match corpus {
+        CorpusSource::Files { document_terms, .. } => document_terms.try_clone().context("open document terms file")?,
+        CorpusSource::Combined { file, document_terms_offset, .. } => {
+            let mut file = file.try_clone().context("open combined corpus file")?;
+            file.seek(SeekFrom::Start(*document_terms_offset)).context("seek to document terms")?;
+            file
+        }
+        CorpusSource::Memory(corpus) => {
+            for (document_id, document_terms) in corpus.document_terms.iter().enumerate() {
+                for term in document_terms {
+                    let (term, long) = prepare_term(term.clone());
+                    if long {
+                        on_long_term(document_id);
+                    } else if term.is_none() {
+                        analysed_out_terms += 1;
+                    };
+                    if let Some(term) = term {
+                        add_document_term(document_id, terms.intern(&term).0);
+                    };
+                };
+            };
+            // Unlike when reading from a file, the amount of documents is known, including trailing documents without terms.
+            terms_by_document.resize(corpus.documents.len(), Vec::new());
+            log_long_terms(long_terms, term_length_limit);
+            log_analysed_out_terms(analysed_out_terms);
+            return Ok((terms, terms_by_document));
+        }
+    };
+    let documents_ended = if parse_threads > 1 {
+        let mut next_document_id = 0;
+        read_document_terms_parallel(document_terms_source, parse_threads, missing_terminator_handling, |block| {
+            // Blocks arrive in input order and their local dictionaries are in order of first occurrence,
+            // so term IDs are assigned exactly as the serial reader would.
+            for local_document in block.invalid_terms.iter() {
+                invalid_term_handling.handle(next_document_id + local_document)?;
+            };
+            let block_term_ids = block.terms.into_iter()
+                .map(|term| {
+                    let (term, long) = prepare_term(term);
+                    (term.map(|term| terms.intern(&term).0), long)
+                })
+                .collect::<Vec<(Option<TermId>, bool)>>();
+            for local_terms in block.documents {
+                let document_id = next_document_id;
+                next_document_id += 1;
+                for local_id in local_terms {
+                    let (term_id, long) = block_term_ids[local_id];
+                    if long {
+                        on_long_term(document_id);
+                    } else if term_id.is_none() {
+                        analysed_out_terms += 1;
+                    };
+                    if let Some(term_id) = term_id {
+                        add_document_term(document_id, term_id);
+                    };
+                };
+            };
+            Ok(())
+        })?;
+        next_document_id
+    } else {
+        let mut reader = DocumentTermsReader::new(document_terms_source, invalid_term_handling, missing_terminator_handling)?;
+        for entry in &mut reader {
+            let (document_id, term) = entry?;
+            let (term, long) = prepare_term(term);
+            if long {
+                on_long_term(document_id);
+            } else if term.is_none() {
+                analysed_out_terms += 1;
+            };
+            if let Some(term) = term {
+                add_document_term(document_id, terms.intern(&term).0);
+            };
+        };
+        reader.documents_ended()
+    };
+    // Documents at the end without terms are only known from their terminators, as no terms are added for them.
+    terms_by_document.resize(terms_by_document.len().max(documents_ended), Vec::new());
+    if let CorpusSource::Combined { document_count, .. } = corpus {
+        // The amount of documents is known from the combined file, including trailing documents without terms.
+        if terms_by_document.len() > *document_count {
+            return Err(BuildError::InvalidInput(format!("Document terms has entries for document {} but there are {} documents", terms_by_document.len() - 1, document_count)));
+        };
+        terms_by_document.resize(*document_count, Vec::new());
+    };
+    log_long_terms(long_terms, term_length_limit);
+    log_analysed_out_terms(analysed_out_terms);
+
+    Ok((terms, terms_by_document))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document_inputs() -> DocumentInputs {
+        DocumentInputs {
+            sort_values: Vec::new(),
+            range_values: Vec::new(),
+            document_ids: None,
+            group_keys: None,
+            term_blocks: None,
+            filters: None,
+        }
+    }
+
+    #[test]
+    fn sort_key_with_a_value_for_each_document_is_valid() {
+        let mut inputs = document_inputs();
+        inputs.sort_values.push(("price".to_string(), vec![1.0, 2.0, 3.0]));
+        assert!(inputs.check_document_count(3).is_ok());
+    }
+
+    #[test]
+    fn sort_key_with_too_few_or_too_many_values_is_an_error() {
+        for values in &[vec![1.0, 2.0], vec![1.0, 2.0, 3.0, 4.0]] {
+            let mut inputs = document_inputs();
+            inputs.sort_values.push(("price".to_string(), values.clone()));
+            assert!(matches!(inputs.check_document_count(3), Err(BuildError::InvalidInput(_))));
+        };
+    }
+    #[test]
+    fn range_field_with_too_few_or_too_many_values_is_an_error() {
+        for values in &[Vec::new(), vec![1.0], vec![1.0, 2.0, 3.0]] {
+            let mut inputs = document_inputs();
+            inputs.range_values.push(("year".to_string(), values.clone()));
+            assert!(matches!(inputs.check_document_count(2), Err(BuildError::InvalidInput(_))));
+        };
+        let mut inputs = document_inputs();
+        inputs.range_values.push(("year".to_string(), vec![1.0, 2.0]));
+        assert!(inputs.check_document_count(2).is_ok());
+    }
+    // Read the terms of a combined corpus file with the given documents and document terms sections.
+    fn read_combined_terms(name: &str, documents: &str, document_terms: &str) -> Result<Vec<Vec<TermId>>, BuildError> {
+        let path = crate::build::js::tests::test_output_dir(name).join("corpus");
+        std::fs::write(&path, format!("{}\0{}", documents, document_terms)).unwrap();
+        let corpus = CorpusSource::combined(File::open(&path).unwrap())?;
+        let term_length_limit = TermLengthLimit { max_bytes: 64, handling: LongTermHandling::Drop };
+        read_terms(TermDictionary::new(), &corpus, InvalidTermHandling::Error, MissingTerminatorHandling::Strict, 1, &Analyser::new(Vec::new()), term_length_limit)
+            .map(|(_, terms_by_document)| terms_by_document)
+    }
+
+    #[test]
+    fn combined_corpus_pads_documents_without_terms() {
+        let terms_by_document = read_combined_terms("combined-pad", "{}\0{}\0{}\0", "a\0\0").unwrap();
+        assert_eq!(terms_by_document.len(), 3);
+    }
+
+    #[test]
+    fn combined_corpus_with_terms_for_more_documents_is_an_error() {
+        let result = read_combined_terms("combined-extra", "{}\0{}\0", "a\0\0b\0\0c\0\0");
+        assert!(matches!(result, Err(BuildError::InvalidInput(_))));
+    }
 }
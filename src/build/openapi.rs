@@ -0,0 +1,238 @@
+use std::fs::write;
+
+use crate::build::EmptyResultBehaviour;
+use crate::build::error::{BuildError, IoContext};
+use crate::build::js::{ErrorFormat, js_string, WorkerJsArgs};
+
+/**
+ * Write an OpenAPI 3 description of the routes the generated worker serves to `openapi.json`, for API documentation and client generation tools.
+ * It is generated from the same arguments as the worker script, so it describes exactly the routes, parameters, and limits of this build.
+ * Keep in sync with script/src/main.ts.
+ */
+pub fn write_openapi_json(args: &WorkerJsArgs) -> Result<(), BuildError> {
+    let content_type = js_string(&args.response.content_type);
+    let error_content_type = match args.response.error_format {
+        ErrorFormat::Text => r#""text/plain""#.to_string(),
+        ErrorFormat::Message | ErrorFormat::Envelope => content_type.clone(),
+    };
+    let error_response = |description: &str| format!(
+        r##"{{"description": {}, "content": {{{}: {{"schema": {{"$ref": "#/components/schemas/Error"}}}}}}}}"##,
+        js_string(description),
+        error_content_type,
+    );
+    // Keep in sync with ERROR_STATUSES in script/src/main.ts.
+    let error_schema = match args.response.error_format {
+        ErrorFormat::Message => r#"{
+        "type": "object",
+        "required": ["error"],
+        "properties": {
+          "error": {"type": "string"}
+        }
+      }"#,
+        ErrorFormat::Envelope => r#"{
+        "type": "object",
+        "required": ["error"],
+        "properties": {
+          "error": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+              "code": {"type": "string", "enum": ["invalid_query", "unknown_term", "not_found", "too_many_terms", "internal_error", "timeout"]},
+              "message": {"type": "string"}
+            }
+          }
+        }
+      }"#,
+        ErrorFormat::Text => r#"{
+        "type": "string"
+      }"#,
+    };
+
+    let mut search_params = vec![
+        format!(
+            r#"{{"name": "t", "in": "query", "description": "A term to match, as `{{mode}}_{{term}}` where mode is 0 to require, 1 to contain, or 2 to exclude the term. Up to {} terms across all modes and groups.", "schema": {{"type": "array", "items": {{"type": "string", "pattern": "^[0-2]_.+$"}}}}, "style": "form", "explode": true}}"#,
+            args.max_query_terms,
+        ),
+        r#"{"name": "g", "in": "query", "description": "A term of a group of terms that must all be present, as `{group}_{term}`. A document matches if it matches any group.", "schema": {"type": "array", "items": {"type": "string", "pattern": "^[0-9]+_.+$"}}, "style": "form", "explode": true}"#.to_string(),
+        r#"{"name": "c", "in": "query", "description": "Continuation from a previous response, to get the next page of results.", "schema": {"type": "integer", "minimum": 0, "default": 0}}"#.to_string(),
+    ];
+    if args.max_prefix_expansions > 0 {
+        search_params.push(format!(
+            r#"{{"name": "p", "in": "query", "description": "Set to 1 to match the last `t` term as a prefix, such as for autocomplete. It must be required, and matches documents with any of the first {} indexed terms starting with it.", "schema": {{"type": "string", "enum": ["1"]}}}}"#,
+            args.max_prefix_expansions,
+        ));
+    };
+    if args.filters {
+        search_params.push(format!(
+            r#"{{"name": "f", "in": "query", "description": "A filter value, such as `category=books`, that matching documents must have exactly. Up to {} filters, which are combined using AND with each other and the terms.", "schema": {{"type": "array", "items": {{"type": "string"}}}}, "style": "form", "explode": true}}"#,
+            args.max_query_terms,
+        ));
+    };
+    if !args.range_field_names.is_empty() {
+        search_params.push(format!(
+            r#"{{"name": "r", "in": "query", "description": "A range of a numeric field that matching documents must have a value in, as `{{field}}:{{min}}:{{max}}` where both bounds are inclusive and either can be empty to leave that side unbounded. Fields are {}. Up to {} ranges, which are combined using AND with each other and the terms.", "schema": {{"type": "array", "items": {{"type": "string", "pattern": "^[A-Za-z0-9_-]+:[^:]*:[^:]*$"}}}}, "style": "form", "explode": true}}"#,
+            args.range_field_names.join(", "),
+            args.max_query_terms,
+        ));
+    };
+    if args.scoped_search {
+        search_params.push(r#"{"name": "i", "in": "query", "description": "Internal IDs of the only documents to match, encoded as unpadded base64url of unsigned LEB128 varints. Each range of consecutive IDs is a varint of the gap from the end of the previous range (or from zero) followed by a varint of its length.", "schema": {"type": "string", "pattern": "^[A-Za-z0-9_-]*$"}}"#.to_string());
+    };
+    if !args.sort_key_names.is_empty() {
+        search_params.push(format!(
+            r#"{{"name": "s", "in": "query", "description": "Sort key to order results by, instead of document order.", "schema": {{"type": "string", "enum": [{}]}}}}"#,
+            args.sort_key_names.iter().map(|n| js_string(n)).collect::<Vec<String>>().join(", "),
+        ));
+        search_params.push(r#"{"name": "o", "in": "query", "description": "Direction to order results by the sort key.", "schema": {"type": "string", "enum": ["asc", "desc"], "default": "asc"}}"#.to_string());
+    };
+    if args.group_scan_limit.is_some() {
+        search_params.push(r#"{"name": "u", "in": "query", "description": "Set to 1 to return at most one result per group key, keeping the highest ranked.", "schema": {"type": "string", "enum": ["1"]}}"#.to_string());
+    };
+    if args.response.explain {
+        search_params.push(r#"{"name": "e", "in": "query", "description": "Set to 1 to explain which query terms each result matched and how it was scored.", "schema": {"type": "string", "enum": ["1"]}}"#.to_string());
+    };
+
+    let mut search_responses = vec![
+        format!(r##""200": {{"description": "Matching documents.", "content": {{{}: {{"schema": {{"$ref": "#/components/schemas/SearchResponse"}}}}}}}}"##, content_type),
+        format!(r#""400": {}"#, error_response("The query is malformed or invalid.")),
+        format!(r#""413": {}"#, error_response("The query has too many terms.")),
+    ];
+    if let EmptyResultBehaviour::NotFound = args.empty_result_behaviour {
+        search_responses.push(format!(r##""404": {{"description": "No documents match.", "content": {{{}: {{"schema": {{"$ref": "#/components/schemas/SearchResponse"}}}}}}}}"##, content_type));
+    };
+    // Any route can time out fetching chunks.
+    let timeout_response = args.chunk_read_timeout.map(|_| format!(r#""504": {}"#, error_response("Fetching data timed out.")));
+    search_responses.extend(timeout_response.clone());
+    // Any route can fail unexpectedly, such as when the data store is unavailable.
+    let internal_error_response = format!(r#""500": {}"#, error_response("An unexpected error occurred, such as failing to fetch data."));
+    search_responses.push(internal_error_response.clone());
+
+    let mut paths = vec![format!(
+        r#"    "{PREFIX}/search": {{
+      "get": {{
+        "operationId": "search",
+        "parameters": [
+          {PARAMS}
+        ],
+        "responses": {{
+          {RESPONSES}
+        }}
+      }}
+    }}"#,
+        PREFIX = args.route_prefix,
+        PARAMS = search_params.join(",\n          "),
+        RESPONSES = search_responses.join(",\n          "),
+    )];
+    if args.document_route {
+        paths.push(format!(
+            r#"    "{PREFIX}/doc/{{id}}": {{
+      "get": {{
+        "operationId": "getDocument",
+        "parameters": [
+          {{"name": "id", "in": "path", "required": true, "description": "Internal ID of the document, which is its position.", "schema": {{"type": "integer", "minimum": 0, "maximum": {MAX_ID}}}}}
+        ],
+        "responses": {{
+          "200": {{"description": "The document.", "content": {{{CONTENT_TYPE}: {{"schema": {{}}}}}}}},
+          "400": {INVALID},
+          "404": {NOT_FOUND},
+          {INTERNAL_ERROR}{TIMEOUT}
+        }}
+      }}
+    }}"#,
+            PREFIX = args.route_prefix,
+            MAX_ID = args.document_count.saturating_sub(1),
+            CONTENT_TYPE = content_type,
+            INVALID = error_response("The document ID is invalid."),
+            NOT_FOUND = error_response("The document does not exist."),
+            INTERNAL_ERROR = internal_error_response,
+            TIMEOUT = timeout_response.as_ref().map(|r| format!(",\n          {}", r)).unwrap_or_default(),
+        ));
+    };
+    if args.term_stats_route {
+        paths.push(format!(
+            r##"    "{PREFIX}/term/{{term}}/stats": {{
+      "get": {{
+        "operationId": "getTermStats",
+        "parameters": [
+          {{"name": "term", "in": "path", "required": true, "schema": {{"type": "string"}}}}
+        ],
+        "responses": {{
+          "200": {{"description": "Statistics about the term.", "content": {{{CONTENT_TYPE}: {{"schema": {{"$ref": "#/components/schemas/TermStats"}}}}}}}},
+          "400": {INVALID},
+          "404": {NOT_FOUND},
+          {INTERNAL_ERROR}{TIMEOUT}
+        }}
+      }}
+    }}"##,
+            PREFIX = args.route_prefix,
+            CONTENT_TYPE = content_type,
+            INVALID = error_response("The term is malformed."),
+            NOT_FOUND = error_response("The term is not in any document."),
+            INTERNAL_ERROR = internal_error_response,
+            TIMEOUT = timeout_response.as_ref().map(|r| format!(",\n          {}", r)).unwrap_or_default(),
+        ));
+    };
+
+    let mut search_response_properties = vec![
+        format!(
+            r#""results": {{"type": "array", "maxItems": {}, "items": {}}}"#,
+            args.max_results,
+            if !args.documents_stored {
+                r#"{"type": "integer", "description": "Internal ID of a matching document."}"#.to_string()
+            } else if args.response.result_projection.is_empty() {
+                r#"{"description": "A document as provided when building."}"#.to_string()
+            } else {
+                format!(
+                    r#"{{"description": {}}}"#,
+                    js_string(&format!("A document as provided when building. Documents that are JSON objects only have the fields {}.", args.response.result_projection.join(", "))),
+                )
+            },
+        ),
+        r#""continuation": {"type": "integer", "nullable": true, "description": "Value of `c` to get the next page of results, or null if there are no more."}"#.to_string(),
+        r#""total": {"type": "integer", "description": "Amount of matching documents."}"#.to_string(),
+        r#""degraded": {"type": "boolean", "description": "Present and true if some data took too long to fetch and was skipped, so results may be incomplete."}"#.to_string(),
+    ];
+    if args.document_id_chunk_starts.is_some() {
+        search_response_properties.push(r#""ids": {"type": "array", "items": {"type": "string", "nullable": true}, "description": "Original ID of each result at the same index, or null if it could not be fetched in time."}"#.to_string());
+    };
+    if args.response.explain {
+        search_response_properties.push(r#""explain": {"type": "object", "description": "Present if `e` is 1.", "properties": {"terms": {"type": "object", "additionalProperties": {"type": "integer"}, "description": "Amount of documents containing each known query term."}, "results": {"type": "array", "items": {"type": "object", "properties": {"terms": {"type": "array", "items": {"type": "string"}, "description": "Query terms the result at the same index contains."}, "proximity": {"type": "integer", "description": "Most query terms in the same block of the result, or 0 if fewer than two share a block. Only results within the proximity scan limit are boosted by it. Present if results were boosted."}}}}}}"#.to_string());
+    };
+
+    let json = format!(r#"{{
+  "openapi": "3.0.3",
+  "info": {{
+    "title": "Edgesearch",
+    "version": "1"
+  }},
+  "paths": {{
+{PATHS}
+  }},
+  "components": {{
+    "schemas": {{
+      "SearchResponse": {{
+        "type": "object",
+        "required": ["results", "continuation", "total"],
+        "properties": {{
+          {SEARCH_RESPONSE_PROPERTIES}
+        }}
+      }},
+      "TermStats": {{
+        "type": "object",
+        "required": ["term", "documents"],
+        "properties": {{
+          "term": {{"type": "string", "description": "The term after normalisation."}},
+          "documents": {{"type": "integer", "description": "Amount of documents containing the term."}}
+        }}
+      }},
+      "Error": {ERROR_SCHEMA}
+    }}
+  }}
+}}
+"#,
+        PATHS = paths.join(",\n"),
+        ERROR_SCHEMA = error_schema,
+        SEARCH_RESPONSE_PROPERTIES = search_response_properties.join(",\n          "),
+    );
+    write(args.output_dir.join("openapi.json"), json).context("write openapi.json")
+}
@@ -0,0 +1,281 @@
+use std::convert::TryInto;
+use std::fs::{read, read_dir};
+use std::ops::Range;
+use std::path::Path;
+use std::thread;
+
+use croaring::Bitmap;
+use log::info;
+
+use crate::{Term, TermId};
+use crate::build::chunks::{CHUNK_STR_KEY_MAX_LEN, ChunkStrKey};
+use crate::build::chunks::bst::{BstChunks, OversizedValue};
+use crate::build::dictionary::TermDictionary;
+use crate::build::error::{BuildError, IoContext};
+use crate::util::format::{number, percent};
+use crate::util::log::status_log_interval;
+
+/**
+ * A set of IDs of documents containing a term, which is serialised as a value in the terms chunks.
+ * The runner deserialises postings lists using the portable format of Roaring Bitmaps, so `serialize` must produce that format.
+ * croaring's `Bitmap` is used by default, but other implementations of the format, such as a pure Rust one, can be used instead.
+ * Postings lists must be `Send`, as they can be built on multiple threads.
+ */
+pub trait PostingsList: Send {
+    fn create() -> Self;
+    fn add(&mut self, id: u32);
+    // Make the representation smaller before serialising, such as by using run-length encoding where it helps.
+    fn optimize(&mut self);
+    fn serialize(&self) -> Vec<u8>;
+    fn cardinality(&self) -> u64;
+    fn maximum(&self) -> Option<u32>;
+    // Get the IDs in ascending order.
+    fn to_vec(&self) -> Vec<u32>;
+}
+
+impl PostingsList for Bitmap {
+    fn create() -> Bitmap {
+        Bitmap::create()
+    }
+
+    fn add(&mut self, id: u32) {
+        Bitmap::add(self, id);
+    }
+
+    fn optimize(&mut self) {
+        self.run_optimize();
+    }
+
+    // croaring uses the portable format.
+    fn serialize(&self) -> Vec<u8> {
+        Bitmap::serialize(self)
+    }
+
+    fn cardinality(&self) -> u64 {
+        Bitmap::cardinality(self)
+    }
+
+    fn maximum(&self) -> Option<u32> {
+        Bitmap::maximum(self)
+    }
+
+    fn to_vec(&self) -> Vec<u32> {
+        Bitmap::to_vec(self)
+    }
+}
+
+/**
+ * Read postings lists serialised by another tool, from a file or every file in a folder in name order.
+ * Each file contains any amount of entries, each a term followed by NULL (ASCII 0) and then its postings list in the portable Roaring Bitmap format.
+ * Postings lists don't need a length, as the format records the size of every container.
+ * If `document_count` is None, it is one more than the largest document ID in any postings list.
+ */
+pub fn read_serialised_postings(path: &Path, document_count: Option<usize>) -> Result<Postings<Bitmap>, BuildError> {
+    let mut files = if path.is_dir() {
+        read_dir(path).context("read postings folder")?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("read postings folder")?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+    let mut terms = Vec::<Term>::new();
+    let mut lists = Vec::<Bitmap>::new();
+    for file in files {
+        let raw = read(&file).with_context(|| format!("read postings file {}", file.display()))?;
+        let mut next = 0;
+        while next < raw.len() {
+            let term_len = raw[next..].iter().position(|b| *b == 0)
+                .ok_or_else(|| BuildError::InvalidInput(format!("Term at byte {} of {} is not followed by NULL", next, file.display())))?;
+            let term = String::from_utf8(raw[next..next + term_len].to_vec())
+                .map_err(|_| BuildError::InvalidInput(format!("Term at byte {} of {} is not valid UTF-8", next, file.display())))?;
+            next += term_len + 1;
+            let list = Bitmap::try_deserialize(&raw[next..])
+                .ok_or_else(|| BuildError::InvalidInput(format!("Postings list of term {:?} in {} is not a valid Roaring Bitmap", term, file.display())))?;
+            next += list.get_serialized_size_in_bytes();
+            terms.push(term);
+            lists.push(list);
+        };
+    };
+    info!("Read {} prebuilt postings lists", number(lists.len()));
+    let document_count = document_count.unwrap_or_else(|| lists.iter().filter_map(|list| list.maximum()).max().map_or(0, |max| max as usize + 1));
+    Postings::new(terms, lists, document_count)
+}
+
+// Build the postings lists of the terms with IDs in `term_ids`, in term ID order.
+fn build_lists<L: PostingsList>(term_ids: Range<TermId>, terms_by_document: &[Vec<TermId>], log: bool) -> Result<Vec<L>, BuildError> {
+    let mut lists = term_ids.clone().map(|_| L::create()).collect::<Vec<L>>();
+    let document_count = terms_by_document.len();
+    let hash_log_interval = status_log_interval(document_count, 10);
+    for (document_id, doc_terms) in terms_by_document.iter().enumerate() {
+        if log {
+            interval_log!(hash_log_interval, document_id, document_count, "Processing documents ({})...");
+        };
+        for term_id in doc_terms.iter().filter(|term_id| term_ids.contains(*term_id)) {
+            // Add to the relevant postings list entry bitmap.
+            lists[*term_id - term_ids.start].add(document_id.try_into().map_err(|_| BuildError::TooManyDocuments)?);
+        };
+    };
+    Ok(lists)
+}
+
+// The packed terms chunks, and each term whose postings list was sampled with the amount of documents it is actually in.
+pub(crate) type PackedPostings = (BstChunks<ChunkStrKey>, Vec<(Term, usize)>);
+
+/**
+ * The postings list of every term, which the terms chunks are packed from.
+ * `build` derives them from the corpus, but postings lists maintained by another system can be provided instead using `Postings::new`,
+ * which skips reading document terms entirely.
+ */
+pub struct Postings<L: PostingsList = Bitmap> {
+    pub(crate) terms: TermDictionary,
+    // term_id => IDs of documents containing the term.
+    pub(crate) lists: Vec<L>,
+    pub(crate) document_count: usize,
+}
+
+impl<L: PostingsList> Postings<L> {
+    /**
+     * Use prebuilt postings lists, where `lists[i]` contains the IDs of documents with the term `terms[i]`.
+     * Document IDs are positions in the documents, so they must be less than `document_count`.
+     * Terms must be unique and follow the same rules as terms in the document terms file, after any normalisation, and be at most 255 bytes.
+     */
+    pub fn new(terms: Vec<Term>, lists: Vec<L>, document_count: usize) -> Result<Postings<L>, BuildError> {
+        if terms.len() != lists.len() {
+            return Err(BuildError::InvalidInput(format!("There are {} terms but {} postings lists", terms.len(), lists.len())));
+        };
+        let mut dictionary = TermDictionary::new();
+        for (term, list) in terms.iter().zip(lists.iter()) {
+            if term.is_empty() || term.contains('\0') {
+                return Err(BuildError::InvalidInput(format!("Invalid term {:?}", term)));
+            };
+            if term.len() > CHUNK_STR_KEY_MAX_LEN {
+                return Err(BuildError::InvalidInput(format!("Term {:?} is {} bytes but terms can be at most {} bytes", term, term.len(), CHUNK_STR_KEY_MAX_LEN)));
+            };
+            if !dictionary.intern(term).1 {
+                return Err(BuildError::InvalidInput(format!("Duplicate term {:?}", term)));
+            };
+            if let Some(max) = list.maximum().filter(|max| *max as usize >= document_count) {
+                return Err(BuildError::InvalidInput(format!("Postings list of term {:?} contains document {} but there are {} documents", term, max, document_count)));
+            };
+        };
+        Ok(Postings {
+            terms: dictionary,
+            lists,
+            document_count,
+        })
+    }
+
+    /**
+     * Build the postings lists of terms read from documents.
+     * With more than one thread, each thread builds the lists of a contiguous range of term IDs by scanning every document in order,
+     * so every list has its documents added in the same order as on one thread and serialises identically.
+     */
+    pub(crate) fn from_document_terms(terms: TermDictionary, terms_by_document: &[Vec<TermId>], threads: usize) -> Result<Postings<L>, BuildError> {
+        let document_count = terms_by_document.len();
+        let lists = if threads > 1 {
+            let terms_per_thread = terms.len().div_ceil(threads);
+            thread::scope(|scope| {
+                let handles = (0..threads)
+                    .map(|t| {
+                        let start = (t * terms_per_thread).min(terms.len());
+                        let end = (start + terms_per_thread).min(terms.len());
+                        // Only the first thread logs progress, as every thread scans every document.
+                        scope.spawn(move || build_lists::<L>(start..end, terms_by_document, t == 0))
+                    })
+                    .collect::<Vec<_>>();
+                let mut lists = Vec::with_capacity(terms.len());
+                for handle in handles {
+                    lists.extend(handle.join().expect("join postings list thread")?);
+                };
+                Ok(lists)
+            })?
+        } else {
+            build_lists::<L>(0..terms.len(), terms_by_document, true)?
+        };
+        Ok(Postings {
+            terms,
+            lists,
+            document_count,
+        })
+    }
+
+    pub fn document_count(&self) -> usize {
+        self.document_count
+    }
+
+    pub fn term_count(&self) -> usize {
+        self.terms.len()
+    }
+
+    // Get every term that is in at least one document, with its ID.
+    pub(crate) fn indexed_terms(&self) -> Vec<(&str, TermId)> {
+        (0..self.terms.len()).filter(|term_id| self.lists[*term_id].cardinality() > 0).map(|term_id| (self.terms.term(term_id), term_id)).collect()
+    }
+
+    // Get the amount of documents each term is in, in term ID order.
+    pub(crate) fn term_frequencies(&self) -> Vec<usize> {
+        self.lists.iter().map(|list| list.cardinality() as usize).collect()
+    }
+
+    /**
+     * Pack the postings lists into chunks in term order.
+     * Postings lists with more than `maximum_cardinality` documents are replaced with an evenly spaced sample of that many documents.
+     * Returns the chunks and each term whose postings list was sampled, with the amount of documents it is actually in.
+     */
+    pub(crate) fn pack(self, chunk_size: usize, maximum_cardinality: Option<usize>) -> Result<PackedPostings, BuildError> {
+        let Postings { terms, mut lists, .. } = self;
+        let mut builder = BstChunks::<ChunkStrKey>::new(chunk_size);
+        let mut terms_sorted = (0..terms.len()).collect::<Vec<TermId>>();
+        terms_sorted.sort_by(|a, b| terms.term(*a).cmp(terms.term(*b)));
+        let mut unused_terms = 0;
+        let mut approximate_terms = Vec::<(Term, usize)>::new();
+        for term_id in terms_sorted.iter() {
+            let postings_list_entry = &mut lists[*term_id];
+            let cardinality = postings_list_entry.cardinality() as usize;
+            // Terms from the term dictionary that no longer occur keep their ID reserved but are not indexed.
+            if cardinality == 0 {
+                unused_terms += 1;
+                continue;
+            };
+            if let Some(max) = maximum_cardinality.filter(|max| cardinality > *max) {
+                let ids = postings_list_entry.to_vec();
+                let mut sampled = L::create();
+                for j in 0..max {
+                    sampled.add(ids[j * cardinality / max]);
+                };
+                *postings_list_entry = sampled;
+                approximate_terms.push((terms.term(*term_id).to_string(), cardinality));
+            };
+            postings_list_entry.optimize();
+            let serialised = postings_list_entry.serialize();
+            let term = terms.term(*term_id);
+            if let Err(OversizedValue { len, max_len }) = builder.insert(ChunkStrKey::new(term)?, serialised) {
+                return Err(BuildError::InvalidInput(format!("Postings list of term {:?} is {} bytes which does not fit in a chunk of up to {} bytes", term, number(len), number(max_len))));
+            };
+        };
+        if unused_terms > 0 {
+            info!("{} terms in the term dictionary are no longer used", number(unused_terms));
+        };
+        if !approximate_terms.is_empty() {
+            info!("Sampled the postings lists of {} terms in more than {} documents", number(approximate_terms.len()), number(maximum_cardinality.unwrap()));
+        };
+        Ok((builder, approximate_terms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postings_list_larger_than_a_chunk_is_an_error() {
+        let mut list = Bitmap::create();
+        for id in 0..1000 {
+            list.add(id * 3);
+        };
+        let postings = Postings::new(vec!["term".to_string()], vec![list], 3000).unwrap();
+        assert!(matches!(postings.pack(64, None), Err(BuildError::InvalidInput(_))));
+    }
+}
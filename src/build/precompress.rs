@@ -0,0 +1,91 @@
+use std::fs::{read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::arg_enum;
+use log::info;
+
+use crate::build::chunks::CHUNK_DATASETS;
+use crate::build::error::{BuildError, IoContext};
+
+// Amount of files passed to each compression process, to stay within command line length limits.
+const FILES_PER_PROCESS: usize = 1000;
+
+arg_enum! {
+    // Encodings to write copies of `runner.wasm` and every chunk in, for static hosts to serve instead of compressing on each request.
+    // Keep in sync with PRECOMPRESSED_GZIP in script/src/main.ts.
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum Precompression {
+        // `{file}.gz`, written with the system `gzip` command. The browser deploy target fetches these and decompresses them using DecompressionStream.
+        Gzip,
+        // `{file}.br`, written with the system `brotli` command, for hosts that serve them to clients that accept Brotli.
+        Brotli,
+    }
+}
+
+impl Precompression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Precompression::Gzip => "gz",
+            Precompression::Brotli => "br",
+        }
+    }
+}
+
+// Whether a file in the output folder is a precompressed copy of another file.
+pub fn is_precompressed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == Precompression::Gzip.extension() || ext == Precompression::Brotli.extension())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), BuildError> {
+    let mut entries = read_dir(dir).context("read chunks folder")?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>().context("read chunks folder")?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        };
+    };
+    Ok(())
+}
+
+/**
+ * Replace the precompressed copies of `runner.wasm` and every chunk in the output folder with copies in each of `encodings`.
+ * Copies from previous builds are always removed, so that a host never serves a copy that differs from its original.
+ * Compression is done by a process per batch of files, as running a process per chunk would be far too slow.
+ */
+pub fn write_precompressed(output_dir: &PathBuf, encodings: &[Precompression]) -> Result<(), BuildError> {
+    let mut files = Vec::<PathBuf>::new();
+    for dataset in CHUNK_DATASETS {
+        let dir = output_dir.join(dataset);
+        if dir.is_dir() {
+            collect_files(&dir, &mut files)?;
+        };
+    };
+    let runner = output_dir.join("runner.wasm");
+    for encoding in &[Precompression::Gzip, Precompression::Brotli] {
+        let _ = remove_file(runner.with_extension(format!("wasm.{}", encoding.extension())));
+    };
+    for path in files.iter().filter(|path| is_precompressed(path)) {
+        remove_file(path).with_context(|| format!("remove precompressed chunk {}", path.display()))?;
+    };
+    files.retain(|path| !is_precompressed(path));
+    files.push(runner);
+
+    for encoding in encodings {
+        for batch in files.chunks(FILES_PER_PROCESS) {
+            let (name, args): (&str, &[&str]) = match encoding {
+                // Omit the name and timestamp from the header so that copies of the same build are identical.
+                Precompression::Gzip => ("gzip", &["-k", "-n", "-9", "-f"]),
+                Precompression::Brotli => ("brotli", &["-k", "-Z", "-f"]),
+            };
+            let status = Command::new(name).args(args).args(batch).status().map_err(|err| BuildError::Command(format!("Failed to run {}: {}", name, err)))?;
+            if !status.success() {
+                return Err(BuildError::Command(format!("Failed to precompress files with {}", name)));
+            };
+        };
+        info!("Wrote {} precompressed copies of {} files", encoding.extension(), files.len());
+    };
+    Ok(())
+}
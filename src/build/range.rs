@@ -0,0 +1,75 @@
+use std::fs::File;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::build::sort::serialise_sort_table;
+
+// A file containing a numeric value for each document, which searches can require to be within a range.
+pub struct RangeField {
+    pub name: String,
+    pub values_source: File,
+}
+
+/**
+ * Serialise the table the worker and runner use to find documents with a value in a range.
+ * It is the sort table of the values, followed by every value in rank order, so ascending, as f64.
+ * The worker binary searches the values for the ranks a range covers, and the runner then gets the documents in those ranks from the table.
+ * Keep in sync with index_range_t in wasm/index.c.
+ */
+pub fn serialise_range_table(values: &[f64]) -> Vec<u8> {
+    let mut out = serialise_sort_table(values);
+    let mut sorted = values.to_vec();
+    // Values are never NaN.
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    for value in sorted {
+        out.write_f64::<LittleEndian>(value).expect("write range table");
+    };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Get the values section of a serialised range table, which follows the two u32 halves of the sort table.
+    fn parse_range_values(table: &[u8], document_count: usize) -> Vec<f64> {
+        table[document_count * 8..].chunks(8).map(|value| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(value);
+            f64::from_le_bytes(bytes)
+        }).collect()
+    }
+
+    #[test]
+    fn table_is_the_sort_table_followed_by_the_ascending_values() {
+        let values = [5.0, -2.5, 5.0, 0.0];
+        let table = serialise_range_table(&values);
+        assert_eq!(table.len(), values.len() * 16);
+        assert_eq!(&table[..values.len() * 8], &serialise_sort_table(&values)[..]);
+        assert_eq!(parse_range_values(&table, values.len()), vec![-2.5, 0.0, 5.0, 5.0]);
+    }
+
+    #[test]
+    fn extreme_values_are_kept_exactly_at_the_ends() {
+        let values = [f64::MAX, f64::INFINITY, 0.0, f64::NEG_INFINITY, f64::MIN, f64::MIN_POSITIVE];
+        let table = serialise_range_table(&values);
+        assert_eq!(parse_range_values(&table, values.len()), vec![f64::NEG_INFINITY, f64::MIN, 0.0, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY]);
+    }
+
+    #[test]
+    fn negative_and_positive_zero_are_the_same_value() {
+        let values = [0.0, -0.0, 1.0, -1.0];
+        let table = serialise_range_table(&values);
+        // They compare as equal, so they're tied and stay in document ID order.
+        assert_eq!(&table[values.len() * 4..values.len() * 8], &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0][..]);
+        let sorted = parse_range_values(&table, values.len());
+        assert!(sorted[1] == 0.0 && sorted[2] == 0.0);
+    }
+
+    #[test]
+    fn single_document_is_its_own_range() {
+        let table = serialise_range_table(&[42.0]);
+        assert_eq!(&table[..8], &[0, 0, 0, 0, 0, 0, 0, 0][..]);
+        assert_eq!(parse_range_values(&table, 1), vec![42.0]);
+    }
+}
@@ -0,0 +1,45 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+
+// How results are ordered when a search doesn't use a sort key.
+#[derive(Clone, Copy)]
+pub enum Ranking {
+    // Matching documents in document ID order, using only the postings lists.
+    BitmapOnly,
+    // Matching documents by descending Okapi BM25 score of the query terms they contain, with ties in document ID order.
+    // `k1` controls how quickly the score of a term saturates, and `b` how much longer documents are penalised, from 0 (not at all) to 1 (fully).
+    Bm25 { k1: f64, b: f64 },
+}
+
+impl Ranking {
+    pub fn is_bm25(&self) -> bool {
+        matches!(self, Ranking::Bm25 { .. })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Ranking::BitmapOnly => Ok(()),
+            Ranking::Bm25 { k1, .. } if !(k1 >= 0.0 && k1.is_finite()) => Err(format!("BM25 k1 must be a non-negative number, but is {}", k1)),
+            Ranking::Bm25 { b, .. } if !(0.0..=1.0).contains(&b) => Err(format!("BM25 b must be between 0 and 1, but is {}", b)),
+            Ranking::Bm25 { .. } => Ok(()),
+        }
+    }
+}
+
+/**
+ * Serialise the table the runner uses to rank results by BM25, which has the length normalisation of each document in document ID order as a f32.
+ * Terms are unique within a document, so every term frequency is 1 and the BM25 weight of a term in a document is its IDF multiplied by
+ * `(k1 + 1) / (1 + k1 * (1 - b + b * length / average_length))`, where the length is the amount of terms in the document.
+ * This factor is the same for every term of a document, so it is computed here and the worker only needs the IDF of each query term.
+ * Keep in sync with index_query_t in wasm/index.c.
+ */
+pub fn serialise_bm25_table(document_lengths: &[usize], k1: f64, b: f64) -> Vec<u8> {
+    let total_length = document_lengths.iter().sum::<usize>();
+    // Every normalisation is the same if no document has terms, so any non-zero average works.
+    let average_length = (total_length as f64 / document_lengths.len() as f64).max(1.0);
+    let mut out = Vec::<u8>::with_capacity(document_lengths.len() * 4);
+    for length in document_lengths {
+        let normalisation = (k1 + 1.0) / (1.0 + k1 * (1.0 - b + b * *length as f64 / average_length));
+        out.write_f32::<LittleEndian>(normalisation as f32).expect("write BM25 table");
+    }
+    out
+}
@@ -0,0 +1,121 @@
+use crate::DocumentId;
+
+const BASE64URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) -> () {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    };
+    out.push(value as u8);
+}
+
+// Unpadded, as padding would need escaping in a query string.
+fn base64url(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 4).div_ceil(3));
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - i * 8));
+        for i in 0..group.len() + 1 {
+            out.push(BASE64URL_ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+        };
+    };
+    out
+}
+
+/**
+ * Encode internal document IDs as the `i` query parameter of a scoped search, which only matches documents with those IDs.
+ * IDs are sorted into ranges of consecutive IDs, and each range is an unsigned LEB128 varint of the gap from the end of the previous range (or from zero), followed by a varint of its length.
+ * The varints are then encoded as unpadded base64url, so that scopes of consecutive IDs stay short however many documents they have.
+ * Keep in sync with parseScope in script/src/main.ts and index_query_t in wasm/index.c.
+ */
+pub fn encode_scope(documents: &[DocumentId]) -> String {
+    let mut sorted = documents.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut out = Vec::new();
+    // Ranges can end after the last possible ID, so ends are u64.
+    let mut end = 0u64;
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i] as u64;
+        let mut len = 1;
+        while i + len < sorted.len() && sorted[i + len] as u64 == start + len as u64 {
+            len += 1;
+        };
+        write_varint(&mut out, start - end);
+        write_varint(&mut out, len as u64);
+        end = start + len as u64;
+        i += len;
+    };
+    base64url(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode a scope back into its document IDs the same way as parseScope in script/src/main.ts, without clamping to a document count.
+    fn decode_scope(scope: &str) -> Vec<u64> {
+        let mut bits = 0u32;
+        let mut bit_count = 0;
+        let mut bytes = Vec::new();
+        for c in scope.bytes() {
+            bits = bits << 6 | BASE64URL_ALPHABET.iter().position(|a| *a == c).unwrap() as u32;
+            bit_count += 6;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                bytes.push((bits >> bit_count) as u8);
+            };
+        };
+        let mut values = Vec::new();
+        let mut value = 0u64;
+        let mut shift = 0;
+        for byte in bytes {
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                values.push(value);
+                value = 0;
+                shift = 0;
+            };
+        };
+        assert_eq!(shift, 0, "scope {} ends within a varint", scope);
+        let mut documents = Vec::new();
+        let mut end = 0;
+        for range in values.chunks(2) {
+            let start = end + range[0];
+            end = start + range[1];
+            assert!(range[1] > 0, "scope {} has an empty range", scope);
+            documents.extend(start..end);
+        };
+        documents
+    }
+
+    #[test]
+    fn scopes_decode_to_their_sorted_unique_documents() {
+        let scopes: &[&[DocumentId]] = &[
+            &[],
+            &[0],
+            &[7, 3, 5, 4, 3],
+            &[0, 1, 2, 3, 100, 101, 1000],
+            // Gaps and lengths of more than one varint byte.
+            &[200, 50_000, 50_001, 1 << 21],
+            &[DocumentId::MAX - 1, DocumentId::MAX, 0],
+        ];
+        for documents in scopes {
+            let mut expected = documents.iter().map(|id| *id as u64).collect::<Vec<u64>>();
+            expected.sort_unstable();
+            expected.dedup();
+            assert_eq!(decode_scope(&encode_scope(documents)), expected, "scope of {:?}", documents);
+        };
+    }
+
+    #[test]
+    fn consecutive_documents_are_one_short_range() {
+        // A gap of 10 and a length of 9990, which is two varint bytes.
+        let consecutive = (10..10_000).collect::<Vec<DocumentId>>();
+        assert_eq!(encode_scope(&consecutive), "CoZO");
+        assert_eq!(decode_scope("CoZO"), (10..10_000).collect::<Vec<u64>>());
+        assert_eq!(encode_scope(&[]), "");
+    }
+}
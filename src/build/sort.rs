@@ -0,0 +1,74 @@
+use std::fs::File;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::DocumentId;
+
+// A file containing a numeric value for each document, which results can be ordered by instead of document ID.
+pub struct SortKey {
+    pub name: String,
+    pub values_source: File,
+}
+
+/**
+ * Serialise the table the runner uses to order results by a sort key.
+ * Documents are ranked by ascending value, with ties broken by ascending document ID.
+ * The table contains the rank of each document in document ID order, followed by the document ID of each rank in rank order.
+ * Keep in sync with index_query_t in wasm/index.c.
+ */
+pub fn serialise_sort_table(values: &[f64]) -> Vec<u8> {
+    let mut order = (0..values.len()).collect::<Vec<usize>>();
+    // Values are never NaN, and the sort is stable so ties stay in document ID order.
+    order.sort_by(|a, b| values[*a].partial_cmp(&values[*b]).unwrap());
+    let mut ranks = vec![0; values.len()];
+    for (rank, document_id) in order.iter().enumerate() {
+        ranks[*document_id] = rank;
+    };
+
+    let mut out = Vec::<u8>::with_capacity(values.len() * 8);
+    for id in ranks.iter().chain(order.iter()) {
+        // The build checks that every document ID fits before packing sort keys.
+        out.write_u32::<LittleEndian>(*id as DocumentId).expect("write sort table");
+    };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Split a serialised sort table back into the rank of each document and the document of each rank.
+    fn parse_sort_table(table: &[u8]) -> (Vec<u32>, Vec<u32>) {
+        let ids = table.chunks(4).map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]])).collect::<Vec<u32>>();
+        let (ranks, order) = ids.split_at(ids.len() / 2);
+        (ranks.to_vec(), order.to_vec())
+    }
+
+    #[test]
+    fn documents_are_ranked_by_ascending_value() {
+        let (ranks, order) = parse_sort_table(&serialise_sort_table(&[3.5, -1.0, 10.0, 0.0]));
+        assert_eq!(order, vec![1, 3, 0, 2]);
+        assert_eq!(ranks, vec![2, 0, 3, 1]);
+    }
+
+    #[test]
+    fn descending_order_reads_the_ranks_from_the_end() {
+        let values = [3.5, -1.0, 10.0, 0.0];
+        let (_, order) = parse_sort_table(&serialise_sort_table(&values));
+        // The runner gets descending results by reading the document of each rank counting from the last.
+        let descending = order.iter().rev().map(|document_id| values[*document_id as usize]).collect::<Vec<f64>>();
+        assert_eq!(descending, vec![10.0, 3.5, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn ties_are_ranked_by_document_id() {
+        let (ranks, order) = parse_sort_table(&serialise_sort_table(&[2.0, 1.0, 2.0, 1.0, 2.0]));
+        assert_eq!(order, vec![1, 3, 0, 2, 4]);
+        assert_eq!(ranks, vec![2, 0, 3, 1, 4]);
+    }
+
+    #[test]
+    fn no_documents_is_an_empty_table() {
+        assert!(serialise_sort_table(&[]).is_empty());
+    }
+}
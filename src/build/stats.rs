@@ -0,0 +1,182 @@
+use std::fs::write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::build::error::{BuildError, IoContext};
+use crate::build::js::js_string;
+
+// A step of a build, reported to `BuildConfig::on_progress` as it happens, such as to show progress in a GUI.
+pub enum BuildProgress {
+    // A phase started, named as in `BuildStats::phase_timings`.
+    PhaseStarted { phase: &'static str },
+    PhaseCompleted { phase: &'static str, duration: Duration },
+}
+
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub duration: Duration,
+}
+
+// How full the chunks of a dataset packed as BST chunks are.
+pub struct ChunkStats {
+    pub dataset: &'static str,
+    pub chunk_count: usize,
+    // Amount of chunks merged into their neighbours after packing.
+    pub merged_chunks: usize,
+    // Average size of chunks relative to the maximum chunk size.
+    pub fill_ratio: f64,
+    pub max_entries_per_chunk: usize,
+    pub average_entry_size: f64,
+}
+
+// Distribution of the amount of documents each term is in, excluding terms in no documents.
+pub struct TermFrequencySummary {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: usize,
+    // Amount of terms in 1-9 documents, then 10-99 documents, and so on.
+    pub histogram: Vec<usize>,
+}
+
+impl TermFrequencySummary {
+    pub(crate) fn new(mut frequencies: Vec<usize>) -> Option<TermFrequencySummary> {
+        frequencies.retain(|f| *f > 0);
+        if frequencies.is_empty() {
+            return None;
+        };
+        frequencies.sort_unstable();
+        let mut histogram = Vec::<usize>::new();
+        for frequency in frequencies.iter() {
+            let bucket = (*frequency as f64).log10().floor() as usize;
+            if histogram.len() <= bucket {
+                histogram.resize(bucket + 1, 0);
+            };
+            histogram[bucket] += 1;
+        };
+        Some(TermFrequencySummary {
+            min: frequencies[0],
+            max: frequencies[frequencies.len() - 1],
+            mean: frequencies.iter().sum::<usize>() as f64 / frequencies.len() as f64,
+            median: frequencies[frequencies.len() / 2],
+            histogram,
+        })
+    }
+}
+
+// Statistics about a completed build.
+pub struct BuildStats {
+    pub document_count: usize,
+    pub term_count: usize,
+    // Amount of terms in more documents than the maximum postings cardinality, whose postings lists were sampled.
+    pub approximate_term_count: usize,
+    // How long each phase that ran took, in the order they ran. Phases resumed from a checkpoint are omitted.
+    pub phase_timings: Vec<PhaseTiming>,
+    // Utilisation of each dataset that was packed, in the order they were packed. Datasets resumed from a checkpoint are omitted.
+    pub chunks: Vec<ChunkStats>,
+    // None if terms were resumed from a checkpoint or no term is in any document.
+    pub term_frequency: Option<TermFrequencySummary>,
+    on_progress: Option<Box<dyn FnMut(BuildProgress) + Send>>,
+}
+
+impl BuildStats {
+    pub(crate) fn new(on_progress: Option<Box<dyn FnMut(BuildProgress) + Send>>) -> BuildStats {
+        BuildStats {
+            document_count: 0,
+            term_count: 0,
+            approximate_term_count: 0,
+            phase_timings: Vec::new(),
+            chunks: Vec::new(),
+            term_frequency: None,
+            on_progress,
+        }
+    }
+
+    // Returns when the phase started, to pass to `record_phase` once it completes.
+    pub(crate) fn start_phase(&mut self, phase: &'static str) -> Instant {
+        if let Some(on_progress) = &mut self.on_progress {
+            on_progress(BuildProgress::PhaseStarted { phase });
+        };
+        Instant::now()
+    }
+
+    pub(crate) fn record_phase(&mut self, phase: &'static str, started: Instant) -> () {
+        let duration = started.elapsed();
+        info!("{} took {:.2}s", phase, duration.as_secs_f64());
+        if let Some(on_progress) = &mut self.on_progress {
+            on_progress(BuildProgress::PhaseCompleted { phase, duration });
+        };
+        self.phase_timings.push(PhaseTiming { phase, duration });
+    }
+
+    // Log a table of how long each phase took.
+    pub(crate) fn log_phase_timings(&self) -> () {
+        let total: Duration = self.phase_timings.iter().map(|t| t.duration).sum();
+        let width = self.phase_timings.iter().map(|t| t.phase.len()).max().unwrap_or(0).max("Total".len());
+        for PhaseTiming { phase, duration } in self.phase_timings.iter() {
+            info!("{:<width$}  {:>9.2}s  {:>6.2}%", phase, duration.as_secs_f64(), duration.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON) * 100.0, width = width);
+        };
+        info!("{:<width$}  {:>9.2}s", "Total", total.as_secs_f64(), width = width);
+    }
+
+    /**
+     * Write these statistics and the resolved build config to a JSON file, so that builds can be compared over time.
+     * `config` contains the name and JSON serialised value of each config option.
+     */
+    pub(crate) fn write_json(&self, path: &PathBuf, config: &[(&str, String)]) -> Result<(), BuildError> {
+        let total: Duration = self.phase_timings.iter().map(|t| t.duration).sum();
+        let term_frequency = match &self.term_frequency {
+            None => "null".to_string(),
+            Some(TermFrequencySummary { min, max, mean, median, histogram }) => format!(
+                r#"{{"min": {}, "max": {}, "mean": {}, "median": {}, "histogram": [{}]}}"#,
+                min,
+                max,
+                mean,
+                median,
+                histogram.iter().enumerate()
+                    .map(|(bucket, terms)| format!(r#"{{"minDocuments": {}, "maxDocuments": {}, "terms": {}}}"#, 10usize.pow(bucket as u32), 10usize.pow(bucket as u32 + 1) - 1, terms))
+                    .collect::<Vec<String>>().join(", "),
+            ),
+        };
+        let json = format!(r#"{{
+  "documents": {DOCUMENTS},
+  "terms": {TERMS},
+  "approximateTerms": {APPROXIMATE_TERMS},
+  "termFrequency": {TERM_FREQUENCY},
+  "chunks": [{CHUNKS}
+  ],
+  "phases": [{PHASES}
+  ],
+  "totalSeconds": {TOTAL_SECONDS},
+  "config": {{{CONFIG}
+  }}
+}}
+"#,
+            DOCUMENTS = self.document_count,
+            TERMS = self.term_count,
+            APPROXIMATE_TERMS = self.approximate_term_count,
+            TERM_FREQUENCY = term_frequency,
+            CHUNKS = self.chunks.iter().map(|c| format!(
+                r#"
+    {{"dataset": {}, "chunks": {}, "mergedChunks": {}, "fillRatio": {}, "maxEntriesPerChunk": {}, "averageEntrySize": {}}}"#,
+                js_string(c.dataset),
+                c.chunk_count,
+                c.merged_chunks,
+                c.fill_ratio,
+                c.max_entries_per_chunk,
+                c.average_entry_size,
+            )).collect::<Vec<String>>().join(","),
+            PHASES = self.phase_timings.iter().map(|t| format!(
+                r#"
+    {{"phase": {}, "seconds": {}}}"#,
+                js_string(t.phase),
+                t.duration.as_secs_f64(),
+            )).collect::<Vec<String>>().join(","),
+            TOTAL_SECONDS = total.as_secs_f64(),
+            CONFIG = config.iter().map(|(name, value)| format!("\n    {}: {}", js_string(name), value)).collect::<Vec<String>>().join(","),
+        );
+        write(path, json).context("write stats JSON")
+    }
+}
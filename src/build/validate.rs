@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::str::from_utf8;
+
+use log::{info, warn};
+
+use crate::build::error::{BuildError, IoContext};
+use crate::build::range::RangeField;
+use crate::build::sort::SortKey;
+use crate::data::corpus::CorpusSource;
+use crate::util::format::number;
+
+// Input files to check, which have the same meanings as the fields of `BuildConfig` with the same names.
+pub struct ValidateConfig {
+    pub corpus: CorpusSource,
+    pub document_filters: Option<File>,
+    pub document_ids: Option<File>,
+    pub group_keys: Option<File>,
+    pub range_fields: Vec<RangeField>,
+    pub sort_keys: Vec<SortKey>,
+    pub store_documents: bool,
+    pub term_blocks: Option<File>,
+}
+
+// A record of an input file, which ends with '\0' unless it is the last record of the file and its terminator is missing.
+struct Record {
+    // Position in the file of the start of the record.
+    offset: u64,
+    data: Vec<u8>,
+    terminated: bool,
+}
+
+struct RecordReader {
+    reader: BufReader<File>,
+    offset: u64,
+}
+
+impl RecordReader {
+    // Read records from `offset` in `input` to the end.
+    fn new(mut input: File, offset: u64) -> Result<RecordReader, BuildError> {
+        input.seek(SeekFrom::Start(offset)).context("seek input file")?;
+        Ok(RecordReader {
+            reader: BufReader::new(input),
+            offset,
+        })
+    }
+}
+
+impl Iterator for RecordReader {
+    type Item = Result<Record, BuildError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = Vec::<u8>::new();
+        let bytes_read = match self.reader.read_until(b'\0', &mut data).context("read input file") {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => return Some(Err(err)),
+        };
+        if bytes_read == 0 {
+            return None;
+        };
+        let offset = self.offset;
+        self.offset += bytes_read as u64;
+        let terminated = data.last() == Some(&b'\0');
+        if terminated {
+            data.pop();
+        };
+        Some(Ok(Record { offset, data, terminated }))
+    }
+}
+
+// Logs each violation as it is found, so that problems in large files are visible before checking finishes.
+struct Report {
+    violations: usize,
+}
+
+impl Report {
+    fn violation(&mut self, file: &str, document_id: usize, offset: u64, problem: impl Display) -> () {
+        self.violations += 1;
+        warn!("{} at byte {} (document {}): {}", file, offset, document_id, problem);
+    }
+
+    fn missing_terminator(&mut self, file: &str, document_id: usize, record: &Record) -> () {
+        if !record.terminated {
+            self.violation(file, document_id, record.offset, "not terminated by '\\0' before the end of the file");
+        };
+    }
+
+    fn document_count(&mut self, file: &str, count: usize, document_count: Option<usize>) -> () {
+        if let Some(document_count) = document_count.filter(|c| *c != count) {
+            self.violations += 1;
+            warn!("{} has {} values but there are {} documents", file, number(count), number(document_count));
+        };
+    }
+}
+
+// Check documents, up to `limit` of them if provided, and return how many there are.
+fn validate_documents(report: &mut Report, file: &str, records: RecordReader, limit: Option<usize>) -> Result<usize, BuildError> {
+    let mut document_count = 0;
+    for record in records.take(limit.unwrap_or(usize::MAX)) {
+        let record = record?;
+        report.missing_terminator(file, document_count, &record);
+        if record.data.is_empty() {
+            report.violation(file, document_count, record.offset, "document is empty");
+        } else if from_utf8(&record.data).is_err() {
+            report.violation(file, document_count, record.offset, "document is not valid UTF-8");
+        };
+        document_count += 1;
+    };
+    Ok(document_count)
+}
+
+/**
+ * Check a file with the structure of the document terms file, where each entry and then each document ends with '\0', and return how many documents there are.
+ * `check_entry` returns a problem with an entry that is valid UTF-8, if any.
+ */
+fn validate_document_entries<F: Fn(&str) -> Option<String>>(report: &mut Report, file: &str, records: RecordReader, unique: bool, check_entry: F) -> Result<usize, BuildError> {
+    let mut document_id = 0;
+    let mut document_offset = None;
+    let mut seen = HashMap::<Vec<u8>, u64>::new();
+    for record in records {
+        let record = record?;
+        if record.data.is_empty() {
+            // End of document.
+            document_id += 1;
+            document_offset = None;
+            seen.clear();
+            continue;
+        };
+        document_offset.get_or_insert(record.offset);
+        report.missing_terminator(file, document_id, &record);
+        match from_utf8(&record.data) {
+            Err(_) => report.violation(file, document_id, record.offset, "entry is not valid UTF-8"),
+            Ok(entry) => if let Some(problem) = check_entry(entry) {
+                report.violation(file, document_id, record.offset, problem);
+            },
+        };
+        if unique {
+            if let Some(first_offset) = seen.get(&record.data) {
+                report.violation(file, document_id, record.offset, format!("{:?} is repeated from byte {}", String::from_utf8_lossy(&record.data), first_offset));
+            } else {
+                seen.insert(record.data, record.offset);
+            };
+        };
+    };
+    if let Some(offset) = document_offset {
+        report.violation(file, document_id, offset, "document is not terminated by an extra '\\0' before the end of the file");
+        document_id += 1;
+    };
+    Ok(document_id)
+}
+
+// Check a file with a value for each document, and return how many values there are.
+fn validate_document_values<F: FnMut(&str) -> Option<String>>(report: &mut Report, file: &str, records: RecordReader, mut check_value: F) -> Result<usize, BuildError> {
+    let mut document_id = 0;
+    for record in records {
+        let record = record?;
+        report.missing_terminator(file, document_id, &record);
+        match from_utf8(&record.data) {
+            Err(_) => report.violation(file, document_id, record.offset, "value is not valid UTF-8"),
+            Ok(value) => if let Some(problem) = check_value(value) {
+                report.violation(file, document_id, record.offset, problem);
+            },
+        };
+        document_id += 1;
+    };
+    Ok(document_id)
+}
+
+fn validate_numeric_values(report: &mut Report, file: &str, input: File, document_count: Option<usize>) -> Result<(), BuildError> {
+    let count = validate_document_values(report, file, RecordReader::new(input, 0)?, |value| match value.trim().parse::<f64>() {
+        Ok(value) if !value.is_nan() => None,
+        _ => Some(format!("{:?} is not a number", value)),
+    })?;
+    report.document_count(file, count, document_count);
+    Ok(())
+}
+
+/**
+ * Check every input file against the invariants the build relies on, without building anything, and return the amount of problems found.
+ * Each problem is logged with the file, byte offset, and document it is in, and checking continues so that every problem is reported in one pass.
+ * Checks are always strict, so records missing their '\0' terminator and terms that aren't valid UTF-8 are reported even if the build would accept them.
+ * Failing to read a file is returned as an error rather than counted as a problem.
+ */
+pub fn validate_inputs(ValidateConfig {
+    corpus,
+    document_filters,
+    document_ids,
+    group_keys,
+    range_fields,
+    sort_keys,
+    store_documents,
+    term_blocks,
+}: ValidateConfig) -> Result<usize, BuildError> {
+    let mut report = Report { violations: 0 };
+
+    // A combined corpus records its amount of documents, so documents at the end without terms can be left out of its document terms.
+    let combined = matches!(corpus, CorpusSource::Combined { .. });
+    // None if documents aren't stored and so aren't read.
+    let (document_count, document_terms) = match corpus {
+        CorpusSource::Files { documents, document_terms } => (
+            if store_documents { Some(validate_documents(&mut report, "Documents", RecordReader::new(documents, 0)?, None)?) } else { None },
+            Some(RecordReader::new(document_terms, 0)?),
+        ),
+        CorpusSource::Combined { file, document_count, document_terms_offset } => {
+            let documents = file.try_clone().context("open combined corpus file")?;
+            validate_documents(&mut report, "Corpus documents", RecordReader::new(documents, 0)?, Some(document_count))?;
+            (Some(document_count), Some(RecordReader::new(file, document_terms_offset)?))
+        }
+        // Documents added in memory are checked as they're added.
+        CorpusSource::Memory(builder) => (Some(builder.documents.len()), None),
+    };
+    if let Some(document_terms) = document_terms {
+        let count = validate_document_entries(&mut report, "Document terms", document_terms, true, |_| None)?;
+        if let Some(document_count) = document_count.filter(|c| count > *c || (count < *c && !combined)) {
+            report.violations += 1;
+            warn!("Document terms has {} documents but there are {} documents", number(count), number(document_count));
+        };
+    };
+    if let Some(document_filters) = document_filters {
+        validate_document_entries(&mut report, "Document filters", RecordReader::new(document_filters, 0)?, false, |_| None)?;
+    };
+    if let Some(term_blocks) = term_blocks {
+        validate_document_entries(&mut report, "Term blocks", RecordReader::new(term_blocks, 0)?, false, |entry| match entry.split_once(':') {
+            Some((block_id, term)) if block_id.parse::<u32>().is_ok() && !term.is_empty() => None,
+            _ => Some(format!("{:?} is not a block ID and term separated by ':'", entry)),
+        })?;
+    };
+    if let Some(document_ids) = document_ids {
+        let mut seen = HashSet::<String>::new();
+        let count = validate_document_values(&mut report, "Document IDs", RecordReader::new(document_ids, 0)?, |id| if seen.insert(id.to_string()) {
+            None
+        } else {
+            Some(format!("{:?} is a duplicate ID", id))
+        })?;
+        report.document_count("Document IDs", count, document_count);
+    };
+    if let Some(group_keys) = group_keys {
+        let count = validate_document_values(&mut report, "Group keys", RecordReader::new(group_keys, 0)?, |_| None)?;
+        report.document_count("Group keys", count, document_count);
+    };
+    for SortKey { name, values_source } in sort_keys {
+        validate_numeric_values(&mut report, &format!("Sort key {}", name), values_source, document_count)?;
+    };
+    for RangeField { name, values_source } in range_fields {
+        validate_numeric_values(&mut report, &format!("Range field {}", name), values_source, document_count)?;
+    };
+
+    match report.violations {
+        0 => info!("Found no problems with the input files"),
+        violations => warn!("Found {} problems with the input files", number(violations)),
+    };
+    Ok(report.violations)
+}
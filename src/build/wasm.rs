@@ -1,12 +1,35 @@
-use std::fs::File;
+use std::fmt;
+use std::fs::{File, metadata, read, read_to_string};
 use std::io::Write;
+use std::env::current_dir;
 use std::path::PathBuf;
 use std::process::Command;
 
+use clap::arg_enum;
+use log::{info, warn};
+
+use crate::build::error::{BuildError, IoContext};
+use crate::util::format::{number, percent};
+
 const RUNNER_C_CHUNKS: &'static str = include_str!("../../wasm/chunks.c");
 const RUNNER_C_INDEX: &'static str = include_str!("../../wasm/index.c");
 const RUNNER_C_ROARING: &'static str = include_str!("../../wasm/roaring.c");
 const RUNNER_C_SYS: &'static str = include_str!("../../wasm/sys.c");
+const RUNNER_C_ZSTD: &'static str = include_str!("../../wasm/zstd.c");
+
+arg_enum! {
+    // Algorithm the runner uses to intersect required terms and the terms of each group. All return identical results.
+    // Keep in sync with INTERSECTION_STRATEGY in wasm/index.c.
+    #[derive(Clone, Copy)]
+    pub enum IntersectionStrategy {
+        // Roaring AND starting from the term with the fewest documents, stopping once the intersection is empty.
+        SmallestFirst,
+        // Roaring AND in query order.
+        RoaringAnd,
+        // Check each document of the term with the fewest documents against the other terms. Can be faster when postings lists are small.
+        Probe,
+    }
+}
 
 #[allow(dead_code)]
 pub enum WasmStandard {
@@ -31,30 +54,70 @@ pub enum WasmCompileWarning {
     UnusedFunction,
 }
 
-pub struct WasmCompileArgs<'iw, 'm, 'i, 'o> {
+// Directory clang runs in by default, which contains the stubs directory that the relative `-isystemstubs` include refers to.
+const DEFAULT_COMPILE_DIR: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/wasm");
+
+// Command run to compile the runner if no compiler is configured, which is looked up in PATH.
+const DEFAULT_COMPILER: &str = "clang";
+
+// Resolve a path against the current directory, so that it still refers to the same file once clang runs in another directory.
+fn absolute(path: &PathBuf) -> PathBuf {
+    if path.is_absolute() {
+        path.clone()
+    } else {
+        current_dir().expect("get current directory").join(path)
+    }
+}
+
+pub struct WasmCompileArgs<'cd, 'id, 'sr, 'iw, 'm, 'rf, 'i, 'o> {
+    // clang to run, either a command looked up in PATH such as `clang-15`, or a path such as `/opt/wasi-sdk/bin/clang`.
+    compiler: PathBuf,
+    // Directory to run clang in, or None for the directory containing the stubs. All other paths are resolved before changing to it.
+    compile_dir: Option<&'cd PathBuf>,
     standard: WasmStandard,
     optimisation_level: WasmOptimisationLevel,
     all_warnings: bool,
     extra_warnings: bool,
     warnings_as_errors: bool,
+    // Allow WASM SIMD instructions. The runner then only loads on runtimes that support SIMD.
+    simd: bool,
+    // Extra system include directories, searched after the bundled stubs.
+    include_dirs: &'id [PathBuf],
+    sysroot: Option<&'sr PathBuf>,
+    // Have the linker write a map of every output symbol to this file.
+    link_map: Option<&'o PathBuf>,
     ignore_warnings: &'iw [WasmCompileWarning],
     macros: &'m [(&'m str, &'m str)],
+    // Unvalidated flags passed to clang verbatim, after the structured flags but before the target and linker options required by the runner.
+    raw_flags: &'rf [String],
     input: &'i PathBuf,
     output: &'o PathBuf,
 }
 
+// Returns the diagnostics clang printed, such as warnings, which are empty if there were none.
+// If compilation fails, they are included in the error, as they are the only way to know what went wrong.
 pub fn compile_to_wasm(WasmCompileArgs {
+    compiler,
+    compile_dir,
     standard,
     optimisation_level,
     all_warnings,
     extra_warnings,
     warnings_as_errors,
+    simd,
+    include_dirs,
+    sysroot,
+    link_map,
     ignore_warnings,
     macros,
+    raw_flags,
     input,
     output,
-}: WasmCompileArgs) -> () {
-    let mut cmd = Command::new("clang");
+}: WasmCompileArgs) -> Result<String, BuildError> {
+    // A bare command name is looked up in PATH, but a path is resolved before changing directory, like other paths.
+    let compiler = if compiler.components().count() > 1 { absolute(&compiler) } else { compiler };
+    let mut cmd = Command::new(&compiler);
+    cmd.current_dir(compile_dir.cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_COMPILE_DIR)));
     cmd.arg(format!("-std={}", match standard {
         WasmStandard::C89 => "c89",
         WasmStandard::C99 => "c99",
@@ -75,11 +138,14 @@ pub fn compile_to_wasm(WasmCompileArgs {
     if all_warnings { cmd.arg("-Wall"); };
     if extra_warnings { cmd.arg("-Wextra"); };
     if warnings_as_errors { cmd.arg("-Werror"); };
+    if simd { cmd.arg("-msimd128"); };
     for warning in ignore_warnings {
         cmd.arg(format!("-Wno-{}", match warning {
             WasmCompileWarning::UnusedFunction => "unused-function",
         }));
     };
+    // Later flags take precedence, so the required options below can't be overridden.
+    cmd.args(raw_flags);
     cmd.arg("--target=wasm32-unknown-unknown-wasm")
         .arg("-nostdlib")
         .arg("-nostdinc")
@@ -93,53 +159,380 @@ pub fn compile_to_wasm(WasmCompileArgs {
         .arg("-Wl,--no-entry")
         .arg("-Wl,--strip-all")
     ;
+    for dir in include_dirs {
+        cmd.arg("-isystem").arg(absolute(dir));
+    };
+    if let Some(sysroot) = sysroot {
+        let sysroot = absolute(sysroot);
+        cmd.arg(format!("--sysroot={}", sysroot.to_str().ok_or_else(|| BuildError::InvalidConfig(format!("sysroot path {} is not valid UTF-8", sysroot.display())))?));
+    };
+    if let Some(link_map) = link_map {
+        let link_map = absolute(link_map);
+        cmd.arg(format!("-Wl,-Map={}", link_map.to_str().ok_or_else(|| BuildError::InvalidConfig(format!("link map path {} is not valid UTF-8", link_map.display())))?));
+    };
     for (name, code) in macros.iter() {
         cmd.arg(format!("-D{}={}", name, code));
     };
-    cmd.arg(input);
-    cmd.arg("-o").arg(output);
+    cmd.arg(absolute(input));
+    cmd.arg("-o").arg(absolute(output));
+
+    let result = cmd.output().map_err(|err| BuildError::Command(format!("Failed to run compiler {}: {}", compiler.display(), err)))?;
+    // clang writes diagnostics to stderr, but include anything on stdout too so that nothing is lost.
+    let diagnostics = format!("{}{}", String::from_utf8_lossy(&result.stdout), String::from_utf8_lossy(&result.stderr)).trim_end().to_string();
+    if !result.status.success() {
+        return Err(BuildError::WasmCompilation(diagnostics));
+    };
+    Ok(diagnostics)
+}
+
+/**
+ * Optimise a compiled WASM module in place with Binaryen's `wasm-opt` at `-O3`, which shrinks it beyond what clang does and so lowers cold starts.
+ * wasm-opt detects the features the module uses, except SIMD, which must be enabled if the module was compiled with it.
+ */
+fn optimise_wasm(wasm_opt: &PathBuf, wasm_path: &PathBuf, simd: bool) -> Result<(), BuildError> {
+    let size_before = metadata(wasm_path).context("get size of runner.wasm")?.len();
+    let mut cmd = Command::new(wasm_opt);
+    cmd.arg("-O3");
+    if simd { cmd.arg("--enable-simd"); };
+    cmd.arg(wasm_path).arg("-o").arg(wasm_path);
+    let result = cmd.output().map_err(|err| BuildError::Command(format!("Failed to run wasm-opt {}: {}", wasm_opt.display(), err)))?;
+    if !result.status.success() {
+        return Err(BuildError::Command(format!(
+            "wasm-opt exited with {}:\n{}{}",
+            result.status,
+            String::from_utf8_lossy(&result.stdout),
+            String::from_utf8_lossy(&result.stderr),
+        ).trim_end().to_string()));
+    };
+    let size_after = metadata(wasm_path).context("get size of runner.wasm")?.len();
+    info!("wasm-opt changed runner.wasm from {} to {} bytes", number(size_before), number(size_after));
+    Ok(())
+}
+
+// results_t->count in wasm/index.c is a uint8_t.
+const MAX_RESULTS_LIMIT: usize = u8::MAX as usize;
+// wasm/index.c keeps arrays with an element per query term on the stack, which must fit in the default 64 KiB WASM stack.
+const MAX_QUERY_TERMS_LIMIT: usize = 4096;
+
+// WASM memory is allocated in pages of 64 KiB.
+pub const WASM_PAGE_BYTES: usize = 65536;
+// wasm32 can address at most 4 GiB.
+const MAX_RUNNER_MEMORY_BYTES: usize = 65536 * WASM_PAGE_BYTES;
+// The runner's stack is at the start of its memory, before its static data and heap.
+const RUNNER_STACK_BYTES: usize = 65536;
+// A zstd decompression context, rounded up from what ZSTD_estimateDCtxSize() reports.
+const ZSTD_DCTX_BYTES: usize = 256 * 1024;
+
+// A value compiled into the runner as a macro, or given to the worker for the runner, that the runner can't handle.
+#[derive(Debug)]
+pub enum RunnerLimitError {
+    MaxResults(usize),
+    MaxQueryTerms(usize),
+    MaxQueryBytes(usize),
+}
+
+impl fmt::Display for RunnerLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunnerLimitError::MaxResults(value) => write!(f, "maximum query results must be between 1 and {}, but is {}", MAX_RESULTS_LIMIT, value),
+            RunnerLimitError::MaxQueryTerms(value) => write!(f, "maximum query terms, including expanded terms, must be between 1 and {}, but is {}", MAX_QUERY_TERMS_LIMIT, value),
+            RunnerLimitError::MaxQueryBytes(value) => write!(f, "maximum query bytes must be more than {} and at most {}, but is {}", RUNNER_STACK_BYTES, MAX_RUNNER_MEMORY_BYTES, value),
+        }
+    }
+}
+
+/**
+ * Check the values passed to clang as MAX_RESULTS and MAX_QUERY_TERMS, as the runner would otherwise be compiled without error but break at runtime,
+ * such as by zero-length arrays or truncated result counts.
+ */
+pub fn validate_runner_limits(max_results: usize, max_query_terms: usize) -> Result<(), RunnerLimitError> {
+    if !(1..=MAX_RESULTS_LIMIT).contains(&max_results) {
+        return Err(RunnerLimitError::MaxResults(max_results));
+    };
+    if !(1..=MAX_QUERY_TERMS_LIMIT).contains(&max_query_terms) {
+        return Err(RunnerLimitError::MaxQueryTerms(max_query_terms));
+    };
+    Ok(())
+}
+
+// Check the size of the memory the worker gives the runner, which is rounded up to whole pages.
+pub fn validate_runner_memory(max_query_bytes: usize) -> Result<(), RunnerLimitError> {
+    if max_query_bytes <= RUNNER_STACK_BYTES || max_query_bytes > MAX_RUNNER_MEMORY_BYTES {
+        return Err(RunnerLimitError::MaxQueryBytes(max_query_bytes));
+    };
+    Ok(())
+}
+
+// The features and limits of a worker that determine how much runner memory a query can use.
+pub struct QueryMemoryLimits {
+    pub bm25: bool,
+    pub document_count: usize,
+    // Size of the zstd dictionary documents were compressed with, or 0 if the runner doesn't decompress documents.
+    pub document_dictionary_bytes: usize,
+    pub filters: bool,
+    pub groups: bool,
+    // Size of the largest document once decompressed.
+    pub max_document_bytes: usize,
+    pub max_prefix_expansions: usize,
+    // Maximum amount of terms the runner receives, after expansion.
+    pub max_query_terms: usize,
+    pub proximity: bool,
+    pub ranges: bool,
+    pub scoped_search: bool,
+    pub sorts: bool,
+}
+
+// The largest a postings list can be, serialised or deserialised, which is when every container of 65536 document IDs is an 8 KiB bitmap.
+fn max_postings_list_bytes(document_count: usize) -> usize {
+    8 + document_count.div_ceil(65536) * (8 + 8192)
+}
 
-    if !cmd.status().expect("compile WASM").success() {
-        panic!("Failed to compile WASM");
+/**
+ * Estimate the most runner memory a single query can use, for the worst case of every term, filter value, and prefix expansion being in every document.
+ * Each postings list is copied into the runner and then deserialised, and combining them creates a few more bitmaps of at most the same size.
+ * Block tables, range tables, and sort and group tables are copied in with up to 8 bytes per document each.
+ * Nothing is freed until the next query, so everything counts towards the total.
+ * With zstd document compression, the dictionary is also kept for the lifetime of the instance, and documents are then decompressed one at a time.
+ */
+pub fn estimate_query_bytes(limits: &QueryMemoryLimits) -> usize {
+    let postings_list = max_postings_list_bytes(limits.document_count);
+    let per_document_table = limits.document_count.saturating_mul(8);
+    let mut postings_lists = limits.max_query_terms + limits.max_prefix_expansions;
+    // Searches can have as many filter values and ranges as terms.
+    if limits.filters {
+        postings_lists += limits.max_query_terms;
+    };
+    // A scope is built into one bitmap.
+    if limits.scoped_search {
+        postings_lists += 1;
+    };
+    let mut tables = 0;
+    if limits.proximity {
+        tables += limits.max_query_terms;
+    };
+    if limits.ranges {
+        tables += limits.max_query_terms;
+    };
+    if limits.sorts {
+        tables += 1;
+    };
+    // Ranking by BM25 deserialises the postings list of each term again, and copies in the BM25 table.
+    // Every match is then scored and sorted, which needs about as much memory again.
+    if limits.bm25 {
+        postings_lists += limits.max_query_terms;
+        tables += 3;
     };
+    let groups = if limits.groups { limits.document_count.saturating_mul(4) } else { 0 };
+    // The dictionary is copied into the runner and then copied again to build the decompression dictionary.
+    // Only compressed documents are copied in, which are smaller than once decompressed.
+    let documents = if limits.document_dictionary_bytes == 0 {
+        0
+    } else {
+        ZSTD_DCTX_BYTES
+            .saturating_add(limits.document_dictionary_bytes.saturating_mul(2))
+            .saturating_add(limits.max_document_bytes.saturating_mul(2))
+    };
+    RUNNER_STACK_BYTES
+        .saturating_add(postings_list.saturating_mul(postings_lists * 2 + 4))
+        .saturating_add(per_document_table.saturating_mul(tables))
+        .saturating_add(groups)
+        .saturating_add(documents)
+}
+
+pub struct RunnerWasmArgs<'o> {
+    pub output_dir: &'o PathBuf,
+    pub max_results: usize,
+    // Maximum amount of terms the runner receives, after expansion.
+    pub max_query_terms: usize,
+    // Each dataset's chunk lookup as C initialisers, from `BstChunks::serialise`, and its amount of chunks.
+    pub terms_chunks_raw: &'o str,
+    pub terms_chunks_len: usize,
+    pub documents_chunks_raw: &'o str,
+    pub documents_chunks_len: usize,
+    pub blocks_chunks_raw: &'o str,
+    pub blocks_chunks_len: usize,
+    pub filters_chunks_raw: &'o str,
+    pub filters_chunks_len: usize,
+    // clang to compile with, or None for `clang` in PATH.
+    pub compiler: Option<&'o PathBuf>,
+    pub compile_dir: Option<&'o PathBuf>,
+    pub include_dirs: &'o [PathBuf],
+    pub intersection_strategy: IntersectionStrategy,
+    pub raw_clang_flags: &'o [String],
+    pub simd: bool,
+    pub sysroot: Option<&'o PathBuf>,
+    pub size_report: bool,
+    // Binaryen's wasm-opt, to optimise the runner with after compiling it, or None to leave it as compiled by clang.
+    pub wasm_opt: Option<&'o PathBuf>,
+    // Path to the zstd single-file decoder, if documents are compressed with zstd.
+    pub zstd_decoder: Option<&'o PathBuf>,
 }
 
-pub fn generate_and_compile_runner_wasm(
-    output_dir: &PathBuf,
-    max_results: usize,
-    max_query_terms: usize,
-    terms_chunks_raw: &str,
-    terms_chunks_len: usize,
-    documents_chunks_raw: &str,
-    documents_chunks_len: usize,
-) -> () {
+pub fn generate_and_compile_runner_wasm(RunnerWasmArgs {
+    output_dir,
+    max_results,
+    max_query_terms,
+    terms_chunks_raw,
+    terms_chunks_len,
+    documents_chunks_raw,
+    documents_chunks_len,
+    blocks_chunks_raw,
+    blocks_chunks_len,
+    filters_chunks_raw,
+    filters_chunks_len,
+    compiler,
+    compile_dir,
+    include_dirs,
+    intersection_strategy,
+    raw_clang_flags,
+    simd,
+    sysroot,
+    size_report,
+    wasm_opt,
+    zstd_decoder,
+}: RunnerWasmArgs) -> Result<(), BuildError> {
     let source_path = output_dir.join("runner.c");
     let output_path = output_dir.join("runner.wasm");
+    let link_map_path = output_dir.join("runner.map");
 
-    let mut source_file = File::create(&source_path).expect("open runner.c for writing");
-    source_file.write_all(RUNNER_C_SYS.as_bytes()).expect("write runner.c");
-    source_file.write_all(RUNNER_C_ROARING.as_bytes()).expect("write runner.c");
-    source_file.write_all(RUNNER_C_INDEX.as_bytes()).expect("write runner.c");
+    let mut source_file = File::create(&source_path).context("open runner.c for writing")?;
+    source_file.write_all(RUNNER_C_SYS.as_bytes()).context("write runner.c")?;
+    source_file.write_all(RUNNER_C_ROARING.as_bytes()).context("write runner.c")?;
+    source_file.write_all(RUNNER_C_INDEX.as_bytes()).context("write runner.c")?;
+    if let Some(zstd_decoder) = zstd_decoder {
+        source_file.write_all(read_to_string(zstd_decoder).context("read zstd decoder source")?.as_bytes()).context("write runner.c")?;
+        source_file.write_all(RUNNER_C_ZSTD.as_bytes()).context("write runner.c")?;
+    };
     source_file.write_all(RUNNER_C_CHUNKS
         .replace("___NORMAL_TERMS_CHUNKS___", terms_chunks_raw)
         .replace("___NORMAL_TERMS_CHUNKS_LEN___", format!("{}", terms_chunks_len).as_str())
         .replace("___DOCUMENTS_CHUNKS___", documents_chunks_raw)
         .replace("___DOCUMENTS_CHUNKS_LEN___", format!("{}", documents_chunks_len).as_str())
+        .replace("___BLOCKS_CHUNKS___", blocks_chunks_raw)
+        .replace("___BLOCKS_CHUNKS_LEN___", format!("{}", blocks_chunks_len).as_str())
+        .replace("___FILTERS_CHUNKS___", filters_chunks_raw)
+        .replace("___FILTERS_CHUNKS_LEN___", format!("{}", filters_chunks_len).as_str())
         .as_bytes()
-    ).expect("write runner.c");
+    ).context("write runner.c")?;
 
-    compile_to_wasm(WasmCompileArgs {
+    let diagnostics = compile_to_wasm(WasmCompileArgs {
+        compiler: compiler.cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_COMPILER)),
+        compile_dir,
         standard: WasmStandard::C11,
         optimisation_level: WasmOptimisationLevel::Level(3),
         all_warnings: true,
         extra_warnings: true,
         warnings_as_errors: false,
+        simd,
+        include_dirs,
+        sysroot,
+        link_map: if size_report { Some(&link_map_path) } else { None },
         ignore_warnings: &vec![WasmCompileWarning::UnusedFunction],
         macros: &[
             ("MAX_RESULTS", format!("{}", max_results).as_str()),
             ("MAX_QUERY_TERMS", format!("{}", max_query_terms).as_str()),
+            ("INTERSECTION_STRATEGY", match intersection_strategy {
+                IntersectionStrategy::SmallestFirst => "INTERSECTION_STRATEGY_SMALLEST_FIRST",
+                IntersectionStrategy::RoaringAnd => "INTERSECTION_STRATEGY_ROARING_AND",
+                IntersectionStrategy::Probe => "INTERSECTION_STRATEGY_PROBE",
+            }),
         ],
+        raw_flags: raw_clang_flags,
         input: &source_path,
         output: &output_path,
-    });
+    })?;
+    if !diagnostics.is_empty() {
+        warn!("Compiling the runner produced warnings:\n{}", diagnostics);
+    };
+    match wasm_opt {
+        Some(wasm_opt) => optimise_wasm(wasm_opt, &output_path, simd)?,
+        None => info!("Not optimising the runner with wasm-opt as it isn't configured"),
+    };
+
+    if size_report {
+        write_wasm_size_report(&output_path, &output_dir.join("runner.size.txt"))?;
+    };
+    Ok(())
+}
+
+fn read_leb128_u32(bytes: &[u8], pos: &mut usize) -> u32 {
+    let mut result = 0u32;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        };
+        shift += 7;
+    };
+    result
+}
+
+fn wasm_section_name(id: u8) -> &'static str {
+    match id {
+        0 => "custom",
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "datacount",
+        _ => "unknown",
+    }
+}
+
+// Number of largest function bodies to list in the size report.
+const SIZE_REPORT_TOP_FUNCTIONS: usize = 50;
+
+// Summarise the size of each section and the largest function bodies in a compiled WASM module.
+// Function names are stripped from the module, so they are listed by index; use runner.map to resolve them.
+pub fn write_wasm_size_report(wasm_path: &PathBuf, report_path: &PathBuf) -> Result<(), BuildError> {
+    let wasm = read(wasm_path).context("read compiled WASM")?;
+    if !wasm.starts_with(b"\0asm") {
+        return Err(BuildError::WasmCompilation("Compiled WASM has invalid magic bytes".to_string()));
+    };
+    let total = wasm.len();
+
+    let mut sections = Vec::<(&'static str, usize)>::new();
+    let mut function_sizes = Vec::<(usize, usize)>::new();
+    // Skip magic bytes and version.
+    let mut pos = 8;
+    while pos < total {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_leb128_u32(&wasm, &mut pos) as usize;
+        let contents_start = pos;
+        if id == 10 {
+            let mut code_pos = contents_start;
+            let count = read_leb128_u32(&wasm, &mut code_pos) as usize;
+            for function_idx in 0..count {
+                let body_size = read_leb128_u32(&wasm, &mut code_pos) as usize;
+                function_sizes.push((function_idx, body_size));
+                code_pos += body_size;
+            };
+        };
+        sections.push((wasm_section_name(id), size));
+        pos = contents_start + size;
+    };
+    function_sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut report = String::new();
+    report.push_str(&format!("Total: {} bytes\n\nSections:\n", number(total)));
+    for (name, size) in sections.iter() {
+        report.push_str(&format!("  {:<12}{:>14} bytes  {}\n", name, number(size), percent(*size as f64 / total as f64)));
+    };
+    report.push_str(&format!("\nLargest functions ({} total):\n", number(function_sizes.len())));
+    for (function_idx, size) in function_sizes.iter().take(SIZE_REPORT_TOP_FUNCTIONS) {
+        report.push_str(&format!("  #{:<10}{:>14} bytes  {}\n", function_idx, number(size), percent(*size as f64 / total as f64)));
+    };
+
+    File::create(report_path).context("create runner.size.txt")?.write_all(report.as_bytes()).context("write runner.size.txt")
 }
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{read_to_string, write};
+use std::path::PathBuf;
+
+use log::warn;
+
+use crate::{DocumentId, Term};
+use crate::build::{DataStore, EmptyResultBehaviour, ExpandedTermsOverflow, OnUnknownTerm, ReadTimeoutBehaviour};
+use crate::build::chunks::{ChunkLayout, verify_chunks};
+use crate::build::compress::DocumentCompression;
+use crate::build::error::{BuildError, check_config, IoContext};
+use crate::build::format::FORMAT_VERSION;
+use crate::build::js::{DeployTarget, generate_worker_js, QueryLogConfig, ResponseFormatConfig, WorkerJsArgs};
+use crate::build::openapi::write_openapi_json;
+use crate::build::precompress::Precompression;
+use crate::build::wasm::{estimate_query_bytes, QueryMemoryLimits, validate_runner_memory, WASM_PAGE_BYTES};
+use crate::data::normalise::{Analyser, AnalyserStage};
+use crate::util::format::number;
+
+/**
+ * Values the worker depends on that are fixed by the data and runner of a build, so they can't be changed without rebuilding.
+ * They are saved to `worker.data` in the output folder, so that the worker can be regenerated later using `generate_worker`.
+ * The file has a `{name} {value}` line for each value, and a line for each analyser stage, stopword, sort key, range field, and approximate term; optional values are omitted when absent.
+ */
+pub(crate) struct WorkerData {
+    // Terms whose postings lists were sampled, with the amount of documents each is actually in.
+    pub approximate_terms: Vec<(Term, usize)>,
+    pub blocks_chunk_count: usize,
+    pub chunk_layout: ChunkLayout,
+    pub document_count: usize,
+    // Size of the zstd dictionary documents were compressed with, or 0 if they weren't.
+    pub document_dictionary_bytes: usize,
+    pub document_id_chunk_starts: Option<Vec<DocumentId>>,
+    pub documents_stored: bool,
+    pub filters_chunk_count: usize,
+    // Whether the BM25 table was written, so that results can be ranked by BM25.
+    pub has_bm25: bool,
+    pub has_groups: bool,
+    // Fixed by the build, as it is part of the KV keys described in `format.json`.
+    pub kv_key_namespace: Option<String>,
+    // Size of the largest stored document before encoding, or 0 if documents aren't stored.
+    pub max_document_bytes: usize,
+    pub max_expanded_terms: Option<usize>,
+    pub max_query_terms: usize,
+    pub max_results: usize,
+    pub per_document_compression: DocumentCompression,
+    // Encodings that `runner.wasm` and every chunk have precompressed copies in.
+    pub precompression: Vec<Precompression>,
+    pub range_field_names: Vec<String>,
+    pub sort_key_names: Vec<String>,
+    pub term_analyser: Analyser,
+    // Length in bytes that long terms were truncated to, if they were.
+    pub term_truncation_bytes: Option<usize>,
+}
+
+impl WorkerData {
+    pub fn save(&self, output_dir: &PathBuf) -> Result<(), BuildError> {
+        let mut out = String::new();
+        writeln!(out, "version {}", FORMAT_VERSION).unwrap();
+        // The words of each stopwords stage follow in order, after the amount of them in the stage's line.
+        for stage in self.term_analyser.stages.iter() {
+            match stage {
+                AnalyserStage::StripStopwords(stopwords) => {
+                    writeln!(out, "analyserStage {} {}", stage, stopwords.len()).unwrap();
+                    for word in stopwords.iter() {
+                        if word.contains('\n') {
+                            return Err(BuildError::InvalidConfig(format!("stopword {:?} contains a line break", word)));
+                        };
+                        writeln!(out, "analyserStopword {}", word).unwrap();
+                    };
+                }
+                _ => writeln!(out, "analyserStage {}", stage).unwrap(),
+            };
+        };
+        for (term, document_count) in self.approximate_terms.iter() {
+            if term.contains('\n') {
+                return Err(BuildError::InvalidInput(format!("Approximate term {:?} contains a line break", term)));
+            };
+            writeln!(out, "approximateTerm {} {}", document_count, term).unwrap();
+        };
+        writeln!(out, "bm25 {}", self.has_bm25).unwrap();
+        writeln!(out, "blocksChunks {}", self.blocks_chunk_count).unwrap();
+        writeln!(out, "chunkLayout {}", self.chunk_layout).unwrap();
+        writeln!(out, "documentCount {}", self.document_count).unwrap();
+        writeln!(out, "documentDictionaryBytes {}", self.document_dictionary_bytes).unwrap();
+        if let Some(starts) = &self.document_id_chunk_starts {
+            writeln!(out, "documentIdChunks {}", starts.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(",")).unwrap();
+        };
+        writeln!(out, "documentsStored {}", self.documents_stored).unwrap();
+        writeln!(out, "filtersChunks {}", self.filters_chunk_count).unwrap();
+        writeln!(out, "groups {}", self.has_groups).unwrap();
+        if let Some(namespace) = &self.kv_key_namespace {
+            writeln!(out, "kvKeyNamespace {}", namespace).unwrap();
+        };
+        writeln!(out, "maxDocumentBytes {}", self.max_document_bytes).unwrap();
+        if let Some(max) = self.max_expanded_terms {
+            writeln!(out, "maxExpandedTerms {}", max).unwrap();
+        };
+        writeln!(out, "maxQueryTerms {}", self.max_query_terms).unwrap();
+        writeln!(out, "maxResults {}", self.max_results).unwrap();
+        writeln!(out, "perDocumentCompression {}", self.per_document_compression).unwrap();
+        for encoding in self.precompression.iter() {
+            writeln!(out, "precompressed {}", encoding).unwrap();
+        };
+        for name in self.range_field_names.iter() {
+            if name.contains('\n') {
+                return Err(BuildError::InvalidConfig(format!("range field name {:?} contains a line break", name)));
+            };
+            writeln!(out, "rangeField {}", name).unwrap();
+        };
+        for name in self.sort_key_names.iter() {
+            if name.contains('\n') {
+                return Err(BuildError::InvalidConfig(format!("sort key name {:?} contains a line break", name)));
+            };
+            writeln!(out, "sortKey {}", name).unwrap();
+        };
+        if let Some(bytes) = self.term_truncation_bytes {
+            writeln!(out, "termTruncationBytes {}", bytes).unwrap();
+        };
+        write(output_dir.join("worker.data"), out).context("write worker.data")
+    }
+
+    // Load the values saved by a build, and check that the chunks they refer to are still there.
+    pub fn load(output_dir: &PathBuf) -> Result<WorkerData, BuildError> {
+        let raw = read_to_string(output_dir.join("worker.data")).context("read worker.data; the output folder must be from a complete build")?;
+        let mut values = HashMap::<&str, Vec<&str>>::new();
+        for line in raw.lines() {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            values.entry(name).or_default().push(value);
+        };
+        let optional = |name: &str| values.get(name).map(|v| v[0]);
+        let required = |name: &str| optional(name).ok_or_else(|| BuildError::InvalidInput(format!("worker.data is missing {}", name)));
+        let invalid = |name: &str| BuildError::InvalidInput(format!("worker.data has an invalid {}", name));
+
+        let version = required("version")?.parse::<u32>().map_err(|_| invalid("version"))?;
+        if version != FORMAT_VERSION {
+            return Err(BuildError::InvalidInput(format!("Output folder has format version {} but this version of Edgesearch uses {}; rebuild it", version, FORMAT_VERSION)));
+        };
+        let data = WorkerData {
+            approximate_terms: values.get("approximateTerm").map_or(Ok(Vec::new()), |entries| entries.iter().map(|entry| {
+                let (document_count, term) = entry.split_once(' ').ok_or_else(|| invalid("approximateTerm"))?;
+                Ok((term.to_string(), document_count.parse().map_err(|_| invalid("approximateTerm"))?))
+            }).collect::<Result<_, BuildError>>())?,
+            blocks_chunk_count: required("blocksChunks")?.parse().map_err(|_| invalid("blocksChunks"))?,
+            chunk_layout: required("chunkLayout")?.parse().map_err(|_| invalid("chunkLayout"))?,
+            document_count: required("documentCount")?.parse().map_err(|_| invalid("documentCount"))?,
+            document_dictionary_bytes: required("documentDictionaryBytes")?.parse().map_err(|_| invalid("documentDictionaryBytes"))?,
+            document_id_chunk_starts: optional("documentIdChunks").map(|raw| raw.split(',').map(|s| s.parse().map_err(|_| invalid("documentIdChunks"))).collect::<Result<_, _>>()).transpose()?,
+            documents_stored: required("documentsStored")?.parse().map_err(|_| invalid("documentsStored"))?,
+            filters_chunk_count: required("filtersChunks")?.parse().map_err(|_| invalid("filtersChunks"))?,
+            has_bm25: required("bm25")?.parse().map_err(|_| invalid("bm25"))?,
+            has_groups: required("groups")?.parse().map_err(|_| invalid("groups"))?,
+            kv_key_namespace: optional("kvKeyNamespace").map(|ns| ns.to_string()),
+            max_document_bytes: required("maxDocumentBytes")?.parse().map_err(|_| invalid("maxDocumentBytes"))?,
+            max_expanded_terms: optional("maxExpandedTerms").map(|raw| raw.parse().map_err(|_| invalid("maxExpandedTerms"))).transpose()?,
+            max_query_terms: required("maxQueryTerms")?.parse().map_err(|_| invalid("maxQueryTerms"))?,
+            max_results: required("maxResults")?.parse().map_err(|_| invalid("maxResults"))?,
+            per_document_compression: required("perDocumentCompression")?.parse().map_err(|_| invalid("perDocumentCompression"))?,
+            precompression: values.get("precompressed").map_or(Ok(Vec::new()), |encodings| encodings.iter().map(|e| e.parse().map_err(|_| invalid("precompressed"))).collect())?,
+            range_field_names: values.get("rangeField").map_or(Vec::new(), |names| names.iter().map(|n| n.to_string()).collect()),
+            sort_key_names: values.get("sortKey").map_or(Vec::new(), |names| names.iter().map(|n| n.to_string()).collect()),
+            // Builds with the identity analyser have no stages.
+            term_analyser: {
+                let mut stopwords = values.get("analyserStopword").map_or(&[][..], |words| &words[..]).iter();
+                Analyser::new(values.get("analyserStage").map_or(&[][..], |stages| &stages[..]).iter().map(|raw| match raw.split_once(' ') {
+                    Some(("stopwords", count)) => {
+                        let count = count.parse::<usize>().map_err(|_| invalid("analyserStage"))?;
+                        Ok(AnalyserStage::StripStopwords(stopwords.by_ref().take(count).map(|word| word.to_string()).collect()))
+                    }
+                    _ => raw.parse().map_err(|_| invalid("analyserStage")),
+                }).collect::<Result<_, BuildError>>()?)
+            },
+            term_truncation_bytes: optional("termTruncationBytes").map(|raw| raw.parse().map_err(|_| invalid("termTruncationBytes"))).transpose()?,
+        };
+        verify_chunks(output_dir, "sorts", data.chunk_layout, data.sort_key_names.len())?;
+        verify_chunks(output_dir, "ranges", data.chunk_layout, data.range_field_names.len())?;
+        verify_chunks(output_dir, "ids", data.chunk_layout, data.document_id_chunk_starts.as_ref().map_or(0, |starts| starts.len()))?;
+        verify_chunks(output_dir, "groups", data.chunk_layout, if data.has_groups { 1 } else { 0 })?;
+        if data.has_bm25 {
+            verify_chunks(output_dir, "ranking", data.chunk_layout, 1)?;
+        };
+        verify_chunks(output_dir, "blocks", data.chunk_layout, data.blocks_chunk_count)?;
+        verify_chunks(output_dir, "filters", data.chunk_layout, data.filters_chunk_count)?;
+        Ok(data)
+    }
+}
+
+/**
+ * Settings of the worker that don't affect the data or runner, so they can be changed by regenerating just the worker.
+ * These have the same meaning as the fields of `BuildConfig` with the same names.
+ */
+pub struct WorkerConfig {
+    pub chunk_read_concurrency: usize,
+    pub chunk_read_retries: usize,
+    pub chunk_read_retry_delay: u64,
+    pub chunk_read_timeout: Option<u64>,
+    pub chunk_read_timeout_behaviour: ReadTimeoutBehaviour,
+    pub data_store: DataStore,
+    pub data_store_url_prefix: Option<String>,
+    pub deploy_target: DeployTarget,
+    pub document_route: bool,
+    pub empty_result_behaviour: EmptyResultBehaviour,
+    pub expanded_terms_overflow: ExpandedTermsOverflow,
+    pub group_scan_limit: usize,
+    pub maximum_prefix_expansions: usize,
+    pub maximum_query_bytes: usize,
+    pub minimum_query_bytes: usize,
+    pub minimum_query_terms: usize,
+    pub on_unknown_term: OnUnknownTerm,
+    pub openapi: bool,
+    // Output folder of a previous build, whose worker script is replaced.
+    pub output_dir: PathBuf,
+    pub proximity_scan_limit: usize,
+    pub query_log: QueryLogConfig,
+    pub response: ResponseFormatConfig,
+    pub route_prefix: String,
+    pub scoped_search: bool,
+    pub term_stats_route: bool,
+    // The body of an async function run after the worker produces a response, with `request` and `response` in scope.
+    // It can change the headers of `response`, or return another Response to respond with instead.
+    pub worker_middleware: Option<String>,
+    // The body of an async function run before the worker handles a request, with `request` in scope.
+    // It can return a Response to respond with instead of handling the request, such as to reject unauthorised requests.
+    pub worker_prelude: Option<String>,
+}
+
+/**
+ * Regenerate the worker script, and `openapi.json` if enabled, of a previous build without building the data or runner again.
+ * This is much faster than `build` when only changing how the worker serves requests, such as its routes or response format.
+ */
+pub fn generate_worker(config: WorkerConfig) -> Result<(), BuildError> {
+    let data = WorkerData::load(&config.output_dir)?;
+    write_worker(config, data)
+}
+
+pub(crate) fn write_worker(WorkerConfig {
+    chunk_read_concurrency,
+    chunk_read_retries,
+    chunk_read_retry_delay,
+    chunk_read_timeout,
+    chunk_read_timeout_behaviour,
+    data_store,
+    data_store_url_prefix,
+    deploy_target,
+    document_route,
+    empty_result_behaviour,
+    expanded_terms_overflow,
+    group_scan_limit,
+    maximum_prefix_expansions,
+    maximum_query_bytes,
+    minimum_query_bytes,
+    minimum_query_terms,
+    on_unknown_term,
+    openapi,
+    output_dir,
+    proximity_scan_limit,
+    query_log,
+    response,
+    route_prefix,
+    scoped_search,
+    term_stats_route,
+    worker_middleware,
+    worker_prelude,
+}: WorkerConfig, data: WorkerData) -> Result<(), BuildError> {
+    check_config(data.documents_stored || !document_route, "the document route requires stored documents")?;
+    check_config(deploy_target != DeployTarget::Browser || matches!(data_store, DataStore::URL), "the browser deploy target requires the URL data store")?;
+    check_config(data.kv_key_namespace.is_none() || matches!(data_store, DataStore::KV), "a KV key namespace requires the KV data store")?;
+    // Otherwise the function would handle every path, hiding the static site of the Pages project.
+    check_config(deploy_target != DeployTarget::PagesFunctions || !route_prefix.trim_end_matches('/').is_empty(), "the Pages Functions deploy target requires a route prefix")?;
+    check_config(chunk_read_concurrency >= 1, "chunk read concurrency must be at least 1")?;
+    check_config(route_prefix.is_empty() || route_prefix.starts_with('/'), "route prefix must start with a slash")?;
+    check_config(!(response.pretty && response.stream_results), "pretty responses cannot be streamed")?;
+    if let Err(err) = validate_runner_memory(maximum_query_bytes) {
+        return Err(BuildError::InvalidConfig(format!("runner limits: {}", err)));
+    };
+    if let Err(err) = query_log.validate(deploy_target) {
+        return Err(BuildError::InvalidConfig(format!("query log: {}", err)));
+    };
+    // The estimate is for the worst case, so only warn, as queries that don't reach it still work.
+    let worst_case_query_bytes = estimate_query_bytes(&QueryMemoryLimits {
+        bm25: data.has_bm25,
+        document_count: data.document_count,
+        document_dictionary_bytes: data.document_dictionary_bytes,
+        filters: data.filters_chunk_count > 0,
+        groups: data.has_groups,
+        // Prefix expansions are also bounded by the maximum expanded terms.
+        max_document_bytes: data.max_document_bytes,
+        max_prefix_expansions: data.max_expanded_terms.map_or(maximum_prefix_expansions, |max| max.min(maximum_prefix_expansions)),
+        max_query_terms: data.max_query_terms,
+        proximity: data.blocks_chunk_count > 0,
+        ranges: !data.range_field_names.is_empty(),
+        scoped_search,
+        sorts: !data.sort_key_names.is_empty(),
+    });
+    if worst_case_query_bytes > maximum_query_bytes {
+        warn!(
+            "A query can use up to {} bytes of runner memory, but the maximum query bytes is {}, so queries with many common terms may fail; raise --maximum-query-bytes or lower the query limits",
+            number(worst_case_query_bytes),
+            number(maximum_query_bytes),
+        );
+    };
+
+    let args = WorkerJsArgs {
+        output_dir: &output_dir,
+        approximate_terms: data.approximate_terms,
+        bm25: data.has_bm25,
+        chunk_layout: data.chunk_layout,
+        chunk_read_concurrency,
+        chunk_read_retries,
+        chunk_read_retry_delay,
+        chunk_read_timeout,
+        chunk_read_timeout_behaviour,
+        data_store,
+        data_store_url_prefix,
+        deploy_target,
+        document_count: data.document_count,
+        document_id_chunk_starts: data.document_id_chunk_starts,
+        document_route,
+        documents_stored: data.documents_stored,
+        empty_result_behaviour,
+        expanded_terms_overflow,
+        filters: data.filters_chunk_count > 0,
+        group_scan_limit: if data.has_groups { Some(group_scan_limit) } else { None },
+        kv_key_namespace: data.kv_key_namespace,
+        max_expanded_terms: data.max_expanded_terms,
+        max_prefix_expansions: maximum_prefix_expansions,
+        max_query_terms: data.max_query_terms,
+        max_results: data.max_results,
+        min_query_bytes: minimum_query_bytes,
+        min_query_terms: minimum_query_terms,
+        on_unknown_term,
+        per_document_compression: data.per_document_compression,
+        runner_memory_pages: maximum_query_bytes.div_ceil(WASM_PAGE_BYTES),
+        precompressed_gzip: data.precompression.contains(&Precompression::Gzip),
+        proximity_scan_limit: if data.blocks_chunk_count > 0 { Some(proximity_scan_limit) } else { None },
+        query_log,
+        response,
+        route_prefix: route_prefix.trim_end_matches('/').to_string(),
+        range_field_names: data.range_field_names,
+        scoped_search,
+        sort_key_names: data.sort_key_names,
+        term_analyser: data.term_analyser,
+        term_truncation_bytes: data.term_truncation_bytes,
+        term_stats_route,
+        worker_middleware,
+        worker_prelude,
+    };
+    // Generated from the same arguments as the worker so that it cannot describe different routes or limits.
+    if openapi {
+        write_openapi_json(&args)?;
+    };
+    generate_worker_js(args)
+}
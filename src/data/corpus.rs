@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::Term;
+use crate::build::{BuildError, IoContext};
+
+// Documents and their terms held in memory, as an alternative to providing them as files.
+#[derive(Default)]
+pub struct CorpusBuilder {
+    pub(crate) documents: Vec<String>,
+    pub(crate) document_terms: Vec<Vec<Term>>,
+}
+
+impl CorpusBuilder {
+    pub fn new() -> CorpusBuilder {
+        CorpusBuilder::default()
+    }
+
+    /**
+     * Add a document with its JSON serialised body and terms.
+     * Documents must be added in ID order starting from 0, as IDs are positions in the index.
+     * The same invariants as the document terms file apply, except that duplicate terms are ignored.
+     */
+    pub fn add_document(&mut self, id: usize, body: &str, terms: &[&str]) -> &mut CorpusBuilder {
+        if id != self.documents.len() {
+            panic!("Document {} was added but the next document ID is {}", id, self.documents.len());
+        };
+        let mut document_terms = Vec::<Term>::with_capacity(terms.len());
+        for term in terms {
+            if term.is_empty() || term.contains('\0') {
+                panic!("Document {} has invalid term {:?}", id, term);
+            };
+            if !document_terms.iter().any(|t| t == term) {
+                document_terms.push(term.to_string());
+            };
+        };
+        self.documents.push(body.to_string());
+        self.document_terms.push(document_terms);
+        self
+    }
+
+}
+
+// Where to read documents and their terms from.
+pub enum CorpusSource {
+    // The documents and document terms files, in the formats described in the README.
+    Files {
+        document_terms: File,
+        documents: File,
+    },
+    Memory(CorpusBuilder),
+    /**
+     * One file with the contents of the documents file, then an extra '\0', then the contents of the document terms file.
+     * Documents are never empty, so the extra '\0' unambiguously marks the end of the documents.
+     * Use `CorpusSource::combined` to create.
+     */
+    Combined {
+        file: File,
+        document_count: usize,
+        // Position in the file of the first document term.
+        document_terms_offset: u64,
+    },
+}
+
+impl CorpusSource {
+    // Find where the document terms start in a combined file. This reads the documents once to find the marker after them.
+    pub fn combined(file: File) -> Result<CorpusSource, BuildError> {
+        let mut reader = BufReader::new(file.try_clone().context("open combined corpus file")?);
+        let mut document_count = 0;
+        let mut offset = 0u64;
+        let mut entry = Vec::<u8>::new();
+        loop {
+            entry.clear();
+            let entry_bytes = reader.read_until(b'\0', &mut entry).context("read combined corpus file")?;
+            if entry_bytes == 0 || entry.last() != Some(&b'\0') {
+                return Err(BuildError::InvalidInput(format!("Combined corpus file has no end of documents marker after {} documents", document_count)));
+            };
+            offset += entry_bytes as u64;
+            if entry_bytes == 1 {
+                break;
+            };
+            document_count += 1;
+        };
+        Ok(CorpusSource::Combined { file, document_count, document_terms_offset: offset })
+    }
+
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+
+use crate::{DocumentId, Term};
+use crate::build::{BuildError, CHUNK_STR_KEY_MAX_LEN};
+use crate::data::MissingTerminatorHandling;
+use crate::data::document_terms::{DocumentTermsReader, InvalidTermHandling};
+
+/**
+ * Read the filter values of each document, such as `category=books` or `in_stock=true`, which searches can require in addition to their terms.
+ * The file has the same structure as the document terms file, with filter values instead of terms.
+ * Filters are matched exactly, so unlike terms they are not analysed. A value repeated within a document is only kept once.
+ * Values must fit in a chunk key.
+ * Returns the IDs of the documents with each value, in ascending order.
+ */
+pub fn read_document_filters(
+    input: File,
+    invalid_term_handling: InvalidTermHandling,
+    missing_terminator: MissingTerminatorHandling,
+) -> Result<HashMap<Term, Vec<DocumentId>>, BuildError> {
+    let mut filters = HashMap::<Term, Vec<DocumentId>>::new();
+    for entry in DocumentTermsReader::new(input, invalid_term_handling, missing_terminator)? {
+        let (document_id, value) = entry?;
+        if value.len() > CHUNK_STR_KEY_MAX_LEN {
+            return Err(BuildError::InvalidInput(format!("Filter value {:?} of document {} is {} bytes but values can be at most {} bytes", value, document_id, value.len(), CHUNK_STR_KEY_MAX_LEN)));
+        };
+        let document_id: DocumentId = document_id.try_into().map_err(|_| BuildError::TooManyDocuments)?;
+        let documents = filters.entry(value).or_default();
+        if documents.last() != Some(&document_id) {
+            documents.push(document_id);
+        };
+    };
+    Ok(filters)
+}
@@ -0,0 +1,21 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::build::BuildError;
+use crate::data::{MissingTerminatorHandling, read_null_terminated};
+
+// Read the original ID of every document, such as a UUID or slug. Each ID is terminated by '\0', in document order.
+pub fn read_document_ids(input: File, missing_terminator: MissingTerminatorHandling) -> Result<Vec<String>, BuildError> {
+    let mut reader = BufReader::new(input);
+    let mut ids = Vec::<String>::new();
+    let mut seen = HashSet::<String>::new();
+    while let Some(data) = read_null_terminated(&mut reader, "Document ID", missing_terminator)? {
+        let id = String::from_utf8(data).map_err(|_| BuildError::InvalidInput(format!("ID of document {} is not valid UTF-8", ids.len())))?;
+        if !seen.insert(id.clone()) {
+            return Err(BuildError::InvalidInput(format!("Document {} has duplicate ID {:?}", ids.len(), id)));
+        };
+        ids.push(id);
+    };
+    Ok(ids)
+}
@@ -1,11 +1,48 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+
+use clap::arg_enum;
+use log::{info, warn};
 
 use crate::Term;
+use crate::build::{BuildError, IoContext};
+use crate::data::MissingTerminatorHandling;
 use crate::util::format::percent;
 use crate::util::log::status_log_interval;
 
+arg_enum! {
+    // What to do with a term that isn't valid UTF-8. Such terms are never indexed, as they'd break JSON responses.
+    #[derive(Clone, Copy)]
+    pub enum InvalidTermHandling {
+        Ignore,
+        Warn,
+        Error,
+    }
+}
+
+impl InvalidTermHandling {
+    pub fn handle(&self, document_id: usize) -> Result<(), BuildError> {
+        match self {
+            InvalidTermHandling::Ignore => {}
+            InvalidTermHandling::Warn => warn!("Skipping term in document {} as it is not valid UTF-8", document_id),
+            InvalidTermHandling::Error => return Err(BuildError::InvalidTerm { document_id }),
+        };
+        Ok(())
+    }
+}
+
+// Get the amount of bytes from the current position of `input` to the end, as terms may not start at the beginning of the file.
+fn remaining_bytes(input: &mut File) -> Result<usize, BuildError> {
+    let position = input.stream_position().context("get terms file position")?;
+    let len = input.metadata().context("get terms file size")?.len();
+    (len - position).try_into().map_err(|_| BuildError::InvalidInput("Terms file is too large".to_string()))
+}
+
 pub struct DocumentTermsReader {
     reader: BufReader<File>,
     next_document_id: usize,
@@ -13,50 +50,239 @@ pub struct DocumentTermsReader {
     eof: bool,
     log_interval: usize,
     total_bytes: usize,
+    invalid_term_handling: InvalidTermHandling,
+    missing_terminator: MissingTerminatorHandling,
+    // Position in the file of the first term, and of the current document relative to it, for errors.
+    start_offset: u64,
+    document_start: usize,
 }
 
 impl DocumentTermsReader {
-    pub fn new(input: File) -> DocumentTermsReader {
-        let file_bytes: usize = input.metadata().unwrap().len().try_into().expect("file is too large");
-        DocumentTermsReader {
+    // Read terms from the current position of `input` to the end.
+    pub fn new(mut input: File, invalid_term_handling: InvalidTermHandling, missing_terminator: MissingTerminatorHandling) -> Result<DocumentTermsReader, BuildError> {
+        let start_offset = input.stream_position().context("get terms file position")?;
+        let file_bytes: usize = remaining_bytes(&mut input)?;
+        Ok(DocumentTermsReader {
             reader: BufReader::new(input),
             next_document_id: 0,
             bytes_read: 0,
             eof: false,
             log_interval: status_log_interval(file_bytes, 20),
             total_bytes: file_bytes,
-        }
+            invalid_term_handling,
+            missing_terminator,
+            start_offset,
+            document_start: 0,
+        })
     }
-}
-
-impl Iterator for DocumentTermsReader {
-    type Item = (usize, Term);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.eof { return None; };
+    // Amount of documents ended so far, which once every term is read includes documents at the end without terms.
+    pub fn documents_ended(&self) -> usize {
+        self.next_document_id
+    }
 
+    fn next_term(&mut self) -> Result<Option<(usize, Term)>, BuildError> {
         loop {
             let mut term = Vec::new();
 
-            let term_bytes = self.reader.read_until(b'\0', &mut term).expect("reading term");
+            let term_bytes = self.reader.read_until(b'\0', &mut term).context("read term")?;
             self.bytes_read += term_bytes;
             interval_log!(self.log_interval, self.bytes_read, self.total_bytes, "Reading document terms ({})...");
             match term_bytes {
                 // End of file.
                 0 => {
                     self.eof = true;
-                    return None;
+                    if self.document_start < self.bytes_read {
+                        self.missing_terminator.handle("Document terms", self.start_offset + self.document_start as u64)?;
+                    };
+                    return Ok(None);
                 }
                 // End of document.
-                1 => {
+                1 if term[0] == b'\0' => {
                     self.next_document_id += 1;
+                    self.document_start = self.bytes_read;
                 }
                 _ => {
-                    // Remove null terminator.
-                    term.pop().filter(|c| *c == b'\0').expect("removal of null terminator");
-                    return Some((self.next_document_id, String::from_utf8(term).expect("read term as UTF-8")));
+                    // Remove null terminator, which can only be missing from the last term.
+                    if term.last() == Some(&b'\0') {
+                        term.pop();
+                    } else {
+                        self.missing_terminator.handle("Term", self.start_offset + (self.bytes_read - term_bytes) as u64)?;
+                    };
+                    match String::from_utf8(term) {
+                        Ok(term) => return Ok(Some((self.next_document_id, term))),
+                        Err(_) => self.invalid_term_handling.handle(self.next_document_id)?,
+                    };
                 }
             };
         }
     }
 }
+
+impl Iterator for DocumentTermsReader {
+    type Item = Result<(usize, Term), BuildError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.eof { return None; };
+
+        let next = self.next_term();
+        // Stop after the first error, as the position in the file is no longer meaningful.
+        if next.is_err() {
+            self.eof = true;
+        };
+        next.transpose()
+    }
+}
+
+// Approximate amount of bytes read from the input per block when parsing in parallel.
+const PARALLEL_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+// Terms of a contiguous run of whole documents, interned into a dictionary local to the block.
+pub struct DocumentTermsBlock {
+    // local_term_id => term, in order of first occurrence within the block.
+    pub terms: Vec<Term>,
+    // Documents in order, each as local_term_id[].
+    pub documents: Vec<Vec<usize>>,
+    // Indices into `documents` of documents that had a term that isn't valid UTF-8, once per such term.
+    pub invalid_terms: Vec<usize>,
+}
+
+// `offset` is the position in the file of the start of the block, for errors.
+fn parse_document_terms_block(block: &[u8], offset: u64, missing_terminator: MissingTerminatorHandling) -> Result<DocumentTermsBlock, BuildError> {
+    let mut terms = Vec::<Term>::new();
+    let mut local_ids = HashMap::<&[u8], usize>::new();
+    let mut documents = Vec::<Vec<usize>>::new();
+    let mut invalid_terms = Vec::<usize>::new();
+    let mut current = Vec::<usize>::new();
+    let mut start = 0;
+    let mut document_start = 0;
+    while start < block.len() {
+        let end = match block[start..].iter().position(|c| *c == b'\0') {
+            Some(len) => start + len,
+            // Only the last block can end without a terminator.
+            None => {
+                missing_terminator.handle("Term", offset + start as u64)?;
+                block.len()
+            }
+        };
+        let term = &block[start..end];
+        if term.is_empty() {
+            // End of document.
+            documents.push(std::mem::take(&mut current));
+            document_start = end + 1;
+        } else {
+            let local_id = match local_ids.get(term) {
+                Some(local_id) => Some(*local_id),
+                None => match String::from_utf8(term.to_vec()) {
+                    Ok(decoded) => {
+                        let local_id = terms.len();
+                        terms.push(decoded);
+                        local_ids.insert(term, local_id);
+                        Some(local_id)
+                    }
+                    Err(_) => None,
+                },
+            };
+            match local_id {
+                Some(local_id) => current.push(local_id),
+                None => invalid_terms.push(documents.len()),
+            };
+        };
+        start = end + 1;
+    };
+    // The last document of the input may not be terminated.
+    if document_start < block.len() {
+        missing_terminator.handle("Document terms", offset + document_start as u64)?;
+        documents.push(current);
+    };
+    Ok(DocumentTermsBlock { terms, documents, invalid_terms })
+}
+
+// Find the end of the last whole document in `data`.
+// A null byte preceded by another null byte always ends a document, as terms cannot be empty.
+// This relies on `data` starting at the beginning of a document.
+fn last_document_boundary(data: &[u8]) -> Option<usize> {
+    (1..data.len()).rev().find(|i| data[*i] == b'\0' && data[*i - 1] == b'\0').map(|i| i + 1)
+}
+
+/**
+ * Read and parse document terms on `threads` threads, calling `on_block` with each block of documents in input order.
+ * Blocks cover consecutive documents, so concatenating their documents gives the same sequence as DocumentTermsReader.
+ * Returns the first error from reading, parsing, or `on_block`, after which no more blocks are passed to `on_block`.
+ */
+pub fn read_document_terms_parallel<F: FnMut(DocumentTermsBlock) -> Result<(), BuildError>>(mut input: File, threads: usize, missing_terminator: MissingTerminatorHandling, mut on_block: F) -> Result<(), BuildError> {
+    let start_offset = input.stream_position().context("get terms file position")?;
+    let total_bytes: usize = remaining_bytes(&mut input)?;
+    let log_interval = status_log_interval(total_bytes, 20);
+    let (block_sender, block_receiver) = sync_channel::<(usize, u64, Vec<u8>)>(threads * 2);
+    let block_receiver = Mutex::new(block_receiver);
+    let (parsed_sender, parsed_receiver) = channel::<(usize, Result<DocumentTermsBlock, BuildError>)>();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let block_receiver = &block_receiver;
+            let parsed_sender = parsed_sender.clone();
+            scope.spawn(move || loop {
+                let next = block_receiver.lock().unwrap().recv();
+                match next {
+                    Ok((block_no, offset, block)) => parsed_sender.send((block_no, parse_document_terms_block(&block, offset, missing_terminator))).expect("send parsed block"),
+                    // Reader has finished.
+                    Err(_) => break,
+                };
+            });
+        };
+        drop(parsed_sender);
+
+        let reader = scope.spawn(move || -> Result<(), BuildError> {
+            let mut pending = Vec::<u8>::new();
+            let mut bytes_read = 0;
+            let mut block_no = 0;
+            let mut block_offset = start_offset;
+            let mut eof = false;
+            while !eof {
+                let mut buf = vec![0u8; PARALLEL_BLOCK_SIZE];
+                let n = input.read(&mut buf).context("read terms")?;
+                eof = n == 0;
+                let prev_logged = bytes_read / log_interval;
+                bytes_read += n;
+                if bytes_read / log_interval != prev_logged {
+                    info!("Reading document terms ({})...", percent(bytes_read as f64 / total_bytes as f64));
+                };
+                pending.extend_from_slice(&buf[..n]);
+                let split = if eof { pending.len() } else {
+                    match last_document_boundary(&pending) {
+                        // Only send reasonably large blocks to amortise thread communication.
+                        Some(boundary) if pending.len() >= PARALLEL_BLOCK_SIZE => boundary,
+                        _ => continue,
+                    }
+                };
+                let rest = pending.split_off(split);
+                if !pending.is_empty() {
+                    let block_len = pending.len() as u64;
+                    block_sender.send((block_no, block_offset, std::mem::replace(&mut pending, rest))).expect("send block");
+                    block_no += 1;
+                    block_offset += block_len;
+                };
+            };
+            Ok(())
+        });
+
+        // Merge on this thread in input order, as callers rely on a deterministic order.
+        // After an error, remaining blocks are still received but discarded, so that the other threads can finish.
+        let mut result = Ok(());
+        let mut parsed = BTreeMap::<usize, Result<DocumentTermsBlock, BuildError>>::new();
+        let mut next_block_no = 0;
+        for (block_no, block) in parsed_receiver {
+            parsed.insert(block_no, block);
+            while let Some(block) = parsed.remove(&next_block_no) {
+                if result.is_ok() {
+                    result = block.and_then(&mut on_block);
+                };
+                next_block_no += 1;
+            };
+        };
+        // Blocks were all read before any error reading the file, so their errors come first.
+        let read_result = reader.join().expect("join terms reader thread");
+        result.and(read_result)
+    })
+}
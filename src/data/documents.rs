@@ -1,30 +1,34 @@
 use std::fs::File;
 use std::io::BufReader;
 
-use crate::data::read_null_terminated;
+use crate::build::BuildError;
+use crate::data::{MissingTerminatorHandling, read_null_terminated};
 
 pub struct DocumentsReader {
     reader: BufReader<File>,
     next_document_id: usize,
+    missing_terminator: MissingTerminatorHandling,
 }
 
 impl DocumentsReader {
-    pub fn new(input: File) -> DocumentsReader {
+    pub fn new(input: File, missing_terminator: MissingTerminatorHandling) -> DocumentsReader {
         DocumentsReader {
             reader: BufReader::new(input),
             next_document_id: 0,
+            missing_terminator,
         }
     }
 }
 
 impl Iterator for DocumentsReader {
-    type Item = (usize, String);
+    type Item = Result<(usize, String), BuildError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        read_null_terminated(&mut self.reader).map(|data| {
+        read_null_terminated(&mut self.reader, "Document", self.missing_terminator).transpose().map(|data| {
             let doc_id = self.next_document_id;
             self.next_document_id += 1;
-            (doc_id, String::from_utf8(data).expect("parsing document as UTF-8"))
+            let document = String::from_utf8(data?).map_err(|_| BuildError::InvalidInput(format!("Document {} is not valid UTF-8", doc_id)))?;
+            Ok((doc_id, document))
         })
     }
 }
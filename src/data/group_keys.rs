@@ -0,0 +1,15 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::build::BuildError;
+use crate::data::{MissingTerminatorHandling, read_null_terminated};
+
+// Read the group key of every document, such as the ID of the article a document is a page of. Each key is terminated by '\0', in document order.
+pub fn read_group_keys(input: File, missing_terminator: MissingTerminatorHandling) -> Result<Vec<String>, BuildError> {
+    let mut reader = BufReader::new(input);
+    let mut keys = Vec::<String>::new();
+    while let Some(data) = read_null_terminated(&mut reader, "Group key", missing_terminator)? {
+        keys.push(String::from_utf8(data).map_err(|_| BuildError::InvalidInput(format!("Group key of document {} is not valid UTF-8", keys.len())))?);
+    };
+    Ok(keys)
+}
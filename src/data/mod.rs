@@ -1,18 +1,56 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Seek};
 
+use clap::arg_enum;
+
+use crate::build::{BuildError, IoContext};
+
+pub mod corpus;
+pub mod document_filters;
+pub mod document_ids;
 pub mod document_terms;
 pub mod documents;
+pub mod group_keys;
+pub mod normalise;
+pub mod sort_keys;
+pub mod term_blocks;
+
+arg_enum! {
+    // What to do when the last record of an input file is missing its '\0' terminator, which many exports omit.
+    #[derive(Clone, Copy)]
+    pub enum MissingTerminatorHandling {
+        // Treat the end of the file as the terminator.
+        Lenient,
+        // Fail with the position of the unterminated record.
+        Strict,
+    }
+}
+
+impl MissingTerminatorHandling {
+    // `offset` is the position in the file of the start of the unterminated record.
+    pub fn handle(&self, record: &str, offset: u64) -> Result<(), BuildError> {
+        match self {
+            MissingTerminatorHandling::Lenient => Ok(()),
+            MissingTerminatorHandling::Strict => Err(BuildError::InvalidInput(format!("{} at byte {} is not terminated by '\\0' before the end of the file", record, offset))),
+        }
+    }
+}
 
-fn read_null_terminated(reader: &mut BufReader<File>) -> Option<Vec<u8>> {
+// `record` describes what is being read, such as "Document", for errors.
+fn read_null_terminated(reader: &mut BufReader<File>, record: &str, missing_terminator: MissingTerminatorHandling) -> Result<Option<Vec<u8>>, BuildError> {
     let mut data = Vec::<u8>::new();
-    let bytes_read = reader.read_until(b'\0', &mut data).expect("reading");
+    let bytes_read = reader.read_until(b'\0', &mut data).with_context(|| format!("read {}", record.to_lowercase()))?;
     match bytes_read {
-        0 => None,
+        0 => Ok(None),
         _ => {
-            // Remove null terminator.
-            data.pop().filter(|c| *c == b'\0').expect("removal of null terminator");
-            Some(data)
+            // Remove null terminator, which can only be missing from the last record.
+            if data.last() == Some(&b'\0') {
+                data.pop();
+            } else {
+                let end = reader.stream_position().context("get file position")?;
+                missing_terminator.handle(record, end - data.len() as u64)?;
+            };
+            Ok(Some(data))
         }
     }
 }
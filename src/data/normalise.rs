@@ -0,0 +1,216 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use clap::arg_enum;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+use crate::Term;
+
+arg_enum! {
+    // How terms are lowercased. Keep in sync with analyseTerm in script/src/main.ts.
+    #[derive(Clone, Copy, PartialEq, Hash)]
+    pub enum CaseFolding {
+        // The default Unicode lowercase mapping, which is correct for most languages.
+        Unicode,
+        // Only lowercase A to Z, leaving all other characters as is.
+        Ascii,
+        // The Unicode mapping, except that I lowercases to dotless ı and İ to i, as in Turkish and Azerbaijani.
+        Turkic,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, PartialEq, Hash)]
+    pub enum UnicodeNormalisation {
+        NFC,
+        NFKC,
+    }
+}
+
+arg_enum! {
+    // What to do with a term that is longer than the maximum term bytes after analysis.
+    #[derive(Clone, Copy, PartialEq, Hash)]
+    pub enum LongTermHandling {
+        // Keep the longest start of the term that fits and ends at a character boundary. The worker truncates query terms the same way.
+        Truncate,
+        // Leave the term out of its document, and log a warning.
+        Drop,
+    }
+}
+
+/**
+ * A limit on the UTF-8 length of analysed terms, so that junk such as a base64 blob mistakenly indexed as a term doesn't bloat the dictionary and lookup tables.
+ * Keep in sync with analyseTerm in script/src/main.ts.
+ */
+#[derive(Clone, Copy, PartialEq, Hash)]
+pub struct TermLengthLimit {
+    pub max_bytes: usize,
+    pub handling: LongTermHandling,
+}
+
+impl TermLengthLimit {
+    // Get the term to index, or None if it should be dropped, and whether the term was too long.
+    pub fn apply(&self, mut term: Term) -> (Option<Term>, bool) {
+        if term.len() <= self.max_bytes {
+            return (Some(term), false);
+        };
+        match self.handling {
+            LongTermHandling::Drop => (None, true),
+            LongTermHandling::Truncate => {
+                let mut end = self.max_bytes;
+                while !term.is_char_boundary(end) {
+                    end -= 1;
+                };
+                term.truncate(end);
+                // The first character alone can be longer than the limit, and terms can't be empty.
+                (Some(term).filter(|t| !t.is_empty()), true)
+            }
+        }
+    }
+}
+
+/**
+ * Something that transforms terms, both when building and when querying, so that query terms match indexed terms.
+ * Keep every implementation in sync with analyseTerm in script/src/main.ts, as the worker must analyse query terms identically.
+ */
+pub trait AnalyseTerm {
+    // Get the transformed term, or None if the term should be dropped, such as a stopword.
+    fn analyse(&self, term: Term) -> Option<Term>;
+}
+
+// A step of an `Analyser`.
+#[derive(Clone, PartialEq, Hash)]
+pub enum AnalyserStage {
+    Lowercase(CaseFolding),
+    // Make equivalent Unicode sequences, such as precomposed and decomposed accents, the same term.
+    // Best placed after lowercasing, as lowercasing can produce text that isn't normalised.
+    Normalise(UnicodeNormalisation),
+    // Remove accents and other combining marks, so that `café` and `cafe` are the same term. Terms of only marks are dropped.
+    // The result is NFC normalised, as decomposing also splits characters without marks, such as Hangul syllables.
+    FoldDiacritics,
+    // Drop terms in the set, which must be written as terms are at this point of the chain, such as lowercased if after a lowercase stage.
+    StripStopwords(BTreeSet<Term>),
+}
+
+impl AnalyseTerm for AnalyserStage {
+    fn analyse(&self, term: Term) -> Option<Term> {
+        match self {
+            AnalyserStage::Lowercase(CaseFolding::Unicode) => Some(term.to_lowercase()),
+            AnalyserStage::Lowercase(CaseFolding::Ascii) => Some(term.to_ascii_lowercase()),
+            AnalyserStage::Lowercase(CaseFolding::Turkic) => Some(term.replace('I', "ı").replace('İ', "i").to_lowercase()),
+            AnalyserStage::Normalise(UnicodeNormalisation::NFC) => Some(term.nfc().collect()),
+            AnalyserStage::Normalise(UnicodeNormalisation::NFKC) => Some(term.nfkc().collect()),
+            AnalyserStage::FoldDiacritics => Some(term.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect::<Term>()).filter(|t| !t.is_empty()),
+            AnalyserStage::StripStopwords(stopwords) => if stopwords.contains(&term) { None } else { Some(term) },
+        }
+    }
+}
+
+// The name of the stage, such as `lowercase-turkic` or `nfc`. The words of a stopwords stage are not included.
+impl fmt::Display for AnalyserStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalyserStage::Lowercase(CaseFolding::Unicode) => write!(f, "lowercase"),
+            AnalyserStage::Lowercase(CaseFolding::Ascii) => write!(f, "lowercase-ascii"),
+            AnalyserStage::Lowercase(CaseFolding::Turkic) => write!(f, "lowercase-turkic"),
+            AnalyserStage::Normalise(UnicodeNormalisation::NFC) => write!(f, "nfc"),
+            AnalyserStage::Normalise(UnicodeNormalisation::NFKC) => write!(f, "nfkc"),
+            AnalyserStage::FoldDiacritics => write!(f, "fold-diacritics"),
+            AnalyserStage::StripStopwords(_) => write!(f, "stopwords"),
+        }
+    }
+}
+
+// Parse a stage from its name. Stopwords stages have words, so they must be created directly instead.
+impl FromStr for AnalyserStage {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<AnalyserStage, String> {
+        match raw.to_ascii_lowercase().as_str() {
+            "lowercase" => Ok(AnalyserStage::Lowercase(CaseFolding::Unicode)),
+            "lowercase-ascii" => Ok(AnalyserStage::Lowercase(CaseFolding::Ascii)),
+            "lowercase-turkic" => Ok(AnalyserStage::Lowercase(CaseFolding::Turkic)),
+            "nfc" => Ok(AnalyserStage::Normalise(UnicodeNormalisation::NFC)),
+            "nfkc" => Ok(AnalyserStage::Normalise(UnicodeNormalisation::NFKC)),
+            "fold-diacritics" => Ok(AnalyserStage::FoldDiacritics),
+            "stopwords" => Err("a stopwords stage needs its words".to_string()),
+            _ => Err(format!("unknown analyser stage {:?}", raw)),
+        }
+    }
+}
+
+/**
+ * The stages every term goes through in order, both when building and when querying.
+ * The stages are recorded in the worker, so the build and query analysis cannot drift apart.
+ * The default has no stages, so terms are indexed and queried exactly as provided.
+ */
+#[derive(Clone, Default, PartialEq, Hash)]
+pub struct Analyser {
+    pub stages: Vec<AnalyserStage>,
+}
+
+impl Analyser {
+    pub fn new(stages: Vec<AnalyserStage>) -> Analyser {
+        Analyser { stages }
+    }
+
+    // Whether no stage is applied. Otherwise, distinct input terms could become the same term.
+    pub fn is_identity(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl AnalyseTerm for Analyser {
+    fn analyse(&self, term: Term) -> Option<Term> {
+        self.stages.iter().try_fold(term, |term, stage| stage.analyse(term))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::js::tests::analyse_in_worker;
+
+    // Check that the worker analyses query terms to the same terms as the build, and return them.
+    fn analyse_as_build_and_worker(name: &str, analyser: &Analyser, terms: &[&str]) -> Vec<Option<Term>> {
+        let built = terms.iter().map(|term| analyser.analyse(term.to_string())).collect::<Vec<_>>();
+        if let Some(queried) = analyse_in_worker(name, analyser, terms) {
+            assert_eq!(queried, built, "worker and build analysed {:?} differently", terms);
+        };
+        built
+    }
+
+    #[test]
+    fn worker_lowercases_non_ascii_like_build() {
+        // Accented Latin, Greek with a final sigma, dotted and dotless I, a titlecase digraph, capital sharp s, fullwidth, Cyrillic, and Deseret.
+        let terms = ["ÀÉÎÕÜ", "ΣΊΣΥΦΟΣ", "İSTANBUL", "IĞDIR", "ǅEMAL", "ẞ", "ＡＢＣ", "ДЖЕМ", "𐐀𐐁"];
+        for case_folding in &[CaseFolding::Unicode, CaseFolding::Ascii, CaseFolding::Turkic] {
+            let analyser = Analyser::new(vec![AnalyserStage::Lowercase(*case_folding)]);
+            analyse_as_build_and_worker(&format!("lowercase-{}", case_folding), &analyser, &terms);
+        };
+    }
+
+    #[test]
+    fn normalisation_makes_composed_and_decomposed_forms_the_same_term() {
+        // Each group is one term written as precomposed characters, then decomposed, then with its marks in another order or as a compatibility character.
+        let groups: &[&[&str]] = &[
+            &["caf\u{e9}", "cafe\u{301}"],
+            &["\u{c5}ngstr\u{f6}m", "A\u{30a}ngstro\u{308}m", "\u{212b}ngstr\u{f6}m"],
+            &["vi\u{1ec7}t", "vie\u{323}\u{302}t", "vie\u{302}\u{323}t"],
+            &["\u{d55c}\u{ae00}", "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}"],
+        ];
+        for form in &[UnicodeNormalisation::NFC, UnicodeNormalisation::NFKC] {
+            let analyser = Analyser::new(vec![AnalyserStage::Normalise(*form)]);
+            for (i, group) in groups.iter().enumerate() {
+                let terms = analyse_as_build_and_worker(&format!("{}-{}", form, i), &analyser, group);
+                assert!(terms.iter().all(|term| *term == terms[0]), "{} analysed {:?} to different terms {:?}", form, group, terms);
+            };
+        };
+        // Only NFKC also makes compatibility characters, such as ligatures and fullwidth forms, the same as what they are equivalent to.
+        let analyser = Analyser::new(vec![AnalyserStage::Normalise(UnicodeNormalisation::NFKC)]);
+        let terms = analyse_as_build_and_worker("nfkc-compatibility", &analyser, &["\u{fb01}le", "file", "\u{ff46}\u{ff49}\u{ff4c}\u{ff45}"]);
+        assert!(terms.iter().all(|term| *term == terms[1]), "NFKC analysed compatibility characters to different terms {:?}", terms);
+    }
+}
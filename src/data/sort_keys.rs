@@ -0,0 +1,19 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::build::BuildError;
+use crate::data::{MissingTerminatorHandling, read_null_terminated};
+
+// Read the value of every document for a numeric field, such as a sort key or range field, which `kind` names in errors.
+// Each value is a number terminated by '\0', in document order.
+pub fn read_numeric_values(kind: &str, name: &str, input: File, missing_terminator: MissingTerminatorHandling) -> Result<Vec<f64>, BuildError> {
+    let mut reader = BufReader::new(input);
+    let mut values = Vec::<f64>::new();
+    while let Some(data) = read_null_terminated(&mut reader, &format!("{} value", kind), missing_terminator)? {
+        let raw = String::from_utf8(data).map_err(|_| BuildError::InvalidInput(format!("{} {} has a value that is not valid UTF-8 for document {}", kind, name, values.len())))?;
+        let value = raw.trim().parse::<f64>().ok().filter(|v| !v.is_nan())
+            .ok_or_else(|| BuildError::InvalidInput(format!("{} {} has invalid value {:?} for document {}", kind, name, raw, values.len())))?;
+        values.push(value);
+    };
+    Ok(values)
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+
+use crate::{DocumentId, Term};
+use crate::build::BuildError;
+use crate::data::MissingTerminatorHandling;
+use crate::data::document_terms::{DocumentTermsReader, InvalidTermHandling};
+use crate::data::normalise::{AnalyseTerm, Analyser, TermLengthLimit};
+
+/**
+ * Read which block, such as a paragraph or section, each term of each document is in.
+ * The file has the same structure as the document terms file, but each term is prefixed with its block ID and a colon, such as `3:fox`.
+ * Block IDs only need to be unique within their document. If a term is in several blocks of a document, only the first is kept.
+ * Terms are analysed and limited in length like document terms, so terms that are dropped, such as stopwords, have no blocks.
+ * Returns the (document ID, block ID) pairs of each term, in document ID order.
+ */
+pub fn read_term_blocks(
+    input: File,
+    invalid_term_handling: InvalidTermHandling,
+    missing_terminator: MissingTerminatorHandling,
+    term_analyser: &Analyser,
+    term_length_limit: TermLengthLimit,
+) -> Result<HashMap<Term, Vec<(DocumentId, u32)>>, BuildError> {
+    let mut blocks = HashMap::<Term, Vec<(DocumentId, u32)>>::new();
+    for entry in DocumentTermsReader::new(input, invalid_term_handling, missing_terminator)? {
+        let (document_id, entry) = entry?;
+        let document_id: DocumentId = document_id.try_into().map_err(|_| BuildError::TooManyDocuments)?;
+        let (block_id, term) = match entry.find(':') {
+            Some(sep) => (entry[..sep].parse::<u32>().ok(), &entry[sep + 1..]),
+            None => (None, ""),
+        };
+        let block_id = block_id.filter(|_| !term.is_empty())
+            .ok_or_else(|| BuildError::InvalidInput(format!("Term block {:?} in document {} is not a block ID and term separated by ':'", entry, document_id)))?;
+        let term = match term_analyser.analyse(term.to_string()).and_then(|term| term_length_limit.apply(term).0) {
+            Some(term) => term,
+            None => continue,
+        };
+        let term_blocks = blocks.entry(term).or_default();
+        if term_blocks.last().filter(|(last_document_id, _)| *last_document_id == document_id).is_none() {
+            term_blocks.push((document_id, block_id));
+        };
+    };
+    Ok(blocks)
+}
@@ -1,42 +1,423 @@
-use std::fs::File;
+use std::fmt::Display;
+use std::fs::{File, read, read_to_string};
 use std::path::PathBuf;
+use std::process::exit;
 
+use env_logger::Env;
+use log::error;
 use structopt::StructOpt;
 
-use edgesearch::build::{build, BuildConfig, DataStore};
+use edgesearch::build::{build, generate_worker, Analyser, AnalyserStage, BuildConfig, BundleCompression, ChunkLayout, CorpusSource, DataStore, DeployTarget, DocumentCompression, EmptyResultBehaviour, ErrorFormat, ExpandedTermsOverflow, IntersectionStrategy, InvalidTermHandling, LongTermHandling, MissingTerminatorHandling, OnUnknownTerm, OversizedDocumentHandling, Precompression, QueryLogConfig, QueryLogSink, Ranking, read_serialised_postings, ReadTimeoutBehaviour, RangeField, ResponseFormatConfig, SortKey, validate_inputs, ValidateConfig, WorkerConfig};
 
 #[derive(StructOpt)]
 struct Cli {
+    #[structopt(long = "analyser-stage", number_of_values = 1, parse(try_from_str = parse_analyser_stage))] analyser_stages: Vec<AnalyserStage>,
+    #[structopt(long)] bm25: bool,
+    #[structopt(long, default_value = "0.75")] bm25_b: f64,
+    #[structopt(long, default_value = "1.2")] bm25_k1: f64,
+    #[structopt(long, parse(from_os_str))] bundle: Option<PathBuf>,
+    #[structopt(long, possible_values = &BundleCompression::variants(), case_insensitive = true, default_value = "None")] bundle_compression: BundleCompression,
+    #[structopt(long)] bundle_only: bool,
+    #[structopt(long)] checkpoint: bool,
+    #[structopt(long, possible_values = &ChunkLayout::variants(), case_insensitive = true, default_value = "Flat")] chunk_layout: ChunkLayout,
+    #[structopt(long, default_value = "6")] chunk_read_concurrency: usize,
+    #[structopt(long, default_value = "2")] chunk_read_retries: usize,
+    #[structopt(long, default_value = "50")] chunk_read_retry_delay: u64,
+    #[structopt(long)] chunk_read_timeout: Option<u64>,
+    #[structopt(long, possible_values = &ReadTimeoutBehaviour::variants(), case_insensitive = true, default_value = "Degrade")] chunk_read_timeout_behaviour: ReadTimeoutBehaviour,
     #[structopt(long, default_value = "26214400")] chunk_size: usize,
-    #[structopt(long, possible_values = &DataStore::variants(), case_insensitive = true)] data_store: DataStore,
+    #[structopt(long = "clang-flag", number_of_values = 1, allow_hyphen_values = true)] clang_flags: Vec<String>,
+    #[structopt(long, parse(from_os_str))] compile_dir: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] compiler: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] corpus: Option<PathBuf>,
+    #[structopt(long, possible_values = &DataStore::variants(), case_insensitive = true, required_unless = "validate-only")] data_store: Option<DataStore>,
     #[structopt(long)] data_store_url_prefix: Option<String>,
-    #[structopt(long, parse(from_os_str))] document_terms: PathBuf,
-    #[structopt(long, parse(from_os_str))] documents: PathBuf,
+    #[structopt(long, possible_values = &DeployTarget::variants(), case_insensitive = true, default_value = "Worker")] deploy_target: DeployTarget,
+    #[structopt(long, parse(from_os_str))] document_filters: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] document_ids: Option<PathBuf>,
+    #[structopt(long)] document_route: bool,
+    #[structopt(long, parse(from_os_str), required_unless_one = &["corpus", "worker-only"], conflicts_with = "corpus")] document_terms: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str), required_unless_one = &["corpus", "worker-only"], conflicts_with = "corpus")] documents: Option<PathBuf>,
+    #[structopt(long, possible_values = &EmptyResultBehaviour::variants(), case_insensitive = true, default_value = "Empty")] empty_result_behaviour: EmptyResultBehaviour,
+    #[structopt(long, possible_values = &ErrorFormat::variants(), case_insensitive = true, default_value = "Message")] error_format: ErrorFormat,
+    #[structopt(long, possible_values = &ExpandedTermsOverflow::variants(), case_insensitive = true, default_value = "Error")] expanded_terms_overflow: ExpandedTermsOverflow,
+    #[structopt(long)] explain: bool,
+    #[structopt(long, parse(from_os_str))] group_keys: Option<PathBuf>,
+    #[structopt(long, default_value = "10000")] group_scan_limit: usize,
+    #[structopt(long, possible_values = &InvalidTermHandling::variants(), case_insensitive = true, default_value = "Error")] invalid_terms: InvalidTermHandling,
+    #[structopt(long)] kv_key_namespace: Option<String>,
+    #[structopt(long, possible_values = &LongTermHandling::variants(), case_insensitive = true, default_value = "Drop")] long_terms: LongTermHandling,
+    #[structopt(long = "include-dir", number_of_values = 1, parse(from_os_str))] include_dirs: Vec<PathBuf>,
+    #[structopt(long, possible_values = &IntersectionStrategy::variants(), case_insensitive = true, default_value = "SmallestFirst")] intersection_strategy: IntersectionStrategy,
+    #[structopt(long)] maximum_expanded_terms: Option<usize>,
+    #[structopt(long)] maximum_postings_cardinality: Option<usize>,
+    #[structopt(long, default_value = "50")] maximum_prefix_expansions: usize,
+    #[structopt(long, default_value = "67108864")] maximum_query_bytes: usize,
     #[structopt(long, default_value = "50")] maximum_query_results: usize,
     #[structopt(long, default_value = "50")] maximum_query_terms: usize,
-    #[structopt(long, parse(from_os_str))] output_dir: PathBuf,
+    #[structopt(long)] maximum_term_bytes: Option<usize>,
+    #[structopt(long, default_value = "1")] minimum_query_bytes: usize,
+    #[structopt(long, possible_values = &MissingTerminatorHandling::variants(), case_insensitive = true, default_value = "Lenient")] missing_terminators: MissingTerminatorHandling,
+    #[structopt(long, default_value = "1")] minimum_query_terms: usize,
+    #[structopt(long, possible_values = &OnUnknownTerm::variants(), case_insensitive = true, default_value = "Empty")] on_unknown_term: OnUnknownTerm,
+    #[structopt(long)] openapi: bool,
+    #[structopt(long, parse(from_os_str), required_unless = "validate-only")] output_dir: Option<PathBuf>,
+    #[structopt(long, possible_values = &OversizedDocumentHandling::variants(), case_insensitive = true, default_value = "Error")] oversized_documents: OversizedDocumentHandling,
+    #[structopt(long, default_value = "1")] parse_threads: usize,
+    #[structopt(long, possible_values = &DocumentCompression::variants(), case_insensitive = true, default_value = "None")] per_document_compression: DocumentCompression,
+    #[structopt(long, parse(from_os_str))] postings: Option<PathBuf>,
+    #[structopt(long, requires = "postings")] postings_document_count: Option<usize>,
+    #[structopt(long, default_value = "1")] postings_threads: usize,
+    #[structopt(long = "precompress", number_of_values = 1, possible_values = &Precompression::variants(), case_insensitive = true)] precompression: Vec<Precompression>,
+    #[structopt(long)] no_store_documents: bool,
+    #[structopt(long)] pretty_responses: bool,
+    #[structopt(long, default_value = "1000")] proximity_scan_limit: usize,
+    #[structopt(long, possible_values = &QueryLogSink::variants(), case_insensitive = true, default_value = "None")] query_log: QueryLogSink,
+    #[structopt(long)] query_log_endpoint: Option<String>,
+    #[structopt(long, default_value = "1")] query_log_sample_rate: f64,
+    #[structopt(long = "range-field", number_of_values = 1, parse(try_from_str = parse_range_field))] range_fields: Vec<(String, PathBuf)>,
+    #[structopt(long, default_value = "application/json")] response_content_type: String,
+    #[structopt(long = "result-field", number_of_values = 1)] result_fields: Vec<String>,
+    #[structopt(long, default_value = "")] route_prefix: String,
+    #[structopt(long)] scoped_search: bool,
+    #[structopt(long)] simd: bool,
+    #[structopt(long)] size_report: bool,
+    #[structopt(long = "sort-key", number_of_values = 1, parse(try_from_str = parse_sort_key))] sort_keys: Vec<(String, PathBuf)>,
+    #[structopt(long, parse(from_os_str))] stats_json: Option<PathBuf>,
+    #[structopt(long)] stream_results: bool,
+    #[structopt(long, parse(from_os_str))] sysroot: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] term_blocks: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] term_dictionary: Option<PathBuf>,
+    #[structopt(long)] term_stats_route: bool,
+    #[structopt(long, conflicts_with = "worker-only")] validate_only: bool,
+    #[structopt(long, parse(from_os_str))] wasm_opt: Option<PathBuf>,
+    #[structopt(long, parse(from_os_str))] worker_middleware: Option<PathBuf>,
+    #[structopt(long)] worker_only: bool,
+    #[structopt(long, parse(from_os_str))] worker_prelude: Option<PathBuf>,
+    #[structopt(long)] write_dictionary: bool,
+    #[structopt(long, parse(from_os_str))] zstd_decoder: Option<PathBuf>,
+}
+
+// Parse an argument in the form `{name}={path}` for a numeric field of each document, which `kind` names in errors.
+fn parse_numeric_field(kind: &str, raw: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = raw.split_once('=').ok_or(format!("{} must be in the form NAME=PATH", kind))?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(format!("invalid {} name {:?}", kind, name));
+    };
+    Ok((name.to_string(), PathBuf::from(path)))
+}
+
+// Log an error and exit, for problems with the arguments or input files that aren't bugs.
+fn exit_with_error<M: Display>(message: M) -> ! {
+    error!("{}", message);
+    exit(1);
+}
+
+// Open an input file, which `kind` describes in the error if it can't be opened.
+fn open_input(kind: &str, path: PathBuf) -> File {
+    File::open(&path).unwrap_or_else(|err| exit_with_error(format!("Failed to open {} {}: {}", kind, path.display(), err)))
+}
+
+fn read_input(kind: &str, path: PathBuf) -> String {
+    read_to_string(&path).unwrap_or_else(|err| exit_with_error(format!("Failed to read {} {}: {}", kind, path.display(), err)))
+}
+
+fn open_corpus(corpus: Option<PathBuf>, document_terms: Option<PathBuf>, documents: Option<PathBuf>) -> CorpusSource {
+    match (corpus, document_terms, documents) {
+        (Some(corpus), _, _) => CorpusSource::combined(open_input("corpus file", corpus)).unwrap_or_else(|err| exit_with_error(err)),
+        (None, Some(document_terms), Some(documents)) => CorpusSource::Files {
+            document_terms: open_input("document terms file", document_terms),
+            documents: open_input("documents file", documents),
+        },
+        _ => exit_with_error("Either --corpus or both --documents and --document-terms must be provided"),
+    }
+}
+
+fn parse_sort_key(raw: &str) -> Result<(String, PathBuf), String> {
+    parse_numeric_field("sort key", raw)
+}
+
+fn parse_range_field(raw: &str) -> Result<(String, PathBuf), String> {
+    parse_numeric_field("range field", raw)
+}
+
+// Parse an analyser stage, which is the name of a stage, or `stopwords={path}` for a file of stopwords each followed by NULL (ASCII 0).
+fn parse_analyser_stage(raw: &str) -> Result<AnalyserStage, String> {
+    match raw.split_once('=') {
+        Some(("stopwords", path)) => {
+            let raw_words = read(path).map_err(|err| format!("cannot read stopwords file {}: {}", path, err))?;
+            let words = raw_words.split(|b| *b == 0).filter(|word| !word.is_empty())
+                .map(|word| String::from_utf8(word.to_vec()).map_err(|_| format!("stopwords file {} is not valid UTF-8", path)))
+                .collect::<Result<_, String>>()?;
+            Ok(AnalyserStage::StripStopwords(words))
+        }
+        _ => raw.parse(),
+    }
 }
 
 fn main() {
     let Cli {
+        analyser_stages,
+        bm25,
+        bm25_b,
+        bm25_k1,
+        bundle,
+        bundle_compression,
+        bundle_only,
+        checkpoint,
+        chunk_layout,
+        chunk_read_concurrency,
+        chunk_read_retries,
+        chunk_read_retry_delay,
+        chunk_read_timeout,
+        chunk_read_timeout_behaviour,
         chunk_size,
+        clang_flags,
+        compile_dir,
+        compiler,
+        corpus,
         data_store,
         data_store_url_prefix,
+        deploy_target,
+        document_filters,
+        document_ids,
+        document_route,
         document_terms,
         documents,
+        empty_result_behaviour,
+        error_format,
+        expanded_terms_overflow,
+        explain,
+        group_keys,
+        group_scan_limit,
+        include_dirs,
+        intersection_strategy,
+        invalid_terms,
+        kv_key_namespace,
+        long_terms,
+        maximum_expanded_terms,
+        maximum_postings_cardinality,
+        maximum_prefix_expansions,
+        maximum_query_bytes,
         maximum_query_results,
         maximum_query_terms,
+        maximum_term_bytes,
+        minimum_query_bytes,
+        minimum_query_terms,
+        missing_terminators,
+        no_store_documents,
+        on_unknown_term,
+        openapi,
         output_dir,
+        oversized_documents,
+        parse_threads,
+        per_document_compression,
+        postings,
+        postings_document_count,
+        postings_threads,
+        precompression,
+        pretty_responses,
+        proximity_scan_limit,
+        query_log,
+        query_log_endpoint,
+        query_log_sample_rate,
+        range_fields,
+        response_content_type,
+        result_fields,
+        route_prefix,
+        scoped_search,
+        simd,
+        size_report,
+        sort_keys,
+        stats_json,
+        stream_results,
+        sysroot,
+        term_blocks,
+        term_dictionary,
+        term_stats_route,
+        validate_only,
+        wasm_opt,
+        worker_middleware,
+        worker_only,
+        worker_prelude,
+        write_dictionary,
+        zstd_decoder,
     } = Cli::from_args();
 
-    build(BuildConfig {
+    // Log progress by default, which can be changed using the RUST_LOG environment variable.
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format_timestamp(None)
+        .format_module_path(false)
+        .init();
+
+    if validate_only {
+        let violations = validate_inputs(ValidateConfig {
+            corpus: open_corpus(corpus, document_terms, documents),
+            document_filters: document_filters.map(|path| open_input("document filters file", path)),
+            document_ids: document_ids.map(|path| open_input("document IDs file", path)),
+            group_keys: group_keys.map(|path| open_input("group keys file", path)),
+            range_fields: range_fields.into_iter().map(|(name, path)| RangeField {
+                name,
+                values_source: open_input("range field file", path),
+            }).collect(),
+            sort_keys: sort_keys.into_iter().map(|(name, path)| SortKey {
+                name,
+                values_source: open_input("sort key file", path),
+            }).collect(),
+            store_documents: !no_store_documents,
+            term_blocks: term_blocks.map(|path| open_input("term blocks file", path)),
+        }).unwrap_or_else(|err| exit_with_error(err));
+        if violations > 0 {
+            exit(1);
+        };
+        return;
+    };
+    // Both are required unless only validating.
+    let data_store = data_store.unwrap();
+    let output_dir = output_dir.unwrap();
+
+    let worker_middleware = worker_middleware.map(|path| read_input("worker middleware file", path));
+    let worker_prelude = worker_prelude.map(|path| read_input("worker prelude file", path));
+
+    if worker_only {
+        if let Err(err) = generate_worker(WorkerConfig {
+            chunk_read_concurrency,
+            chunk_read_retries,
+            chunk_read_retry_delay,
+            chunk_read_timeout,
+            chunk_read_timeout_behaviour,
+            data_store,
+            data_store_url_prefix,
+            deploy_target,
+            document_route,
+            empty_result_behaviour,
+            expanded_terms_overflow,
+            group_scan_limit,
+            maximum_prefix_expansions,
+            maximum_query_bytes,
+            minimum_query_bytes,
+            minimum_query_terms,
+            on_unknown_term,
+            openapi,
+            output_dir,
+            proximity_scan_limit,
+            query_log: QueryLogConfig {
+                sink: query_log,
+                endpoint: query_log_endpoint,
+                sample_rate: query_log_sample_rate,
+            },
+            response: ResponseFormatConfig {
+                pretty: pretty_responses,
+                content_type: response_content_type,
+                result_projection: result_fields,
+                stream_results,
+                explain,
+                error_format,
+            },
+            route_prefix,
+            scoped_search,
+            term_stats_route,
+            worker_middleware,
+            worker_prelude,
+        }) {
+            error!("{}", err);
+            exit(1);
+        };
+        return;
+    };
+
+    if let Err(err) = build(BuildConfig {
+        bundle,
+        bundle_compression,
+        bundle_only,
+        checkpoint,
+        chunk_layout,
+        chunk_read_concurrency,
+        chunk_read_retries,
+        chunk_read_retry_delay,
+        chunk_read_timeout,
+        chunk_read_timeout_behaviour,
         chunk_size,
+        compile_dir,
+        compiler,
+        corpus: open_corpus(corpus, document_terms, documents),
         data_store,
         data_store_url_prefix,
-        document_terms_source: File::open(document_terms).expect("open document terms file"),
-        documents_source: File::open(documents).expect("open documents file"),
+        deploy_target,
+        document_filters: document_filters.map(|path| open_input("document filters file", path)),
+        document_ids: document_ids.map(|path| open_input("document IDs file", path)),
+        document_route,
+        empty_result_behaviour,
+        expanded_terms_overflow,
+        group_keys: group_keys.map(|path| open_input("group keys file", path)),
+        group_scan_limit,
+        include_dirs,
+        intersection_strategy,
+        invalid_term_handling: invalid_terms,
+        kv_key_namespace,
+        long_term_handling: long_terms,
+        maximum_expanded_terms,
+        maximum_postings_cardinality,
+        maximum_prefix_expansions,
+        maximum_query_bytes,
         maximum_query_results,
         maximum_query_terms,
+        maximum_term_bytes,
+        minimum_query_bytes,
+        minimum_query_terms,
+        missing_terminator_handling: missing_terminators,
+        on_progress: None,
+        on_unknown_term,
+        openapi,
         output_dir,
-    });
+        oversized_document_handling: oversized_documents,
+        parse_threads,
+        per_document_compression,
+        postings: postings.map(|path| read_serialised_postings(&path, postings_document_count).unwrap_or_else(|err| exit_with_error(err))),
+        postings_threads,
+        precompression,
+        proximity_scan_limit,
+        query_log: QueryLogConfig {
+            sink: query_log,
+            endpoint: query_log_endpoint,
+            sample_rate: query_log_sample_rate,
+        },
+        range_fields: range_fields.into_iter().map(|(name, path)| RangeField {
+            name,
+            values_source: open_input("range field file", path),
+        }).collect(),
+        ranking: if bm25 { Ranking::Bm25 { k1: bm25_k1, b: bm25_b } } else { Ranking::BitmapOnly },
+        raw_clang_flags: clang_flags,
+        response: ResponseFormatConfig {
+            pretty: pretty_responses,
+            content_type: response_content_type,
+            result_projection: result_fields,
+            stream_results,
+            explain,
+            error_format,
+        },
+        route_prefix,
+        scoped_search,
+        simd,
+        size_report,
+        sort_keys: sort_keys.into_iter().map(|(name, path)| SortKey {
+            name,
+            values_source: open_input("sort key file", path),
+        }).collect(),
+        stats_json,
+        store_documents: !no_store_documents,
+        sysroot,
+        term_analyser: Analyser::new(analyser_stages),
+        term_blocks: term_blocks.map(|path| open_input("term blocks file", path)),
+        term_dictionary,
+        term_stats_route,
+        wasm_opt,
+        worker_middleware,
+        worker_prelude,
+        write_dictionary,
+        zstd_decoder,
+    }) {
+        error!("{}", err);
+        exit(1);
+    };
 }
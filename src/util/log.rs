@@ -1,7 +1,10 @@
+// Log progress at info level every `$interval` items, and at trace level for every other item.
 macro_rules! interval_log {
     ($interval:expr, $no:expr, $len:expr, $fmt:literal) => {
         if $no % $interval == 0 {
-            println!($fmt, percent($no as f64 / $len as f64));
+            ::log::info!($fmt, percent($no as f64 / $len as f64));
+        } else {
+            ::log::trace!($fmt, format!("{} of {}", $no, $len));
         };
     };
 }
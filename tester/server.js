@@ -2,14 +2,39 @@ const express = require('express');
 const fs = require('fs');
 const minimist = require('minimist');
 const path = require('path');
+const zlib = require('zlib');
 
 const args = minimist(process.argv.slice(2));
 
+const BUNDLE = args['bundle'];
 const OUTPUT_DIR = args['output-dir'];
 const PORT = args['port'];
 
-const workerScript = fs.readFileSync(path.join(OUTPUT_DIR, 'worker.js'), 'utf8');
-const runnerWasm = fs.readFileSync(path.join(OUTPUT_DIR, 'runner.wasm'));
+// Read all files in a tar archive, which may be gzip compressed, into a map from path to contents.
+const readBundle = file => {
+  let data = fs.readFileSync(file);
+  if (data[0] === 0x1f && data[1] === 0x8b) {
+    data = zlib.gunzipSync(data);
+  }
+  const files = new Map();
+  let pos = 0;
+  // Archive ends with empty records.
+  while (pos + 512 <= data.length && data[pos] !== 0) {
+    const name = data.slice(pos, pos + 100).toString('utf8').replace(/\0.*$/, '');
+    const size = Number.parseInt(data.slice(pos + 124, pos + 136).toString('ascii').replace(/\0.*$/, '').trim(), 8);
+    pos += 512;
+    files.set(name, data.slice(pos, pos + size));
+    pos += Math.ceil(size / 512) * 512;
+  }
+  return files;
+};
+
+const bundleFiles = BUNDLE && readBundle(BUNDLE);
+
+const readOutputFile = name => bundleFiles ? bundleFiles.get(name) : fs.readFileSync(path.join(OUTPUT_DIR, name));
+
+const workerScript = readOutputFile('worker.js').toString('utf8');
+const runnerWasm = readOutputFile('runner.wasm');
 
 let onFetch;
 
@@ -50,13 +75,41 @@ global.TransformStream = class TransformStream {
   }
 }
 
+// Map KV keys to chunk files, which may be nested in subfolders depending on the chunk layout.
+const chunkFiles = new Map();
+const findChunkFiles = (prefix, dir) => {
+  for (const entry of fs.readdirSync(dir, {withFileTypes: true})) {
+    const entryPath = path.join(dir, entry.name);
+    if (entry.isDirectory()) {
+      findChunkFiles(prefix, entryPath);
+    } else {
+      chunkFiles.set(`${prefix}/${entry.name}`, entryPath);
+    }
+  }
+};
+if (bundleFiles) {
+  for (const [name, contents] of bundleFiles) {
+    const parts = name.split('/');
+    if (['blocks', 'dictionaries', 'documents', 'groups', 'ids', 'sorts', 'terms'].includes(parts[0]) && parts.length > 1) {
+      chunkFiles.set(`${parts[0]}/${parts[parts.length - 1]}`, contents);
+    }
+  }
+} else {
+  for (const prefix of ['blocks', 'dictionaries', 'documents', 'groups', 'ids', 'sorts', 'terms']) {
+    findChunkFiles(prefix, path.join(OUTPUT_DIR, prefix));
+  }
+}
+
 global.KV = {
   async get (key) {
-    const [prefix, id] = key.split('/');
-    if (!['documents', 'terms'].includes(prefix) || !/^[0-9]+$/.test(id)) {
+    const file = chunkFiles.get(key);
+    if (!file) {
       throw new Error(`Unknown KV key: ${key}`);
     }
-    return readBuffer(path.join(OUTPUT_DIR, prefix, id));
+    // Bundled chunks are already in memory.
+    return bundleFiles
+      ? file.buffer.slice(file.byteOffset, file.byteOffset + file.byteLength)
+      : readBuffer(file);
   },
 };
 
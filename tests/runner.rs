@@ -0,0 +1,247 @@
+// Build small indexes with the CLI and run searches against them with the generated worker and runner in Node.js.
+// These need clang with the wasm32 target and Node.js, and are skipped with a message if either isn't installed.
+
+use std::collections::BTreeSet;
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use edgesearch::build::encode_scope;
+
+const DOCUMENT_COUNT: usize = 200;
+const TERMS: &[&str] = &["a", "b", "c", "d", "e", "f", "g", "h"];
+
+// Load a built worker with its runner and chunks from the output folder, and print the response body of each search, one per line.
+const SEARCH_JS: &str = r#"
+    const fs = require('fs');
+    const path = require('path');
+    const [outputDir, ...queries] = process.argv.slice(1);
+    // The worker logs chunk fetches, which must not be mixed with the responses.
+    console.log = (...args) => console.error(...args);
+    global.QUERY_RUNNER_WASM = new WebAssembly.Module(fs.readFileSync(path.join(outputDir, 'runner.wasm')));
+    global.KV = {
+      async get (key) {
+        const data = fs.readFileSync(path.join(outputDir, key));
+        return data.buffer.slice(data.byteOffset, data.byteOffset + data.byteLength);
+      },
+    };
+    global.self = {addEventListener () {}};
+    const requestHandler = Function(`${fs.readFileSync(path.join(outputDir, 'worker.js'), 'utf8')}\nreturn requestHandler;`)();
+    (async () => {
+      for (const query of queries) {
+        const response = await requestHandler(new Request(`https://localhost/search?${query}`));
+        process.stdout.write(`${(await response.text()).replace(/\n/g, ' ')}\n`);
+      }
+    })();
+"#;
+
+// A search and the terms each document must have to match it, following the formula in the README.
+struct Search {
+    query: &'static str,
+    require: &'static [&'static str],
+    contain: &'static [&'static str],
+    exclude: &'static [&'static str],
+    groups: &'static [&'static [&'static str]],
+}
+
+const SEARCHES: &[Search] = &[
+    Search { query: "t=0_a", require: &["a"], contain: &[], exclude: &[], groups: &[] },
+    Search { query: "t=0_a&t=0_b", require: &["a", "b"], contain: &[], exclude: &[], groups: &[] },
+    // The rarest term last, so that reordering by cardinality changes the order of intersection.
+    Search { query: "t=0_a&t=0_c&t=0_h", require: &["a", "c", "h"], contain: &[], exclude: &[], groups: &[] },
+    Search { query: "t=0_h&t=0_g&t=0_a&t=0_b", require: &["h", "g", "a", "b"], contain: &[], exclude: &[], groups: &[] },
+    Search { query: "t=1_g&t=1_h", require: &[], contain: &["g", "h"], exclude: &[], groups: &[] },
+    Search { query: "t=0_a&t=2_b", require: &["a"], contain: &[], exclude: &["b"], groups: &[] },
+    Search { query: "t=0_a&t=0_b&t=1_c&t=1_d&t=2_e", require: &["a", "b"], contain: &["c", "d"], exclude: &["e"], groups: &[] },
+    Search { query: "g=0_a&g=0_f&g=1_b&g=1_g", require: &[], contain: &[], exclude: &[], groups: &[&["a", "f"], &["b", "g"]] },
+    Search { query: "t=0_c&g=0_a&g=0_b&g=1_d", require: &["c"], contain: &[], exclude: &[], groups: &[&["a", "b"], &["d"]] },
+    // Intersections that become empty part way through.
+    Search { query: "t=0_g&t=0_h&t=0_a&t=0_b&t=0_c", require: &["g", "h", "a", "b", "c"], contain: &[], exclude: &[], groups: &[] },
+];
+
+// Whether document `id` has the `i`-th term. Each term is in fewer documents than the one before it, from about two in three to one in nine.
+fn has_term(id: usize, i: usize) -> bool {
+    let hash = (id as u64 + 1).wrapping_mul(2654435761).wrapping_add(i as u64 * 40503) % 1009;
+    hash.is_multiple_of(i as u64 + 2) || (i == 0 && id.is_multiple_of(3))
+}
+
+fn document_terms(id: usize) -> BTreeSet<&'static str> {
+    TERMS.iter().enumerate().filter(|(i, _)| has_term(id, *i)).map(|(_, term)| *term).collect()
+}
+
+// The IDs of the documents matching a search, found by checking every document.
+fn expected_results(search: &Search) -> Vec<usize> {
+    (0..DOCUMENT_COUNT).filter(|id| {
+        let terms = document_terms(*id);
+        search.require.iter().all(|t| terms.contains(t))
+            && (search.contain.is_empty() || search.contain.iter().any(|t| terms.contains(t)))
+            && (search.groups.is_empty() || search.groups.iter().any(|group| group.iter().all(|t| terms.contains(t))))
+            && !search.exclude.iter().any(|t| terms.contains(t))
+    }).collect()
+}
+
+// Get the IDs of the results in a response body, as every document is `{"id":N}`.
+fn result_ids(body: &str) -> Vec<usize> {
+    body.split("{\"id\":").skip(1).map(|rest| rest[..rest.find('}').unwrap()].parse().unwrap()).collect()
+}
+
+fn installed(program: &str) -> bool {
+    match Command::new(program).arg("--version").output() {
+        Ok(_) => true,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            eprintln!("{} not found, skipping", program);
+            false
+        }
+        Err(err) => panic!("Failed to run {}: {}", program, err),
+    }
+}
+
+// Write the documents and document terms of the test corpus to a new folder named `name`, and return it.
+fn write_corpus(name: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("edgesearch-runner-test-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    let mut documents = String::new();
+    let mut terms = String::new();
+    for id in 0..DOCUMENT_COUNT {
+        documents.push_str(&format!("{{\"id\":{}}}\0", id));
+        for term in document_terms(id) {
+            terms.push_str(term);
+            terms.push('\0');
+        };
+        terms.push('\0');
+    };
+    write(dir.join("documents"), documents).unwrap();
+    write(dir.join("document-terms"), terms).unwrap();
+    dir
+}
+
+// Build the corpus in `dir` into `dir/{name}` with extra CLI arguments, and return the output folder.
+fn build(dir: &Path, name: &str, args: &[&str]) -> PathBuf {
+    let output_dir = dir.join(name);
+    create_dir_all(&output_dir).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_edgesearch"))
+        .arg("--documents").arg(dir.join("documents"))
+        .arg("--document-terms").arg(dir.join("document-terms"))
+        .arg("--output-dir").arg(&output_dir)
+        .args(["--data-store", "kv", "--maximum-query-results", "255", "--maximum-query-terms", "10"])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "Build {} failed", name);
+    output_dir
+}
+
+// Run each query against the build in `output_dir`, and return the response body of each.
+fn search<Q: AsRef<str>>(output_dir: &Path, queries: &[Q]) -> Vec<String> {
+    let output = Command::new("node")
+        .arg("-e").arg(SEARCH_JS)
+        .arg(output_dir)
+        .args(queries.iter().map(|query| query.as_ref()))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "Searching build {} failed:\n{}", output_dir.display(), String::from_utf8_lossy(&output.stderr));
+    let bodies = String::from_utf8(output.stdout).unwrap().lines().map(|line| line.to_string()).collect::<Vec<_>>();
+    assert_eq!(bodies.len(), queries.len());
+    bodies
+}
+
+// Build the corpus in `dir` into `dir/{name}` with extra CLI arguments, and return the response body of each search.
+fn build_and_search(dir: &Path, name: &str, args: &[&str]) -> Vec<String> {
+    let output_dir = build(dir, name, args);
+    search(&output_dir, &SEARCHES.iter().map(|search| search.query).collect::<Vec<_>>())
+}
+
+#[test]
+fn results_match_every_document_checked_directly() {
+    if !installed("clang") || !installed("node") {
+        return;
+    };
+    let dir = write_corpus("default");
+    let bodies = build_and_search(&dir, "default", &[]);
+    for (search, body) in SEARCHES.iter().zip(bodies.iter()) {
+        let mut ids = result_ids(body);
+        ids.sort_unstable();
+        assert_eq!(ids, expected_results(search), "search {} returned {}", search.query, body);
+    };
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn intersection_strategies_return_identical_results() {
+    if !installed("clang") || !installed("node") {
+        return;
+    };
+    let dir = write_corpus("strategies");
+    let strategies = ["SmallestFirst", "RoaringAnd", "Probe"];
+    let bodies = strategies.iter()
+        .map(|strategy| build_and_search(&dir, strategy, &["--intersection-strategy", strategy]))
+        .collect::<Vec<_>>();
+    for (strategy, strategy_bodies) in strategies.iter().zip(bodies.iter()).skip(1) {
+        for (i, search) in SEARCHES.iter().enumerate() {
+            assert_eq!(strategy_bodies[i], bodies[0][i], "{} and {} returned different results for search {}", strategy, strategies[0], search.query);
+        };
+    };
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn simd_runner_returns_identical_results() {
+    if !installed("clang") || !installed("node") {
+        return;
+    };
+    let dir = write_corpus("simd");
+    let scalar = build_and_search(&dir, "scalar", &[]);
+    let simd = build_and_search(&dir, "simd", &["--simd"]);
+    for (i, search) in SEARCHES.iter().enumerate() {
+        assert_eq!(simd[i], scalar[i], "SIMD runner returned different results for search {}", search.query);
+    };
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn scoped_searches_only_match_documents_in_scope() {
+    if !installed("clang") || !installed("node") {
+        return;
+    };
+    let dir = write_corpus("scoped");
+    let output_dir = build(&dir, "scoped", &["--scoped-search"]);
+    // Consecutive and scattered IDs, and IDs past the last document which are ignored.
+    let scope = (20..60).chain((0..DOCUMENT_COUNT as u32).filter(|id| id % 7 == 3)).chain([199, 250, 251]).collect::<Vec<u32>>();
+    let encoded = encode_scope(&scope);
+    let queries = SEARCHES.iter().map(|search| format!("{}&i={}", search.query, encoded)).collect::<Vec<_>>();
+    for (search, body) in SEARCHES.iter().zip(search(&output_dir, &queries).iter()) {
+        let mut ids = result_ids(body);
+        ids.sort_unstable();
+        let expected = expected_results(search).into_iter().filter(|id| scope.contains(&(*id as u32))).collect::<Vec<_>>();
+        assert_eq!(ids, expected, "search {} scoped to {} returned {}", search.query, encoded, body);
+    };
+    // A scope with only IDs past the last document matches nothing.
+    let outside = search(&output_dir, &[format!("t=0_a&i={}", encode_scope(&[DOCUMENT_COUNT as u32, 1000]))]);
+    assert!(result_ids(&outside[0]).is_empty(), "search scoped past the last document returned {}", outside[0]);
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unknown_term_error_names_the_unknown_term() {
+    if !installed("clang") || !installed("node") {
+        return;
+    };
+    let dir = write_corpus("unknown");
+    // Small chunks put the terms in different chunks, and fetching one chunk at a time makes the order chunks are fetched in fixed.
+    let output_dir = build(&dir, "unknown", &["--on-unknown-term", "Error", "--chunk-size", "512", "--chunk-read-concurrency", "1"]);
+    let queries = [
+        // "hz" is in the chunk of "h", so would be found missing before the chunk of "a" is fetched.
+        ("t=0_h&t=0_a&t=0_hz", "hz"),
+        ("t=0_a&t=0_hz&t=0_b", "hz"),
+        // "0" sorts before every term, so it's looked for in the first chunk with "a".
+        ("t=0_a&t=0_h&t=0_0", "0"),
+    ];
+    let bodies = search(&output_dir, &queries.iter().map(|(query, _)| *query).collect::<Vec<_>>());
+    for ((query, unknown), body) in queries.iter().zip(bodies.iter()) {
+        assert!(body.contains(&format!("Unknown term \\\"{}\\\"", unknown)), "search {} returned {}", query, body);
+    };
+    remove_dir_all(&dir).unwrap();
+}
@@ -27,6 +27,16 @@ bst_chunk_ref_t DOCUMENTS_CHUNKS[] = {
   ___DOCUMENTS_CHUNKS___
 };
 uint32_t DOCUMENTS_CHUNKS_LEN = ___DOCUMENTS_CHUNKS_LEN___;
+// Empty unless terms have block tables.
+bst_chunk_ref_t BLOCKS_CHUNKS[] = {
+  ___BLOCKS_CHUNKS___
+};
+uint32_t BLOCKS_CHUNKS_LEN = ___BLOCKS_CHUNKS_LEN___;
+// Empty unless documents have filters.
+bst_chunk_ref_t FILTERS_CHUNKS[] = {
+  ___FILTERS_CHUNKS___
+};
+uint32_t FILTERS_CHUNKS_LEN = ___FILTERS_CHUNKS_LEN___;
 
 static inline int compare_int(int a, int b) {
   return (a > b) - (a < b);
@@ -80,6 +90,29 @@ WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_term(char* term, uint8_t term
   return find_chunk(NORMAL_TERMS_CHUNKS, NORMAL_TERMS_CHUNKS_LEN, KEY_STR, key);
 }
 
+// Chunks are in key order, so terms can be enumerated in order by getting chunks with increasing IDs. Returns NULL after the last chunk.
+WASM_EXPORT bst_chunk_ref_t* find_term_chunk(uint32_t id) {
+  return id < NORMAL_TERMS_CHUNKS_LEN ? &NORMAL_TERMS_CHUNKS[id] : NULL;
+}
+
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_term_blocks(char* term, uint8_t term_len) {
+  str_t term_str;
+  term_str.len = term_len;
+  term_str.val = term;
+  str_or_uint32_t key;
+  key.strval = term_str;
+  return find_chunk(BLOCKS_CHUNKS, BLOCKS_CHUNKS_LEN, KEY_STR, key);
+}
+
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_filter(char* value, uint8_t value_len) {
+  str_t value_str;
+  value_str.len = value_len;
+  value_str.val = value;
+  str_or_uint32_t key;
+  key.strval = value_str;
+  return find_chunk(FILTERS_CHUNKS, FILTERS_CHUNKS_LEN, KEY_STR, key);
+}
+
 WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_doc(doc_id_t doc) {
   str_or_uint32_t key;
   key.intval = doc;
@@ -9,21 +9,87 @@ typedef enum {
 
 // This should be called before every query.
 WASM_EXPORT void reset(void) {
-  heap = &__heap_base;
+  heap = heap_start;
 }
 
+// The documents with a value of a numeric field in a range.
+// Keep in sync with build::range::serialise_range_table.
+typedef struct {
+  // document ID => rank.
+  uint32_t const* ranks;
+  // rank => document ID.
+  uint32_t const* order;
+  uint32_t document_count;
+  // The range covers the ranks from `start` inclusive to `end` exclusive.
+  uint32_t start;
+  uint32_t end;
+} index_range_t;
+
 typedef struct {
   uint32_t first_rank;
+  // Whether to return documents in descending instead of ascending order.
+  uint32_t sort_descending;
+  // If not NULL, documents are ordered by their rank in these tables instead of by document ID.
+  // Keep in sync with build::sort::serialise_sort_table.
+  // document ID => rank.
+  uint32_t const* sort_ranks;
+  // rank => document ID.
+  uint32_t const* sort_order;
+  // If not NULL, at most one document is returned per group, keeping the highest ranked, and `first_rank` counts collapsed results.
+  // Keep in sync with build::groups::serialise_group_table.
+  // document ID => group ID.
+  uint32_t const* group_ids;
+  // Maximum amount of matching documents, in rank order, to scan when collapsing by group.
+  uint32_t group_scan_limit;
+  // Maximum amount of matching documents, in rank order, to boost by proximity, or zero to not boost.
+  // Documents where more query terms share a block are moved ahead of the others, which otherwise keep their order.
+  uint32_t proximity_scan_limit;
+  // Amount of block tables in `proximity_tables`.
+  uint32_t proximity_table_count;
+  // Length of and pointer to the block table of each query term that has one.
+  // Keep in sync with build::blocks::pack_block_tables.
+  uint32_t const* proximity_tables;
+  // Amount of filter values in `filters`.
+  uint32_t filter_count;
+  // Length of and pointer to the serialised postings list of each filter value, which every matching document must have.
+  // Keep in sync with build::filters::pack_filters.
+  uint32_t const* filters;
+  // Amount of ranges in `ranges`.
+  uint32_t range_count;
+  // Ranges of numeric fields that every matching document must have a value in.
+  index_range_t const* ranges;
+  // Amount of terms in `prefix_expansions`.
+  uint32_t prefix_expansion_count;
+  // Length of and pointer to the serialised postings list of each indexed term starting with the prefix term, which every matching document must have one of.
+  uint32_t const* prefix_expansions;
+  // Amount of ranges in `scope`, or zero if the search isn't scoped.
+  uint32_t scope_range_count;
+  // Start inclusive and end exclusive of each range of document IDs the search is scoped to, which every matching document must be in one of.
+  // Keep in sync with build::scope::encode_scope.
+  uint32_t const* scope;
+  // If not NULL, matching documents are ordered by descending BM25 score instead of by document ID, unless ordered by a sort key.
+  // document ID => BM25 length normalisation.
+  // Keep in sync with build::ranking::serialise_bm25_table.
+  float const* bm25_table;
+  // Amount of terms in `bm25_terms` and `bm25_idfs`.
+  uint32_t bm25_term_count;
+  // Length of and pointer to the serialised postings list of each query term that documents are scored by.
+  uint32_t const* bm25_terms;
+  // BM25 inverse document frequency of each term in `bm25_terms`.
+  float const* bm25_idfs;
   // This is a flattened form of (size_t, byte*)[][].
   // There's a subarray for each mode, and they are ordered according to their numeric value (see mode_t).
   // Each mode contains array lengths followed by pointers to byte arrays containing serialised Roaring Bitmaps representing a term.
   // Each mode is terminated by NULL.
+  // The modes are followed by groups, where each group is a conjunction of terms and the groups are combined using OR.
+  // Each group is terminated by NULL, and the groups are terminated by an additional NULL.
   // For example: `{
   //   200, &bitmapForHello, 100, &bitmapForWorld, NULL,
   //   60, &bitmapForThe, 130, &bitmapForQuick, 140, &bitmapForFox, NULL,
   //   5, &bitmapForAstronaut, NULL,
+  //   20, &bitmapForRed, 40, &bitmapForApple, NULL, 30, &bitmapForBlue, NULL, NULL,
   // }`.
-  uint32_t serialised[MAX_QUERY_TERMS * 2 + 3];
+  uint32_t serialised[MAX_QUERY_TERMS * 3 + 4];
 } index_query_t;
 
 // Result of a query executed within WASM.
@@ -42,6 +108,25 @@ uint32_t min(uint32_t a, uint32_t b) {
   return a < b ? a : b;
 }
 
+// Get the amount of documents in a serialised postings list, which is the document frequency of its term.
+WASM_EXPORT uint32_t postings_list_cardinality(char const* serialised, size_t serialised_size) {
+  roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize_safe(serialised, serialised_size);
+  return bitmap == NULL ? 0 : roaring_bitmap_get_cardinality(bitmap);
+}
+
+// Function to be called from JS to explain which results have a term. Sets `out[i]` to whether `documents[i]` is in a serialised postings list.
+// Returns false if the postings list could not be deserialised.
+WASM_EXPORT bool postings_list_contains_each(char const* serialised, size_t serialised_size, doc_id_t const* documents, uint32_t count, uint8_t* out) {
+  roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize_safe(serialised, serialised_size);
+  if (bitmap == NULL) {
+    return false;
+  }
+  for (uint32_t i = 0; i < count; i++) {
+    out[i] = roaring_bitmap_contains(bitmap, documents[i]);
+  }
+  return true;
+}
+
 // Function to be called from JS that allocates enough memory for a query and returns the pointer to it.
 WASM_EXPORT index_query_t* index_query_malloc(void) {
   return malloc(sizeof(index_query_t));
@@ -69,22 +154,416 @@ inline roaring_bitmap_t* index_deserialise_and_combine(roaring_bitmap_t** deseri
   return NULL;
 }
 
+typedef struct {
+  uint32_t const* ranks;
+  roaring_bitmap_t* ranked;
+} index_rank_iterator_state_t;
+
+bool index_rank_iterator(uint32_t document_id, void* param) {
+  index_rank_iterator_state_t* state = param;
+  roaring_bitmap_add(state->ranked, state->ranks[document_id]);
+  return true;
+}
+
+// Algorithms for intersecting required terms and the terms of each group, selected at compile time using the INTERSECTION_STRATEGY macro.
+// Keep in sync with build::wasm::IntersectionStrategy.
+// - SMALLEST_FIRST: roaring AND of bitmaps in ascending order of cardinality, stopping early if the intersection becomes empty.
+// - ROARING_AND: roaring AND of bitmaps in query order.
+// - PROBE: check each document of the smallest bitmap against the other bitmaps, which avoids visiting containers the smallest bitmap doesn't have and suits many small postings lists.
+#define INTERSECTION_STRATEGY_SMALLEST_FIRST 0
+#define INTERSECTION_STRATEGY_ROARING_AND 1
+#define INTERSECTION_STRATEGY_PROBE 2
+#ifndef INTERSECTION_STRATEGY
+#define INTERSECTION_STRATEGY INTERSECTION_STRATEGY_SMALLEST_FIRST
+#endif
+
+// Internal function used to deserialise and intersect multiple bitmaps from a `index_query_t->serialised` value, with the same arguments as `index_deserialise_and_combine`.
+// Every strategy returns the same intersection. Except for PROBE, the intersection is done in place on one of the deserialised bitmaps.
+// If there are no bitmaps to intersect, NULL is returned instead.
+static inline roaring_bitmap_t* index_deserialise_and_intersect(uint32_t* query_data, size_t* query_data_next) {
+  // Repurpose query data array for storing pointers to deserialised bitmaps.
+  // This is safe as each pointer is written after the two elements it replaces have been read.
+  roaring_bitmap_t** deserialised_holding = (roaring_bitmap_t**) &query_data[*query_data_next];
+#if INTERSECTION_STRATEGY != INTERSECTION_STRATEGY_ROARING_AND
+  uint64_t cardinalities[MAX_QUERY_TERMS];
+#endif
+  size_t count = 0;
+  while (query_data[*query_data_next]) {
+    size_t serialised_size = query_data[*query_data_next];
+    char const* serialised = (char const*) query_data[*query_data_next + 1];
+    roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize_safe(serialised, serialised_size);
+#if INTERSECTION_STRATEGY == INTERSECTION_STRATEGY_ROARING_AND
+    deserialised_holding[count] = bitmap;
+#else
+    uint64_t cardinality = roaring_bitmap_get_cardinality(bitmap);
+    // Insertion sort, as there are only a few terms.
+    size_t j = count;
+    while (j > 0 && cardinalities[j - 1] > cardinality) {
+      deserialised_holding[j] = deserialised_holding[j - 1];
+      cardinalities[j] = cardinalities[j - 1];
+      j--;
+    }
+    deserialised_holding[j] = bitmap;
+    cardinalities[j] = cardinality;
+#endif
+    count++;
+    *query_data_next += 2;
+  }
+  (*query_data_next)++;
+  if (!count) {
+    return NULL;
+  }
+  roaring_bitmap_t* intersection = deserialised_holding[0];
+#if INTERSECTION_STRATEGY == INTERSECTION_STRATEGY_PROBE
+  if (count == 1) {
+    return intersection;
+  }
+  uint32_t* candidates = malloc(cardinalities[0] * sizeof(uint32_t));
+  roaring_bitmap_to_uint32_array(intersection, candidates);
+  // Candidates are in ascending order, so matches can be compacted in place.
+  size_t matched = 0;
+  for (size_t c = 0; c < cardinalities[0]; c++) {
+    bool in_all = true;
+    for (size_t j = 1; j < count && in_all; j++) {
+      in_all = roaring_bitmap_contains(deserialised_holding[j], candidates[c]);
+    }
+    if (in_all) {
+      candidates[matched++] = candidates[c];
+    }
+  }
+  intersection = roaring_bitmap_of_ptr(matched, candidates);
+  free(candidates);
+#elif INTERSECTION_STRATEGY == INTERSECTION_STRATEGY_ROARING_AND
+  for (size_t j = 1; j < count; j++) {
+    roaring_bitmap_and_inplace(intersection, deserialised_holding[j]);
+  }
+#else
+  for (size_t j = 1; j < count && !roaring_bitmap_is_empty(intersection); j++) {
+    roaring_bitmap_and_inplace(intersection, deserialised_holding[j]);
+  }
+#endif
+  return intersection;
+}
+
+// Internal function used to deserialise and intersect the postings lists of `index_query_t->filters`.
+// If there are no filters, NULL is returned instead.
+static roaring_bitmap_t* index_intersect_filters(index_query_t const* query) {
+  roaring_bitmap_t* intersection = NULL;
+  for (uint32_t f = 0; f < query->filter_count; f++) {
+    roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize_safe((char const*) query->filters[f * 2 + 1], query->filters[f * 2]);
+    if (intersection == NULL) {
+      intersection = bitmap;
+    } else {
+      roaring_bitmap_and_inplace(intersection, bitmap);
+    }
+    if (roaring_bitmap_is_empty(intersection)) {
+      break;
+    }
+  }
+  return intersection;
+}
+
+// Internal function used to create a bitmap of the document IDs in `index_query_t->scope`.
+// If the search isn't scoped, NULL is returned instead.
+static roaring_bitmap_t* index_create_scope(index_query_t const* query) {
+  if (!query->scope_range_count) {
+    return NULL;
+  }
+  roaring_bitmap_t* bitmap = roaring_bitmap_create();
+  for (uint32_t s = 0; s < query->scope_range_count; s++) {
+    roaring_bitmap_add_range(bitmap, query->scope[s * 2], query->scope[s * 2 + 1]);
+  }
+  return bitmap;
+}
+
+// Internal function used to deserialise and combine using OR the postings lists of `index_query_t->prefix_expansions`.
+// If the query has no prefix term, NULL is returned instead.
+static roaring_bitmap_t* index_combine_prefix_expansions(index_query_t const* query) {
+  uint32_t count = query->prefix_expansion_count;
+  if (!count) {
+    return NULL;
+  }
+  roaring_bitmap_t** bitmaps = malloc(count * sizeof(roaring_bitmap_t*));
+  for (uint32_t e = 0; e < count; e++) {
+    bitmaps[e] = roaring_bitmap_portable_deserialize_safe((char const*) query->prefix_expansions[e * 2 + 1], query->prefix_expansions[e * 2]);
+  }
+  roaring_bitmap_t* combined = roaring_bitmap_or_many(count, (roaring_bitmap_t const**) bitmaps);
+  free(bitmaps);
+  return combined;
+}
+
+// Internal function used to narrow `result` to the documents in a range, or to get every document in the range if `result` is NULL.
+// Ranges can cover most documents, so to bound the work, the rank of each result is checked if there are fewer results than documents in or out of the range.
+// Otherwise, whichever of the documents in and out of the range are fewer are intersected with or removed from the result.
+static roaring_bitmap_t* index_apply_range(index_range_t const* range, roaring_bitmap_t* result) {
+  uint32_t in_range = range->end - range->start;
+  uint32_t out_of_range = range->document_count - in_range;
+  if (result != NULL && roaring_bitmap_get_cardinality(result) <= min(in_range, out_of_range)) {
+    uint32_t count = (uint32_t) roaring_bitmap_get_cardinality(result);
+    uint32_t* candidates = malloc(count * sizeof(uint32_t));
+    roaring_bitmap_to_uint32_array(result, candidates);
+    // Candidates are in ascending order, so matches can be compacted in place.
+    size_t matched = 0;
+    for (size_t c = 0; c < count; c++) {
+      uint32_t rank = range->ranks[candidates[c]];
+      if (rank >= range->start && rank < range->end) {
+        candidates[matched++] = candidates[c];
+      }
+    }
+    roaring_bitmap_t* narrowed = roaring_bitmap_of_ptr(matched, candidates);
+    free(candidates);
+    return narrowed;
+  }
+  if (in_range <= out_of_range) {
+    roaring_bitmap_t* documents = roaring_bitmap_of_ptr(in_range, &range->order[range->start]);
+    if (result == NULL) return documents;
+    roaring_bitmap_and_inplace(result, documents);
+    return result;
+  }
+  roaring_bitmap_t* excluded = roaring_bitmap_of_ptr(range->start, range->order);
+  roaring_bitmap_add_many(excluded, range->document_count - range->end, &range->order[range->end]);
+  if (result == NULL) result = roaring_bitmap_from_range(0, range->document_count, 1);
+  roaring_bitmap_andnot_inplace(result, excluded);
+  return result;
+}
+
+results_t* index_empty_results(void) {
+  results_t* results = malloc(sizeof(results_t));
+  results->continuation = -1;
+  results->total = 0;
+  results->count = 0;
+  return results;
+}
+
+// Matching documents in result order.
+typedef struct {
+  index_query_t const* query;
+  // Document IDs, or ranks if sorted.
+  roaring_bitmap_t const* bitmap;
+  uint32_t count;
+  // If not NULL, the documents at the first positions after boosting by proximity, replacing those that would otherwise be there.
+  doc_id_t const* boosted;
+  uint32_t boosted_count;
+  // If not NULL, every matching document in order of relevance, which replaces the order of `bitmap`.
+  doc_id_t const* scored;
+} index_ordered_t;
+
+// Write the IDs of the documents at positions [pos, pos + len) in result order to `out`.
+static void index_ordered_range(index_ordered_t const* ordered, uint32_t pos, uint32_t len, doc_id_t* out) {
+  while (len && pos < ordered->boosted_count) {
+    *out++ = ordered->boosted[pos++];
+    len--;
+  }
+  if (!len) {
+    return;
+  }
+  if (ordered->scored != NULL) {
+    for (uint32_t j = 0; j < len; j++) {
+      out[j] = ordered->scored[pos + j];
+    }
+    return;
+  }
+  index_query_t const* query = ordered->query;
+  if (query->sort_descending) {
+    // Get the same range counting from the end, then reverse it.
+    roaring_bitmap_range_uint32_array(ordered->bitmap, ordered->count - pos - len, len, out);
+    for (uint32_t j = 0; j < len / 2; j++) {
+      doc_id_t tmp = out[j];
+      out[j] = out[len - 1 - j];
+      out[len - 1 - j] = tmp;
+    }
+  } else {
+    roaring_bitmap_range_uint32_array(ordered->bitmap, pos, len, out);
+  }
+  if (query->sort_order != NULL) {
+    for (uint32_t j = 0; j < len; j++) {
+      out[j] = query->sort_order[out[j]];
+    }
+  }
+}
+
+// Find the block a term is in within a document using its block table, which has the document IDs in ascending order followed by the block in each.
+static bool index_find_block(uint32_t table_len, uint32_t const* table, doc_id_t document, uint32_t* block) {
+  uint32_t entries = table_len / 8;
+  uint32_t lo = 0, hi = entries;
+  while (lo < hi) {
+    uint32_t mid = lo + (hi - lo) / 2;
+    if (table[mid] < document) lo = mid + 1;
+    else hi = mid;
+  }
+  if (lo == entries || table[lo] != document) {
+    return false;
+  }
+  *block = table[entries + lo];
+  return true;
+}
+
+// Get the largest amount of query terms in the same block of a document, or zero if no terms share a block.
+// `tables` has the length of and pointer to each of `table_count` block tables, like `index_query_t->proximity_tables`.
+static uint32_t index_proximity_score_tables(uint32_t table_count, uint32_t const* tables, doc_id_t document) {
+  uint32_t blocks[MAX_QUERY_TERMS];
+  uint32_t found = 0;
+  uint32_t best = 0;
+  for (uint32_t t = 0; t < table_count; t++) {
+    uint32_t block;
+    if (!index_find_block(tables[t * 2], (uint32_t const*) tables[t * 2 + 1], document, &block)) {
+      continue;
+    }
+    uint32_t shared = 1;
+    for (uint32_t j = 0; j < found; j++) {
+      if (blocks[j] == block) shared++;
+    }
+    blocks[found++] = block;
+    best = shared > best ? shared : best;
+  }
+  return best >= 2 ? best : 0;
+}
+
+static uint32_t index_proximity_score(index_query_t const* query, doc_id_t document) {
+  return index_proximity_score_tables(query->proximity_table_count, query->proximity_tables, document);
+}
+
+// Function to be called from JS to explain the proximity score a result was boosted by.
+WASM_EXPORT uint32_t index_explain_proximity(uint32_t table_count, uint32_t const* tables, doc_id_t document) {
+  return index_proximity_score_tables(table_count, tables, document);
+}
+
+// Move the documents where more query terms share a block ahead of the others, within the first `query->proximity_scan_limit` matches.
+// This is a stable counting sort by descending score, as scores are at most MAX_QUERY_TERMS.
+static void index_boost_proximity(index_ordered_t* ordered) {
+  index_query_t const* query = ordered->query;
+  uint32_t scan_count = min(ordered->count, query->proximity_scan_limit);
+  doc_id_t* documents = malloc(scan_count * sizeof(doc_id_t));
+  uint32_t* scores = malloc(scan_count * sizeof(uint32_t));
+  uint32_t score_starts[MAX_QUERY_TERMS + 1] = {0};
+  index_ordered_range(ordered, 0, scan_count, documents);
+  for (uint32_t j = 0; j < scan_count; j++) {
+    scores[j] = index_proximity_score(query, documents[j]);
+    score_starts[scores[j]]++;
+  }
+  uint32_t next = 0;
+  for (int32_t score = MAX_QUERY_TERMS; score >= 0; score--) {
+    uint32_t count = score_starts[score];
+    score_starts[score] = next;
+    next += count;
+  }
+  doc_id_t* boosted = malloc(scan_count * sizeof(doc_id_t));
+  for (uint32_t j = 0; j < scan_count; j++) {
+    boosted[score_starts[scores[j]]++] = documents[j];
+  }
+  free(documents);
+  free(scores);
+  ordered->boosted = boosted;
+  ordered->boosted_count = scan_count;
+}
+
+// Sort documents by descending score and return them, using a stable merge sort so that documents with the same score stay in ascending document ID order.
+// `documents` and `scores` are used as scratch space, so their contents are undefined afterwards.
+static doc_id_t* index_sort_by_score(doc_id_t* documents, float* scores, uint32_t count) {
+  doc_id_t* documents_out = malloc(count * sizeof(doc_id_t));
+  float* scores_out = malloc(count * sizeof(float));
+  for (uint32_t width = 1; width < count; width *= 2) {
+    for (uint32_t lo = 0; lo < count; lo += 2 * width) {
+      uint32_t mid = min(lo + width, count);
+      uint32_t hi = min(lo + 2 * width, count);
+      uint32_t a = lo, b = mid, next = lo;
+      while (a < mid || b < hi) {
+        // Take from the left run on ties to keep the sort stable.
+        uint32_t from = b == hi || (a < mid && scores[a] >= scores[b]) ? a++ : b++;
+        documents_out[next] = documents[from];
+        scores_out[next] = scores[from];
+        next++;
+      }
+    }
+    doc_id_t* documents_tmp = documents;
+    documents = documents_out;
+    documents_out = documents_tmp;
+    float* scores_tmp = scores;
+    scores = scores_out;
+    scores_out = scores_tmp;
+  }
+  return documents;
+}
+
+// Order every matching document by descending BM25 score, which is the sum of the IDFs of the scored terms it contains multiplied by its length normalisation.
+// Terms are unique within a document, so each term found in a document adds its IDF once.
+static void index_rank_bm25(index_ordered_t* ordered) {
+  index_query_t const* query = ordered->query;
+  uint32_t count = ordered->count;
+  doc_id_t* documents = malloc(count * sizeof(doc_id_t));
+  float* scores = malloc(count * sizeof(float));
+  roaring_bitmap_to_uint32_array(ordered->bitmap, documents);
+  for (uint32_t j = 0; j < count; j++) {
+    scores[j] = 0;
+  }
+  for (uint32_t t = 0; t < query->bm25_term_count; t++) {
+    roaring_bitmap_t* matched = roaring_bitmap_portable_deserialize_safe((char const*) query->bm25_terms[t * 2 + 1], query->bm25_terms[t * 2]);
+    if (matched == NULL) {
+      continue;
+    }
+    roaring_bitmap_and_inplace(matched, ordered->bitmap);
+    uint32_t matched_count = (uint32_t) roaring_bitmap_get_cardinality(matched);
+    doc_id_t* matched_documents = malloc(matched_count * sizeof(doc_id_t));
+    roaring_bitmap_to_uint32_array(matched, matched_documents);
+    // Both are in ascending order, and every matched document is a matching document, so they can be merged.
+    uint32_t j = 0;
+    for (uint32_t m = 0; m < matched_count; m++) {
+      while (documents[j] != matched_documents[m]) j++;
+      scores[j] += query->bm25_idfs[t];
+    }
+  }
+  for (uint32_t j = 0; j < count; j++) {
+    scores[j] *= query->bm25_table[documents[j]];
+  }
+  ordered->scored = index_sort_by_score(documents, scores, count);
+}
+
+#define COLLAPSE_BATCH_SIZE 256
+
+// Fill `results` with the page of documents starting at `query->first_rank` after collapsing matches by group.
+// Every earlier match must be scanned to know which groups have already appeared, so only the first `query->group_scan_limit` matches are considered.
+static void index_collapse_groups(index_ordered_t const* ordered, results_t* results) {
+  index_query_t const* query = ordered->query;
+  roaring_bitmap_t* seen_groups = roaring_bitmap_create();
+  doc_id_t batch[COLLAPSE_BATCH_SIZE];
+  uint32_t scan_count = min(ordered->count, query->group_scan_limit);
+  uint32_t collapsed_rank = 0;
+  uint32_t count = 0;
+  bool more = false;
+  for (uint32_t pos = 0; pos < scan_count && !more; pos += COLLAPSE_BATCH_SIZE) {
+    uint32_t batch_len = min(COLLAPSE_BATCH_SIZE, scan_count - pos);
+    index_ordered_range(ordered, pos, batch_len, batch);
+    for (uint32_t j = 0; j < batch_len; j++) {
+      doc_id_t document = batch[j];
+      // Skip documents whose group already has a higher ranked document.
+      if (!roaring_bitmap_add_checked(seen_groups, query->group_ids[document])) {
+        continue;
+      }
+      if (collapsed_rank >= query->first_rank) {
+        if (count == MAX_RESULTS) {
+          more = true;
+          break;
+        }
+        results->documents[count++] = document;
+      }
+      collapsed_rank++;
+    }
+  }
+  results->continuation = more ? (int32_t) (query->first_rank + count) : -1;
+  results->count = count;
+}
+
 // Function to be called from JS that executes a query. May return NULL if an error occurred.
 WASM_EXPORT results_t* index_query(index_query_t* query) {
   // Portable deserialisation method is used as the source code for croaring-rs seems to use the portable serialisation method.
-  roaring_bitmap_t* result_bitmap = NULL;
   size_t i = 0;
 
   // REQUIRE.
-  while (query->serialised[i]) {
-    size_t serialised_size = query->serialised[i];
-    char const* serialised = (char const*) query->serialised[i + 1];
-    roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize_safe(serialised, serialised_size);
-    if (result_bitmap == NULL) result_bitmap = bitmap;
-    else roaring_bitmap_and_inplace(result_bitmap, bitmap);
-    i += 2;
+  roaring_bitmap_t* result_bitmap = index_deserialise_and_intersect(query->serialised, &i);
+  // Nothing else can add documents back, so skip the remaining work.
+  if (result_bitmap != NULL && roaring_bitmap_is_empty(result_bitmap)) {
+    return index_empty_results();
   }
-  i++;
 
   // CONTAIN.
   // Repurpose query data array for storing pointers to deserialised bitmaps.
@@ -96,7 +575,54 @@ WASM_EXPORT results_t* index_query(index_query_t* query) {
 
   // EXCLUDE.
   // Repurpose query data array for storing pointers to deserialised bitmaps.
+  // This is applied after groups, as exclusion must be last.
   roaring_bitmap_t* exclude_bitmaps_combined = index_deserialise_and_combine((roaring_bitmap_t**) &query->serialised[i], query->serialised, &i);
+
+  // GROUPS.
+  // Repurpose query data array for storing pointers to the intersection of each group.
+  // This is safe as each group takes up at least three elements, so writes never overtake reads.
+  roaring_bitmap_t** group_bitmaps = (roaring_bitmap_t**) &query->serialised[i];
+  size_t group_count = 0;
+  while (query->serialised[i]) {
+    group_bitmaps[group_count] = index_deserialise_and_intersect(query->serialised, &i);
+    group_count++;
+  }
+  i++;
+  if (group_count) {
+    roaring_bitmap_t* groups_combined = roaring_bitmap_or_many(group_count, (roaring_bitmap_t const**) group_bitmaps);
+    if (result_bitmap == NULL) result_bitmap = groups_combined;
+    else roaring_bitmap_and_inplace(result_bitmap, groups_combined);
+  }
+
+  // PREFIX.
+  // The expansions of the prefix term are alternatives, so a document needs only one, but it is then required like the other required terms.
+  roaring_bitmap_t* prefix_combined = index_combine_prefix_expansions(query);
+  if (prefix_combined != NULL) {
+    if (result_bitmap == NULL) result_bitmap = prefix_combined;
+    else roaring_bitmap_and_inplace(result_bitmap, prefix_combined);
+  }
+
+  // FILTERS.
+  // These narrow the results like required terms, but are a separate dataset so that they never match terms.
+  roaring_bitmap_t* filters_combined = index_intersect_filters(query);
+  if (filters_combined != NULL) {
+    if (result_bitmap == NULL) result_bitmap = filters_combined;
+    else roaring_bitmap_and_inplace(result_bitmap, filters_combined);
+  }
+
+  // SCOPE.
+  // Like filters, but the documents are provided by the client instead of stored.
+  roaring_bitmap_t* scope = index_create_scope(query);
+  if (scope != NULL) {
+    if (result_bitmap == NULL) result_bitmap = scope;
+    else roaring_bitmap_and_inplace(result_bitmap, scope);
+  }
+
+  // RANGES.
+  for (uint32_t r = 0; r < query->range_count; r++) {
+    result_bitmap = index_apply_range(&query->ranges[r], result_bitmap);
+  }
+
   if (exclude_bitmaps_combined != NULL) {
     if (result_bitmap == NULL) result_bitmap = exclude_bitmaps_combined;
     else roaring_bitmap_andnot_inplace(result_bitmap, exclude_bitmaps_combined);
@@ -106,20 +632,48 @@ WASM_EXPORT results_t* index_query(index_query_t* query) {
     return NULL;
   }
 
+  // Replace document IDs with their ranks, so that the bitmap is iterated in sort order.
+  if (query->sort_ranks != NULL) {
+    index_rank_iterator_state_t state = {
+      .ranks = query->sort_ranks,
+      .ranked = roaring_bitmap_create(),
+    };
+    roaring_iterate(result_bitmap, index_rank_iterator, &state);
+    result_bitmap = state.ranked;
+  }
+
   uint64_t doc_count = roaring_bitmap_get_cardinality(result_bitmap);
   results_t* results = malloc(sizeof(results_t));
 
   uint32_t first_rank = query->first_rank;
 
+  index_ordered_t ordered = {
+    .query = query,
+    .bitmap = result_bitmap,
+    .count = (uint32_t) doc_count,
+    .boosted = NULL,
+    .boosted_count = 0,
+    .scored = NULL,
+  };
+  // Boosting by proximity then reorders the most relevant matches. Sorted results are never ranked, as a sort key should be followed exactly.
+  if (query->bm25_table != NULL && query->sort_ranks == NULL) {
+    index_rank_bm25(&ordered);
+  }
+  if (query->proximity_scan_limit) {
+    index_boost_proximity(&ordered);
+  }
+
   // TODO Should we worry about this unchecked cast?
   results->total = (uint32_t) doc_count;
-  if (first_rank >= doc_count) {
+  if (query->group_ids != NULL) {
+    index_collapse_groups(&ordered, results);
+  } else if (first_rank >= doc_count) {
     results->continuation = -1;
     results->count = 0;
   } else {
     uint32_t last_rank = min(doc_count - 1, first_rank + MAX_RESULTS - 1);
     uint32_t count = last_rank + 1 - first_rank;
-    roaring_bitmap_range_uint32_array(result_bitmap, first_rank, count, results->documents);
+    index_ordered_range(&ordered, first_rank, count, results->documents);
     results->continuation = last_rank == doc_count - 1 ? -1 : last_rank + 1;
     results->count = count;
   }
@@ -45,6 +45,8 @@ void _wasm_import_error(void* args_ptr);
 extern byte __heap_base;
 // Must be initialised by reset() before use.
 byte* heap = NULL;
+// Where reset() rewinds the heap to. Allocations below it are kept for the lifetime of the instance.
+byte* heap_start = &__heap_base;
 byte* last_alloc = NULL;
 // Basic allocator that bumps downwards. Stores bytes allocated in word before allocation for realloc().
 // Each query simply resets the bump allocation offset so we are not concerned about moving and freeing memory during execution.
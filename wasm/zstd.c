@@ -0,0 +1,39 @@
+// Decompression of documents compressed against a trained zstd dictionary.
+// Only compiled in when documents are compressed with zstd, after the zstd single-file decoder (zstddeclib.c).
+// Keep in sync with decodeDocuments in script/src/main.ts.
+
+typedef struct {
+  uint32_t len;
+  byte* ptr;
+} decompressed_document_t;
+
+// Loaded once per instance, and kept across every reset().
+ZSTD_DDict* document_dictionary = NULL;
+ZSTD_DCtx* document_dctx = NULL;
+
+// Must be called straight after reset(), as everything allocated since then is kept, including the copy of `dict`.
+WASM_EXPORT bool load_document_dictionary(byte const* dict, uint32_t dict_len) {
+  document_dictionary = ZSTD_createDDict(dict, dict_len);
+  document_dctx = ZSTD_createDCtx();
+  if (document_dictionary == NULL || document_dctx == NULL) {
+    return false;
+  }
+  heap_start = heap;
+  return true;
+}
+
+// Returns NULL if the document is not a valid zstd frame with a known content size.
+WASM_EXPORT decompressed_document_t* decompress_document(byte const* src, uint32_t src_len) {
+  unsigned long long content_size = ZSTD_getFrameContentSize(src, src_len);
+  if (content_size == ZSTD_CONTENTSIZE_UNKNOWN || content_size == ZSTD_CONTENTSIZE_ERROR) {
+    return NULL;
+  }
+  decompressed_document_t* out = malloc(sizeof(decompressed_document_t));
+  out->ptr = malloc(content_size);
+  size_t len = ZSTD_decompress_usingDDict(document_dctx, out->ptr, content_size, src, src_len, document_dictionary);
+  if (ZSTD_isError(len)) {
+    return NULL;
+  }
+  out->len = len;
+  return out;
+}